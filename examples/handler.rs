@@ -0,0 +1,40 @@
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate afterparty_ng;
+extern crate env_logger;
+extern crate serde_json;
+
+use afterparty_ng::{Delivery, Hub};
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// counts pushes and newly opened pull requests
+#[derive(Default, WebhookHandler)]
+#[hook(event = "push", method = "on_push")]
+#[hook(event = "pull_request", action = "opened", method = "on_pr")]
+struct Counter {
+    pushes: AtomicUsize,
+    pulls: AtomicUsize,
+}
+
+impl Counter {
+    fn on_push(&self, delivery: &Delivery, _: Value) {
+        let pushes = self.pushes.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("delivery {} is push {}", delivery.id, pushes)
+    }
+
+    fn on_pr(&self, delivery: &Delivery, _: Value) {
+        let pulls = self.pulls.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("delivery {} is pull request {}", delivery.id, pulls)
+    }
+}
+
+pub fn main() {
+    env_logger::init();
+    let mut hub = Hub::new();
+    hub.handle("*", Counter::default());
+    let handle = hub.run("0.0.0.0:4567").unwrap();
+    info!("listening on {}", handle.addr());
+    handle.join();
+}
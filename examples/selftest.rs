@@ -0,0 +1,23 @@
+//! smoke tests a deployed hub
+//!
+//! `cargo run --example selftest -- https://hooks.example.com/ secret`
+
+extern crate afterparty_ng;
+
+use std::env;
+use std::process;
+
+pub fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() != 2 {
+        eprintln!("usage: selftest <url> <secret>");
+        process::exit(2);
+    }
+    match afterparty_ng::selftest(&args[0], &args[1]) {
+        Ok(()) => println!("{} verified a signed ping", args[0]),
+        Err(err) => {
+            eprintln!("self test failed: {}", err);
+            process::exit(1);
+        }
+    }
+}
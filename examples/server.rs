@@ -14,8 +14,8 @@ pub fn main() {
     let mut hub = Hub::new();
     hub.handle("pull_request", |delivery: &Delivery| {
         info!("rec delivery {:#?}", delivery);
-        /*match delivery.payload {
-            Event::PullRequest { ref action, ref sender, .. } => {
+        /*match delivery.payload() {
+            Some(&Event::PullRequest { ref action, ref sender, .. }) => {
                 info!("sender {} action {}", sender.login, action)
             }
             _ => (),
@@ -0,0 +1,29 @@
+#[macro_use]
+extern crate log;
+extern crate afterparty_ng;
+extern crate env_logger;
+extern crate serde_json;
+
+use afterparty_ng::{webhook, Delivery, Hub};
+use serde_json::Value;
+
+/// greets the authors of newly opened pull requests
+#[webhook(event = "pull_request", action = "opened")]
+fn on_pr(delivery: &Delivery, pr: Value) {
+    info!(
+        "delivery {} opened pull request {}",
+        delivery.id,
+        pr.pointer("/pull_request/title")
+            .and_then(Value::as_str)
+            .unwrap_or("")
+    )
+}
+
+pub fn main() {
+    env_logger::init();
+    let mut hub = Hub::new();
+    hub.register(on_pr);
+    let handle = hub.run("0.0.0.0:4567").unwrap();
+    info!("listening on {}", handle.addr());
+    handle.join();
+}
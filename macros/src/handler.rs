@@ -0,0 +1,140 @@
+use proc_macro2::TokenStream;
+use syn::{DeriveInput, Error, Ident, Lit, Meta, NestedMeta, Result};
+
+/// a method handling deliveries of an event, and optionally action
+#[derive(Debug, PartialEq)]
+struct Route {
+    event: String,
+    action: Option<String>,
+    method: Ident,
+}
+
+fn route(meta: Meta) -> Result<Route> {
+    let list = match meta {
+        Meta::List(list) => list,
+        other => {
+            return Err(Error::new_spanned(
+                other,
+                "expected `#[hook(event = \"...\", method = \"...\")]`",
+            ))
+        }
+    };
+    let span = list.path.clone();
+    let mut event = None;
+    let mut action = None;
+    let mut method = None;
+    for nested in list.nested {
+        match nested {
+            NestedMeta::Meta(Meta::NameValue(pair)) => {
+                let value = match pair.lit {
+                    Lit::Str(ref value) => value.clone(),
+                    ref lit => return Err(Error::new_spanned(lit, "expected a string")),
+                };
+                if pair.path.is_ident("event") {
+                    event = Some(value.value())
+                } else if pair.path.is_ident("action") {
+                    action = Some(value.value())
+                } else if pair.path.is_ident("method") {
+                    method = Some(value.parse::<Ident>()?)
+                } else {
+                    return Err(Error::new_spanned(
+                        pair.path,
+                        "expected `event`, `action` or `method`",
+                    ));
+                }
+            }
+            other => return Err(Error::new_spanned(other, "expected `name = \"...\"`")),
+        }
+    }
+    match (event, method) {
+        (Some(event), Some(method)) => Ok(Route {
+            event,
+            action,
+            method,
+        }),
+        _ => Err(Error::new_spanned(
+            span,
+            "hooks need both an `event` and a `method`",
+        )),
+    }
+}
+
+/// implements `Hook` for a struct, handing deliveries to the methods its
+/// `#[hook(...)]` attributes name for their event
+pub fn expand(input: DeriveInput) -> Result<TokenStream> {
+    let routes = input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("hook"))
+        .map(|attr| attr.parse_meta().and_then(route))
+        .collect::<Result<Vec<_>>>()?;
+    if routes.is_empty() {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "expected at least one `#[hook(event = \"...\", method = \"...\")]` attribute",
+        ));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let action = if routes.iter().any(|route| route.action.is_some()) {
+        Some(quote!(let action = ::afterparty_ng::codegen::action(delivery);))
+    } else {
+        None
+    };
+    let dispatch = routes.iter().map(|route| {
+        let event = &route.event;
+        let method = &route.method;
+        let action = route
+            .action
+            .as_ref()
+            .map(|action| quote!(&& action.as_ref().map(String::as_str) == Some(#action)));
+        quote! {
+            if delivery.event == #event #action {
+                if let Some(payload) = ::afterparty_ng::codegen::payload(delivery) {
+                    self.#method(delivery, payload)
+                }
+            }
+        }
+    });
+    Ok(quote! {
+        impl #impl_generics ::afterparty_ng::Hook for #name #ty_generics #where_clause {
+            fn handle(&self, delivery: &::afterparty_ng::Delivery) {
+                #action
+                #(#dispatch)*
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_handlers() {
+        let input = syn::parse2::<DeriveInput>(quote! {
+            #[hook(event = "push", method = "on_push")]
+            #[hook(event = "pull_request", action = "opened", method = "on_pr")]
+            struct Deployer<P> {
+                pool: P,
+            }
+        })
+        .unwrap();
+        let expanded = expand(input).unwrap().to_string();
+        assert!(expanded.contains("Hook for Deployer < P >"));
+        assert!(expanded.contains("self . on_push ( delivery , payload )"));
+        assert!(expanded.contains("codegen :: action"));
+
+        let missing = syn::parse2::<DeriveInput>(quote! {
+            #[hook(event = "push")]
+            struct Deployer;
+        })
+        .unwrap();
+        assert!(expand(missing).is_err());
+        let unannotated = syn::parse2::<DeriveInput>(quote!(
+            struct Deployer;
+        ))
+        .unwrap();
+        assert!(expand(unannotated).is_err());
+    }
+}
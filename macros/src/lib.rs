@@ -0,0 +1,66 @@
+//! Macros generating afterparty-ng hooks from handler functions.
+//!
+//! These are re-exported by afterparty-ng with its `macros` feature enabled
+//! and expect it to be available as `afterparty_ng`
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate syn;
+
+mod handler;
+mod webhook;
+
+use proc_macro::TokenStream;
+use syn::{AttributeArgs, DeriveInput, ItemFn};
+
+/// Turns a function into a hook which may be registered with `Hub::register`.
+///
+/// `event` names the event handled and `action`, optionally, the payload's action.
+/// arguments typed `&Delivery` receive the delivery and all others are
+/// deserialized from its payload
+///
+/// ```ignore
+/// #[webhook(event = "pull_request", action = "opened")]
+/// fn on_pr(pr: PullRequestEvent) { }
+///
+/// hub.register(on_pr);
+/// ```
+#[proc_macro_attribute]
+pub fn webhook(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let item = parse_macro_input!(item as ItemFn);
+    webhook::expand(args, item)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Implements `Hook` for a struct, handing deliveries to its methods.
+///
+/// each `#[hook(event = "...", method = "...")]` attribute, optionally with an
+/// `action`, names a method taking the delivery and its payload, deserialized
+/// into the method's argument type. register the struct for every event with
+/// `hub.handle("*", handler)`
+///
+/// ```ignore
+/// #[derive(WebhookHandler)]
+/// #[hook(event = "push", method = "on_push")]
+/// #[hook(event = "pull_request", action = "opened", method = "on_pr")]
+/// struct Deployer {
+///     pool: Pool,
+/// }
+///
+/// impl Deployer {
+///     fn on_push(&self, delivery: &Delivery, push: PushEvent) { }
+///     fn on_pr(&self, delivery: &Delivery, pr: PullRequestEvent) { }
+/// }
+/// ```
+#[proc_macro_derive(WebhookHandler, attributes(hook))]
+pub fn webhook_handler(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    handler::expand(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
@@ -0,0 +1,165 @@
+use proc_macro2::{Span, TokenStream};
+use syn::{AttributeArgs, Error, FnArg, ItemFn, Lit, Meta, NestedMeta, Result, Type};
+
+/// the event, and optionally action, a webhook function handles
+#[derive(Debug, PartialEq)]
+struct Target {
+    event: String,
+    action: Option<String>,
+}
+
+fn target(args: AttributeArgs) -> Result<Target> {
+    let mut event = None;
+    let mut action = None;
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(ref pair)) if pair.path.is_ident("event") => {
+                event = Some(string(&pair.lit)?)
+            }
+            NestedMeta::Meta(Meta::NameValue(ref pair)) if pair.path.is_ident("action") => {
+                action = Some(string(&pair.lit)?)
+            }
+            other => {
+                return Err(Error::new_spanned(
+                    other,
+                    "expected `event = \"...\"` or `action = \"...\"`",
+                ))
+            }
+        }
+    }
+    match event {
+        Some(event) => Ok(Target { event, action }),
+        None => Err(Error::new(
+            Span::call_site(),
+            "missing the handled `event = \"...\"`",
+        )),
+    }
+}
+
+fn string(lit: &Lit) -> Result<String> {
+    match *lit {
+        Lit::Str(ref value) => Ok(value.value()),
+        _ => Err(Error::new_spanned(lit, "expected a string")),
+    }
+}
+
+/// true for `&Delivery` arguments, which are handed the delivery itself
+fn is_delivery(ty: &Type) -> bool {
+    if let Type::Reference(ref reference) = *ty {
+        if let Type::Path(ref path) = *reference.elem {
+            return path
+                .path
+                .segments
+                .last()
+                .map(|segment| segment.ident == "Delivery")
+                .unwrap_or(false);
+        }
+    }
+    false
+}
+
+fn argument(input: &FnArg) -> Result<TokenStream> {
+    match *input {
+        FnArg::Typed(ref arg) if is_delivery(&arg.ty) => Ok(quote!(delivery)),
+        FnArg::Typed(ref arg) => {
+            let ty = &arg.ty;
+            Ok(quote! {
+                match ::afterparty_ng::codegen::payload::<#ty>(delivery) {
+                    Some(payload) => payload,
+                    None => return,
+                }
+            })
+        }
+        FnArg::Receiver(ref receiver) => Err(Error::new_spanned(
+            receiver,
+            "webhook functions can't take self",
+        )),
+    }
+}
+
+/// replaces a function with a unit struct of the same name implementing
+/// `Hook` and `Webhook`, which calls the function when handling deliveries
+pub fn expand(args: AttributeArgs, item: ItemFn) -> Result<TokenStream> {
+    let target = target(args)?;
+    let name = &item.sig.ident;
+    let vis = &item.vis;
+    let docs = item.attrs.iter().filter(|attr| attr.path.is_ident("doc"));
+    let event = &target.event;
+    let arguments = item
+        .sig
+        .inputs
+        .iter()
+        .map(argument)
+        .collect::<Result<Vec<_>>>()?;
+    let action = target.action.as_ref().map(|action| {
+        quote! {
+            if ::afterparty_ng::codegen::action(delivery).as_ref().map(String::as_str) != Some(#action) {
+                return;
+            }
+        }
+    });
+    Ok(quote! {
+        #(#docs)*
+        #[allow(non_camel_case_types)]
+        #vis struct #name;
+
+        impl ::afterparty_ng::Hook for #name {
+            fn handle(&self, delivery: &::afterparty_ng::Delivery) {
+                #item
+                #action
+                #name(#(#arguments),*)
+            }
+        }
+
+        impl ::afterparty_ng::Webhook for #name {
+            fn event(&self) -> &str {
+                #event
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse::Parser;
+    use syn::punctuated::Punctuated;
+
+    fn args(tokens: TokenStream) -> AttributeArgs {
+        Punctuated::<NestedMeta, Token![,]>::parse_terminated
+            .parse2(tokens)
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn webhook_functions() {
+        assert_eq!(
+            Target {
+                event: "pull_request".to_owned(),
+                action: Some("opened".to_owned()),
+            },
+            target(args(quote!(event = "pull_request", action = "opened"))).unwrap()
+        );
+        assert!(target(args(quote!(action = "opened"))).is_err());
+        assert!(target(args(quote!(event = 1))).is_err());
+
+        let item = syn::parse2::<ItemFn>(quote! {
+            pub fn on_pr(delivery: &Delivery, pr: PullRequest) {}
+        })
+        .unwrap();
+        let expanded = expand(args(quote!(event = "pull_request")), item)
+            .unwrap()
+            .to_string();
+        assert!(expanded.contains("pub struct on_pr"));
+        assert!(expanded.contains("codegen :: payload :: < PullRequest >"));
+        assert!(!expanded.contains("codegen :: action"));
+
+        let method = syn::parse2::<ItemFn>(quote! {
+            fn on_pr(&self) {}
+        })
+        .unwrap();
+        assert!(expand(args(quote!(event = "pull_request")), method).is_err());
+    }
+}
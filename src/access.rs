@@ -0,0 +1,159 @@
+//! Access logs of the requests a hub serves
+//!
+//! With an access log enabled a hub logs one line per request, once it's
+//! been responded to, to the `afterparty::access` target, so operators may
+//! route them apart from what hooks log about deliveries
+
+use super::{clock, Hub};
+use hyper::server::Request;
+use hyper::status::StatusCode;
+use serde_json::{self, Value};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Formats access log lines are written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLog {
+    /// Apache's combined log format, followed by the request's latency
+    /// in seconds, delivery id and event, `-` when it has none
+    Combined,
+    /// a json object per line
+    Json,
+}
+
+/// What's logged about a request, gathered before it's handled
+pub struct Access {
+    time: SystemTime,
+    started: Instant,
+    remote_addr: SocketAddr,
+    request_line: (String, String, String),
+    referer: Option<String>,
+    user_agent: Option<String>,
+    delivery: Option<String>,
+    event: Option<String>,
+}
+
+impl Access {
+    pub fn new(req: &Request) -> Access {
+        let header = |name: &str| {
+            req.headers
+                .get_raw(name)
+                .and_then(|values| values.first())
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        };
+        Access {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            remote_addr: req.remote_addr,
+            request_line: (
+                req.method.to_string(),
+                req.uri.to_string(),
+                req.version.to_string(),
+            ),
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+            delivery: header("X-Github-Delivery"),
+            event: header("X-Github-Event"),
+        }
+    }
+
+    /// the line logged about the request once it's been responded to
+    pub fn line(&self, format: AccessLog, status: StatusCode) -> String {
+        let latency = self.started.elapsed();
+        match format {
+            AccessLog::Combined => self.combined(status, latency),
+            AccessLog::Json => self.json(status, latency),
+        }
+    }
+
+    fn combined(&self, status: StatusCode, latency: Duration) -> String {
+        let (ref method, ref path, ref version) = self.request_line;
+        let field = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_owned());
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} - \"{}\" \"{}\" {:.3} {} {}",
+            self.remote_addr.ip(),
+            clock::clf(self.time),
+            method,
+            path,
+            version,
+            status.to_u16(),
+            field(&self.referer).replace('"', "\\\""),
+            field(&self.user_agent).replace('"', "\\\""),
+            seconds(latency),
+            field(&self.delivery),
+            field(&self.event)
+        )
+    }
+
+    fn json(&self, status: StatusCode, latency: Duration) -> String {
+        let (ref method, ref path, _) = self.request_line;
+        let text = |value: &Option<String>| {
+            value
+                .as_ref()
+                .map(|value| Value::String(value.clone()))
+                .unwrap_or(Value::Null)
+        };
+        let mut line = serde_json::Map::new();
+        line.insert("time".to_owned(), Value::String(clock::rfc3339(self.time)));
+        line.insert(
+            "remote_addr".to_owned(),
+            Value::String(self.remote_addr.ip().to_string()),
+        );
+        line.insert("method".to_owned(), Value::String(method.clone()));
+        line.insert("path".to_owned(), Value::String(path.clone()));
+        line.insert("status".to_owned(), Value::from(status.to_u16()));
+        line.insert("latency".to_owned(), Value::from(seconds(latency)));
+        line.insert("delivery".to_owned(), text(&self.delivery));
+        line.insert("event".to_owned(), text(&self.event));
+        line.insert("user_agent".to_owned(), text(&self.user_agent));
+        serde_json::to_string(&Value::Object(line)).unwrap_or_default()
+    }
+}
+
+fn seconds(latency: Duration) -> f64 {
+    latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) / 1e9
+}
+
+impl Hub {
+    /// logs a line in `format` about every request the hub serves
+    /// to the `afterparty::access` target
+    pub fn access_log(&mut self, format: AccessLog) {
+        self.access_log = Some(format);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn access_log_lines() {
+        let access = Access {
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            started: Instant::now(),
+            remote_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 4711),
+            request_line: (
+                "POST".to_owned(),
+                "/hooks".to_owned(),
+                "HTTP/1.1".to_owned(),
+            ),
+            referer: None,
+            user_agent: Some("GitHub-Hookshot/044aadd".to_owned()),
+            delivery: Some("72d3162e".to_owned()),
+            event: Some("push".to_owned()),
+        };
+        let combined = access.line(AccessLog::Combined, StatusCode::Ok);
+        assert!(combined.starts_with(
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"POST /hooks HTTP/1.1\" 200 - \"-\" \"GitHub-Hookshot/044aadd\" "
+        ));
+        assert!(combined.ends_with(" 72d3162e push"));
+
+        let json: Value =
+            serde_json::from_str(&access.line(AccessLog::Json, StatusCode::BadRequest)).unwrap();
+        assert_eq!(Some(400), json.get("status").and_then(Value::as_u64));
+        assert_eq!(Some("/hooks"), json.get("path").and_then(Value::as_str));
+        assert_eq!(Some("push"), json.get("event").and_then(Value::as_str));
+    }
+}
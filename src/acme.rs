@@ -0,0 +1,726 @@
+//! Certificates obtained and renewed automatically over ACME
+//!
+//! An `Acme` value orders a certificate for a hub's hostnames from an ACME
+//! certificate authority, Let's Encrypt by default, proving control of them
+//! with `http-01` challenges answered on port 80. Certificates and keys are
+//! kept in a directory so restarts reuse them, and are renewed 30 days
+//! before they expire. Certificate authorities only issue certificates to
+//! accounts agreeing to their terms of service, which operators have to
+//! read and agree to with `Acme::agree_to_terms`
+//!
+//! ```no_run
+//! # extern crate afterparty_ng;
+//! # extern crate hyper;
+//! # use afterparty_ng::Hub;
+//! # use afterparty_ng::acme::Acme;
+//! # use hyper::Server;
+//! # let hub = Hub::new();
+//! let acme = Acme::new("hooks.example.com", "/var/lib/afterparty")
+//!     .contact("ops@example.com")
+//!     .agree_to_terms();
+//! let _challenges = acme.serve_challenges("0.0.0.0:80").unwrap();
+//! let tls = acme.tls().unwrap();
+//! Server::https("0.0.0.0:443", tls).unwrap().handle(hub).unwrap();
+//! ```
+
+use super::tls::{Certificates, Tls, TlsClient};
+use base64;
+use hyper;
+use hyper::header::{Headers, Location, UserAgent};
+use hyper::net::HttpsConnector;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use hyper::Client;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Req, X509};
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Let's Encrypt's production directory
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Let's Encrypt's staging directory, for trying deployments out
+/// without running into the production rate limits
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+const USER_AGENT: &str = concat!("afterparty/", env!("CARGO_PKG_VERSION"));
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// certificates expiring within this many days are renewed
+const RENEW_WITHIN_DAYS: u32 = 30;
+
+/// how often `tls` checks whether the certificate is due for renewal
+const RENEWAL_CHECKS: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// how soon `tls` tries again when obtaining a certificate failed
+const RENEWAL_RETRIES: Duration = Duration::from_secs(60 * 60);
+
+/// number of times pending authorizations and orders are polled
+const POLLS: usize = 30;
+
+/// Reasons a certificate couldn't be obtained
+#[derive(Debug)]
+pub enum AcmeError {
+    /// the request to the certificate authority failed
+    Http(hyper::Error),
+    /// the certificate authority responded with an error status and problem
+    Status(StatusCode, String),
+    /// the certificate authority's response wasn't as expected
+    Protocol(String),
+    /// keys or certificates couldn't be generated or loaded
+    Tls(ErrorStack),
+    /// keys or certificates couldn't be read or written
+    Io(io::Error),
+    /// the certificate authority's terms of service weren't agreed to
+    TermsNotAgreed,
+}
+
+impl fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AcmeError::Http(ref err) => write!(f, "request failed: {}", err),
+            AcmeError::Status(status, ref problem) => {
+                write!(f, "certificate authority responded {}: {}", status, problem)
+            }
+            AcmeError::Protocol(ref message) => write!(f, "unexpected response: {}", message),
+            AcmeError::Tls(ref err) => err.fmt(f),
+            AcmeError::Io(ref err) => err.fmt(f),
+            AcmeError::TermsNotAgreed => {
+                f.write_str("the certificate authority's terms of service weren't agreed to")
+            }
+        }
+    }
+}
+
+impl Error for AcmeError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            AcmeError::Http(ref err) => Some(err),
+            AcmeError::Tls(ref err) => Some(err),
+            AcmeError::Io(ref err) => Some(err),
+            AcmeError::Status(..) | AcmeError::Protocol(_) | AcmeError::TermsNotAgreed => None,
+        }
+    }
+}
+
+impl From<hyper::Error> for AcmeError {
+    fn from(err: hyper::Error) -> AcmeError {
+        AcmeError::Http(err)
+    }
+}
+
+impl From<ErrorStack> for AcmeError {
+    fn from(err: ErrorStack) -> AcmeError {
+        AcmeError::Tls(err)
+    }
+}
+
+impl From<io::Error> for AcmeError {
+    fn from(err: io::Error) -> AcmeError {
+        AcmeError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for AcmeError {
+    fn from(err: serde_json::Error) -> AcmeError {
+        AcmeError::Protocol(err.to_string())
+    }
+}
+
+/// key authorizations of pending challenges, by token
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+/// The certificate of a set of hostnames, ordered from an ACME directory
+pub struct Acme {
+    hosts: Vec<String>,
+    dir: PathBuf,
+    directory: String,
+    contact: Vec<String>,
+    agreed: bool,
+    challenges: Challenges,
+}
+
+impl Acme {
+    /// orders a certificate for `host` from Let's Encrypt, keeping
+    /// it and the keys involved in the directory `dir`
+    pub fn new<H, D>(host: H, dir: D) -> Acme
+    where
+        H: Into<String>,
+        D: AsRef<Path>,
+    {
+        Acme {
+            hosts: vec![host.into()],
+            dir: dir.as_ref().to_owned(),
+            directory: LETS_ENCRYPT.to_owned(),
+            contact: Vec::new(),
+            agreed: false,
+            challenges: Challenges::default(),
+        }
+    }
+
+    /// covers another hostname with the same certificate
+    pub fn host<H: Into<String>>(mut self, host: H) -> Acme {
+        self.hosts.push(host.into());
+        self
+    }
+
+    /// an email address the certificate authority may send
+    /// expiry and policy notices to
+    pub fn contact<E: AsRef<str>>(mut self, email: E) -> Acme {
+        self.contact.push(format!("mailto:{}", email.as_ref()));
+        self
+    }
+
+    /// agrees to the certificate authority's terms of service, e.g. Let's
+    /// Encrypt's subscriber agreement, without which no certificate is
+    /// ordered. they're linked from the directory's `meta.termsOfService`
+    pub fn agree_to_terms(mut self) -> Acme {
+        self.agreed = true;
+        self
+    }
+
+    /// orders from another ACME directory, e.g. `LETS_ENCRYPT_STAGING`
+    pub fn directory<U: Into<String>>(mut self, url: U) -> Acme {
+        self.directory = url.into();
+        self
+    }
+
+    /// where the PEM encoded certificate chain is kept
+    pub fn cert_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.crt", self.hosts[0]))
+    }
+
+    /// where the certificate's PEM encoded private key is kept
+    pub fn key_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.key", self.hosts[0]))
+    }
+
+    /// answers the certificate authority's `http-01` challenges on `addr`,
+    /// which it reaches on port 80 of each of the hostnames
+    pub fn serve_challenges<A: ToSocketAddrs>(&self, addr: A) -> hyper::Result<Listening> {
+        Server::http(addr)?.handle(Responder(self.challenges.clone()))
+    }
+
+    /// orders a certificate unless the one already kept is valid for
+    /// more than 30 days, returning whether one was issued. fails
+    /// without ordering one unless the terms of service were agreed to
+    pub fn obtain(&self) -> Result<bool, AcmeError> {
+        if !expiring(&self.cert_path(), RENEW_WITHIN_DAYS) {
+            return Ok(false);
+        }
+        if !self.agreed {
+            return Err(AcmeError::TermsNotAgreed);
+        }
+        fs::create_dir_all(&self.dir)?;
+        info!("ordering a certificate for {}", self.hosts.join(", "));
+        let mut session = Session::new(self)?;
+        session.register(&self.contact)?;
+        let (key, chain) = session.order(&self.hosts, &self.challenges)?;
+        // the key first, so the certificate's files never mismatch
+        // for longer than it takes to write the certificate
+        write(&self.key_path(), &key.private_key_to_pem_pkcs8()?, true)?;
+        write(&self.cert_path(), chain.as_bytes(), false)?;
+        info!("obtained a certificate for {}", self.hosts.join(", "));
+        Ok(true)
+    }
+
+    /// obtains a certificate and serves it, renewing it from a background
+    /// thread which checks twice a day whether renewal is due.
+    /// renewed certificates are served without a restart. when no
+    /// certificate can be obtained, one kept which hasn't expired yet
+    /// is served meanwhile, and obtaining one tried again hourly. hubs
+    /// dropping privileges should name its directory with `ServerConfig::writes`
+    pub fn tls(self) -> Result<Tls, AcmeError> {
+        let mut wait = RENEWAL_CHECKS;
+        if let Err(err) = self.obtain() {
+            if expiring(&self.cert_path(), 0) {
+                return Err(err);
+            }
+            error!("failed to renew certificate, serving the one kept: {}", err);
+            wait = RENEWAL_RETRIES;
+        }
+        let tls = Tls::sni(Certificates::new(self.cert_path(), self.key_path())?)?;
+        thread::Builder::new()
+            .name("afterparty-acme".to_owned())
+            .spawn(move || loop {
+                thread::sleep(wait);
+                wait = match self.obtain() {
+                    Ok(_) => RENEWAL_CHECKS,
+                    Err(err) => {
+                        error!("failed to renew certificate: {}", err);
+                        RENEWAL_RETRIES
+                    }
+                };
+            })?;
+        Ok(tls)
+    }
+}
+
+/// answers challenges with their key authorizations
+struct Responder(Challenges);
+
+impl Handler for Responder {
+    fn handle(&self, req: Request, mut res: Response) {
+        let authorization = match req.uri {
+            RequestUri::AbsolutePath(ref path) if path.starts_with(CHALLENGE_PATH) => self
+                .0
+                .lock()
+                .unwrap()
+                .get(&path[CHALLENGE_PATH.len()..])
+                .cloned(),
+            _ => None,
+        };
+        match authorization {
+            Some(authorization) => {
+                let _ = res.send(authorization.as_bytes());
+            }
+            None => *res.status_mut() = StatusCode::NotFound,
+        }
+    }
+}
+
+header! {(ReplayNonce, "Replay-Nonce") => [String]}
+
+/// An account's conversation with a certificate authority
+struct Session {
+    client: Client,
+    key: EcKey<Private>,
+    jwk: Value,
+    thumbprint: String,
+    directory: Value,
+    nonce: Option<String>,
+    account: Option<String>,
+}
+
+impl Session {
+    fn new(acme: &Acme) -> Result<Session, AcmeError> {
+        let tls = TlsClient::new()?;
+        let client = Client::with_connector(HttpsConnector::new(tls));
+        let key = account_key(&acme.dir.join("account.key"))?;
+        let (jwk, thumbprint) = jwk(&key)?;
+        let mut response = client
+            .get(&acme.directory)
+            .header(UserAgent(USER_AGENT.to_owned()))
+            .send()?;
+        let directory = serde_json::from_str(&body(&mut response)?)?;
+        Ok(Session {
+            client: client,
+            key: key,
+            jwk: jwk,
+            thumbprint: thumbprint,
+            directory: directory,
+            nonce: None,
+            account: None,
+        })
+    }
+
+    fn endpoint(&self, name: &str) -> Result<String, AcmeError> {
+        self.directory
+            .get(name)
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+            .ok_or_else(|| AcmeError::Protocol(format!("directory without {}", name)))
+    }
+
+    /// creates the account, or finds the one the account key already has
+    fn register(&mut self, contact: &[String]) -> Result<(), AcmeError> {
+        let url = self.endpoint("newAccount")?;
+        let mut account = serde_json::Map::new();
+        // only ordered from once the operator agreed, see `Acme::agree_to_terms`
+        account.insert("termsOfServiceAgreed".to_owned(), Value::Bool(true));
+        account.insert(
+            "contact".to_owned(),
+            Value::Array(contact.iter().cloned().map(Value::String).collect()),
+        );
+        let (headers, _) = self.post(&url, Some(&Value::Object(account)))?;
+        self.account = Some(location(&headers)?);
+        Ok(())
+    }
+
+    /// orders a certificate, answering the challenges of its authorizations,
+    /// returning its key and PEM encoded chain
+    fn order(
+        &mut self,
+        hosts: &[String],
+        challenges: &Challenges,
+    ) -> Result<(PKey<Private>, String), AcmeError> {
+        let identifiers = hosts
+            .iter()
+            .map(|host| {
+                let mut identifier = serde_json::Map::new();
+                identifier.insert("type".to_owned(), Value::String("dns".to_owned()));
+                identifier.insert("value".to_owned(), Value::String(host.clone()));
+                Value::Object(identifier)
+            })
+            .collect();
+        let mut order = serde_json::Map::new();
+        order.insert("identifiers".to_owned(), Value::Array(identifiers));
+        let url = self.endpoint("newOrder")?;
+        let (headers, body) = self.post(&url, Some(&Value::Object(order)))?;
+        let order_url = location(&headers)?;
+        let order: Value = serde_json::from_str(&body)?;
+
+        let authorizations = order
+            .get("authorizations")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        for authorization in authorizations.iter().filter_map(Value::as_str) {
+            self.authorize(authorization, challenges)?;
+        }
+
+        let group = p256()?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let mut csr = serde_json::Map::new();
+        csr.insert(
+            "csr".to_owned(),
+            Value::String(encode(&request(&key, hosts)?.to_der()?)),
+        );
+        let finalize = field(&order, "finalize")?;
+        self.post(&finalize, Some(&Value::Object(csr)))?;
+        let order = self.poll(&order_url)?;
+        let (_, chain) = self.post(&field(&order, "certificate")?, None)?;
+        Ok((key, chain))
+    }
+
+    /// answers an authorization's `http-01` challenge and
+    /// waits for the certificate authority to validate it
+    fn authorize(&mut self, url: &str, challenges: &Challenges) -> Result<(), AcmeError> {
+        let (_, body) = self.post(url, None)?;
+        let authorization: Value = serde_json::from_str(&body)?;
+        if authorization.get("status").and_then(Value::as_str) == Some("valid") {
+            return Ok(());
+        }
+        let challenge = authorization
+            .get("challenges")
+            .and_then(Value::as_array)
+            .and_then(|challenges| {
+                challenges.iter().find(|challenge| {
+                    challenge.get("type").and_then(Value::as_str) == Some("http-01")
+                })
+            })
+            .ok_or_else(|| AcmeError::Protocol("no http-01 challenge offered".to_owned()))?;
+        let token = field(challenge, "token")?;
+        challenges
+            .lock()
+            .unwrap()
+            .insert(token.clone(), format!("{}.{}", token, self.thumbprint));
+        let validated = self
+            .post(
+                &field(challenge, "url")?,
+                Some(&Value::Object(serde_json::Map::new())),
+            )
+            .and_then(|_| self.poll(url));
+        challenges.lock().unwrap().remove(&token);
+        validated.map(|_| ())
+    }
+
+    /// polls an authorization or order until it's valid
+    fn poll(&mut self, url: &str) -> Result<Value, AcmeError> {
+        for _ in 0..POLLS {
+            let (_, body) = self.post(url, None)?;
+            let resource: Value = serde_json::from_str(&body)?;
+            match resource.get("status").and_then(Value::as_str) {
+                Some("valid") => return Ok(resource),
+                Some("pending") | Some("processing") | Some("ready") => {
+                    thread::sleep(Duration::from_secs(2))
+                }
+                _ => return Err(AcmeError::Protocol(format!("{} failed: {}", url, body))),
+            }
+        }
+        Err(AcmeError::Protocol(format!("{} still pending", url)))
+    }
+
+    /// posts a signed request, without a payload when `payload` is `None`,
+    /// retrying requests the certificate authority rejected for their nonce
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<(Headers, String), AcmeError> {
+        let payload = match payload {
+            Some(payload) => encode(serde_json::to_string(payload)?.as_bytes()),
+            None => String::new(),
+        };
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let nonce = self.nonce()?;
+            let jws = self.sign(url, &nonce, &payload)?;
+            let mut headers = Headers::new();
+            headers.set(UserAgent(USER_AGENT.to_owned()));
+            headers.set_raw("Content-Type", vec![b"application/jose+json".to_vec()]);
+            let mut response = self
+                .client
+                .post(url)
+                .headers(headers)
+                .body(&jws[..])
+                .send()?;
+            self.nonce = response
+                .headers
+                .get::<ReplayNonce>()
+                .map(|nonce| nonce.0.clone());
+            let text = body(&mut response)?;
+            if response.status.is_success() {
+                return Ok((response.headers.clone(), text));
+            }
+            if !(text.contains("urn:ietf:params:acme:error:badNonce") && attempts < 3) {
+                return Err(AcmeError::Status(response.status, text));
+            }
+        }
+    }
+
+    fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .client
+            .head(&self.endpoint("newNonce")?)
+            .header(UserAgent(USER_AGENT.to_owned()))
+            .send()?;
+        response
+            .headers
+            .get::<ReplayNonce>()
+            .map(|nonce| nonce.0.clone())
+            .ok_or_else(|| AcmeError::Protocol("no Replay-Nonce".to_owned()))
+    }
+
+    /// a flattened JWS of a payload, identifying the account by its key
+    /// until it's registered and by its url afterwards
+    fn sign(&self, url: &str, nonce: &str, payload: &str) -> Result<String, AcmeError> {
+        let mut protected = serde_json::Map::new();
+        protected.insert("alg".to_owned(), Value::String("ES256".to_owned()));
+        protected.insert("nonce".to_owned(), Value::String(nonce.to_owned()));
+        protected.insert("url".to_owned(), Value::String(url.to_owned()));
+        match self.account {
+            Some(ref account) => protected.insert("kid".to_owned(), Value::String(account.clone())),
+            None => protected.insert("jwk".to_owned(), self.jwk.clone()),
+        };
+        let protected = encode(serde_json::to_string(&Value::Object(protected))?.as_bytes());
+        let signature = es256(&self.key, format!("{}.{}", protected, payload).as_bytes())?;
+        let mut jws = serde_json::Map::new();
+        jws.insert("protected".to_owned(), Value::String(protected));
+        jws.insert("payload".to_owned(), Value::String(payload.to_owned()));
+        jws.insert("signature".to_owned(), Value::String(encode(&signature)));
+        Ok(serde_json::to_string(&Value::Object(jws))?)
+    }
+}
+
+fn body(response: &mut hyper::client::Response) -> Result<String, AcmeError> {
+    let mut text = String::new();
+    response.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+fn location(headers: &Headers) -> Result<String, AcmeError> {
+    headers
+        .get::<Location>()
+        .map(|location| location.0.clone())
+        .ok_or_else(|| AcmeError::Protocol("no Location".to_owned()))
+}
+
+fn field(resource: &Value, name: &str) -> Result<String, AcmeError> {
+    resource
+        .get(name)
+        .and_then(Value::as_str)
+        .map(str::to_owned)
+        .ok_or_else(|| AcmeError::Protocol(format!("no {} in {}", name, resource)))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn p256() -> Result<EcGroup, ErrorStack> {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+}
+
+/// the account key kept at `path`, generated when there's none yet
+fn account_key(path: &Path) -> Result<EcKey<Private>, AcmeError> {
+    match fs::read(path) {
+        Ok(pem) => Ok(EcKey::private_key_from_pem(&pem)?),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            let group = p256()?;
+            let key = EcKey::generate(&group)?;
+            write(path, &key.private_key_to_pem()?, true)?;
+            Ok(key)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// the public JSON web key of an account key and its thumbprint
+fn jwk(key: &EcKey<Private>) -> Result<(Value, String), ErrorStack> {
+    let (mut x, mut y, mut context) = (BigNum::new()?, BigNum::new()?, BigNumContext::new()?);
+    key.public_key()
+        .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut context)?;
+    let (x, y) = (encode(&padded(&x)), encode(&padded(&y)));
+    // members in lexicographic order, without whitespace, as thumbprints require
+    let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+    let thumbprint = encode(&hash(MessageDigest::sha256(), canonical.as_bytes())?);
+    let jwk = serde_json::from_str(&canonical).expect("json web key");
+    Ok((jwk, thumbprint))
+}
+
+/// a P-256 coordinate or signature component as its 32 big endian bytes
+fn padded(number: &BigNum) -> Vec<u8> {
+    let bytes = number.to_vec();
+    let mut padded = vec![0; 32usize.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+/// an ES256 signature, the concatenated components rather than DER
+fn es256(key: &EcKey<Private>, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let signature = EcdsaSig::sign(&hash(MessageDigest::sha256(), data)?, key)?;
+    let mut bytes = padded(&signature.r().to_owned()?);
+    bytes.extend_from_slice(&padded(&signature.s().to_owned()?));
+    Ok(bytes)
+}
+
+/// a certificate signing request covering `hosts`
+fn request(key: &PKey<Private>, hosts: &[String]) -> Result<X509Req, ErrorStack> {
+    let mut builder = X509Req::builder()?;
+    builder.set_pubkey(key)?;
+    let mut names = SubjectAlternativeName::new();
+    for host in hosts {
+        names.dns(host);
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(names.build(&builder.x509v3_context(None))?)?;
+    builder.add_extensions(&extensions)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+/// whether the certificate at `path` is missing, unreadable or
+/// expires within `days`
+fn expiring(path: &Path, days: u32) -> bool {
+    let expiring = |cert: X509| {
+        Asn1Time::days_from_now(days)
+            .map(|renewal| cert.not_after() < renewal)
+            .unwrap_or(true)
+    };
+    fs::read(path)
+        .ok()
+        .and_then(|pem| X509::from_pem(&pem).ok())
+        .map(expiring)
+        .unwrap_or(true)
+}
+
+/// replaces a file with `contents` at once, so certificates being
+/// reloaded are never read half written. keys are only readable
+/// by their owner
+fn write(path: &Path, contents: &[u8], private: bool) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options.open(&partial)?.write_all(contents)?;
+    fs::rename(&partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509NameBuilder;
+    use std::env;
+    use std::process;
+
+    fn certificate(days: u32) -> X509 {
+        let key = PKey::from_ec_key(EcKey::generate(&p256().unwrap()).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "hooks.example.com")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn acme_signing_and_renewal() {
+        let dir = env::temp_dir().join(format!("afterparty-acme-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let acme = Acme::new("hooks.example.com", &dir);
+
+        let key = account_key(&dir.join("account.key")).unwrap();
+        let reloaded = account_key(&dir.join("account.key")).unwrap();
+        assert_eq!(
+            key.private_key_to_pem().unwrap(),
+            reloaded.private_key_to_pem().unwrap()
+        );
+        let (jwk, thumbprint) = jwk(&key).unwrap();
+        assert_eq!(Some("EC"), jwk.get("kty").and_then(Value::as_str));
+        assert_eq!(43, thumbprint.len());
+        let signature = es256(&key, b"protected.payload").unwrap();
+        assert_eq!(64, signature.len());
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32]).unwrap(),
+            BigNum::from_slice(&signature[32..]).unwrap(),
+        )
+        .unwrap();
+        let digest = hash(MessageDigest::sha256(), b"protected.payload").unwrap();
+        assert!(signature.verify(&digest, &key).unwrap());
+
+        assert!(expiring(&acme.cert_path(), 0));
+        write(&acme.cert_path(), &certificate(90).to_pem().unwrap(), false).unwrap();
+        assert!(!expiring(&acme.cert_path(), RENEW_WITHIN_DAYS));
+        write(&acme.cert_path(), &certificate(10).to_pem().unwrap(), false).unwrap();
+        assert!(expiring(&acme.cert_path(), RENEW_WITHIN_DAYS));
+        assert!(!expiring(&acme.cert_path(), 0));
+        match acme.obtain() {
+            Err(AcmeError::TermsNotAgreed) => (),
+            obtained => panic!("ordered without agreeing to terms: {:?}", obtained),
+        }
+
+        acme.challenges
+            .lock()
+            .unwrap()
+            .insert("token".to_owned(), "token.thumbprint".to_owned());
+        let mut listening = acme.serve_challenges("127.0.0.1:0").unwrap();
+        let url = format!("http://{}{}", listening.socket, CHALLENGE_PATH);
+        let client = Client::new();
+        let mut response = client.get(&format!("{}token", url)).send().unwrap();
+        assert_eq!("token.thumbprint", body(&mut response).unwrap());
+        let response = client.get(&format!("{}other", url)).send().unwrap();
+        assert_eq!(StatusCode::NotFound, response.status);
+        listening.close().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Filtering deliveries by their payload's action
+//!
+//! Most events come with an `action`, e.g. `opened` or `synchronize` for
+//! pull requests, and hooks are often only interested in a few of them
+
+use super::filter::Filter;
+use super::Delivery;
+use serde_json::Value;
+
+/// A filter matching deliveries by their payload's `action`
+#[derive(Debug, Clone, Default)]
+pub struct Actions {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl Actions {
+    /// matches only deliveries with one of these actions
+    pub fn allow<I, A>(actions: I) -> Actions
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        Actions {
+            allowed: actions.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// matches all deliveries except those with one of these actions.
+    /// deliveries without an action still match
+    pub fn deny<I, A>(actions: I) -> Actions
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        Actions::default().except(actions)
+    }
+
+    /// also denies these actions
+    pub fn except<I, A>(mut self, actions: I) -> Actions
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        self.denied.extend(actions.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl Filter for Actions {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        let action = delivery
+            .json()
+            .and_then(|payload| payload.get("action"))
+            .and_then(Value::as_str);
+        match action {
+            Some(action) if self.denied.iter().any(|denied| denied == action) => false,
+            Some(action) if !self.allowed.is_empty() => {
+                self.allowed.iter().any(|allowed| allowed == action)
+            }
+            _ => self.allowed.is_empty(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut description = "actions".to_owned();
+        if !self.allowed.is_empty() {
+            description = format!("{} {}", description, self.allowed.join(", "));
+        }
+        if !self.denied.is_empty() {
+            description = format!("{} except {}", description, self.denied.join(", "));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use Hub;
+
+    #[test]
+    fn filter_actions() {
+        let opened = Delivery::new("id", "pull_request", r#"{"action": "opened"}"#, None);
+        let closed = Delivery::new("id", "pull_request", r#"{"action": "closed"}"#, None);
+        let push = Delivery::new("id", "push", "{}", None);
+        let workflow = Actions::allow(vec!["opened", "synchronize", "reopened"]);
+        assert!(workflow.matches(&opened));
+        assert!(!workflow.matches(&closed));
+        assert!(!workflow.matches(&push));
+        let unclosed = Actions::deny(vec!["closed"]);
+        assert!(unclosed.matches(&opened));
+        assert!(!unclosed.matches(&closed));
+        assert!(unclosed.matches(&push));
+        assert!(!workflow.except(vec!["opened"]).matches(&opened));
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let mut hub = Hub::new();
+        hub.handle_actions(
+            "pull_request",
+            Actions::allow(vec!["opened"]),
+            move |_: &Delivery| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        for payload in &[r#"{"action": "opened"}"#, r#"{"action": "closed"}"#] {
+            hub.deliver(&mut Delivery::new("id", "pull_request", payload, None));
+        }
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,449 @@
+//! Composable delivery authentication
+//!
+//! Authenticators decide whether a delivery is authentic. They may be chained
+//! with `All`, which requires every authenticator to accept a delivery, and
+//! `Any`, which requires at least one to, e.g. HMAC signatures *and* an ip
+//! allowlist, or HMAC signatures *or* a bearer token while senders migrate.
+//! `AuthHook` applies an authenticator in front of a hook.
+
+use super::{AuthError, Delivery, Hook, IpRange};
+use base64;
+use crypto;
+use hex::FromHex;
+use secret::Secret;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::form_urlencoded;
+use zeroize::Zeroizing;
+
+/// Decides whether deliveries are authentic
+pub trait Authenticator: Send + Sync {
+    /// returns true if the delivery should be accepted
+    fn authenticate(&self, delivery: &Delivery) -> bool;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&Delivery) -> bool,
+    F: Sync + Send,
+{
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        self(delivery)
+    }
+}
+
+/// Verifies Github's `X-Hub-Signature` HMAC payload signatures.
+///
+/// Signatures are expected to cover the decompressed payload, as they do
+/// when a proxy compresses deliveries after they were signed
+pub struct Signature {
+    secret: Secret,
+    encoded: bool,
+}
+
+impl Signature {
+    pub fn new<S>(secret: S) -> Signature
+    where
+        S: Into<String>,
+    {
+        Signature {
+            secret: Secret::new(secret),
+            encoded: false,
+        }
+    }
+
+    /// verifies signatures against compressed bodies as they were sent,
+    /// for senders which sign their payloads after compressing them
+    pub fn encoded(mut self) -> Signature {
+        self.encoded = true;
+        self
+    }
+
+    /// verifies a `sha1={hex digest}` signature of a payload
+    pub fn verify(&self, payload: &str, signature: &str) -> bool {
+        self.verify_bytes(payload.as_bytes(), signature)
+    }
+
+    fn verify_bytes(&self, payload: &[u8], signature: &str) -> bool {
+        // https://developer.github.com/webhooks/securing/#validating-payloads-from-github
+        let sans_prefix = match signature.get(..5) {
+            Some("sha1=") => signature[5..].as_bytes(),
+            _ => return false,
+        };
+        match Vec::from_hex(sans_prefix) {
+            Ok(sigbytes) => crypto::verify_hmac_sha1(self.secret.as_bytes(), payload, &sigbytes),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Authenticator for Signature {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        let payload = match delivery.encoded_body {
+            Some(body) if self.encoded => body,
+            _ => delivery.original_payload().as_bytes(),
+        };
+        delivery
+            .signature
+            .map(|signature| self.verify_bytes(payload, signature))
+            .unwrap_or(false)
+    }
+}
+
+/// Verifies credentials sent in the `Authorization` header
+pub struct Token {
+    scheme: &'static str,
+    credentials: Secret,
+}
+
+impl Token {
+    /// accepts an `Authorization: Bearer {token}` header
+    pub fn bearer<T>(token: T) -> Token
+    where
+        T: Into<String>,
+    {
+        Token {
+            scheme: "Bearer",
+            credentials: Secret::new(token),
+        }
+    }
+
+    /// accepts basic auth credentials
+    pub fn basic<U, P>(username: U, password: P) -> Token
+    where
+        U: AsRef<str>,
+        P: AsRef<str>,
+    {
+        let credentials = Zeroizing::new(format!("{}:{}", username.as_ref(), password.as_ref()));
+        Token {
+            scheme: "Basic",
+            credentials: Secret::new(base64::encode(credentials.as_bytes())),
+        }
+    }
+
+    /// verifies the value of an `Authorization` header
+    pub fn verify(&self, authorization: &str) -> bool {
+        let mut parts = authorization.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(scheme), Some(credentials)) if scheme.eq_ignore_ascii_case(self.scheme) => {
+                crypto::constant_time_eq(credentials.trim().as_bytes(), self.credentials.as_bytes())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Authenticator for Token {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        delivery
+            .header("Authorization")
+            .map(|authorization| self.verify(authorization))
+            .unwrap_or(false)
+    }
+}
+
+/// Verifies a token passed as a query string parameter.
+/// See `QueryTokenHook` for why this is weaker than signatures
+pub struct QueryToken {
+    param: String,
+    token: Secret,
+}
+
+impl QueryToken {
+    pub fn new<P, T>(param: P, token: T) -> QueryToken
+    where
+        P: Into<String>,
+        T: Into<String>,
+    {
+        QueryToken {
+            param: param.into(),
+            token: Secret::new(token),
+        }
+    }
+
+    /// name of the query parameter holding the token
+    pub fn param(&self) -> &str {
+        &self.param
+    }
+
+    /// verifies a raw, url encoded query string
+    pub fn verify(&self, query: &str) -> bool {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|&(ref name, _)| *name == self.param)
+            .map(|(_, value)| {
+                // decoded values are copies of what may well be the token
+                let value = Zeroizing::new(value.into_owned());
+                crypto::constant_time_eq(value.as_bytes(), self.token.as_bytes())
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl Authenticator for QueryToken {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        self.verify(delivery.query.unwrap_or(""))
+    }
+}
+
+/// Accepts deliveries sent from a set of ip ranges, e.g. those listed
+/// in the `hooks` field of Github's `/meta` api.
+///
+/// The client's real ip is used when the hub trusts a proxy to report it
+pub struct IpAllowlist {
+    ranges: Vec<IpRange>,
+}
+
+impl IpAllowlist {
+    pub fn new(ranges: Vec<IpRange>) -> IpAllowlist {
+        IpAllowlist { ranges: ranges }
+    }
+}
+
+impl Authenticator for IpAllowlist {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        delivery
+            .real_ip
+            .or_else(|| delivery.remote_addr.map(|addr| addr.ip()))
+            .map(|ip| self.ranges.iter().any(|range| range.contains(&ip)))
+            .unwrap_or(false)
+    }
+}
+
+/// Rejects deliveries whose sender supplied timestamp, given in
+/// unix seconds, is further than `window` from the time they were received.
+///
+/// The timestamp should be covered by the sender's signature, otherwise
+/// a replayed request could simply carry a fresh one, so chain this with
+/// a signature verifying authenticator using `All`
+pub struct Timestamp {
+    header: String,
+    window: Duration,
+}
+
+impl Timestamp {
+    pub fn new<H>(header: H, window: Duration) -> Timestamp
+    where
+        H: Into<String>,
+    {
+        Timestamp {
+            header: header.into(),
+            window: window,
+        }
+    }
+}
+
+impl Authenticator for Timestamp {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        let sent = match delivery
+            .header(&self.header)
+            .and_then(|value| value.trim().parse::<u64>().ok())
+        {
+            Some(sent) => sent,
+            None => return false,
+        };
+        let received = match delivery.received_at.duration_since(UNIX_EPOCH) {
+            Ok(received) => received.as_secs(),
+            Err(_) => return false,
+        };
+        let skew = if received > sent {
+            received - sent
+        } else {
+            sent - received
+        };
+        skew <= self.window.as_secs()
+    }
+}
+
+/// Rejects deliveries whose id was already seen within `window`.
+///
+/// Github's delivery GUIDs are unique per delivery, so this blunts replays of
+/// captured requests for senders which don't sign a timestamp. Note that
+/// redelivering an event from Github's settings page reuses its id. Place this
+/// last in an `All` chain so only otherwise authentic deliveries are remembered
+pub struct ReplayGuard {
+    window: Duration,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    ids: HashSet<String>,
+    order: VecDeque<(SystemTime, String)>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration) -> ReplayGuard {
+        ReplayGuard {
+            window: window,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+}
+
+impl Authenticator for ReplayGuard {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        let now = delivery.received_at;
+        let mut seen = self.seen.lock().unwrap();
+        while seen
+            .order
+            .front()
+            .map(|&(at, _)| now.duration_since(at).unwrap_or_default() > self.window)
+            .unwrap_or(false)
+        {
+            if let Some((_, id)) = seen.order.pop_front() {
+                seen.ids.remove(&id);
+            }
+        }
+        if seen.ids.contains(delivery.id) {
+            log_delivery!(warn, delivery; "rejecting replayed delivery {}", delivery.id);
+            return false;
+        }
+        seen.ids.insert(delivery.id.to_owned());
+        seen.order.push_back((now, delivery.id.to_owned()));
+        true
+    }
+}
+
+/// Accepts deliveries which every one of its authenticators accepts.
+/// An empty chain accepts nothing
+#[derive(Default)]
+pub struct All {
+    authenticators: Vec<Box<Authenticator>>,
+}
+
+impl All {
+    pub fn new() -> All {
+        All {
+            ..Default::default()
+        }
+    }
+
+    /// adds an authenticator to the chain
+    pub fn with<A>(mut self, authenticator: A) -> All
+    where
+        A: Authenticator + 'static,
+    {
+        self.authenticators.push(Box::new(authenticator));
+        self
+    }
+}
+
+impl Authenticator for All {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        !self.authenticators.is_empty()
+            && self
+                .authenticators
+                .iter()
+                .all(|authenticator| authenticator.authenticate(delivery))
+    }
+}
+
+/// Accepts deliveries which at least one of its authenticators accepts
+#[derive(Default)]
+pub struct Any {
+    authenticators: Vec<Box<Authenticator>>,
+}
+
+impl Any {
+    pub fn new() -> Any {
+        Any {
+            ..Default::default()
+        }
+    }
+
+    /// adds an authenticator to the chain
+    pub fn with<A>(mut self, authenticator: A) -> Any
+    where
+        A: Authenticator + 'static,
+    {
+        self.authenticators.push(Box::new(authenticator));
+        self
+    }
+}
+
+impl Authenticator for Any {
+    fn authenticate(&self, delivery: &Delivery) -> bool {
+        self.authenticators
+            .iter()
+            .any(|authenticator| authenticator.authenticate(delivery))
+    }
+}
+
+/// A hook which only hands authentic deliveries to its inner hook
+pub struct AuthHook<A: Authenticator + 'static, H: Hook + 'static> {
+    authenticator: A,
+    hook: H,
+}
+
+impl<A: Authenticator + 'static, H: Hook + 'static> AuthHook<A, H> {
+    pub fn new(authenticator: A, hook: H) -> AuthHook<A, H> {
+        AuthHook {
+            authenticator: authenticator,
+            hook: hook,
+        }
+    }
+}
+
+impl<A: Authenticator + 'static, H: Hook + 'static> Hook for AuthHook<A, H> {
+    fn handle(&self, delivery: &Delivery) {
+        if self.authenticator.authenticate(delivery) {
+            delivery.verified();
+            self.hook.handle(delivery)
+        } else {
+            log_delivery!(error, delivery; "failed to authenticate request");
+            delivery.rejected(AuthError::InvalidCredentials);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC: &str = include_str!("../data/public.json");
+
+    #[test]
+    fn authenticator_chains() {
+        let headers = vec![("Authorization".to_owned(), "Bearer token".to_owned())];
+        let mut delivery = Delivery::new("id", "public", PUBLIC, None);
+        delivery.headers = &headers;
+        delivery.real_ip = Some("192.30.252.1".parse().unwrap());
+
+        let github = || IpAllowlist::new(vec!["192.30.252.0/22".parse().unwrap()]);
+        let signed = || Signature::new("secret");
+        assert!(All::new()
+            .with(github())
+            .with(Token::bearer("token"))
+            .authenticate(&delivery));
+        assert!(!All::new()
+            .with(github())
+            .with(signed())
+            .authenticate(&delivery));
+        assert!(Any::new()
+            .with(signed())
+            .with(Token::bearer("token"))
+            .authenticate(&delivery));
+        assert!(!Any::new().with(signed()).authenticate(&delivery));
+        assert!(!All::new().authenticate(&delivery));
+        assert!(!Any::new().authenticate(&delivery));
+    }
+
+    #[test]
+    fn replay_windows() {
+        let received = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let headers = vec![("X-Timestamp".to_owned(), "1499999970".to_owned())];
+        let mut delivery = Delivery::new("id", "public", PUBLIC, None);
+        delivery.headers = &headers;
+        delivery.received_at = received;
+        assert!(Timestamp::new("X-Timestamp", Duration::from_secs(60)).authenticate(&delivery));
+        assert!(!Timestamp::new("X-Timestamp", Duration::from_secs(10)).authenticate(&delivery));
+        assert!(!Timestamp::new("X-Missing", Duration::from_secs(60)).authenticate(&delivery));
+
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        assert!(guard.authenticate(&delivery));
+        assert!(!guard.authenticate(&delivery));
+        delivery.id = "other";
+        assert!(guard.authenticate(&delivery));
+    }
+}
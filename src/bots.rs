@@ -0,0 +1,73 @@
+//! Ignoring deliveries sent by bots
+//!
+//! Automation usually wants to ignore the events its own bot account
+//! generates, which would otherwise loop back into it. Hooks which would
+//! rather treat such deliveries differently than drop them can check
+//! `Delivery::from_bot` themselves
+
+use super::filter::Filter;
+use super::Delivery;
+use serde_json::Value;
+
+/// A filter matching deliveries whose sender isn't a bot
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreBots {
+    allowed: Vec<String>,
+}
+
+impl IgnoreBots {
+    pub fn new() -> IgnoreBots {
+        IgnoreBots {
+            ..Default::default()
+        }
+    }
+
+    /// still matches deliveries sent by a bot, by its login, e.g. `dependabot[bot]`
+    pub fn allow<L: Into<String>>(mut self, login: L) -> IgnoreBots {
+        self.allowed.push(login.into());
+        self
+    }
+}
+
+impl Filter for IgnoreBots {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        if !delivery.from_bot() {
+            return true;
+        }
+        let login = delivery
+            .json()
+            .and_then(|payload| payload.pointer("/sender/login"))
+            .and_then(Value::as_str);
+        match login {
+            Some(login) => self.allowed.iter().any(|allowed| allowed == login),
+            None => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        if self.allowed.is_empty() {
+            "no bots".to_owned()
+        } else {
+            format!("no bots except {}", self.allowed.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_bots() {
+        let bot = r#"{"sender": {"login": "dependabot[bot]", "type": "Bot"}}"#;
+        let bot = Delivery::new("id", "push", bot, None);
+        let user = r#"{"sender": {"login": "octocat", "type": "User"}}"#;
+        let user = Delivery::new("id", "push", user, None);
+        assert!(bot.from_bot());
+        assert!(!user.from_bot());
+        assert!(!IgnoreBots::new().matches(&bot));
+        assert!(IgnoreBots::new().matches(&user));
+        assert!(IgnoreBots::new().allow("dependabot[bot]").matches(&bot));
+        assert!(!IgnoreBots::new().allow("renovate[bot]").matches(&bot));
+    }
+}
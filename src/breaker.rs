@@ -0,0 +1,237 @@
+//! Circuit breakers in front of hooks forwarding deliveries downstream
+//!
+//! A downstream service which is down makes every delivery forwarded to it
+//! wait out a timeout. A `CircuitBreaker` stops forwarding after a number of
+//! consecutive failures, handing deliveries to a dead letter hook instead,
+//! and after a cool down lets one delivery through to find out whether the
+//! service recovered
+
+use super::{clock, Delivery, Hook};
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Hooks which send deliveries downstream and can tell whether they arrived
+pub trait Forwarder: Send + Sync {
+    fn forward(&self, delivery: &Delivery) -> Result<(), Box<Error + Send + Sync>>;
+}
+
+impl<F, E> Forwarder for F
+where
+    F: Fn(&Delivery) -> Result<(), E>,
+    F: Sync + Send,
+    E: Into<Box<Error + Send + Sync>>,
+{
+    fn forward(&self, delivery: &Delivery) -> Result<(), Box<Error + Send + Sync>> {
+        self(delivery).map_err(Into::into)
+    }
+}
+
+/// The state of a circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    /// deliveries are forwarded
+    Closed,
+    /// the downstream failed too often, deliveries go to the dead letter hook
+    Open,
+    /// one delivery is being forwarded to find out whether the downstream
+    /// recovered, others go to the dead letter hook meanwhile
+    HalfOpen,
+}
+
+enum State {
+    Closed { failures: usize },
+    Open { since: Option<Instant> },
+    HalfOpen,
+}
+
+/// A hook forwarding deliveries until its forwarder keeps failing
+pub struct CircuitBreaker<F: Forwarder> {
+    forwarder: F,
+    threshold: usize,
+    cooldown: Duration,
+    dead_letter: Option<Box<Hook>>,
+    state: Mutex<State>,
+}
+
+impl<F: Forwarder> CircuitBreaker<F> {
+    /// opens after 5 consecutive failures, trying again after 30 seconds
+    pub fn new(forwarder: F) -> CircuitBreaker<F> {
+        CircuitBreaker {
+            forwarder: forwarder,
+            threshold: 5,
+            cooldown: Duration::from_secs(30),
+            dead_letter: None,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// number of consecutive failures which open the breaker
+    pub fn threshold(mut self, failures: usize) -> CircuitBreaker<F> {
+        self.threshold = failures.max(1);
+        self
+    }
+
+    /// how long the breaker stays open before trying the forwarder again.
+    /// without a clock, e.g. on wasm32, the next delivery tries it
+    pub fn cooldown(mut self, cooldown: Duration) -> CircuitBreaker<F> {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// hands deliveries which weren't forwarded, because the forwarder failed
+    /// or the breaker is open, to another hook, e.g. one persisting them
+    pub fn dead_letter<H: Hook + 'static>(mut self, hook: H) -> CircuitBreaker<F> {
+        self.dead_letter = Some(Box::new(hook));
+        self
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen => BreakerState::HalfOpen,
+        }
+    }
+
+    /// whether a delivery may be forwarded, half opening
+    /// the breaker once it has cooled down
+    fn admit(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let cooled = match *state {
+            State::Closed { .. } => return true,
+            State::HalfOpen => return false,
+            State::Open { since } => since
+                .map(|since| since.elapsed() >= self.cooldown)
+                .unwrap_or(true),
+        };
+        if cooled {
+            *state = State::HalfOpen;
+        }
+        cooled
+    }
+
+    fn record(&self, forwarded: bool) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            _ if forwarded => 0,
+            State::Closed { failures } => failures + 1,
+            _ => self.threshold,
+        };
+        *state = if failures >= self.threshold {
+            State::Open {
+                since: clock::instant(),
+            }
+        } else {
+            State::Closed { failures: failures }
+        };
+    }
+
+    fn divert(&self, delivery: &Delivery) {
+        if let Some(ref dead_letter) = self.dead_letter {
+            dead_letter.handle(delivery)
+        }
+    }
+}
+
+impl<F: Forwarder> Hook for CircuitBreaker<F> {
+    fn handle(&self, delivery: &Delivery) {
+        if !self.admit() {
+            log_delivery!(
+                debug,
+                delivery;
+                "circuit open, not forwarding delivery {}",
+                delivery.id
+            );
+            return self.divert(delivery);
+        }
+        // a panicking forwarder failed too, or a half open
+        // breaker would never forward another delivery
+        let forwarded = panic::catch_unwind(AssertUnwindSafe(|| self.forwarder.forward(delivery)))
+            .unwrap_or_else(|panic| {
+                self.record(false);
+                panic::resume_unwind(panic)
+            });
+        match forwarded {
+            Ok(()) => self.record(true),
+            Err(err) => {
+                log_delivery!(
+                    warn,
+                    delivery;
+                    "failed to forward delivery {}: {}",
+                    delivery.id,
+                    err
+                );
+                self.record(false);
+                self.divert(delivery)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn breaker_opens_and_recovers() {
+        let (up, forwarded, dead) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let (available, count, dead_count) = (up.clone(), forwarded.clone(), dead.clone());
+        let breaker = CircuitBreaker::new(move |_: &Delivery| {
+            count.fetch_add(1, Ordering::SeqCst);
+            if available.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err("downstream unavailable")
+            }
+        })
+        .threshold(2)
+        .cooldown(Duration::from_millis(50))
+        .dead_letter(move |_: &Delivery| {
+            dead_count.fetch_add(1, Ordering::SeqCst);
+        });
+        let delivery = Delivery::new("id", "ping", "{}", None);
+
+        for _ in 0..4 {
+            breaker.handle(&delivery);
+        }
+        assert_eq!(BreakerState::Open, breaker.state());
+        assert_eq!(2, forwarded.load(Ordering::SeqCst));
+        assert_eq!(4, dead.load(Ordering::SeqCst));
+
+        // still failing once cooled down, so it opens again
+        ::std::thread::sleep(Duration::from_millis(60));
+        breaker.handle(&delivery);
+        assert_eq!(BreakerState::Open, breaker.state());
+        assert_eq!(3, forwarded.load(Ordering::SeqCst));
+
+        up.store(true, Ordering::SeqCst);
+        ::std::thread::sleep(Duration::from_millis(60));
+        breaker.handle(&delivery);
+        assert_eq!(BreakerState::Closed, breaker.state());
+        assert_eq!(4, forwarded.load(Ordering::SeqCst));
+        assert_eq!(5, dead.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn breaker_opens_on_panic() {
+        let breaker = CircuitBreaker::new(|_: &Delivery| -> Result<(), &'static str> {
+            panic!("forwarder panicked")
+        })
+        .threshold(1)
+        .cooldown(Duration::from_millis(0));
+        let delivery = Delivery::new("id", "ping", "{}", None);
+        for _ in 0..2 {
+            let panicked = panic::catch_unwind(AssertUnwindSafe(|| breaker.handle(&delivery)));
+            assert!(panicked.is_err());
+            assert_eq!(BreakerState::Open, breaker.state());
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! Streams deliveries to connected clients as server sent events
+//!
+//! A hub broadcasting on a path, e.g. `/events`, responds to `GET` requests
+//! for it, authorized with a `Token`, with a stream of the deliveries it
+//! receives from then on, so dashboards or sidecar processes can tail webhook
+//! traffic live. Clients may narrow the stream to some events with
+//! `?event=push&event=issues`.
+//!
+//! Only deliveries an authenticating hook accepted are broadcast, as their
+//! id, event and json payload, so a hub without one broadcasts nothing.
+//!
+//! Requests to upgrade to a WebSocket are streamed deliveries as json text
+//! messages instead, e.g. for browser based tooling
+
+use super::auth::Token;
+use super::filter::Filter;
+use super::observer::Observer;
+use super::shutdown::Drain;
+use super::{Delivery, Hub};
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use serde_json::{self, Value};
+use server;
+use std::io::{self, Write};
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::form_urlencoded;
+use websocket;
+
+/// number of deliveries buffered for a client which isn't
+/// keeping up before further deliveries to it are dropped
+const BACKLOG: usize = 64;
+
+/// how often idle streams are sent a comment, keeping proxies from
+/// timing them out and noticing clients which disconnected
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// The streams a hub serves at once, of deliveries it broadcasts or
+/// exports. each occupies one of the server's threads, so they're bounded
+/// by one less than the number of threads, always leaving one to receive
+/// deliveries with. further clients are answered `503 Service Unavailable`
+#[derive(Debug)]
+pub struct Streams {
+    open: Mutex<usize>,
+    max: usize,
+}
+
+/// A stream being served, closed when it's dropped
+pub struct Stream<'a>(&'a Streams);
+
+impl<'a> Drop for Stream<'a> {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap() -= 1;
+    }
+}
+
+impl Default for Streams {
+    fn default() -> Streams {
+        Streams::new(server::default_threads().saturating_sub(1))
+    }
+}
+
+impl Streams {
+    pub fn new(max: usize) -> Streams {
+        Streams {
+            open: Mutex::new(0),
+            max: max,
+        }
+    }
+
+    /// bounds the number of streams served at once
+    pub fn limit(&mut self, max: usize) {
+        self.max = max;
+    }
+
+    /// opens a stream, unless the most streams already are
+    pub fn open(&self) -> Option<Stream> {
+        let mut open = self.open.lock().unwrap();
+        if *open >= self.max {
+            return None;
+        }
+        *open += 1;
+        Some(Stream(self))
+    }
+}
+
+/// A delivery as sent to clients
+pub struct Message {
+    pub id: String,
+    pub event: String,
+    pub data: String,
+}
+
+/// An observer broadcasting verified deliveries to the clients streaming them
+pub struct Broadcast {
+    filter: Box<Filter>,
+    token: Token,
+    subscribers: Mutex<Vec<(usize, SyncSender<Arc<Message>>)>>,
+    next: AtomicUsize,
+}
+
+/// A client's subscription, ended when it's dropped
+struct Subscription<'a> {
+    broadcast: &'a Broadcast,
+    id: usize,
+    receiver: Receiver<Arc<Message>>,
+}
+
+impl<'a> Drop for Subscription<'a> {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.broadcast
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|&(subscriber, _)| subscriber != id);
+    }
+}
+
+impl Broadcast {
+    /// broadcasts deliveries matching `filter` to clients authorized with `token`
+    pub fn new<F: Filter + 'static>(token: Token, filter: F) -> Broadcast {
+        Broadcast {
+            filter: Box::new(filter),
+            token: token,
+            subscribers: Mutex::new(Vec::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// number of clients currently streaming deliveries
+    pub fn clients(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /// subscribes a client
+    fn subscribe(&self) -> Subscription {
+        let id = self.next.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+        self.subscribers.lock().unwrap().push((id, sender));
+        Subscription {
+            broadcast: self,
+            id: id,
+            receiver: receiver,
+        }
+    }
+
+    /// streams deliveries of the events named by the request's query, or all
+    /// of them, in response to a request until the client disconnects or the
+    /// hub shuts down. this occupies one of the server's threads while
+    /// streaming, see `ServerConfig::threads`, unless `streams` already
+    /// occupy all they may. returns the status responded with
+    pub fn respond(
+        &self,
+        req: Request,
+        mut res: Response,
+        drain: &Drain,
+        streams: &Streams,
+    ) -> StatusCode {
+        let authorized = req
+            .headers
+            .get_raw("Authorization")
+            .and_then(|values| values.first())
+            .and_then(|value| str::from_utf8(value).ok())
+            .map(|authorization| self.token.verify(authorization))
+            .unwrap_or(false);
+        if !authorized {
+            *res.status_mut() = StatusCode::Unauthorized;
+            let _ = res.send(b"unauthorized");
+            return StatusCode::Unauthorized;
+        }
+        let events = match req.uri {
+            RequestUri::AbsolutePath(ref uri) => uri
+                .splitn(2, '?')
+                .nth(1)
+                .map(|query| {
+                    form_urlencoded::parse(query.as_bytes())
+                        .filter(|&(ref name, _)| name == "event")
+                        .map(|(_, event)| event.into_owned())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        let _stream = match streams.open() {
+            Some(stream) => stream,
+            None => {
+                *res.status_mut() = StatusCode::ServiceUnavailable;
+                let _ = res.send(b"too many clients");
+                return StatusCode::ServiceUnavailable;
+            }
+        };
+        let subscription = self.subscribe();
+        let receiver = &subscription.receiver;
+        let key = websocket::key(&req.headers);
+        let status = match key {
+            Some(_) => StatusCode::SwitchingProtocols,
+            None => StatusCode::Ok,
+        };
+        let streamed = match key {
+            Some(key) => websocket::stream(res, &key, receiver, &events, drain),
+            None => {
+                res.headers_mut()
+                    .set_raw("Content-Type", vec![b"text/event-stream".to_vec()]);
+                res.headers_mut()
+                    .set_raw("Cache-Control", vec![b"no-cache".to_vec()]);
+                res.start().and_then(|mut out| {
+                    let streamed = stream(&mut out, receiver, &events, drain);
+                    out.end().and(streamed)
+                })
+            }
+        };
+        if let Err(err) = streamed {
+            debug!("event stream closed: {}", err);
+        }
+        status
+    }
+}
+
+/// writes messages to a client as server sent events
+fn stream<W: Write>(
+    out: &mut W,
+    messages: &Receiver<Arc<Message>>,
+    events: &[String],
+    drain: &Drain,
+) -> io::Result<()> {
+    out.write_all(b": connected\n\n")?;
+    out.flush()?;
+    while drain.accepting() {
+        match messages.recv_timeout(KEEP_ALIVE) {
+            Ok(message) => {
+                if events.is_empty() || events.contains(&message.event) {
+                    let event = format!(
+                        "id: {}\nevent: {}\ndata: {}\n\n",
+                        message.id, message.event, message.data
+                    );
+                    out.write_all(event.as_bytes())?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => out.write_all(b": keep alive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+impl Observer for Arc<Broadcast> {
+    fn on_verified(&self, delivery: &Delivery) {
+        if !self.filter.matches(delivery) {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        // only the delivery's id, event and payload, which is null when it
+        // isn't json, leaving out headers and the like
+        let mut broadcasted = serde_json::Map::new();
+        broadcasted.insert("id".to_owned(), Value::String(delivery.id.to_owned()));
+        broadcasted.insert("event".to_owned(), Value::String(delivery.event.to_owned()));
+        broadcasted.insert(
+            "payload".to_owned(),
+            delivery.json().cloned().unwrap_or(Value::Null),
+        );
+        let message = match serde_json::to_string(&Value::Object(broadcasted)) {
+            Ok(data) => Arc::new(Message {
+                id: delivery.id.to_owned(),
+                event: delivery.event.to_owned(),
+                data: data,
+            }),
+            Err(err) => {
+                log_delivery!(
+                    warn,
+                    delivery;
+                    "failed to broadcast delivery {}: {}",
+                    delivery.id,
+                    err
+                );
+                return;
+            }
+        };
+        subscribers.retain(|&(_, ref sender)| match sender.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log_delivery!(
+                    warn,
+                    delivery;
+                    "event stream lagging, dropped delivery {}",
+                    delivery.id
+                );
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+impl Hub {
+    /// streams verified deliveries to clients requesting `path`, e.g.
+    /// `/events`, authorized with `token`, as server sent events
+    pub fn broadcast(&mut self, path: &str, token: Token) -> Arc<Broadcast> {
+        self.broadcast_filtered(path, token, |_: &Delivery| true)
+    }
+
+    /// like `broadcast`, only streaming deliveries which match `filter`
+    pub fn broadcast_filtered<F>(&mut self, path: &str, token: Token, filter: F) -> Arc<Broadcast>
+    where
+        F: Filter + 'static,
+    {
+        let broadcast = Arc::new(Broadcast::new(token, filter));
+        self.observe(broadcast.clone());
+        self.broadcast = Some((path.to_owned(), broadcast.clone()));
+        broadcast
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::server::{XGithubDelivery, XGithubEvent, XHubSignature};
+    use super::*;
+    use crypto;
+    use hex;
+    use hyper::Client;
+    use server::ServerConfig;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+
+    #[test]
+    fn broadcast_deliveries() {
+        let mut streams = Streams::new(2);
+        let mut open = (0..2).filter_map(|_| streams.open()).collect::<Vec<_>>();
+        assert!(streams.open().is_none());
+        open.pop();
+        assert!(streams.open().is_some());
+        drop(open);
+        streams.limit(0);
+        assert!(streams.open().is_none());
+
+        let mut hub = Hub::new();
+        hub.handle_authenticated("*", "secret", |_: &Delivery| {});
+        let broadcast = hub.broadcast("/events", Token::bearer("admin"));
+        let config = ServerConfig::new().threads(4);
+        let handle = hub.run_with("127.0.0.1:0", config).unwrap();
+        let url = format!("http://{}/events", handle.addr());
+        let unauthorized = Client::new().get(&url).send().unwrap();
+        assert_eq!(StatusCode::Unauthorized, unauthorized.status);
+
+        let mut client = TcpStream::connect(handle.addr()).unwrap();
+        write!(client, "GET /events?event=public HTTP/1.1\r\nHost: hub\r\n").unwrap();
+        write!(client, "Authorization: Bearer admin\r\n\r\n").unwrap();
+        let mut lines = BufReader::new(client).lines().map(|line| line.unwrap());
+        assert!(lines.any(|line| line == ": connected"));
+        assert_eq!(1, broadcast.clients());
+        let payload = include_str!("../data/public.json");
+        let signature = crypto::sign_hmac_sha1(b"secret", payload.as_bytes());
+        let signed = format!("sha1={}", hex::encode(signature));
+        for &(event, id, signature) in &[
+            ("push", "push-id", &signed),
+            ("public", "forged-id", &"sha1=00".to_owned()),
+            ("public", "public-id", &signed),
+        ] {
+            Client::new()
+                .post(&format!("http://{}/", handle.addr()))
+                .header(XGithubEvent(event.to_string()))
+                .header(XGithubDelivery(id.to_string()))
+                .header(XHubSignature(signature.to_string()))
+                .body(payload)
+                .send()
+                .unwrap();
+        }
+        let streamed = lines
+            .skip_while(|line| !line.starts_with("id: "))
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!("id: public-id", streamed[0]);
+        assert_eq!("event: public", streamed[1]);
+        let data = serde_json::from_str::<Value>(&streamed[2][6..]).unwrap();
+        assert_eq!(Some("public-id"), data.get("id").and_then(Value::as_str));
+        assert!(data.pointer("/payload/repository").is_some());
+        assert!(data.get("headers").is_none());
+        handle.stop(Duration::from_secs(1));
+    }
+}
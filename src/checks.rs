@@ -0,0 +1,215 @@
+//! Reporting the outcome of work on a commit as a check run
+//!
+//! A `CheckRun` creates an in progress check run on the head commit of a
+//! push, check suite or merge queue group, runs a closure on a thread of its
+//! own, so deliveries complete while it works, and completes the check run
+//! with the closure's conclusion and output, the core loop of a CI bot
+
+use super::extract::FromDelivery;
+use super::github::Client;
+use super::{clock, Delivery, Hook, OwnedDelivery};
+use serde_json::Value;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+/// The output shown with a completed check run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    title: String,
+    summary: String,
+    text: Option<String>,
+}
+
+impl Output {
+    /// output of a title and a markdown summary
+    pub fn new<T, S>(title: T, summary: S) -> Output
+    where
+        T: Into<String>,
+        S: Into<String>,
+    {
+        Output {
+            title: title.into(),
+            summary: summary.into(),
+            text: None,
+        }
+    }
+
+    /// adds markdown details, e.g. a build's log
+    pub fn text<T: Into<String>>(mut self, text: T) -> Output {
+        self.text = Some(text.into());
+        self
+    }
+
+    fn json(&self) -> Value {
+        let mut output = json!({
+            "title": self.title,
+            "summary": self.summary,
+        });
+        if let (Some(output), Some(text)) = (output.as_object_mut(), self.text.as_ref()) {
+            output.insert("text".to_owned(), Value::String(text.clone()));
+        }
+        output
+    }
+}
+
+/// A hook running a check on the head commit of pushes and check suites,
+/// reporting it as a check run. the closure succeeds with `Ok` and fails
+/// with `Err`, and is handed a client of the hub's `GitHub`
+pub struct CheckRun<F> {
+    name: String,
+    check: Arc<F>,
+}
+
+impl<F> CheckRun<F>
+where
+    F: Fn(&Delivery, &Client) -> Result<Output, Output> + Send + Sync + 'static,
+{
+    /// runs `check` as the check run named `name`
+    pub fn new<N: Into<String>>(name: N, check: F) -> CheckRun<F> {
+        CheckRun {
+            name: name.into(),
+            check: Arc::new(check),
+        }
+    }
+}
+
+impl<F> Hook for CheckRun<F>
+where
+    F: Fn(&Delivery, &Client) -> Result<Output, Output> + Send + Sync + 'static,
+{
+    fn handle(&self, delivery: &Delivery) {
+        let sha = match head_sha(delivery) {
+            Some(sha) => sha,
+            None => return,
+        };
+        let client = match Client::from_delivery(delivery) {
+            Some(client) => client,
+            None => return,
+        };
+        let runs = match client.repo_path("check-runs") {
+            Some(runs) => runs,
+            None => return,
+        };
+        let created = client.post(
+            &runs,
+            &json!({
+                "name": self.name,
+                "head_sha": sha,
+                "status": "in_progress",
+                "started_at": clock::rfc3339(clock::now()),
+            }),
+        );
+        let run = match created.map(|run| run.get("id").and_then(Value::as_u64)) {
+            Ok(Some(id)) => format!("{}/{}", runs, id),
+            Ok(None) => return,
+            Err(err) => {
+                log_delivery!(error, delivery; "failed to create check run {}: {}", self.name, err);
+                return;
+            }
+        };
+        let (owned, check, name) = (
+            OwnedDelivery::from(delivery),
+            self.check.clone(),
+            self.name.clone(),
+        );
+        thread::spawn(move || {
+            let delivery = owned.delivery();
+            let concluded = panic::catch_unwind(AssertUnwindSafe(|| check(&delivery, &client)))
+                .unwrap_or_else(|_| Err(Output::new(name.as_str(), "the check panicked")));
+            if let Err(err) = client.patch(&run, &completed(&concluded)) {
+                log_delivery!(error, &delivery; "failed to complete check run {}: {}", name, err);
+            }
+        });
+    }
+}
+
+/// the commit a delivery asks to be checked, that of pushes creating or
+/// updating a ref, of check suites requested to run, or to run again, and
+/// of merge queue groups requesting checks
+pub fn head_sha(delivery: &Delivery) -> Option<String> {
+    let payload = delivery.json()?;
+    let text = |pointer: &str| payload.pointer(pointer).and_then(Value::as_str);
+    let sha = match delivery.event {
+        "push" if payload.get("deleted").and_then(Value::as_bool) != Some(true) => text("/after"),
+        "check_suite" => match text("/action") {
+            Some("requested") | Some("rerequested") => text("/check_suite/head_sha"),
+            _ => None,
+        },
+        "check_run" if text("/action") == Some("rerequested") => text("/check_run/head_sha"),
+        "merge_group" if text("/action") == Some("checks_requested") => {
+            text("/merge_group/head_sha")
+        }
+        _ => None,
+    };
+    sha.map(str::to_owned)
+}
+
+/// the update completing a check run with its conclusion
+fn completed(concluded: &Result<Output, Output>) -> Value {
+    let (conclusion, output) = match *concluded {
+        Ok(ref output) => ("success", output),
+        Err(ref output) => ("failure", output),
+    };
+    json!({
+        "status": "completed",
+        "conclusion": conclusion,
+        "completed_at": clock::rfc3339(clock::now()),
+        "output": output.json(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_runs() {
+        let delivery = |event, payload| Delivery::new("id", event, payload, None);
+        assert_eq!(
+            Some("0d1a26e6".to_owned()),
+            head_sha(&delivery("push", r#"{"after":"0d1a26e6","deleted":false}"#))
+        );
+        assert_eq!(
+            None,
+            head_sha(&delivery("push", r#"{"after":"0000000","deleted":true}"#))
+        );
+        assert_eq!(
+            Some("ec26c3e5".to_owned()),
+            head_sha(&delivery(
+                "check_suite",
+                r#"{"action":"rerequested","check_suite":{"head_sha":"ec26c3e5"}}"#
+            ))
+        );
+        assert_eq!(
+            None,
+            head_sha(&delivery(
+                "check_suite",
+                r#"{"action":"completed","check_suite":{"head_sha":"ec26c3e5"}}"#
+            ))
+        );
+        assert_eq!(
+            Some("ec26c3e57ca3a959ca5aad62de7213c562f8c821".to_owned()),
+            head_sha(&delivery(
+                "merge_group",
+                include_str!("../data/merge_group.json")
+            ))
+        );
+
+        let failed = completed(&Err(Output::new("tests", "2 failed").text("log")));
+        assert_eq!(
+            Some("failure"),
+            failed.get("conclusion").and_then(Value::as_str)
+        );
+        assert_eq!(
+            Some("log"),
+            failed.pointer("/output/text").and_then(Value::as_str)
+        );
+        let passed = completed(&Ok(Output::new("tests", "all passed")));
+        assert_eq!(
+            Some("success"),
+            passed.get("conclusion").and_then(Value::as_str)
+        );
+        assert!(passed.pointer("/output/text").is_none());
+    }
+}
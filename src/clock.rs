@@ -0,0 +1,149 @@
+//! Clocks for targets which may not have one.
+//!
+//! `wasm32-unknown-unknown` provides neither a wall clock nor a monotonic
+//! clock and std panics when asked for the time there. Deliveries built on
+//! that target are stamped with the unix epoch, which callers may replace
+//! with the host's notion of now, e.g. javascript's `Date.now()`
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// the current wall clock time
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+/// the unix epoch, as there's no wall clock to consult
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> SystemTime {
+    UNIX_EPOCH
+}
+
+/// the current monotonic time, if available
+#[cfg(not(target_arch = "wasm32"))]
+pub fn instant() -> Option<Instant> {
+    Some(Instant::now())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn instant() -> Option<Instant> {
+    None
+}
+
+/// time elapsed since `start`, or zero without a monotonic clock
+pub fn since(start: Option<Instant>) -> Duration {
+    start
+        .map(|start| start.elapsed())
+        .unwrap_or_else(|| Duration::from_secs(0))
+}
+
+/// a time in RFC 3339, to the second, e.g. `2019-05-15T15:20:32Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    let (year, month, day) = civil(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// a time in the common log format, e.g. `10/Oct/2000:13:55:36 +0000`
+#[cfg(feature = "server")]
+pub fn clf(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    let (year, month, day) = civil(days as i64);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// parses a time in RFC 3339, as Github's api formats them,
+/// ignoring fractions of seconds
+#[cfg(any(feature = "registration", feature = "github"))]
+pub fn parse_rfc3339(text: &str) -> Option<SystemTime> {
+    let number =
+        |from: usize, to: usize| text.get(from..to).and_then(|part| part.parse::<i64>().ok());
+    // times are ascii, which makes slicing them anywhere safe
+    let separated = text.is_ascii()
+        && text.len() >= 20
+        && &text[4..5] == "-"
+        && &text[7..8] == "-"
+        && text[10..11].eq_ignore_ascii_case("t")
+        && &text[13..14] == ":"
+        && &text[16..17] == ":";
+    if !separated {
+        return None;
+    }
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+    let zone = text[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" | "z" => 0,
+        _ if zone.len() == 6 && &zone[3..4] == ":" => {
+            let minutes = zone[1..3].parse::<i64>().ok()? * 60 + zone[4..6].parse::<i64>().ok()?;
+            match &zone[..1] {
+                "+" => minutes * 60,
+                "-" => -minutes * 60,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    if month < 1 || month > 12 || day < 1 || day > 31 || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let seconds = days(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    if seconds < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+
+/// the calendar date of a number of days since the unix epoch
+fn civil(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// the number of days since the unix epoch of a calendar date
+#[cfg(any(feature = "registration", feature = "github"))]
+fn days(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
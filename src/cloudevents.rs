@@ -0,0 +1,83 @@
+//! Deliveries as CloudEvents
+//!
+//! `envelope` wraps a delivery in a CloudEvents 1.0 envelope, in structured
+//! mode, so forwarded deliveries integrate with pipelines consuming
+//! CloudEvents, e.g. Knative's. Events are typed `com.github.<event>`, sourced
+//! from their repository and identified by their delivery's GUID
+
+use super::{clock, Delivery};
+use serde_json::{Map, Value};
+
+/// the media type of structured mode CloudEvents in json
+pub const CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// the CloudEvents envelope of a delivery, with its payload as data
+pub fn envelope(delivery: &Delivery) -> Value {
+    let mut event = Map::new();
+    let mut set = |name: &str, value: Value| {
+        event.insert(name.to_owned(), value);
+    };
+    set("specversion", Value::from("1.0"));
+    set(
+        "type",
+        Value::from(format!("com.github.{}", delivery.event)),
+    );
+    set("source", Value::from(source(delivery)));
+    set("id", Value::from(delivery.id));
+    set("time", Value::from(clock::rfc3339(delivery.received_at)));
+    set("datacontenttype", Value::from("application/json"));
+    if let Some(action) = delivery
+        .json()
+        .and_then(|payload| payload.get("action"))
+        .and_then(Value::as_str)
+    {
+        set("subject", Value::from(action));
+    }
+    set(
+        "data",
+        delivery
+            .json()
+            .cloned()
+            .unwrap_or_else(|| Value::from(delivery.unparsed_payload)),
+    );
+    Value::Object(event)
+}
+
+/// the url of the delivery's repository, or organization, or Github's
+fn source(delivery: &Delivery) -> String {
+    let field = |pointer| {
+        delivery
+            .json()
+            .and_then(|payload| payload.pointer(pointer))
+            .and_then(Value::as_str)
+    };
+    match (field("/repository/full_name"), field("/organization/login")) {
+        (Some(repository), _) => format!("https://github.com/{}", repository),
+        (None, Some(org)) => format!("https://github.com/{}", org),
+        (None, None) => "https://github.com".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn cloud_events() {
+        let mut delivery =
+            Delivery::new("guid", "public", include_str!("../data/public.json"), None);
+        delivery.received_at = UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+        let event = envelope(&delivery);
+        let attribute = |name| event.get(name).and_then(Value::as_str);
+        assert_eq!(Some("1.0"), attribute("specversion"));
+        assert_eq!(Some("com.github.public"), attribute("type"));
+        assert_eq!(
+            Some("https://github.com/Codertocat/Hello-World"),
+            attribute("source")
+        );
+        assert_eq!(Some("guid"), attribute("id"));
+        assert_eq!(Some("2024-02-29T23:59:59Z"), attribute("time"));
+        assert_eq!(delivery.json(), event.get("data"));
+    }
+}
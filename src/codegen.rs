@@ -0,0 +1,48 @@
+//! Support for code generated by afterparty-ng-macros
+
+use super::Delivery;
+use serde::Deserialize;
+use serde_json;
+
+/// deserializes a delivery's payload as a handler function argument
+pub fn payload<T>(delivery: &Delivery) -> Option<T>
+where
+    T: Deserialize,
+{
+    match serde_json::from_str::<T>(delivery.unparsed_payload) {
+        Ok(payload) => Some(payload),
+        Err(err) => {
+            log_delivery!(
+                error,
+                delivery;
+                "failed to deserialize delivery {} payload: {}",
+                delivery.id,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// the payload's `action`, for handlers of a specific action
+pub fn action(delivery: &Delivery) -> Option<String> {
+    delivery
+        .json()
+        .and_then(|payload| payload.get("action"))
+        .and_then(|action| action.as_str().map(|action| action.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Map, Value};
+
+    #[test]
+    fn payload_arguments() {
+        let delivery = Delivery::new("id", "public", include_str!("../data/public.json"), None);
+        let object = payload::<Map<String, Value>>(&delivery).unwrap();
+        assert!(object.contains_key("repository"));
+        assert!(payload::<Vec<u8>>(&delivery).is_none());
+        assert_eq!(None, action(&delivery));
+    }
+}
@@ -0,0 +1,174 @@
+//! Replying to pull requests and issues with a comment
+//!
+//! A `Comment` renders a template from a delivery's payload and the result
+//! of a closure, e.g. a lint summary or a deployment's link, and comments it
+//! on the delivery's pull request or issue. Later deliveries update that
+//! comment rather than adding another, so a bot's reply stays current
+//!
+//! Templates refer to values with `{{payload.pull_request.title}}` and
+//! `{{result.url}}`, by their keys and array indices, and insert the result
+//! itself with `{{result}}`
+
+use super::extract::FromDelivery;
+use super::github::{Client, GitHubError};
+use super::{Delivery, Hook, OwnedDelivery};
+use serde_json::Value;
+use std::sync::Arc;
+use std::thread;
+
+/// A hook commenting the rendering of a template on pull requests and issues
+pub struct Comment<F> {
+    name: String,
+    template: String,
+    result: Arc<F>,
+}
+
+impl<F> Comment<F>
+where
+    F: Fn(&Delivery, &Client) -> Option<Value> + Send + Sync + 'static,
+{
+    /// comments `template` rendered with the result of `result`, and updates
+    /// the comment named `name` when there already is one. deliveries `result`
+    /// returns `None` for aren't commented on
+    pub fn new<N, T>(name: N, template: T, result: F) -> Comment<F>
+    where
+        N: Into<String>,
+        T: Into<String>,
+    {
+        Comment {
+            name: name.into(),
+            template: template.into(),
+            result: Arc::new(result),
+        }
+    }
+}
+
+impl<F> Hook for Comment<F>
+where
+    F: Fn(&Delivery, &Client) -> Option<Value> + Send + Sync + 'static,
+{
+    fn handle(&self, delivery: &Delivery) {
+        let number = match delivery.json().and_then(number) {
+            Some(number) => number,
+            None => return,
+        };
+        let client = match Client::from_delivery(delivery) {
+            Some(client) => client,
+            None => return,
+        };
+        let (owned, name, template, result) = (
+            OwnedDelivery::from(delivery),
+            self.name.clone(),
+            self.template.clone(),
+            self.result.clone(),
+        );
+        thread::spawn(move || {
+            let delivery = owned.delivery();
+            let result = match result(&delivery, &client) {
+                Some(result) => result,
+                None => return,
+            };
+            let context = json!({
+                "payload": delivery.json().cloned().unwrap_or(Value::Null),
+                "result": result,
+            });
+            let marker = format!("<!-- afterparty:{} -->", name);
+            let body = format!("{}\n\n{}", render(&template, &context), marker);
+            if let Err(err) = reply(&client, number, &marker, &body) {
+                log_delivery!(error, &delivery; "failed to comment {} on #{}: {}", name, number, err);
+            }
+        });
+    }
+}
+
+/// comments `body`, or updates the comment carrying `marker` to it
+fn reply(client: &Client, number: u64, marker: &str, body: &str) -> Result<(), GitHubError> {
+    let comments = match client.repo_path(&format!("issues/{}/comments", number)) {
+        Some(comments) => comments,
+        None => return Ok(()),
+    };
+    let listed = client.list(&comments)?;
+    let existing = listed
+        .as_array()
+        .and_then(|comments| {
+            comments.iter().find(|comment| {
+                comment
+                    .get("body")
+                    .and_then(Value::as_str)
+                    .map(|body| body.contains(marker))
+                    .unwrap_or(false)
+            })
+        })
+        .and_then(|comment| comment.get("id"))
+        .and_then(Value::as_u64);
+    let body = json!({ "body": body });
+    match existing.and_then(|id| client.repo_path(&format!("issues/comments/{}", id))) {
+        Some(comment) => client.patch(&comment, &body),
+        None => client.post(&comments, &body),
+    }
+    .map(|_| ())
+}
+
+/// the number of a payload's pull request or issue
+fn number(payload: &Value) -> Option<u64> {
+    ["/pull_request/number", "/issue/number", "/number"]
+        .iter()
+        .filter_map(|pointer| payload.pointer(pointer))
+        .filter_map(Value::as_u64)
+        .next()
+}
+
+/// replaces each `{{path}}` of a template with the value at the path
+/// through `context`, strings as they are and other values as json
+fn render(template: &str, context: &Value) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        rendered.push_str(&rest[..start]);
+        let value = rest[start + 2..end]
+            .trim()
+            .split('.')
+            .try_fold(context, |value, key| match key.parse::<usize>() {
+                Ok(index) if value.is_array() => value.get(index),
+                _ => value.get(key),
+            });
+        match value {
+            Some(&Value::String(ref text)) => rendered.push_str(text),
+            Some(&Value::Null) | None => (),
+            Some(value) => rendered.push_str(&value.to_string()),
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_templates() {
+        let payload = json!({
+            "action": "opened",
+            "pull_request": { "number": 7, "title": "Fix", "labels": [{ "name": "bug" }] },
+        });
+        assert_eq!(Some(7), number(&payload));
+        let context = json!({
+            "payload": payload,
+            "result": { "warnings": 2, "url": "https://ci.example.com/7" },
+        });
+        assert_eq!(
+            "Fix (bug): 2 warnings, see https://ci.example.com/7. {{ unclosed",
+            render(
+                "{{payload.pull_request.title}} ({{ payload.pull_request.labels.0.name }}): \
+                 {{result.warnings}} warnings, see {{result.url}}{{result.missing}}. {{ unclosed",
+                &context
+            )
+        );
+    }
+}
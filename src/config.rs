@@ -0,0 +1,418 @@
+//! Configures hubs from environment variables
+//!
+//! `HubConfig::from_env` reads the settings a hub is deployed with from the
+//! process's environment, so containers and other twelve-factor deployments
+//! need neither a config file nor code changes. Every variable is optional
+//!
+//! | variable | setting |
+//! |----------|---------|
+//! | `AFTERPARTY_ADDR` | address to listen on, `0.0.0.0:$PORT` or `0.0.0.0:4567` by default |
+//! | `PORT` | port to listen on on all interfaces, when `AFTERPARTY_ADDR` isn't set |
+//! | `AFTERPARTY_SECRET` | secret deliveries must be signed with to reach hooks |
+//! | `AFTERPARTY_SECRETS` | comma separated secrets, any one of which may sign deliveries |
+//! | `AFTERPARTY_THREADS` | number of threads handling connections |
+//! | `AFTERPARTY_KEEP_ALIVE` | seconds idle connections are kept open, `0` disabling keep-alive |
+//! | `AFTERPARTY_READ_TIMEOUT` | seconds to wait on reads of requests |
+//! | `AFTERPARTY_WRITE_TIMEOUT` | seconds to wait on writes of responses |
+//! | `AFTERPARTY_MAX_IN_FLIGHT` | number of deliveries processed at once |
+//! | `AFTERPARTY_OVERFLOW` | `queue` or `shed` deliveries past that limit, `queue` by default |
+//! | `AFTERPARTY_MAX_DECOMPRESSED` | bytes compressed bodies may decompress to, enabling decompression |
+//! | `AFTERPARTY_TLS_CERT` | PEM encoded certificate chain, serving https with `AFTERPARTY_TLS_KEY` |
+//! | `AFTERPARTY_TLS_KEY` | PEM encoded private key |
+//! | `AFTERPARTY_TLS_CLIENT_CA` | PEM encoded CA client certificates must be signed by |
+//! | `AFTERPARTY_USER` | user to switch to once listening, on unix |
+//! | `AFTERPARTY_GROUP` | group to switch to once listening, the user's by default |
+//! | `AFTERPARTY_FILTER` | expression deliveries must match to reach hooks, see `filter::Expr` |
+
+use super::auth::{Any, Authenticator, Signature};
+use super::filter::{Expr, Filter};
+use super::{AuthError, Delivery, Hook, Hub, Overflow, ServerConfig};
+#[cfg(feature = "tls")]
+use openssl::error::ErrorStack;
+use secret::Secret;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "tls")]
+use tls::Tls;
+
+const DEFAULT_PORT: &str = "4567";
+
+/// A variable which couldn't be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    variable: String,
+    reason: String,
+}
+
+impl ConfigError {
+    fn new<R: fmt::Display>(variable: &str, reason: R) -> ConfigError {
+        ConfigError {
+            variable: variable.to_owned(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// the name of the offending variable
+    pub fn variable(&self) -> &str {
+        &self.variable
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.variable, self.reason)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// The settings a hub is deployed with, see the `config` module
+/// for the variables they're read from
+#[derive(Debug, Clone)]
+pub struct HubConfig {
+    addr: String,
+    secrets: Vec<Secret>,
+    threads: Option<usize>,
+    keep_alive: Option<Option<Duration>>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_in_flight: Option<(usize, Overflow)>,
+    max_decompressed: Option<usize>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_client_ca: Option<PathBuf>,
+    user: Option<String>,
+    group: Option<String>,
+    filter: Option<Expr>,
+}
+
+impl HubConfig {
+    /// reads settings from the process's environment
+    pub fn from_env() -> Result<HubConfig, ConfigError> {
+        HubConfig::from_vars(env::vars())
+    }
+
+    /// reads settings from the given variables rather than the process's
+    pub fn from_vars<I, K, V>(vars: I) -> Result<HubConfig, ConfigError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: Into<String>,
+    {
+        let vars = vars
+            .into_iter()
+            .map(|(name, value)| (name.as_ref().to_owned(), value.into()))
+            .filter(|&(_, ref value)| !value.trim().is_empty())
+            .collect::<Vec<_>>();
+        let var = |name: &str| {
+            vars.iter()
+                .find(|&&(ref var, _)| var == name)
+                .map(|&(_, ref value)| value.trim())
+        };
+
+        let addr = match (var("AFTERPARTY_ADDR"), var("PORT")) {
+            (Some(addr), _) => addr.to_owned(),
+            (None, Some(port)) => {
+                number::<u16>("PORT", port)?;
+                format!("0.0.0.0:{}", port)
+            }
+            (None, None) => format!("0.0.0.0:{}", DEFAULT_PORT),
+        };
+        let mut secrets = var("AFTERPARTY_SECRET")
+            .into_iter()
+            .map(Secret::new)
+            .collect::<Vec<_>>();
+        if let Some(listed) = var("AFTERPARTY_SECRETS") {
+            secrets.extend(
+                listed
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|secret| !secret.is_empty())
+                    .map(Secret::new),
+            );
+        }
+        let overflow = match var("AFTERPARTY_OVERFLOW") {
+            None | Some("queue") => Overflow::Queue,
+            Some("shed") => Overflow::Shed,
+            Some(other) => {
+                return Err(ConfigError::new(
+                    "AFTERPARTY_OVERFLOW",
+                    format!("expected queue or shed, not '{}'", other),
+                ))
+            }
+        };
+        let seconds = |name: &str| {
+            var(name)
+                .map(|value| number(name, value).map(Duration::from_secs))
+                .transpose()
+        };
+        let count = |name: &str| var(name).map(|value| number(name, value)).transpose();
+        let path = |name: &str| var(name).map(PathBuf::from);
+
+        let config = HubConfig {
+            addr: addr,
+            secrets: secrets,
+            threads: count("AFTERPARTY_THREADS")?,
+            keep_alive: seconds("AFTERPARTY_KEEP_ALIVE")?
+                .map(|timeout| Some(timeout).filter(|timeout| *timeout > Duration::from_secs(0))),
+            read_timeout: seconds("AFTERPARTY_READ_TIMEOUT")?,
+            write_timeout: seconds("AFTERPARTY_WRITE_TIMEOUT")?,
+            max_in_flight: count("AFTERPARTY_MAX_IN_FLIGHT")?.map(|limit| (limit, overflow)),
+            max_decompressed: count("AFTERPARTY_MAX_DECOMPRESSED")?,
+            tls_cert: path("AFTERPARTY_TLS_CERT"),
+            tls_key: path("AFTERPARTY_TLS_KEY"),
+            tls_client_ca: path("AFTERPARTY_TLS_CLIENT_CA"),
+            user: var("AFTERPARTY_USER").map(str::to_owned),
+            group: var("AFTERPARTY_GROUP").map(str::to_owned),
+            filter: var("AFTERPARTY_FILTER")
+                .map(|source| {
+                    Expr::parse(source).map_err(|err| ConfigError::new("AFTERPARTY_FILTER", err))
+                })
+                .transpose()?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if cfg!(not(unix)) && (self.user.is_some() || self.group.is_some()) {
+            return Err(ConfigError::new(
+                "AFTERPARTY_USER",
+                "switching users requires unix",
+            ));
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => Err(ConfigError::new("AFTERPARTY_TLS_KEY", "missing")),
+            (None, Some(_)) => Err(ConfigError::new("AFTERPARTY_TLS_CERT", "missing")),
+            (None, None) if self.tls_client_ca.is_some() => {
+                Err(ConfigError::new("AFTERPARTY_TLS_CERT", "missing"))
+            }
+            (Some(_), Some(_)) if cfg!(not(feature = "tls")) => Err(ConfigError::new(
+                "AFTERPARTY_TLS_CERT",
+                "serving https requires the tls feature",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// the address to listen on
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// the connection settings of these settings
+    pub fn server_config(&self) -> ServerConfig {
+        let mut config = ServerConfig::new();
+        if let Some(keep_alive) = self.keep_alive {
+            config = config.keep_alive(keep_alive);
+        }
+        if let Some(timeout) = self.read_timeout {
+            config = config.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.write_timeout {
+            config = config.write_timeout(timeout);
+        }
+        if let Some(threads) = self.threads {
+            config = config.threads(threads);
+        }
+        #[cfg(unix)]
+        {
+            if let Some(ref user) = self.user {
+                config = config.run_as(user.as_str());
+            }
+            if let Some(ref group) = self.group {
+                config = config.run_as_group(group.as_str());
+            }
+        }
+        config
+    }
+
+    /// an authenticator accepting deliveries signed with any one of the
+    /// configured secrets, so they can be rotated, which `apply` guards a
+    /// hub's hooks with. `None` when no secret is configured
+    pub fn authenticator(&self) -> Option<Any> {
+        if self.secrets.is_empty() {
+            return None;
+        }
+        Some(self.secrets.iter().fold(Any::new(), |any, secret| {
+            any.with(Signature::new(secret.as_str()))
+        }))
+    }
+
+    /// applies the configured limits to a hub, and guards the hooks
+    /// registered with it so far with the configured secrets and filter,
+    /// only handing them deliveries signed with one of the secrets. hubs
+    /// started with `Hub::run_config` apply it once all their hooks are
+    /// registered
+    pub fn apply(&self, hub: &mut Hub) {
+        if let Some((limit, overflow)) = self.max_in_flight {
+            hub.max_in_flight(limit, overflow);
+        }
+        if let Some(limit) = self.max_decompressed {
+            hub.decompress(limit);
+        }
+        let authenticator = self.authenticator().map(Arc::new);
+        let filter = self.filter.clone().map(Arc::new);
+        if authenticator.is_none() && filter.is_none() {
+            return;
+        }
+        for registered in hub.hooks.values_mut().flat_map(|hooks| hooks.iter_mut()) {
+            registered.hook = Arc::new(Guarded {
+                authenticator: authenticator.clone(),
+                filter: filter.clone(),
+                hook: registered.hook.clone(),
+            });
+        }
+    }
+
+    /// the TLS configuration connections are served with,
+    /// `None` when no certificate and key are configured
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<Result<Tls, ErrorStack>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(match self.tls_client_ca {
+                Some(ref ca) => Tls::mutual(cert, key, ca),
+                None => Tls::new(cert, key),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// a hook registered before a config was applied to its hub, only handed
+/// the deliveries signed with a configured secret which its filter matches
+struct Guarded {
+    authenticator: Option<Arc<Any>>,
+    filter: Option<Arc<Expr>>,
+    hook: Arc<Hook>,
+}
+
+impl Hook for Guarded {
+    fn handle(&self, delivery: &Delivery) {
+        if let Some(ref authenticator) = self.authenticator {
+            if delivery.signature.is_none() {
+                log_delivery!(error, delivery; "request carried no credentials");
+                return delivery.rejected(AuthError::MissingCredentials);
+            }
+            if !authenticator.authenticate(delivery) {
+                log_delivery!(error, delivery; "failed to authenticate request");
+                return delivery.rejected(AuthError::InvalidCredentials);
+            }
+            delivery.verified();
+        }
+        if let Some(ref filter) = self.filter {
+            if !filter.matches(delivery) {
+                log_delivery!(debug, delivery; "delivery {} filtered out", delivery.id);
+                return;
+            }
+        }
+        self.hook.handle(delivery)
+    }
+
+    fn filters(&self) -> Vec<String> {
+        let mut filters = self
+            .filter
+            .iter()
+            .map(|filter| filter.describe())
+            .collect::<Vec<_>>();
+        filters.extend(self.hook.filters());
+        filters
+    }
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|err| ConfigError::new(name, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto;
+    use hex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn config_from_vars() {
+        let config = HubConfig::from_vars(vec![
+            ("PORT", "8080"),
+            ("AFTERPARTY_SECRET", "old"),
+            ("AFTERPARTY_SECRETS", "new, newer,"),
+            ("AFTERPARTY_THREADS", "16"),
+            ("AFTERPARTY_KEEP_ALIVE", "0"),
+            ("AFTERPARTY_READ_TIMEOUT", "10"),
+            ("AFTERPARTY_MAX_IN_FLIGHT", "4"),
+            ("AFTERPARTY_OVERFLOW", "shed"),
+            ("AFTERPARTY_TLS_CERT", ""),
+            ("AFTERPARTY_USER", "hooks"),
+        ])
+        .unwrap();
+        assert_eq!("0.0.0.0:8080", config.addr);
+        assert_eq!(
+            vec![&b"old"[..], b"new", b"newer"],
+            config
+                .secrets
+                .iter()
+                .map(Secret::as_bytes)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(16), config.threads);
+        assert_eq!(Some(None), config.keep_alive);
+        assert_eq!(Some(Duration::from_secs(10)), config.read_timeout);
+        assert_eq!(None, config.write_timeout);
+        assert_eq!(Some((4, Overflow::Shed)), config.max_in_flight);
+        assert_eq!(None, config.tls_cert);
+        assert_eq!(Some("hooks"), config.user.as_ref().map(String::as_str));
+        assert!(config.authenticator().is_some());
+
+        let defaults = HubConfig::from_vars(Vec::<(String, String)>::new()).unwrap();
+        assert_eq!("0.0.0.0:4567", defaults.addr);
+        assert!(defaults.authenticator().is_none());
+
+        let err = HubConfig::from_vars(vec![("AFTERPARTY_THREADS", "many")]).unwrap_err();
+        assert_eq!("AFTERPARTY_THREADS", err.variable());
+        let err = HubConfig::from_vars(vec![("AFTERPARTY_TLS_CERT", "cert.pem")]).unwrap_err();
+        assert_eq!("AFTERPARTY_TLS_KEY", err.variable());
+        let err = HubConfig::from_vars(vec![("AFTERPARTY_FILTER", "event =")]).unwrap_err();
+        assert_eq!("AFTERPARTY_FILTER", err.variable());
+
+        let config = HubConfig::from_vars(vec![("AFTERPARTY_ADDR", "127.0.0.1:0")]).unwrap();
+        let handle = Hub::new().run_config(&config).unwrap();
+        assert!(handle.addr().ip().is_loopback());
+        handle.stop(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn config_filters() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let mut hub = Hub::new();
+        hub.handle("*", move |_: &Delivery| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let config =
+            HubConfig::from_vars(vec![("AFTERPARTY_FILTER", r#"event == "push""#)]).unwrap();
+        config.apply(&mut hub);
+        assert!(hub.deliver(&mut Delivery::new("id", "public", "{}", None)));
+        assert!(hub.deliver(&mut Delivery::new("id", "push", "{}", None)));
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+
+        let config = HubConfig::from_vars(vec![("AFTERPARTY_SECRETS", "old,new")]).unwrap();
+        config.apply(&mut hub);
+        let signed = format!(
+            "sha1={}",
+            hex::encode(crypto::sign_hmac_sha1(b"new", b"{}"))
+        );
+        for signature in &[None, Some("sha1=00"), Some(signed.as_str())] {
+            hub.deliver(&mut Delivery::new("id", "push", "{}", *signature));
+        }
+        assert_eq!(2, handled.load(Ordering::SeqCst));
+    }
+}
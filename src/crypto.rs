@@ -0,0 +1,180 @@
+//! Primitives for verifying deliveries, backed by ring, or by the pure rust
+//! RustCrypto crates with the `rustcrypto` feature and on wasm32, where
+//! ring doesn't build
+
+#[cfg(not(any(feature = "ring", feature = "rustcrypto", target_arch = "wasm32")))]
+compile_error!("afterparty needs the `ring` or `rustcrypto` feature to verify deliveries");
+
+#[cfg(all(
+    feature = "ring",
+    not(feature = "rustcrypto"),
+    not(target_arch = "wasm32")
+))]
+mod backend {
+    use ring::aead::{self, Aad, Nonce, OpeningKey, SealingKey, AES_256_GCM, NONCE_LEN};
+    use ring::constant_time;
+    use ring::digest;
+    use ring::hmac;
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    /// verifies an HMAC-SHA1 `tag` of `message`
+    pub fn verify_hmac_sha1(key: &[u8], message: &[u8], tag: &[u8]) -> bool {
+        let key = hmac::SigningKey::new(&digest::SHA1, key);
+        hmac::verify_with_own_key(&key, message, tag).is_ok()
+    }
+
+    /// signs `message` with an HMAC-SHA1 tag
+    #[cfg(feature = "server")]
+    pub fn sign_hmac_sha1(key: &[u8], message: &[u8]) -> Vec<u8> {
+        let key = hmac::SigningKey::new(&digest::SHA1, key);
+        hmac::sign(&key, message).as_ref().to_vec()
+    }
+
+    /// signs `message` with an HMAC-SHA256 tag
+    #[cfg(feature = "server")]
+    pub fn sign_hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+        let key = hmac::SigningKey::new(&digest::SHA256, key);
+        hmac::sign(&key, message).as_ref().to_vec()
+    }
+
+    /// the SHA-1 digest of `message`
+    #[cfg(feature = "server")]
+    pub fn sha1(message: &[u8]) -> Vec<u8> {
+        digest::digest(&digest::SHA1, message).as_ref().to_vec()
+    }
+
+    /// compares two byte strings in time independent of their contents
+    pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        constant_time::verify_slices_are_equal(a, b).is_ok()
+    }
+
+    /// encrypts and authenticates `message` with AES-256-GCM under a 32 byte
+    /// `key`, prefixed by the random nonce it was sealed with
+    pub fn seal(key: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+        let key = SealingKey::new(&AES_256_GCM, key).ok()?;
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+        let tag_len = AES_256_GCM.tag_len();
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(message);
+        sealed.resize(NONCE_LEN + message.len() + tag_len, 0);
+        let nonce = Nonce::assume_unique_for_key(nonce);
+        let len = aead::seal_in_place(&key, nonce, Aad::empty(), &mut sealed[NONCE_LEN..], tag_len)
+            .ok()?;
+        sealed.truncate(NONCE_LEN + len);
+        Some(sealed)
+    }
+
+    /// decrypts a message `seal` sealed under `key`, unless it was tampered with
+    pub fn open(key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let key = OpeningKey::new(&AES_256_GCM, key).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut ciphertext = ciphertext.to_vec();
+        aead::open_in_place(&key, nonce, Aad::empty(), 0, &mut ciphertext)
+            .ok()
+            .map(|message| message.to_vec())
+    }
+}
+
+#[cfg(any(feature = "rustcrypto", target_arch = "wasm32"))]
+mod backend {
+    use hmac::{Hmac, Mac};
+    #[cfg(feature = "server")]
+    use sha1::Digest;
+    use sha1::Sha1;
+    #[cfg(feature = "server")]
+    use sha2::Sha256;
+    use subtle::ConstantTimeEq;
+
+    /// verifies an HMAC-SHA1 `tag` of `message`
+    pub fn verify_hmac_sha1(key: &[u8], message: &[u8], tag: &[u8]) -> bool {
+        match Hmac::<Sha1>::new_varkey(key) {
+            Ok(mut mac) => {
+                mac.input(message);
+                mac.verify(tag).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// signs `message` with an HMAC-SHA1 tag
+    #[cfg(feature = "server")]
+    pub fn sign_hmac_sha1(key: &[u8], message: &[u8]) -> Vec<u8> {
+        // hmac accepts keys of any length
+        let mut mac = Hmac::<Sha1>::new_varkey(key).unwrap();
+        mac.input(message);
+        mac.result().code().to_vec()
+    }
+
+    /// signs `message` with an HMAC-SHA256 tag
+    #[cfg(feature = "server")]
+    pub fn sign_hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+        mac.input(message);
+        mac.result().code().to_vec()
+    }
+
+    /// the SHA-1 digest of `message`
+    #[cfg(feature = "server")]
+    pub fn sha1(message: &[u8]) -> Vec<u8> {
+        Sha1::digest(message).to_vec()
+    }
+
+    /// compares two byte strings in time independent of their contents
+    pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && bool::from(a.ct_eq(b))
+    }
+}
+
+pub use self::backend::{constant_time_eq, verify_hmac_sha1};
+#[cfg(all(
+    feature = "ring",
+    not(feature = "rustcrypto"),
+    not(target_arch = "wasm32")
+))]
+pub use self::backend::{open, seal};
+#[cfg(feature = "server")]
+pub use self::backend::{sha1, sign_hmac_sha1, sign_hmac_sha256};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crypto_backend() {
+        // RFC 2202's first HMAC-SHA1 test case
+        let tag = ::hex::decode("b617318655057264e28bc0b6fb378c8ef146be00").unwrap();
+        assert!(verify_hmac_sha1(&[0x0b; 20], b"Hi There", &tag));
+        assert!(!verify_hmac_sha1(&[0x0b; 20], b"Hi there", &tag));
+        // and RFC 4231's first HMAC-SHA256 one
+        #[cfg(feature = "server")]
+        assert_eq!(
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ::hex::encode(sign_hmac_sha256(&[0x0b; 20], b"Hi There"))
+        );
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokens"));
+    }
+
+    #[cfg(all(
+        feature = "ring",
+        not(feature = "rustcrypto"),
+        not(target_arch = "wasm32")
+    ))]
+    #[test]
+    fn sealed_messages() {
+        let key = [7; 32];
+        let sealed = seal(&key, b"payload").unwrap();
+        assert_ne!(seal(&key, b"payload").unwrap(), sealed);
+        assert_eq!(Some(b"payload".to_vec()), open(&key, &sealed));
+        assert_eq!(None, open(&[8; 32], &sealed));
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(None, open(&key, &tampered));
+        assert_eq!(None, seal(&[7; 16], b"payload"));
+    }
+}
@@ -0,0 +1,294 @@
+//! Coalesces bursts of related deliveries
+//!
+//! A `Debounce` buffers deliveries by a key, by default their event,
+//! repository and ref, and hands its hook only the latest delivery of a
+//! key once no other arrived for a quiet period, or once a batch grew to
+//! its maximum size. Ten rapid pushes to a branch then trigger one rebuild
+//! rather than ten. Without a clock to tell quiet periods by, e.g. on
+//! wasm32, deliveries are handed on at once
+
+use super::{clock, Delivery, Hook, OwnedDelivery};
+use serde_json::Value;
+use state::States;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A hook handing its inner hook the latest of a burst of deliveries
+///
+/// Batches are handed on from a thread of the debounce's own, after the
+/// deliveries in them completed, and those still pending when it's
+/// dropped are handed on at once
+pub struct Debounce<H: Hook + 'static> {
+    shared: Arc<Shared<H>>,
+}
+
+struct Shared<H: Hook + 'static> {
+    hook: H,
+    quiet: Duration,
+    max_batch: Option<usize>,
+    key: Box<Fn(&Delivery) -> String + Send + Sync>,
+    pending: Mutex<Pending>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Pending {
+    batches: HashMap<String, Batch>,
+    flushing: bool,
+    closed: bool,
+}
+
+/// the deliveries of a key received since its hook last ran
+struct Batch {
+    latest: OwnedDelivery,
+    states: Option<States>,
+    size: usize,
+    last: Instant,
+}
+
+impl<H: Hook + 'static> Debounce<H> {
+    /// hands `hook` the latest delivery of an event to a repository and ref
+    /// once none other arrived for `quiet`
+    pub fn new(quiet: Duration, hook: H) -> Debounce<H> {
+        Debounce {
+            shared: Arc::new(Shared {
+                hook: hook,
+                quiet: quiet,
+                max_batch: None,
+                key: Box::new(key),
+                pending: Mutex::new(Pending::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// coalesces deliveries by `key` rather than their event, repository
+    /// and ref, e.g. only by repository
+    pub fn key<K>(mut self, key: K) -> Debounce<H>
+    where
+        K: Fn(&Delivery) -> String + Send + Sync + 'static,
+    {
+        self.configure().key = Box::new(key);
+        self
+    }
+
+    /// hands on a batch as soon as it holds `size` deliveries,
+    /// so steady streams of deliveries aren't held back forever
+    pub fn max_batch(mut self, size: usize) -> Debounce<H> {
+        self.configure().max_batch = Some(size.max(1));
+        self
+    }
+
+    fn configure(&mut self) -> &mut Shared<H> {
+        // the flushing thread only starts with the first delivery
+        Arc::get_mut(&mut self.shared).expect("debounces are configured before handling deliveries")
+    }
+}
+
+impl<H: Hook + 'static> Shared<H> {
+    /// hands on batches once they've been quiet, until the debounce is dropped
+    fn flush_quiet(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let quiet = pending
+                .batches
+                .iter()
+                .filter(|&(_, batch)| {
+                    pending.closed || clock::since(Some(batch.last)) >= self.quiet
+                })
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            if !quiet.is_empty() {
+                let batches = quiet
+                    .iter()
+                    .filter_map(|key| pending.batches.remove(key))
+                    .collect::<Vec<_>>();
+                drop(pending);
+                for batch in batches {
+                    // a panicking hook mustn't end the thread, which
+                    // would leave later batches pending for good
+                    let handled = panic::catch_unwind(AssertUnwindSafe(|| self.run(&batch)));
+                    if handled.is_err() {
+                        let delivery = batch.latest.delivery();
+                        log_delivery!(
+                            error,
+                            &delivery;
+                            "hook panicked handling debounced delivery {}",
+                            delivery.id
+                        );
+                    }
+                }
+                pending = self.pending.lock().unwrap();
+                continue;
+            }
+            if pending.closed {
+                return;
+            }
+            let next = pending
+                .batches
+                .values()
+                .map(|batch| {
+                    self.quiet
+                        .checked_sub(clock::since(Some(batch.last)))
+                        .unwrap_or_default()
+                })
+                .min();
+            pending = match next {
+                Some(next) => self.changed.wait_timeout(pending, next).unwrap().0,
+                None => self.changed.wait(pending).unwrap(),
+            };
+        }
+    }
+
+    fn run(&self, batch: &Batch) {
+        let mut delivery = batch.latest.delivery();
+        delivery.states = batch.states.as_ref();
+        log_delivery!(
+            debug,
+            &delivery;
+            "handing on delivery {}, the latest of {}",
+            delivery.id,
+            batch.size
+        );
+        self.hook.handle(&delivery)
+    }
+}
+
+impl<H: Hook + 'static> Hook for Debounce<H> {
+    fn handle(&self, delivery: &Delivery) {
+        let last = match clock::instant() {
+            Some(now) => now,
+            None => return self.shared.hook.handle(delivery),
+        };
+        let key = (self.shared.key)(delivery);
+        let mut pending = self.shared.pending.lock().unwrap();
+        if !pending.flushing {
+            pending.flushing = true;
+            let shared = self.shared.clone();
+            thread::spawn(move || shared.flush_quiet());
+        }
+        let size = pending
+            .batches
+            .get(&key)
+            .map(|batch| batch.size)
+            .unwrap_or(0)
+            + 1;
+        let batch = Batch {
+            latest: OwnedDelivery::from(delivery),
+            states: delivery.states.cloned(),
+            size: size,
+            last: last,
+        };
+        if self
+            .shared
+            .max_batch
+            .map(|max| size >= max)
+            .unwrap_or(false)
+        {
+            pending.batches.remove(&key);
+            drop(pending);
+            self.shared.run(&batch);
+        } else {
+            log_delivery!(debug, delivery; "holding back delivery {}", delivery.id);
+            pending.batches.insert(key, batch);
+            self.shared.changed.notify_one();
+        }
+    }
+
+    fn filters(&self) -> Vec<String> {
+        self.shared.hook.filters()
+    }
+}
+
+impl<H: Hook + 'static> Drop for Debounce<H> {
+    fn drop(&mut self) {
+        self.shared.pending.lock().unwrap().closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+/// the event, repository and ref of a delivery
+fn key(delivery: &Delivery) -> String {
+    let field = |pointer: &str| {
+        delivery
+            .json()
+            .and_then(|payload| payload.pointer(pointer))
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_owned()
+    };
+    format!(
+        "{} {} {}",
+        delivery.event,
+        field("/repository/full_name"),
+        field("/ref")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn debounce_bursts() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let debounce = Debounce::new(Duration::from_millis(50), move |delivery: &Delivery| {
+            sender.lock().unwrap().send(delivery.id.to_owned()).unwrap();
+        });
+        let push = |id: &str, branch: &str| {
+            let payload = format!(
+                r#"{{"ref":"refs/heads/{}","repository":{{"full_name":"octo/cat"}}}}"#,
+                branch
+            );
+            debounce.handle(&Delivery::new(id, "push", &payload, None));
+        };
+        for id in &["1", "2", "3"] {
+            push(id, "master");
+        }
+        push("4", "docs");
+        let mut handed = vec![
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+        ];
+        handed.sort();
+        assert_eq!(vec!["3", "4"], handed);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let batched = Debounce::new(Duration::from_secs(60), move |delivery: &Delivery| {
+            sender.lock().unwrap().send(delivery.id.to_owned()).unwrap();
+        })
+        .max_batch(2);
+        for id in &["1", "2", "3"] {
+            batched.handle(&Delivery::new(id, "push", "{}", None));
+        }
+        assert_eq!(Ok("2".to_owned()), receiver.try_recv());
+        drop(batched);
+        assert_eq!(
+            Ok("3".to_owned()),
+            receiver.recv_timeout(Duration::from_secs(5))
+        );
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let panicking = Debounce::new(Duration::from_millis(10), move |delivery: &Delivery| {
+            if delivery.id == "panics" {
+                panic!("hook panicked");
+            }
+            sender.lock().unwrap().send(delivery.id.to_owned()).unwrap();
+        });
+        panicking.handle(&Delivery::new("panics", "push", "{}", None));
+        thread::sleep(Duration::from_millis(50));
+        panicking.handle(&Delivery::new("handled", "push", "{}", None));
+        assert_eq!(
+            Ok("handled".to_owned()),
+            receiver.recv_timeout(Duration::from_secs(5))
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Decompression of request bodies compressed by proxies or senders
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+
+/// Reasons a compressed body could not be decoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// the body was compressed with an unsupported `Content-Encoding`
+    Unsupported(String),
+    /// the body, compressed or decompressed, exceeded the configured limit
+    TooLarge,
+    /// the body was not valid for its `Content-Encoding`
+    Invalid,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Unsupported(ref encoding) => {
+                write!(f, "unsupported content encoding '{}'", encoding)
+            }
+            DecodeError::TooLarge => write!(f, "payload too large"),
+            DecodeError::Invalid => write!(f, "invalid compressed payload"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// decodes a body sent with the given `Content-Encoding`, producing at most
+/// `limit` bytes so small compressed bodies can't expand without bound.
+/// bodies without an encoding are returned as is, and bodies of more than
+/// `limit` bytes are rejected before they're decoded
+pub fn decode<'a>(
+    encoding: Option<&str>,
+    body: &'a [u8],
+    limit: usize,
+) -> Result<Cow<'a, [u8]>, DecodeError> {
+    if body.len() > limit {
+        return Err(DecodeError::TooLarge);
+    }
+    let encoding = encoding.unwrap_or("").trim().to_ascii_lowercase();
+    match encoding.as_str() {
+        "" | "identity" => Ok(Cow::Borrowed(body)),
+        "gzip" | "x-gzip" => read(GzDecoder::new(body), limit).map(Cow::Owned),
+        // http's deflate is zlib wrapped but some senders send it raw.
+        // bodies which were zlib but too large aren't decoded a second time
+        "deflate" => match read(ZlibDecoder::new(body), limit) {
+            Err(DecodeError::Invalid) => read(DeflateDecoder::new(body), limit),
+            zlib => zlib,
+        }
+        .map(Cow::Owned),
+        _ => Err(DecodeError::Unsupported(encoding)),
+    }
+}
+
+fn read<R>(reader: R, limit: usize) -> Result<Vec<u8>, DecodeError>
+where
+    R: Read,
+{
+    let mut decoded = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|_| DecodeError::Invalid)?;
+    if decoded.len() > limit {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn decode_bodies() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"{\"zen\": \"hi\"}").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        assert_eq!(
+            Ok(b"{\"zen\": \"hi\"}".to_vec()),
+            decode(Some("gzip"), &gzipped, 1024).map(|body| body.into_owned())
+        );
+        assert_eq!(
+            Err(DecodeError::TooLarge),
+            decode(Some("gzip"), &gzipped, 4)
+        );
+        assert_eq!(Ok(Cow::Borrowed(&b"plain"[..])), decode(None, b"plain", 5));
+        assert_eq!(Err(DecodeError::TooLarge), decode(None, b"plain", 4));
+        assert_eq!(
+            Err(DecodeError::Unsupported("br".to_owned())),
+            decode(Some("br"), b"", 4)
+        );
+        assert_eq!(Err(DecodeError::Invalid), decode(Some("gzip"), b"nope", 4));
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 1024]).unwrap();
+        let zlibbed = encoder.finish().unwrap();
+        assert_eq!(
+            Err(DecodeError::TooLarge),
+            decode(Some("deflate"), &zlibbed, 512)
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Errors reported to observers when deliveries fail
+
+#[cfg(feature = "server")]
+use super::DecodeError;
+use super::ParseError;
+#[cfg(feature = "server")]
+use hyper;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Reasons a delivery failed, as reported to `Observer::on_error`
+#[derive(Debug)]
+pub enum Error {
+    /// the delivery could not be parsed
+    Parse(ParseError),
+    /// the delivery was not authentic
+    Auth(AuthError),
+    /// the delivery could not be received
+    Server(ServerError),
+    /// a hook failed to handle the delivery
+    Hook(HookError),
+}
+
+/// Reasons an authenticating hook rejected a delivery
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    /// the delivery's credentials, e.g. its signature, didn't match
+    InvalidCredentials,
+    /// the delivery carried no credentials, e.g. no signature header
+    MissingCredentials,
+}
+
+/// Reasons a server failed to receive a delivery
+#[derive(Debug)]
+pub enum ServerError {
+    /// the hub was shutting down
+    ShuttingDown,
+    /// the hub's in-flight limit was exhausted
+    Overloaded,
+    /// the request body could not be read
+    Io(io::Error),
+    /// the request body could not be decompressed
+    #[cfg(feature = "server")]
+    Decode(DecodeError),
+    /// the server failed to bind or serve
+    #[cfg(feature = "server")]
+    Http(hyper::Error),
+}
+
+/// Reasons a hook failed to handle a delivery
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookError {
+    /// the hook panicked, with the panic's message
+    Panicked(String),
+}
+
+/// errors describe only what failed, leaving why to their `source`
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(_) => write!(f, "failed to parse delivery"),
+            Error::Auth(_) => write!(f, "failed to authenticate delivery"),
+            Error::Server(_) => write!(f, "failed to receive delivery"),
+            Error::Hook(_) => write!(f, "hook failed to handle delivery"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::Parse(ref err) => Some(err),
+            Error::Auth(ref err) => Some(err),
+            Error::Server(ref err) => Some(err),
+            Error::Hook(ref err) => Some(err),
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::InvalidCredentials => write!(f, "failed to authenticate request"),
+            AuthError::MissingCredentials => write!(f, "request carried no credentials"),
+        }
+    }
+}
+
+impl error::Error for AuthError {}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::ShuttingDown => write!(f, "shutting down"),
+            ServerError::Overloaded => write!(f, "in-flight limit reached"),
+            ServerError::Io(_) => write!(f, "failed to read request"),
+            #[cfg(feature = "server")]
+            ServerError::Decode(_) => write!(f, "failed to decode request body"),
+            #[cfg(feature = "server")]
+            ServerError::Http(_) => write!(f, "failed to serve"),
+        }
+    }
+}
+
+impl error::Error for ServerError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            ServerError::Io(ref err) => Some(err),
+            #[cfg(feature = "server")]
+            ServerError::Decode(ref err) => Some(err),
+            #[cfg(feature = "server")]
+            ServerError::Http(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HookError::Panicked(ref message) => write!(f, "hook panicked: {}", message),
+        }
+    }
+}
+
+impl error::Error for HookError {}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::Parse(err)
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(err: AuthError) -> Error {
+        Error::Auth(err)
+    }
+}
+
+impl From<ServerError> for Error {
+    fn from(err: ServerError) -> Error {
+        Error::Server(err)
+    }
+}
+
+impl From<HookError> for Error {
+    fn from(err: HookError) -> Error {
+        Error::Hook(err)
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Error {
+        Error::Server(ServerError::Decode(err))
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Error {
+        Error::Server(ServerError::Http(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn error_sources() {
+        let err = Error::from(ParseError::MissingHeader("X-Github-Event"));
+        assert_eq!("failed to parse delivery", err.to_string());
+        assert_eq!(
+            Some("missing X-Github-Event header".to_owned()),
+            err.source().map(|source| source.to_string())
+        );
+        match err {
+            Error::Parse(ParseError::MissingHeader(name)) => assert_eq!("X-Github-Event", name),
+            other => panic!("unexpected {:?}", other),
+        }
+        let err = Error::from(ServerError::Overloaded);
+        assert!(err.source().unwrap().source().is_none());
+        // messages aren't repeated along the chain of sources
+        let err = Error::from(ServerError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            "reset",
+        )));
+        let mut messages = vec![err.to_string()];
+        let mut source = err.source();
+        while let Some(err) = source {
+            messages.push(err.to_string());
+            source = err.source();
+        }
+        assert_eq!(
+            vec![
+                "failed to receive delivery",
+                "failed to read request",
+                "reset"
+            ],
+            messages
+        );
+    }
+}
@@ -9,9 +9,19 @@ use case::CaseExt;
 
 // generated Event enum goes here
 
-// Enumeration of availble Github events
+// Enumeration of availble Github events.
+// payload structs are non-exhaustive, so fields may be added as Github adds
+// them, and fields they don't declare are ignored when deserializing
 include!(concat!(env!("OUT_DIR"), "/events.rs"));
 
+/// names of the events `Event` has variants for
+const EVENTS: &str = include_str!("../events.txt");
+
+/// returns true if `event` names an event with an `Event` variant
+pub fn known(event: &str) -> bool {
+    EVENTS.lines().any(|name| name.trim() == event)
+}
+
 /// to support enum deserialization, we need to
 /// patch the raw json from github with a field for the enum
 /// name
@@ -32,3 +42,153 @@ impl Default for Value {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lenient_payloads() {
+        let payload = include_str!("../data/public.json")
+            .replacen("{", r#"{"added_by_github": {"nested": [1, 2]},"#, 1)
+            .replace(
+                r#""login": "Codertocat","#,
+                r#""login": "Codertocat", "new": true,"#,
+            );
+        let patched = patch_payload_json("public", &payload);
+        match serde_json::from_str::<Event>(&patched) {
+            Ok(Event::Public { sender, .. }) => assert_eq!("Codertocat", sender.login),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(known("pull_request"));
+        assert!(!known("merge_queue"));
+    }
+
+    #[test]
+    fn security_alerts() {
+        let parse =
+            |event, payload| serde_json::from_str::<Event>(&patch_payload_json(event, payload));
+        match parse(
+            "dependabot_alert",
+            include_str!("../data/dependabot_alert.json"),
+        ) {
+            Ok(Event::DependabotAlert { action, alert, .. }) => {
+                assert_eq!(("dismissed", "dismissed"), (&action[..], &alert.state[..]));
+                assert_eq!("django", alert.dependency.package.name);
+                assert!(alert.security_advisory.withdrawn_at.is_none());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse(
+            "code_scanning_alert",
+            include_str!("../data/code_scanning_alert.json"),
+        ) {
+            Ok(Event::CodeScanningAlert {
+                action,
+                alert,
+                _ref,
+                ..
+            }) => {
+                assert_eq!(("fixed", "fixed"), (&action[..], &alert.state[..]));
+                assert_eq!("refs/heads/main", _ref);
+                assert!(alert.dismissed_by.is_none());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse(
+            "secret_scanning_alert",
+            include_str!("../data/secret_scanning_alert.json"),
+        ) {
+            Ok(Event::SecretScanningAlert {
+                action,
+                alert,
+                organization,
+                ..
+            }) => {
+                assert_eq!("resolved", action);
+                assert_eq!(Some("revoked"), alert.resolution.as_ref().map(|r| &r[..]));
+                assert!(organization.is_none());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(known("code_scanning_alert"));
+    }
+
+    #[test]
+    fn projects_and_discussions() {
+        let parse =
+            |event, payload| serde_json::from_str::<Event>(&patch_payload_json(event, payload));
+        match parse(
+            "projects_v2_item",
+            include_str!("../data/projects_v2_item.json"),
+        ) {
+            Ok(Event::ProjectsV2Item {
+                projects_v2_item,
+                changes,
+                ..
+            }) => {
+                assert_eq!("Issue", projects_v2_item.content_type);
+                assert!(changes
+                    .and_then(|changes| changes.pointer("/field_value/field_type").cloned())
+                    .is_some());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse("projects_v2", include_str!("../data/projects_v2.json")) {
+            Ok(Event::ProjectsV2 {
+                projects_v2,
+                organization,
+                ..
+            }) => {
+                assert!(projects_v2.closed_at.is_some());
+                assert_eq!("Octo-Org", organization.login);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse("discussion", include_str!("../data/discussion.json")) {
+            Ok(Event::Discussion {
+                action,
+                discussion,
+                answer,
+                ..
+            }) => {
+                assert_eq!("answered", action);
+                assert!(discussion.category.is_answerable);
+                assert_eq!(Some(544_078), answer.map(|answer| answer.id));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse(
+            "discussion_comment",
+            include_str!("../data/discussion_comment.json"),
+        ) {
+            Ok(Event::DiscussionComment {
+                comment,
+                discussion,
+                ..
+            }) => {
+                assert_eq!(discussion.id, comment.discussion_id);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn merge_groups() {
+        let patched = patch_payload_json("merge_group", include_str!("../data/merge_group.json"));
+        match serde_json::from_str::<Event>(&patched) {
+            Ok(Event::MergeGroup {
+                action,
+                reason,
+                merge_group,
+                ..
+            }) => {
+                assert_eq!("checks_requested", action);
+                assert!(reason.is_none());
+                assert_eq!("refs/heads/main", merge_group.base_ref);
+                assert_eq!(merge_group.head_sha, merge_group.head_commit.id);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Exporting deliveries as newline delimited json
+//!
+//! An `Export` selects deliveries by the time they were received, their
+//! event and their repository, e.g. for piping stored deliveries into
+//! analytics tools with `Store::export`. A hub exporting on a path serves
+//! its store's deliveries in response to `GET` requests for it, e.g.
+//! `/export?since=1700000000&event=push&repo=octocat/hello-world`, and with
+//! `follow=true` keeps streaming those it receives from then on. Hubs only
+//! store deliveries an authenticating hook accepted, so anyone able to reach
+//! the hub can't fill its store, or its exports, with forged deliveries
+
+#[cfg(feature = "server")]
+pub use self::endpoint::Exports;
+use super::Delivery;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::form_urlencoded;
+
+/// A selection of deliveries to export, all of them until narrowed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Export {
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    events: Vec<String>,
+    repos: Vec<String>,
+}
+
+impl Export {
+    pub fn new() -> Export {
+        Export {
+            ..Default::default()
+        }
+    }
+
+    /// selects deliveries received at or after `time`
+    pub fn since(mut self, time: SystemTime) -> Export {
+        self.since = Some(time);
+        self
+    }
+
+    /// selects deliveries received before `time`
+    pub fn until(mut self, time: SystemTime) -> Export {
+        self.until = Some(time);
+        self
+    }
+
+    /// selects deliveries of an event, or of any of the events added
+    pub fn event<E: Into<String>>(mut self, event: E) -> Export {
+        self.events.push(event.into());
+        self
+    }
+
+    /// selects deliveries of a repository, named `owner/name`,
+    /// or of any of the repositories added
+    pub fn repo<R: Into<String>>(mut self, repo: R) -> Export {
+        self.repos.push(repo.into());
+        self
+    }
+
+    /// the selection named by a query string's `since` and `until`, in
+    /// seconds since the unix epoch, and `event` and `repo` parameters,
+    /// which may be repeated. unknown parameters are ignored
+    pub fn from_query(query: &str) -> Export {
+        let time = |seconds: &str| {
+            seconds
+                .parse::<u64>()
+                .ok()
+                .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+        };
+        form_urlencoded::parse(query.as_bytes()).fold(Export::new(), |export, (name, value)| {
+            match &name[..] {
+                "since" => Export {
+                    since: time(&value),
+                    ..export
+                },
+                "until" => Export {
+                    until: time(&value),
+                    ..export
+                },
+                "event" => export.event(value.into_owned()),
+                "repo" => export.repo(value.into_owned()),
+                _ => export,
+            }
+        })
+    }
+
+    /// whether a delivery is selected
+    pub fn matches(&self, delivery: &Delivery) -> bool {
+        let received = delivery.received_at;
+        self.since.map(|since| received >= since).unwrap_or(true)
+            && self.until.map(|until| received < until).unwrap_or(true)
+            && (self.events.is_empty() || self.events.iter().any(|event| event == delivery.event))
+            && (self.repos.is_empty()
+                || delivery
+                    .repository()
+                    .map(|name| {
+                        self.repos
+                            .iter()
+                            .any(|repo| repo.eq_ignore_ascii_case(name))
+                    })
+                    .unwrap_or(false))
+    }
+}
+
+#[cfg(feature = "server")]
+mod endpoint {
+    use super::super::auth::Token;
+    use super::super::broadcast::Streams;
+    use super::super::observer::Observer;
+    use super::super::shutdown::Drain;
+    use super::super::{store, Delivery, Hook, Hub, Store};
+    use super::Export;
+    use hyper::server::{Request, Response};
+    use hyper::status::StatusCode;
+    use hyper::uri::RequestUri;
+    use std::io::{self, Write};
+    use std::str;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// how often streams following a store check whether the hub shuts down
+    const POLL: Duration = Duration::from_secs(1);
+
+    /// Serves a store's deliveries as newline delimited json
+    pub struct Exports {
+        store: Arc<Store>,
+        token: Token,
+    }
+
+    impl Exports {
+        /// responds with the deliveries the request's query selects, when
+        /// authorized, and those stored later while following them. this
+        /// occupies one of the server's threads while following, unless
+        /// `streams` already occupy all they may. returns the status
+        /// responded with
+        pub fn respond(
+            &self,
+            req: Request,
+            mut res: Response,
+            drain: &Drain,
+            streams: &Streams,
+        ) -> StatusCode {
+            let authorized = req
+                .headers
+                .get_raw("Authorization")
+                .and_then(|values| values.first())
+                .and_then(|value| str::from_utf8(value).ok())
+                .map(|authorization| self.token.verify(authorization))
+                .unwrap_or(false);
+            if !authorized {
+                *res.status_mut() = StatusCode::Unauthorized;
+                let _ = res.send(b"unauthorized");
+                return StatusCode::Unauthorized;
+            }
+            let query = match req.uri {
+                RequestUri::AbsolutePath(ref uri) => uri.splitn(2, '?').nth(1).unwrap_or(""),
+                _ => "",
+            };
+            let export = Export::from_query(query);
+            let follow = query.split('&').any(|param| param == "follow=true");
+            let _stream = if follow {
+                match streams.open() {
+                    Some(stream) => Some(stream),
+                    None => {
+                        *res.status_mut() = StatusCode::ServiceUnavailable;
+                        let _ = res.send(b"too many clients");
+                        return StatusCode::ServiceUnavailable;
+                    }
+                }
+            } else {
+                None
+            };
+            // subscribe before reading the store, so nothing stored meanwhile is missed
+            let followed = if follow {
+                Some(self.store.follow())
+            } else {
+                None
+            };
+            res.headers_mut()
+                .set_raw("Content-Type", vec![b"application/x-ndjson".to_vec()]);
+            let exported = res.start().and_then(|mut out| {
+                let exported = self
+                    .store
+                    .export(&export, &mut out)
+                    .and_then(|_| match followed {
+                        Some(followed) => {
+                            out.flush()?;
+                            while drain.accepting() {
+                                match followed.recv_timeout(POLL) {
+                                    Ok(ref line) if store::matches(&export, line) => {
+                                        out.write_all(line.as_bytes())?;
+                                        out.write_all(b"\n")?;
+                                        out.flush()?;
+                                    }
+                                    Ok(_) | Err(RecvTimeoutError::Timeout) => (),
+                                    Err(RecvTimeoutError::Disconnected) => break,
+                                }
+                            }
+                            Ok(())
+                        }
+                        None => Ok(()),
+                    });
+                out.end().and(exported)
+            });
+            if let Err(err) = exported.or_else(ignore_disconnects) {
+                warn!("failed to export deliveries: {}", err);
+            }
+            StatusCode::Ok
+        }
+    }
+
+    fn ignore_disconnects(err: io::Error) -> io::Result<()> {
+        match err.kind() {
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset => Ok(()),
+            _ => Err(err),
+        }
+    }
+
+    /// stores deliveries once they're verified
+    impl Observer for Arc<Store> {
+        fn on_verified(&self, delivery: &Delivery) {
+            self.handle(delivery)
+        }
+    }
+
+    impl Hub {
+        /// stores the deliveries the hub receives in `store`, serving them
+        /// as newline delimited json in response to `GET` requests for
+        /// `path`, e.g. `/export`, authorized with `token`. only deliveries
+        /// an authenticating hook accepts are stored, so a hub without one
+        /// stores nothing
+        pub fn export(&mut self, path: &str, token: Token, store: Store) -> Arc<Store> {
+            let store = Arc::new(store);
+            self.observe(store.clone());
+            let exports = Exports {
+                store: store.clone(),
+                token: token,
+            };
+            self.exports = Some((path.to_owned(), Arc::new(exports)));
+            store
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_deliveries() {
+        let mut delivery = Delivery::new("id", "public", include_str!("../data/public.json"), None);
+        delivery.received_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(Export::new().matches(&delivery));
+        assert!(Export::new()
+            .event("push")
+            .event("public")
+            .repo("codertocat/hello-world")
+            .matches(&delivery));
+        assert!(!Export::new().event("push").matches(&delivery));
+        assert!(!Export::new().repo("octocat/hello-world").matches(&delivery));
+        let query =
+            Export::from_query("since=1700000000&until=1700000001&event=public&follow=true");
+        assert_eq!(
+            Export::new()
+                .since(delivery.received_at)
+                .until(delivery.received_at + Duration::from_secs(1))
+                .event("public"),
+            query
+        );
+        assert!(query.matches(&delivery));
+        assert!(!Export::from_query("since=1700000001").matches(&delivery));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn export_endpoint() {
+        use super::super::auth::Token;
+        use super::super::server::{ServerConfig, XGithubDelivery, XGithubEvent, XHubSignature};
+        use super::super::{Hub, Store};
+        use crypto;
+        use hex;
+        use hyper::Client;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpStream;
+        use std::{env, fs, process};
+
+        let path = env::temp_dir().join(format!("afterparty-export-{}.ndjson", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut hub = Hub::new();
+        hub.handle_authenticated("public", "secret", |_: &Delivery| {});
+        hub.export(
+            "/export",
+            Token::bearer("admin"),
+            Store::open(&path).unwrap(),
+        );
+        let handle = hub
+            .run_with("127.0.0.1:0", ServerConfig::new().threads(4))
+            .unwrap();
+        let payload = include_str!("../data/public.json");
+        let signed = format!(
+            "sha1={}",
+            hex::encode(crypto::sign_hmac_sha1(b"secret", payload.as_bytes()))
+        );
+        let deliver = |id: &str, signature: &str| {
+            Client::new()
+                .post(&format!("http://{}/", handle.addr()))
+                .header(XGithubEvent("public".to_owned()))
+                .header(XGithubDelivery(id.to_owned()))
+                .header(XHubSignature(signature.to_owned()))
+                .body(payload)
+                .send()
+                .unwrap();
+        };
+        deliver("forged", "sha1=00");
+        deliver("stored", &signed);
+
+        let mut follower = TcpStream::connect(handle.addr()).unwrap();
+        write!(
+            follower,
+            "GET /export?event=public&follow=true HTTP/1.1\r\nHost: hub\r\nAuthorization: Bearer admin\r\n\r\n"
+        )
+        .unwrap();
+        let mut lines = BufReader::new(follower)
+            .lines()
+            .map(|line| line.unwrap())
+            .filter(|line| line.starts_with('{'));
+        assert!(lines.next().unwrap().contains(r#""id":"stored""#));
+        deliver("live", &signed);
+        assert!(lines.next().unwrap().contains(r#""id":"live""#));
+
+        let mut unauthorized = String::new();
+        Client::new()
+            .get(&format!("http://{}/export", handle.addr()))
+            .send()
+            .unwrap()
+            .read_to_string(&mut unauthorized)
+            .unwrap();
+        assert_eq!("unauthorized", unauthorized);
+        handle.stop(Duration::from_secs(2));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,586 @@
+//! A small expression language for filtering deliveries, e.g.
+//! `event == "push" && payload.ref == "refs/heads/main" && repo.owner == "myorg"`
+//!
+//! Expressions are compiled once and evaluated against each delivery. They
+//! compare fields with string, number, `true`, `false` and `null` literals
+//! using `==`, `!=`, `<`, `<=`, `>`, `>=` and `contains`, and combine
+//! comparisons with `&&`, `||`, `!` and parentheses. A field on its own is
+//! true when it is present and not `false`, `null`, zero or empty.
+//!
+//! Fields are
+//!
+//! * `event` and `id`, the delivery's event name and GUID
+//! * `header.{name}`, a request header
+//! * `payload.{path}`, a path into the json payload, e.g. `payload.commits[0].id`
+//! * `action`, shorthand for `payload.action`
+//! * `sender`, shorthand for `payload.sender.login`
+//! * `repo`, shorthand for `payload.repository.full_name`, `repo.owner` for
+//!   `payload.repository.owner.login` and `repo.{path}` for `payload.repository.{path}`
+
+use super::filter::Filter;
+use super::Delivery;
+use serde_json::{Number, Value};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Error returned when compiling an invalid `Expr`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprError {
+    position: usize,
+    message: String,
+}
+
+impl ExprError {
+    fn new<M>(position: usize, message: M) -> ExprError
+    where
+        M: Into<String>,
+    {
+        ExprError {
+            position: position,
+            message: message.into(),
+        }
+    }
+
+    /// byte offset into the expression at which the error was found
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl Error for ExprError {}
+
+/// A compiled filter expression
+#[derive(Debug, Clone)]
+pub struct Expr {
+    node: Node,
+    payload: bool,
+    source: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Cmp(Cmp),
+    Str(String),
+    Num(f64),
+    Word(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Or(Vec<Node>),
+    And(Vec<Node>),
+    Not(Box<Node>),
+    Compare(Operand, Cmp, Operand),
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Literal(Value),
+    Field(Field),
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    Event,
+    Id,
+    Header(String),
+    Payload(Vec<Segment>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl Expr {
+    /// compiles an expression
+    pub fn parse(source: &str) -> Result<Expr, ExprError> {
+        let tokens = lex(source)?;
+        let mut parser = Parser {
+            tokens: tokens,
+            pos: 0,
+            end: source.len(),
+            payload: false,
+            depth: 0,
+        };
+        let node = parser.or()?;
+        if let Some(&(position, _)) = parser.tokens.get(parser.pos) {
+            return Err(ExprError::new(position, "unexpected token"));
+        }
+        Ok(Expr {
+            node: node,
+            payload: parser.payload,
+            source: source.trim().to_owned(),
+        })
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ExprError;
+
+    fn from_str(s: &str) -> Result<Expr, ExprError> {
+        Expr::parse(s)
+    }
+}
+
+impl Filter for Expr {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        // only pay for parsing the payload when a payload field is referenced
+        let null = Value::Null;
+        let payload = if self.payload {
+            delivery.json().unwrap_or(&null)
+        } else {
+            &null
+        };
+        Context {
+            delivery: delivery,
+            payload: payload,
+        }
+        .eval(&self.node)
+    }
+
+    fn describe(&self) -> String {
+        format!("expr {}", self.source)
+    }
+}
+
+fn lex(source: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '&' | '|' | '=' => match chars.next() {
+                Some((_, next)) if next == c => match c {
+                    '&' => Token::And,
+                    '|' => Token::Or,
+                    _ => Token::Cmp(Cmp::Eq),
+                },
+                _ => return Err(ExprError::new(start, format!("expected '{}{}'", c, c))),
+            },
+            '!' | '<' | '>' => {
+                let eq = match chars.peek() {
+                    Some(&(_, '=')) => {
+                        chars.next();
+                        true
+                    }
+                    _ => false,
+                };
+                match (c, eq) {
+                    ('!', true) => Token::Cmp(Cmp::Ne),
+                    ('!', false) => Token::Not,
+                    ('<', true) => Token::Cmp(Cmp::Le),
+                    ('<', false) => Token::Cmp(Cmp::Lt),
+                    ('>', true) => Token::Cmp(Cmp::Ge),
+                    _ => Token::Cmp(Cmp::Gt),
+                }
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => value.push('\n'),
+                            Some((_, 't')) => value.push('\t'),
+                            Some((_, escaped)) => value.push(escaped),
+                            None => return Err(ExprError::new(start, "unterminated string")),
+                        },
+                        Some((_, quote)) if quote == c => {
+                            tokens.push((start, Token::Str(value)));
+                            break;
+                        }
+                        Some((_, other)) => value.push(other),
+                        None => return Err(ExprError::new(start, "unterminated string")),
+                    }
+                }
+                continue;
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, next)) = chars.peek() {
+                    if !(next.is_ascii_digit() || next == '.' || next == 'e' || next == 'E') {
+                        break;
+                    }
+                    end = i + next.len_utf8();
+                    chars.next();
+                }
+                match source[start..end].parse::<f64>() {
+                    Ok(number) => Token::Num(number),
+                    Err(_) => return Err(ExprError::new(start, "invalid number")),
+                }
+            }
+            c if is_word(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, next)) = chars.peek() {
+                    if !(is_word(next) || next == '-') {
+                        break;
+                    }
+                    end = i + next.len_utf8();
+                    chars.next();
+                }
+                match &source[start..end] {
+                    "contains" => Token::Cmp(Cmp::Contains),
+                    word => Token::Word(word.to_owned()),
+                }
+            }
+            other => {
+                return Err(ExprError::new(
+                    start,
+                    format!("unexpected character '{}'", other),
+                ))
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '[' || c == ']'
+}
+
+/// how deeply `!` and parentheses may nest. chains of `&&` and `||` are
+/// parsed into single nodes, so this bounds the depth of the tree and
+/// expressions can't exhaust the stack of the recursive parser, of
+/// evaluation or of dropping them
+const MAX_DEPTH: usize = 64;
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+    payload: bool,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|&(_, ref token)| token)
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), ExprError> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token)
+            }
+            None => Err(ExprError::new(self.end, "unexpected end of expression")),
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, ExprError> {
+        let mut nodes = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            nodes.push(self.and()?);
+        }
+        Ok(match nodes.len() {
+            1 => nodes.remove(0),
+            _ => Node::Or(nodes),
+        })
+    }
+
+    fn and(&mut self) -> Result<Node, ExprError> {
+        let mut nodes = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            nodes.push(self.unary()?);
+        }
+        Ok(match nodes.len() {
+            1 => nodes.remove(0),
+            _ => Node::And(nodes),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        let node = match self.peek() {
+            Some(&Token::Not) => {
+                self.nest()?;
+                Node::Not(Box::new(self.unary()?))
+            }
+            Some(&Token::LParen) => {
+                self.nest()?;
+                let node = self.or()?;
+                match self.next()? {
+                    (_, Token::RParen) => node,
+                    (position, _) => return Err(ExprError::new(position, "expected ')'")),
+                }
+            }
+            _ => return self.comparison(),
+        };
+        self.depth -= 1;
+        Ok(node)
+    }
+
+    /// consumes a `!` or `(`, failing when they're nested too deeply
+    fn nest(&mut self) -> Result<(), ExprError> {
+        let (position, _) = self.next()?;
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ExprError::new(position, "expression nested too deeply"));
+        }
+        Ok(())
+    }
+
+    fn comparison(&mut self) -> Result<Node, ExprError> {
+        let left = self.operand()?;
+        match self.peek() {
+            Some(&Token::Cmp(cmp)) => {
+                self.pos += 1;
+                Ok(Node::Compare(left, cmp, self.operand()?))
+            }
+            _ => Ok(Node::Truthy(left)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, ExprError> {
+        match self.next()? {
+            (_, Token::Str(value)) => Ok(Operand::Literal(Value::String(value))),
+            (position, Token::Num(number)) => Number::from_f64(number)
+                .map(|number| Operand::Literal(Value::Number(number)))
+                .ok_or_else(|| ExprError::new(position, "invalid number")),
+            (_, Token::Word(ref word)) if word == "true" => Ok(Operand::Literal(Value::Bool(true))),
+            (_, Token::Word(ref word)) if word == "false" => {
+                Ok(Operand::Literal(Value::Bool(false)))
+            }
+            (_, Token::Word(ref word)) if word == "null" => Ok(Operand::Literal(Value::Null)),
+            (position, Token::Word(word)) => {
+                let field = field(position, &word)?;
+                if let Field::Payload(_) = field {
+                    self.payload = true;
+                }
+                Ok(Operand::Field(field))
+            }
+            (position, _) => Err(ExprError::new(position, "expected a field or literal")),
+        }
+    }
+}
+
+/// resolves a dotted path to the field it refers to
+fn field(position: usize, path: &str) -> Result<Field, ExprError> {
+    let invalid = || ExprError::new(position, format!("invalid field '{}'", path));
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let mut pieces = part.split('[');
+        match pieces.next() {
+            Some(key) if !key.is_empty() => segments.push(Segment::Key(key.to_owned())),
+            _ => return Err(invalid()),
+        }
+        for index in pieces {
+            if !index.ends_with(']') {
+                return Err(invalid());
+            }
+            let index = index[..index.len() - 1]
+                .parse::<usize>()
+                .map_err(|_| invalid())?;
+            segments.push(Segment::Index(index));
+        }
+    }
+    let root = match segments.remove(0) {
+        Segment::Key(root) => root,
+        Segment::Index(_) => return Err(invalid()),
+    };
+    let key = |key: &str| Segment::Key(key.to_owned());
+    let prefixed = |mut prefix: Vec<Segment>, rest: Vec<Segment>| {
+        prefix.extend(rest);
+        Field::Payload(prefix)
+    };
+    match root.as_str() {
+        "event" if segments.is_empty() => Ok(Field::Event),
+        "id" if segments.is_empty() => Ok(Field::Id),
+        "header" => match segments.as_slice() {
+            [Segment::Key(name)] => Ok(Field::Header(name.clone())),
+            _ => Err(invalid()),
+        },
+        "payload" => Ok(Field::Payload(segments)),
+        "action" => Ok(prefixed(vec![key("action")], segments)),
+        "sender" if segments.is_empty() => {
+            Ok(prefixed(vec![key("sender"), key("login")], segments))
+        }
+        "sender" => Ok(prefixed(vec![key("sender")], segments)),
+        "repo" if segments.is_empty() => Ok(prefixed(
+            vec![key("repository"), key("full_name")],
+            segments,
+        )),
+        "repo" if segments == [key("owner")] => Ok(prefixed(
+            vec![key("repository"), key("owner"), key("login")],
+            vec![],
+        )),
+        "repo" => Ok(prefixed(vec![key("repository")], segments)),
+        _ => Err(ExprError::new(
+            position,
+            format!("unknown field '{}'", path),
+        )),
+    }
+}
+
+struct Context<'a, 'd: 'a> {
+    delivery: &'a Delivery<'d>,
+    payload: &'a Value,
+}
+
+impl<'a, 'd> Context<'a, 'd> {
+    fn eval(&self, node: &Node) -> bool {
+        match *node {
+            Node::Or(ref nodes) => nodes.iter().any(|node| self.eval(node)),
+            Node::And(ref nodes) => nodes.iter().all(|node| self.eval(node)),
+            Node::Not(ref node) => !self.eval(node),
+            Node::Compare(ref left, cmp, ref right) => {
+                compare(&self.resolve(left), cmp, &self.resolve(right))
+            }
+            Node::Truthy(ref operand) => truthy(&self.resolve(operand)),
+        }
+    }
+
+    fn resolve<'o>(&self, operand: &'o Operand) -> Cow<'o, Value>
+    where
+        'a: 'o,
+    {
+        let field = match *operand {
+            Operand::Literal(ref value) => return Cow::Borrowed(value),
+            Operand::Field(ref field) => field,
+        };
+        match *field {
+            Field::Event => Cow::Owned(Value::String(self.delivery.event.to_owned())),
+            Field::Id => Cow::Owned(Value::String(self.delivery.id.to_owned())),
+            Field::Header(ref name) => Cow::Owned(
+                self.delivery
+                    .header(name)
+                    .map(|value| Value::String(value.to_owned()))
+                    .unwrap_or(Value::Null),
+            ),
+            Field::Payload(ref segments) => {
+                let mut value = Some(self.payload);
+                for segment in segments {
+                    value = match (segment, value) {
+                        (&Segment::Key(ref key), Some(&Value::Object(ref map))) => map.get(key),
+                        (&Segment::Index(index), Some(&Value::Array(ref items))) => {
+                            items.get(index)
+                        }
+                        _ => None,
+                    };
+                }
+                value.map(Cow::Borrowed).unwrap_or(Cow::Owned(Value::Null))
+            }
+        }
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// compares json values, numbers by value regardless of their representation
+pub fn compare(a: &Value, cmp: Cmp, b: &Value) -> bool {
+    let order = || match (a, b) {
+        (&Value::Number(_), &Value::Number(_)) => a
+            .as_f64()
+            .and_then(|a| b.as_f64().and_then(|b| a.partial_cmp(&b))),
+        (&Value::String(ref a), &Value::String(ref b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    match cmp {
+        Cmp::Eq => equal(a, b),
+        Cmp::Ne => !equal(a, b),
+        Cmp::Lt => order() == Some(Ordering::Less),
+        Cmp::Le => order().map(|o| o != Ordering::Greater).unwrap_or(false),
+        Cmp::Gt => order() == Some(Ordering::Greater),
+        Cmp::Ge => order().map(|o| o != Ordering::Less).unwrap_or(false),
+        Cmp::Contains => match (a, b) {
+            (&Value::String(ref a), &Value::String(ref b)) => a.contains(b.as_str()),
+            (&Value::Array(ref items), _) => items.iter().any(|item| equal(item, b)),
+            (&Value::Object(ref map), &Value::String(ref key)) => map.contains_key(key),
+            _ => false,
+        },
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match *value {
+        Value::Null => false,
+        Value::Bool(b) => b,
+        Value::Number(ref n) => n.as_f64().map(|n| n != 0.0).unwrap_or(true),
+        Value::String(ref s) => !s.is_empty(),
+        Value::Array(ref items) => !items.is_empty(),
+        Value::Object(ref map) => !map.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC: &str = include_str!("../data/public.json");
+
+    #[test]
+    fn filter_expressions() {
+        let headers = vec![("X-Github-Hook-Id".to_owned(), "42".to_owned())];
+        let mut delivery = Delivery::new("id", "public", PUBLIC, None);
+        delivery.headers = &headers;
+        let matches = |source: &str| Expr::parse(source).unwrap().matches(&delivery);
+        assert!(matches(
+            r#"event == "public" && payload.repository.name == "Hello-World" && repo.owner == "Codertocat""#
+        ));
+        assert!(matches(
+            r#"repo == "Codertocat/Hello-World" && sender == 'Codertocat'"#
+        ));
+        assert!(matches(
+            r#"!(event == "issues" || header.x-github-hook-id != "42")"#
+        ));
+        assert!(matches(
+            "repo.id > 1 && payload.repository.private == false"
+        ));
+        assert!(matches(
+            r#"repo.full_name contains "Hello" && !payload.missing"#
+        ));
+        assert!(!matches(r#"payload.commits[0].id == "abc""#));
+        assert!(!matches(r#"event == "issues""#));
+
+        assert_eq!(
+            Some(6),
+            Expr::parse(r#"event = "push""#).err().map(|e| e.position())
+        );
+        assert!(Expr::parse("repo.").is_err());
+        assert!(Expr::parse("unknown == 1").is_err());
+        assert!(Expr::parse(r#"(event == "push""#).is_err());
+        assert!(Expr::parse(r#"event == "push" event"#).is_err());
+        let nested = format!("{}true{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(Some(64), Expr::parse(&nested).err().map(|e| e.position()));
+        assert!(Expr::parse(&format!("{}true", "!".repeat(64))).is_ok());
+        let chained = vec![r#"event == "issues""#; 100_000].join(" || ");
+        assert!(!matches(&format!("{} && true", chained)));
+        assert!(matches(&format!(r#"{} || event == "public""#, chained)));
+    }
+}
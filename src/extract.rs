@@ -0,0 +1,241 @@
+//! Typed handler arguments
+//!
+//! Closures taking up to six arguments implementing `FromDelivery` may be
+//! handed to `Hub::handle` in place of a hook. Each argument is extracted
+//! from the delivery before the closure is called, e.g.
+//! `hub.handle("push", |Payload(push): Payload<Push>, repo: Repository| { })`.
+//! Deliveries any argument can't be extracted from are skipped.
+//!
+//! `State<T>` hands closures state registered with `Hub::with_state`, e.g. a
+//! database pool, in place of capturing an `Arc` of it in each of them.
+
+use super::events::{Event, Repo, Repository, User};
+use super::{Delivery, Hook};
+use serde::Deserialize;
+use serde_json;
+use std::any::Any;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Values which may be extracted from a delivery
+pub trait FromDelivery: Sized {
+    /// returns None if the delivery doesn't provide this value
+    fn from_delivery(delivery: &Delivery) -> Option<Self>;
+}
+
+/// The whole payload, deserialized into any type
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload<T>(pub T);
+
+impl<T: Deserialize> FromDelivery for Payload<T> {
+    fn from_delivery(delivery: &Delivery) -> Option<Payload<T>> {
+        match serde_json::from_str::<T>(delivery.unparsed_payload) {
+            Ok(payload) => Some(Payload(payload)),
+            Err(err) => {
+                log_delivery!(
+                    debug,
+                    delivery;
+                    "delivery {} payload didn't match: {}",
+                    delivery.id,
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+/// State shared by a hub's hooks, registered with `Hub::with_state`
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Any + Send + Sync> FromDelivery for State<T> {
+    fn from_delivery(delivery: &Delivery) -> Option<State<T>> {
+        let state = delivery.state();
+        if state.is_none() {
+            log_delivery!(
+                warn,
+                delivery;
+                "no {} state registered with the hub",
+                ::std::any::type_name::<T>()
+            );
+        }
+        state.map(State)
+    }
+}
+
+/// deserializes a top level field of a delivery's payload
+fn field<T: Deserialize>(delivery: &Delivery, name: &str) -> Option<T> {
+    delivery
+        .json()
+        .and_then(|payload| payload.get(name))
+        .and_then(|value| serde_json::from_value(value.clone()).ok())
+}
+
+impl FromDelivery for Event {
+    fn from_delivery(delivery: &Delivery) -> Option<Event> {
+        delivery.parse_payload().ok()
+    }
+}
+
+/// the repository of most events
+impl FromDelivery for Repo {
+    fn from_delivery(delivery: &Delivery) -> Option<Repo> {
+        field(delivery, "repository")
+    }
+}
+
+/// the repository of push events
+impl FromDelivery for Repository {
+    fn from_delivery(delivery: &Delivery) -> Option<Repository> {
+        field(delivery, "repository")
+    }
+}
+
+/// the user which triggered the event
+impl FromDelivery for User {
+    fn from_delivery(delivery: &Delivery) -> Option<User> {
+        field(delivery, "sender")
+    }
+}
+
+/// Values which may be registered as hooks: hooks themselves,
+/// and closures taking `FromDelivery` arguments
+pub trait IntoHook<Args> {
+    type Hook: Hook + 'static;
+
+    fn into_hook(self) -> Self::Hook;
+}
+
+impl<H: Hook + 'static> IntoHook<()> for H {
+    type Hook = H;
+
+    fn into_hook(self) -> H {
+        self
+    }
+}
+
+/// A hook extracting a closure's arguments from deliveries
+pub struct Extract<F, Args> {
+    handler: F,
+    args: PhantomData<fn(Args)>,
+}
+
+macro_rules! extract {
+    ($($arg:ident),+) => {
+        impl<F, $($arg),+> IntoHook<($($arg,)+)> for F
+        where
+            F: Fn($($arg),+) + Send + Sync + 'static,
+            $($arg: FromDelivery + 'static),+
+        {
+            type Hook = Extract<F, ($($arg,)+)>;
+
+            fn into_hook(self) -> Self::Hook {
+                Extract {
+                    handler: self,
+                    args: PhantomData,
+                }
+            }
+        }
+
+        impl<F, $($arg),+> Hook for Extract<F, ($($arg,)+)>
+        where
+            F: Fn($($arg),+) + Send + Sync,
+            $($arg: FromDelivery),+
+        {
+            #[allow(non_snake_case)]
+            fn handle(&self, delivery: &Delivery) {
+                $(
+                    let $arg = match $arg::from_delivery(delivery) {
+                        Some(arg) => arg,
+                        None => {
+                            log_delivery!(
+                                debug,
+                                delivery;
+                                "delivery {} skipped, missing handler arguments",
+                                delivery.id
+                            );
+                            return;
+                        }
+                    };
+                )+
+                (self.handler)($($arg),+)
+            }
+        }
+    };
+}
+
+extract!(A);
+extract!(A, B);
+extract!(A, B, C);
+extract!(A, B, C, D);
+extract!(A, B, C, D, E);
+extract!(A, B, C, D, E, G);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use Hub;
+
+    #[test]
+    fn extract_arguments() {
+        let delivery = Delivery::new("id", "public", include_str!("../data/public.json"), None);
+        assert_eq!(
+            "Codertocat/Hello-World",
+            Repo::from_delivery(&delivery).unwrap().full_name
+        );
+        assert_eq!("Codertocat", User::from_delivery(&delivery).unwrap().login);
+        assert!(Payload::<Vec<u8>>::from_delivery(&delivery).is_none());
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let mut hub = Hub::new();
+        let counter = handled.clone();
+        hub.handle("public", move |repo: Repo, sender: User| {
+            assert_eq!(repo.owner.login, sender.login);
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        // payloads aren't lists, so this hook is skipped
+        let counter = handled.clone();
+        hub.handle("public", move |_: Payload<Vec<u8>>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        hub.handle("public", |_: &Delivery| {});
+        let mut delivery = delivery;
+        assert!(hub.deliver(&mut delivery));
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn extract_state() {
+        let mut hub = Hub::new();
+        hub.with_state(AtomicUsize::new(0));
+        hub.handle("public", |counter: State<AtomicUsize>, _: Repo| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        // there's no string state, so this hook is skipped
+        let skipped = Arc::new(AtomicUsize::new(0));
+        let handled = skipped.clone();
+        hub.handle("public", move |_: State<String>| {
+            handled.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut delivery = Delivery::new("id", "public", include_str!("../data/public.json"), None);
+        assert!(hub.deliver(&mut delivery));
+        let counter = delivery.state::<AtomicUsize>().unwrap();
+        assert_eq!(1, counter.load(Ordering::SeqCst));
+        assert_eq!(0, skipped.load(Ordering::SeqCst));
+
+        let unhandled = Delivery::new("id", "ping", "{}", None);
+        assert!(State::<AtomicUsize>::from_delivery(&unhandled).is_none());
+    }
+}
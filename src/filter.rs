@@ -0,0 +1,68 @@
+//! Filters deciding which deliveries reach a hook
+//!
+//! Filters are evaluated before a hook runs so hooks only see the deliveries
+//! they care about. Deliveries a filter doesn't match are skipped quietly,
+//! unlike those an authenticator rejects.
+
+use super::{Delivery, Hook};
+pub use actions::Actions;
+pub use bots::IgnoreBots;
+pub use expr::{Expr, ExprError};
+pub use jsonpath::{InvalidJsonPath, JsonPath, PathFilter};
+pub use labels::Labels;
+pub use paths::{InvalidGlob, Paths};
+#[cfg(feature = "registration")]
+pub use people::Membership;
+pub use people::People;
+
+/// Decides whether a delivery should be handed to a hook
+pub trait Filter: Send + Sync {
+    /// returns true if the delivery should be handled
+    fn matches(&self, delivery: &Delivery) -> bool;
+
+    /// what the filter matches, as listed by `Hub::registry`
+    fn describe(&self) -> String {
+        "condition".to_owned()
+    }
+}
+
+impl<F> Filter for F
+where
+    F: Fn(&Delivery) -> bool,
+    F: Sync + Send,
+{
+    fn matches(&self, delivery: &Delivery) -> bool {
+        self(delivery)
+    }
+}
+
+/// A hook which only hands deliveries matching a filter to its inner hook
+pub struct FilterHook<F: Filter + 'static, H: Hook + 'static> {
+    filter: F,
+    hook: H,
+}
+
+impl<F: Filter + 'static, H: Hook + 'static> FilterHook<F, H> {
+    pub fn new(filter: F, hook: H) -> FilterHook<F, H> {
+        FilterHook {
+            filter: filter,
+            hook: hook,
+        }
+    }
+}
+
+impl<F: Filter + 'static, H: Hook + 'static> Hook for FilterHook<F, H> {
+    fn handle(&self, delivery: &Delivery) {
+        if self.filter.matches(delivery) {
+            self.hook.handle(delivery)
+        } else {
+            log_delivery!(debug, delivery; "delivery {} filtered out", delivery.id);
+        }
+    }
+
+    fn filters(&self) -> Vec<String> {
+        let mut filters = vec![self.filter.describe()];
+        filters.extend(self.hook.filters());
+        filters
+    }
+}
@@ -0,0 +1,287 @@
+//! Relays deliveries to other receivers
+//!
+//! A `Forward` hook posts each delivery it handles to another url, e.g. an
+//! internal service behind the hub, with the headers Github sent it and the
+//! body as it was received, so the receiver can verify its signature as if
+//! Github had delivered it directly. Github's signatures aren't relayed with
+//! payloads which differ from those they signed, e.g. transformed or redacted
+//! ones, or envelopes. Forwards re-signing deliveries with a secret of their
+//! own instead let receivers verify them without sharing the webhook's secret
+
+use super::cloudevents;
+use super::crypto;
+use super::proxy::{Proxy, ProxyConnector};
+use super::secret::Secret;
+use super::{Delivery, Forwarder, Hook};
+use hex;
+use hyper;
+use hyper::header::{ContentType, Headers, UserAgent};
+use hyper::mime::Mime;
+use hyper::Client;
+use serde_json;
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+const USER_AGENT: &str = concat!("afterparty/", env!("CARGO_PKG_VERSION"));
+
+/// headers relayed along with the payload, when Github sent them
+const RELAYED: &[&str] = &[
+    "X-Github-Event",
+    "X-Github-Delivery",
+    "X-Github-Hook-Id",
+    "X-Github-Hook-Installation-Target-Id",
+    "X-Github-Hook-Installation-Target-Type",
+    "X-Hub-Signature",
+    "X-Hub-Signature-256",
+];
+
+/// the header forwarded requests carry their delivery's GUID in, so
+/// receivers can tell relayed deliveries and correlate them with the hub's
+const RELAY: &str = "X-Afterparty-Relay";
+
+/// A hook posting deliveries to a url
+pub struct Forward {
+    url: String,
+    proxy: Option<Proxy>,
+    timeout: Duration,
+    cloud_events: bool,
+    secret: Option<Secret>,
+    client: Client,
+}
+
+impl Forward {
+    /// forwards deliveries to `url`, connecting to it
+    /// directly and waiting up to 10 seconds on it
+    pub fn new<U: Into<String>>(url: U) -> Forward {
+        let timeout = Duration::from_secs(10);
+        Forward {
+            url: url.into(),
+            proxy: None,
+            timeout: timeout,
+            cloud_events: false,
+            secret: None,
+            client: client(None, timeout),
+        }
+    }
+
+    /// connects through a proxy, e.g. `Proxy::from_env(url)`.
+    /// `None` connects directly
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> Forward {
+        self.proxy = proxy;
+        self.client = client(self.proxy.clone(), self.timeout);
+        self
+    }
+
+    /// how long to wait on reads and writes of the receiver
+    pub fn timeout(mut self, timeout: Duration) -> Forward {
+        self.timeout = timeout;
+        self.client = client(self.proxy.clone(), self.timeout);
+        self
+    }
+
+    /// posts deliveries wrapped in CloudEvents envelopes, in structured
+    /// mode, see the `cloudevents` module, instead of as Github sent them
+    pub fn cloud_events(mut self) -> Forward {
+        self.cloud_events = true;
+        self
+    }
+
+    /// signs the bodies posted with `secret`, as Github signs them, in place
+    /// of Github's signatures, e.g. a secret of this receiver's own. both
+    /// `X-Hub-Signature` and `X-Hub-Signature-256` are set, and envelopes
+    /// are signed too
+    pub fn sign<S: Into<String>>(mut self, secret: S) -> Forward {
+        self.secret = Some(Secret::new(secret));
+        self
+    }
+
+    /// the url deliveries are forwarded to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// posts a delivery to the url, failing when it can't be
+    /// sent or the receiver doesn't respond with a success status
+    pub fn send(&self, delivery: &Delivery) -> hyper::Result<()> {
+        // Github's signatures are only relayed with what they signed
+        let relayed = self.secret.is_none()
+            && !self.cloud_events
+            && delivery.signature.is_some()
+            && delivery.original_payload() == delivery.unparsed_payload;
+        let mut headers = Headers::new();
+        let signed = |name: &&str| !relayed && name.starts_with("X-Hub-Signature");
+        for name in RELAYED.iter().filter(|name| !signed(name)) {
+            if let Some(value) = delivery.header(name) {
+                headers.set_raw(*name, vec![value.as_bytes().to_vec()]);
+            }
+        }
+        headers.set_raw(RELAY, vec![delivery.id.as_bytes().to_vec()]);
+        headers.set(UserAgent(USER_AGENT.to_owned()));
+        let envelope;
+        let body = if self.cloud_events {
+            envelope = serde_json::to_string(&cloudevents::envelope(delivery)).map_err(|err| {
+                hyper::Error::Io(io::Error::new(io::ErrorKind::Other, err.to_string()))
+            })?;
+            let media = cloudevents::CONTENT_TYPE.parse::<Mime>();
+            headers.set(ContentType(media.map_err(|_| hyper::Error::Header)?));
+            envelope.as_bytes()
+        } else {
+            headers.set(ContentType::json());
+            match (delivery.encoded_body, delivery.header("Content-Encoding")) {
+                // compressed bodies are relayed as they were received,
+                // as their sender may have signed them compressed
+                (Some(body), Some(encoding)) if relayed => {
+                    headers.set_raw("Content-Encoding", vec![encoding.as_bytes().to_vec()]);
+                    body
+                }
+                _ => delivery.unparsed_payload.as_bytes(),
+            }
+        };
+        if let Some(ref secret) = self.secret {
+            let signature = crypto::sign_hmac_sha1(secret.as_bytes(), body);
+            let signature = format!("sha1={}", hex::encode(signature));
+            headers.set_raw("X-Hub-Signature", vec![signature.into_bytes()]);
+            let signature = crypto::sign_hmac_sha256(secret.as_bytes(), body);
+            let signature = format!("sha256={}", hex::encode(signature));
+            headers.set_raw("X-Hub-Signature-256", vec![signature.into_bytes()]);
+        }
+        let response = self
+            .client
+            .post(&self.url)
+            .headers(headers)
+            .body(body)
+            .send()?;
+        if !response.status.is_success() {
+            return Err(hyper::Error::Status);
+        }
+        Ok(())
+    }
+}
+
+impl Hook for Forward {
+    fn handle(&self, delivery: &Delivery) {
+        if let Err(err) = self.send(delivery) {
+            log_delivery!(
+                warn,
+                delivery;
+                "failed to forward delivery {} to {}: {}",
+                delivery.id,
+                self.url,
+                err
+            );
+        }
+    }
+}
+
+impl Forwarder for Forward {
+    fn forward(&self, delivery: &Delivery) -> Result<(), Box<Error + Send + Sync>> {
+        self.send(delivery).map_err(Into::into)
+    }
+}
+
+fn client(proxy: Option<Proxy>, timeout: Duration) -> Client {
+    let mut client = Client::with_connector(ProxyConnector::new(proxy));
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::auth::{Authenticator, Signature};
+    use super::super::server::ServerConfig;
+    use super::super::Hub;
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn forward_deliveries() {
+        let verified = Arc::new(AtomicUsize::new(0));
+        let counter = verified.clone();
+        let mut receiver = Hub::new();
+        receiver.handle_authenticated("public", "secret", move |_: &Delivery| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let receiver = receiver
+            .run_with("127.0.0.1:0", ServerConfig::new().threads(2))
+            .unwrap();
+
+        let payload = include_str!("../data/public.json");
+        let signature = format!(
+            "sha1={}",
+            ::hex::encode(::crypto::sign_hmac_sha1(b"secret", payload.as_bytes()))
+        );
+        let headers = vec![
+            ("X-Github-Event".to_owned(), "public".to_owned()),
+            ("X-Github-Delivery".to_owned(), "id".to_owned()),
+            ("X-Hub-Signature".to_owned(), signature),
+            ("Host".to_owned(), "hub".to_owned()),
+        ];
+        let delivery = Delivery::parse(&headers, payload.as_bytes(), None).unwrap();
+        let forward = Forward::new(format!("http://{}/", receiver.addr()));
+        forward.send(&delivery).unwrap();
+        assert_eq!(1, verified.load(Ordering::SeqCst));
+        // envelopes aren't signed like the payloads they wrap
+        forward.cloud_events().send(&delivery).unwrap();
+        assert_eq!(1, verified.load(Ordering::SeqCst));
+        assert!(Forward::new("http://127.0.0.1:1/").send(&delivery).is_err());
+        receiver.stop(Duration::from_secs(1));
+
+        // compressed bodies are relayed as they were received, and Github's
+        // signatures aren't relayed with payloads other than those signed
+        let (sender, received) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let mut plain = Hub::new();
+        plain.decompress(1 << 20);
+        plain.handle("public", move |delivery: &Delivery| {
+            let signature = delivery.signature.map(str::to_owned);
+            let signed = Signature::new("secret").authenticate(delivery);
+            sender.lock().unwrap().send((signature, signed)).unwrap();
+        });
+        let plain = plain.run("127.0.0.1:0").unwrap();
+        let forward = Forward::new(format!("http://{}/", plain.addr()));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let mut encoded = headers.clone();
+        encoded.push(("Content-Encoding".to_owned(), "gzip".to_owned()));
+        let mut compressed = Delivery::parse(&encoded, payload.as_bytes(), None).unwrap();
+        compressed.encoded_body = Some(&gzipped);
+        forward.send(&compressed).unwrap();
+        assert!(received.recv().unwrap().1);
+        let transformed = delivery.with_payload(r#"{"transformed":true}"#);
+        forward.send(&transformed).unwrap();
+        assert_eq!((None, false), received.recv().unwrap());
+        plain.stop(Duration::from_secs(1));
+
+        let relayed = Arc::new(AtomicUsize::new(0));
+        let counter = relayed.clone();
+        let mut downstream = Hub::new();
+        downstream.handle_authenticated("public", "downstream", move |delivery: &Delivery| {
+            assert_eq!(Some("id"), delivery.header(RELAY));
+            let signature =
+                ::crypto::sign_hmac_sha256(b"downstream", delivery.unparsed_payload.as_bytes());
+            assert_eq!(
+                Some(format!("sha256={}", ::hex::encode(signature)).as_str()),
+                delivery.header("X-Hub-Signature-256")
+            );
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let downstream = downstream.run("127.0.0.1:0").unwrap();
+        let forward = Forward::new(format!("http://{}/", downstream.addr()));
+        forward.send(&delivery).unwrap();
+        assert_eq!(0, relayed.load(Ordering::SeqCst));
+        let forward = forward.sign("downstream");
+        forward.send(&delivery).unwrap();
+        assert_eq!(1, relayed.load(Ordering::SeqCst));
+        forward.cloud_events().send(&delivery).unwrap();
+        assert_eq!(2, relayed.load(Ordering::SeqCst));
+        downstream.stop(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,458 @@
+//! Calling back to Github from hooks
+//!
+//! A hub holding a `GitHub`, registered with `Hub::github`, hands hooks a
+//! `Client` authenticated for the delivery they're handling, which closures
+//! take as an argument. Hubs authenticating as a Github app hand out clients
+//! with a token of the delivery's installation, which are requested as
+//! they're needed and cached until shortly before they expire
+//!
+//! ```no_run
+//! # extern crate afterparty_ng;
+//! # fn main() {
+//! # use afterparty_ng::Hub;
+//! use afterparty_ng::github::{Client, GitHub};
+//! use afterparty_ng::Repo;
+//!
+//! let mut hub = Hub::new();
+//! hub.github(GitHub::token("token").unwrap());
+//! hub.handle("issues", |github: Client, repo: Repo| {
+//!     let labels = github.get(&format!("/repos/{}/labels", repo.full_name));
+//! });
+//! # }
+//! ```
+
+use super::extract::FromDelivery;
+use super::link;
+use super::tls::TlsClient;
+use super::{clock, Delivery, Hub};
+use base64;
+pub use checks::{CheckRun, Output};
+pub use comments::Comment;
+use hyper;
+use hyper::header::{Authorization, ContentType, Headers, UserAgent};
+use hyper::method::Method;
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use secret::Secret;
+use serde_json::{self, Value};
+pub use statuses::CommitStatus;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const API: &str = "https://api.github.com";
+const USER_AGENT: &str = concat!("afterparty/", env!("CARGO_PKG_VERSION"));
+
+/// installation tokens are requested anew this long before they expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Reasons a call to Github failed
+#[derive(Debug)]
+pub enum GitHubError {
+    /// the request to Github failed
+    Http(hyper::Error),
+    /// Github responded with an error status and message
+    Status(StatusCode, String),
+    /// Github's response wasn't json
+    Json(serde_json::Error),
+    /// the app's private key couldn't be read or used
+    Key(ErrorStack),
+    /// the delivery wasn't sent to an installation of the app
+    NoInstallation,
+}
+
+impl fmt::Display for GitHubError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GitHubError::Http(ref err) => write!(f, "request failed: {}", err),
+            GitHubError::Status(status, ref message) => {
+                write!(f, "github responded {}: {}", status, message)
+            }
+            GitHubError::Json(ref err) => write!(f, "invalid response: {}", err),
+            GitHubError::Key(ref err) => write!(f, "invalid private key: {}", err),
+            GitHubError::NoInstallation => f.write_str("delivery names no installation"),
+        }
+    }
+}
+
+impl Error for GitHubError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            GitHubError::Http(ref err) => Some(err),
+            GitHubError::Json(ref err) => Some(err),
+            GitHubError::Key(ref err) => Some(err),
+            GitHubError::Status(..) | GitHubError::NoInstallation => None,
+        }
+    }
+}
+
+impl From<hyper::Error> for GitHubError {
+    fn from(err: hyper::Error) -> GitHubError {
+        GitHubError::Http(err)
+    }
+}
+
+impl From<serde_json::Error> for GitHubError {
+    fn from(err: serde_json::Error) -> GitHubError {
+        GitHubError::Json(err)
+    }
+}
+
+impl From<ErrorStack> for GitHubError {
+    fn from(err: ErrorStack) -> GitHubError {
+        GitHubError::Key(err)
+    }
+}
+
+/// how a hub authenticates with Github
+enum Credentials {
+    Token(Secret),
+    App(App),
+}
+
+/// a Github app, and the tokens of its installations
+struct App {
+    id: u64,
+    key: PKey<Private>,
+    installations: Mutex<HashMap<u64, (Secret, SystemTime)>>,
+}
+
+/// Credentials for Github's api, handing out clients for deliveries
+#[derive(Clone)]
+pub struct GitHub {
+    credentials: Arc<Credentials>,
+    api: String,
+    http: Arc<hyper::Client>,
+}
+
+impl GitHub {
+    /// authenticates with a personal access or app installation token
+    pub fn token<T: Into<String>>(token: T) -> Result<GitHub, GitHubError> {
+        GitHub::with(Credentials::Token(Secret::new(token)))
+    }
+
+    /// authenticates as a Github app, by its id and PEM encoded private key,
+    /// handing out clients for the installation each delivery was sent to
+    pub fn app(id: u64, private_key: &[u8]) -> Result<GitHub, GitHubError> {
+        GitHub::with(Credentials::App(App {
+            id: id,
+            key: PKey::private_key_from_pem(private_key)?,
+            installations: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn with(credentials: Credentials) -> Result<GitHub, GitHubError> {
+        let tls = TlsClient::new().map_err(|err| hyper::Error::Ssl(Box::new(err)))?;
+        Ok(GitHub {
+            credentials: Arc::new(credentials),
+            api: API.to_owned(),
+            http: Arc::new(hyper::Client::with_connector(HttpsConnector::new(tls))),
+        })
+    }
+
+    /// uses another api, e.g. a Github Enterprise server's
+    /// `https://github.example.com/api/v3`
+    pub fn api<A: Into<String>>(mut self, api: A) -> GitHub {
+        self.api = api.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// a client authenticated for the delivery's installation, or
+    /// with the token, scoped to the delivery's repository
+    pub fn client(&self, delivery: &Delivery) -> Result<Client, GitHubError> {
+        let repo = delivery
+            .json()
+            .and_then(|payload| payload.pointer("/repository/full_name"))
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+        let token = match *self.credentials {
+            Credentials::Token(ref token) => Secret::new(format!("token {}", token.as_str())),
+            Credentials::App(ref app) => {
+                let installation = delivery
+                    .json()
+                    .and_then(|payload| payload.pointer("/installation/id"))
+                    .and_then(Value::as_u64)
+                    .ok_or(GitHubError::NoInstallation)?;
+                self.installation_token(app, installation)?
+            }
+        };
+        Ok(Client {
+            authorization: token,
+            repo: repo,
+            api: self.api.clone(),
+            http: self.http.clone(),
+        })
+    }
+
+    /// a token of an installation of the app, cached until shortly before it expires
+    fn installation_token(&self, app: &App, installation: u64) -> Result<Secret, GitHubError> {
+        if let Some(&(ref token, expires)) = app.installations.lock().unwrap().get(&installation) {
+            if clock::now() + EXPIRY_MARGIN < expires {
+                return Ok(token.clone());
+            }
+        }
+        let client = Client {
+            authorization: Secret::new(format!("Bearer {}", jwt(app.id, &app.key, clock::now())?)),
+            repo: None,
+            api: self.api.clone(),
+            http: self.http.clone(),
+        };
+        let granted = client.post(
+            &format!("/app/installations/{}/access_tokens", installation),
+            &json!({}),
+        )?;
+        let token = granted
+            .get("token")
+            .and_then(Value::as_str)
+            .map(|token| Secret::new(format!("token {}", token)))
+            .ok_or_else(|| GitHubError::Status(StatusCode::Ok, granted.to_string()))?;
+        let expires = granted
+            .get("expires_at")
+            .and_then(Value::as_str)
+            .and_then(clock::parse_rfc3339)
+            .unwrap_or_else(clock::now);
+        app.installations
+            .lock()
+            .unwrap()
+            .insert(installation, (token.clone(), expires));
+        Ok(token)
+    }
+}
+
+/// the json web token a Github app authenticates with, valid for ten minutes
+fn jwt(id: u64, key: &PKey<Private>, now: SystemTime) -> Result<String, ErrorStack> {
+    let issued = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    // backdated a minute in case the clocks disagree
+    let claims = json!({ "iat": issued - 60, "exp": issued + 540, "iss": id });
+    let signed = format!(
+        "{}.{}",
+        encode(br#"{"alg":"RS256","typ":"JWT"}"#),
+        encode(claims.to_string().as_bytes())
+    );
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(signed.as_bytes())?;
+    Ok(format!("{}.{}", signed, encode(&signer.sign_to_vec()?)))
+}
+
+/// A client of Github's api, authenticated for a delivery
+///
+/// Paths are relative to the api's root, e.g. `/repos/octocat/hello-world/labels`
+#[derive(Clone)]
+pub struct Client {
+    authorization: Secret,
+    repo: Option<String>,
+    api: String,
+    http: Arc<hyper::Client>,
+}
+
+impl Client {
+    /// the full name of the delivery's repository, if it names one
+    pub fn repo(&self) -> Option<&str> {
+        self.repo.as_ref().map(String::as_str)
+    }
+
+    /// the path of a resource of the delivery's repository,
+    /// e.g. `statuses/{sha}`, if it names one
+    pub fn repo_path(&self, path: &str) -> Option<String> {
+        self.repo
+            .as_ref()
+            .map(|repo| format!("/repos/{}/{}", repo, path.trim_start_matches('/')))
+    }
+
+    pub fn get(&self, path: &str) -> Result<Value, GitHubError> {
+        self.request(Method::Get, path, None)
+    }
+
+    pub fn post(&self, path: &str, body: &Value) -> Result<Value, GitHubError> {
+        self.request(Method::Post, path, Some(body))
+    }
+
+    pub fn patch(&self, path: &str, body: &Value) -> Result<Value, GitHubError> {
+        self.request(Method::Patch, path, Some(body))
+    }
+
+    pub fn put(&self, path: &str, body: &Value) -> Result<Value, GitHubError> {
+        self.request(Method::Put, path, Some(body))
+    }
+
+    pub fn delete(&self, path: &str) -> Result<Value, GitHubError> {
+        self.request(Method::Delete, path, None)
+    }
+
+    /// every page of the list at a path, e.g. of an issue's comments,
+    /// following the `next` links of Github's responses
+    pub fn list(&self, path: &str) -> Result<Value, GitHubError> {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let mut listed = Vec::new();
+        let mut page = Some(self.url(&format!("{}{}per_page=100", path, separator)));
+        while let Some(url) = page {
+            let (response, next) = self.exchange(Method::Get, &url, None)?;
+            listed.extend(response.as_array().cloned().unwrap_or_default());
+            page = next;
+        }
+        Ok(Value::Array(listed))
+    }
+
+    /// requests a path, returning the json response, `null` when it's empty
+    pub fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<Value, GitHubError> {
+        self.exchange(method, &self.url(path), body)
+            .map(|(response, _)| response)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.api, path.trim_start_matches('/'))
+    }
+
+    /// requests `url`, returning the response and the url of its next
+    /// page, if there is one. pages elsewhere than the api aren't followed,
+    /// so the client's token isn't sent there
+    fn exchange(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&Value>,
+    ) -> Result<(Value, Option<String>), GitHubError> {
+        let body = body.map(serde_json::to_string).transpose()?;
+        let mut headers = Headers::new();
+        headers.set(Authorization(self.authorization.as_str().to_owned()));
+        headers.set(UserAgent(USER_AGENT.to_owned()));
+        headers.set_raw("Accept", vec![b"application/vnd.github.v3+json".to_vec()]);
+        let mut request = self.http.request(method, url);
+        if let Some(ref body) = body {
+            headers.set(ContentType::json());
+            request = request.body(body.as_str());
+        }
+        let mut response = request.headers(headers).send()?;
+        let mut text = String::new();
+        response
+            .read_to_string(&mut text)
+            .map_err(hyper::Error::Io)?;
+        if !response.status.is_success() {
+            return Err(GitHubError::Status(response.status, text));
+        }
+        let next = response
+            .headers
+            .get_raw("Link")
+            .and_then(|values| values.first())
+            .and_then(|value| str::from_utf8(value).ok())
+            .and_then(link::next_page)
+            .filter(|next| next.starts_with(&format!("{}/", self.api)));
+        if text.trim().is_empty() {
+            return Ok((Value::Null, next));
+        }
+        Ok((serde_json::from_str(&text)?, next))
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("repo", &self.repo)
+            .field("api", &self.api)
+            .finish()
+    }
+}
+
+/// a client for the delivery, from the `GitHub` registered with `Hub::github`
+impl FromDelivery for Client {
+    fn from_delivery(delivery: &Delivery) -> Option<Client> {
+        let github = match delivery.state::<GitHub>() {
+            Some(github) => github,
+            None => {
+                log_delivery!(warn, delivery; "no GitHub registered with the hub");
+                return None;
+            }
+        };
+        match github.client(delivery) {
+            Ok(client) => Some(client),
+            Err(err) => {
+                log_delivery!(
+                    error,
+                    delivery;
+                    "failed to authenticate with github for delivery {}: {}",
+                    delivery.id,
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+impl Hub {
+    /// hands hooks clients of Github's api authenticated with `github`,
+    /// which closures take as `github::Client` arguments
+    pub fn github(&mut self, github: GitHub) {
+        self.with_state(github)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+    use std::sync::mpsc;
+
+    #[test]
+    fn github_clients() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let mut hub = Hub::new();
+        hub.github(
+            GitHub::token("t0k3n")
+                .unwrap()
+                .api("https://github.example.com/api/v3/"),
+        );
+        hub.handle("public", move |github: Client| {
+            sender.lock().unwrap().send(github).unwrap();
+        });
+        let delivery = || Delivery::new("id", "public", include_str!("../data/public.json"), None);
+        hub.deliver(&mut delivery());
+        let client = receiver.try_recv().unwrap();
+        assert_eq!(Some("Codertocat/Hello-World"), client.repo());
+        assert_eq!(
+            Some("/repos/Codertocat/Hello-World/statuses/abc".to_owned()),
+            client.repo_path("statuses/abc")
+        );
+        assert_eq!("https://github.example.com/api/v3", client.api);
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let app = GitHub::app(42, &key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        match app.client(&delivery()) {
+            Err(GitHubError::NoInstallation) => (),
+            other => panic!("expected no installation, got {:?}", other),
+        }
+        let token = jwt(42, &key, UNIX_EPOCH + Duration::from_secs(1_600_000_000)).unwrap();
+        let parts = token.split('.').collect::<Vec<_>>();
+        let claims: Value = serde_json::from_slice(
+            &base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(Some(42), claims.get("iss").and_then(Value::as_u64));
+        assert_eq!(
+            Some(1_600_000_540),
+            claims.get("exp").and_then(Value::as_u64)
+        );
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+        verifier
+            .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+            .unwrap();
+        let signature = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+        assert!(verifier.verify(&signature).unwrap());
+    }
+}
@@ -0,0 +1,162 @@
+//! Hook groups bundle hooks with the filters and authenticators guarding them
+//! so that a set of related hooks, e.g. those owned by one team or exported
+//! by one crate, can be built separately and mounted into a hub
+
+use super::auth::{Authenticator, Signature};
+use super::extract::IntoHook;
+use super::filter::Filter;
+use super::{AuthError, Delivery, Hook};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A registry of hooks sharing filters and authenticators,
+/// mounted into a hub with `Hub::mount`
+#[derive(Default)]
+pub struct HookGroup {
+    hooks: HashMap<String, Vec<Arc<Hook>>>,
+    filters: Vec<Box<Filter>>,
+    authenticators: Vec<Box<Authenticator>>,
+}
+
+impl HookGroup {
+    /// construct a new, empty group
+    pub fn new() -> HookGroup {
+        HookGroup {
+            ..Default::default()
+        }
+    }
+
+    /// only hands deliveries matching `filter` to this group's hooks.
+    /// deliveries which don't match any one of a group's filters are skipped
+    pub fn filter<F>(mut self, filter: F) -> HookGroup
+    where
+        F: Filter + 'static,
+    {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// only hands deliveries `authenticator` accepts to this group's hooks.
+    /// deliveries must be accepted by every one of a group's authenticators
+    pub fn authenticate<A>(mut self, authenticator: A) -> HookGroup
+    where
+        A: Authenticator + 'static,
+    {
+        self.authenticators.push(Box::new(authenticator));
+        self
+    }
+
+    /// only hands deliveries signed with `secret` to this group's hooks
+    pub fn secret<S>(self, secret: S) -> HookGroup
+    where
+        S: Into<String>,
+    {
+        self.authenticate(Signature::new(secret))
+    }
+
+    /// add a hook to the list of hooks in this group
+    /// interested in a given event. named `on` rather than `handle`
+    /// so it isn't shadowed by `Hook::handle`, which groups implement
+    pub fn on<H, Args>(&mut self, event: &str, hook: H)
+    where
+        H: IntoHook<Args>,
+    {
+        self.hooks
+            .entry(event.to_owned())
+            .or_insert(vec![])
+            .push(Arc::new(hook.into_hook()));
+    }
+
+    /// nests another group within this one, which applies its own
+    /// filters and authenticators after this group's
+    pub fn mount(&mut self, group: HookGroup) {
+        self.on("*", group)
+    }
+
+    /// get all interested hooks in this group for a given event
+    fn hooks(&self, event: &str) -> (&[Arc<Hook>], &[Arc<Hook>]) {
+        let explicit = self.hooks.get(event).map(|hooks| &hooks[..]).unwrap_or(&[]);
+        let implicit = self.hooks.get("*").map(|hooks| &hooks[..]).unwrap_or(&[]);
+        (explicit, implicit)
+    }
+}
+
+impl Hook for HookGroup {
+    fn handle(&self, delivery: &Delivery) {
+        let (explicit, implicit) = self.hooks(delivery.event);
+        if explicit.is_empty() && implicit.is_empty() {
+            return;
+        }
+        if !self.filters.iter().all(|filter| filter.matches(delivery)) {
+            log_delivery!(debug, delivery; "delivery {} filtered out", delivery.id);
+            return;
+        }
+        if !self.authenticators.is_empty() {
+            if self
+                .authenticators
+                .iter()
+                .all(|authenticator| authenticator.authenticate(delivery))
+            {
+                delivery.verified();
+            } else {
+                log_delivery!(error, delivery; "failed to authenticate request");
+                delivery.rejected(AuthError::InvalidCredentials);
+                return;
+            }
+        }
+        for hook in explicit.iter().chain(implicit) {
+            hook.handle(delivery)
+        }
+    }
+
+    fn filters(&self) -> Vec<String> {
+        self.filters
+            .iter()
+            .map(|filter| filter.describe())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::Token;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const PUBLIC: &str = include_str!("../data/public.json");
+
+    #[test]
+    fn hook_groups() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = || {
+            let handled = handled.clone();
+            move |_: &Delivery| {
+                handled.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let mut ours = HookGroup::new()
+            .filter(|delivery: &Delivery| delivery.header("X-Team") == Some("ours"))
+            .authenticate(Token::bearer("token"));
+        ours.on("public", counter());
+        ours.on("push", counter());
+        let mut nested = HookGroup::new().secret("secret");
+        nested.on("*", counter());
+        ours.mount(nested);
+
+        let theirs = vec![("Authorization".to_owned(), "Bearer token".to_owned())];
+        let mut delivery = Delivery::new("id", "public", PUBLIC, None);
+        delivery.headers = &theirs;
+        ours.handle(&delivery);
+        assert_eq!(0, handled.load(Ordering::SeqCst));
+
+        let headers = vec![
+            ("Authorization".to_owned(), "Bearer token".to_owned()),
+            ("X-Team".to_owned(), "ours".to_owned()),
+        ];
+        delivery.headers = &headers;
+        ours.handle(&delivery);
+        // the nested group's hook requires a signature
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,302 @@
+//! Forwards deliveries to a gRPC service
+//!
+//! A `GrpcHook` calls the `afterparty.Forwarder/Deliver` method, defined in
+//! `proto/afterparty.proto`, of an endpoint with each delivery it handles,
+//! so services written in other languages can consume webhooks without
+//! speaking Github's protocol. Deliveries are sent from a background thread,
+//! over plaintext http/2, and retried while the endpoint is unavailable.
+//! Endpoints only reachable through a proxy are connected to with
+//! `GrpcHook::proxied`
+
+use super::owned;
+use super::proxy::Proxy;
+use super::{Delivery, Hook};
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use h2::client::{self, SendRequest};
+use http::{HeaderMap, Request, StatusCode};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::reactor::Handle;
+use tokio::runtime::current_thread::Runtime;
+
+/// number of deliveries buffered while the endpoint is slow
+/// or unavailable before further deliveries are dropped
+const BACKLOG: usize = 1024;
+
+/// number of times a delivery is sent before it's dropped
+const ATTEMPTS: usize = 3;
+
+const DELIVER: &str = "/afterparty.Forwarder/Deliver";
+
+/// A hook forwarding deliveries to a gRPC endpoint
+pub struct GrpcHook {
+    deliveries: Mutex<SyncSender<(String, Vec<u8>)>>,
+}
+
+impl GrpcHook {
+    /// forwards deliveries to the `afterparty.Forwarder`
+    /// service listening on `endpoint`
+    pub fn new<A: ToSocketAddrs>(endpoint: A) -> io::Result<GrpcHook> {
+        let addr = endpoint
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no endpoint address"))?;
+        GrpcHook::start(Endpoint::Direct(addr))
+    }
+
+    /// forwards deliveries to the service listening on
+    /// `host` and `port`, connecting through `proxy`
+    pub fn proxied<H: Into<String>>(host: H, port: u16, proxy: Proxy) -> io::Result<GrpcHook> {
+        GrpcHook::start(Endpoint::Proxied(host.into(), port, proxy))
+    }
+
+    fn start(endpoint: Endpoint) -> io::Result<GrpcHook> {
+        let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+        thread::Builder::new()
+            .name("afterparty-grpc".to_owned())
+            .spawn(move || forward(endpoint, receiver))?;
+        Ok(GrpcHook {
+            deliveries: Mutex::new(sender),
+        })
+    }
+}
+
+/// where deliveries are forwarded to
+enum Endpoint {
+    Direct(SocketAddr),
+    Proxied(String, u16, Proxy),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Direct(ref addr) => write!(f, "{}", addr),
+            Endpoint::Proxied(ref host, port, _) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl Hook for GrpcHook {
+    fn handle(&self, delivery: &Delivery) {
+        let forwarded = (delivery.id.to_owned(), encode(delivery));
+        match self.deliveries.lock().unwrap().try_send(forwarded) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => log_delivery!(
+                warn,
+                delivery;
+                "grpc endpoint lagging, dropped delivery {}",
+                delivery.id
+            ),
+            Err(TrySendError::Disconnected(_)) => log_delivery!(
+                error,
+                delivery;
+                "grpc forwarder stopped, dropped delivery {}",
+                delivery.id
+            ),
+        }
+    }
+}
+
+/// sends deliveries to the endpoint until the hook is dropped
+fn forward(endpoint: Endpoint, deliveries: Receiver<(String, Vec<u8>)>) {
+    let mut runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("failed to start grpc forwarder: {}", err);
+            return;
+        }
+    };
+    let authority = endpoint.to_string();
+    let mut client = None;
+    for (id, message) in deliveries {
+        for attempt in 1..=ATTEMPTS {
+            if client.is_none() {
+                client = connect(&mut runtime, &endpoint)
+                    .map_err(|err| {
+                        warn!("failed to connect to grpc endpoint {}: {}", endpoint, err)
+                    })
+                    .ok();
+            }
+            let sent = match client {
+                Some(ref client) => call(&mut runtime, client, &authority, &message),
+                None => Err("not connected".to_owned()),
+            };
+            match sent {
+                Ok(()) => break,
+                Err(err) => {
+                    warn!(
+                        "failed to forward delivery {} (attempt {}): {}",
+                        id, attempt, err
+                    );
+                    client = None;
+                    if attempt < ATTEMPTS {
+                        thread::sleep(Duration::from_secs(attempt as u64));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn connect(runtime: &mut Runtime, endpoint: &Endpoint) -> Result<SendRequest<Bytes>, String> {
+    let tcp = match *endpoint {
+        Endpoint::Direct(ref addr) => runtime.block_on(TcpStream::connect(addr)),
+        Endpoint::Proxied(ref host, port, ref proxy) => proxy
+            .connect(host, port)
+            .and_then(|stream| TcpStream::from_std(stream, &Handle::default())),
+    }
+    .map_err(|err| err.to_string())?;
+    let (client, connection) = runtime
+        .block_on(client::handshake(tcp))
+        .map_err(|err| err.to_string())?;
+    runtime.spawn(connection.map_err(|err| debug!("grpc connection closed: {}", err)));
+    Ok(client)
+}
+
+/// calls the endpoint's `Deliver` method with an encoded delivery
+fn call(
+    runtime: &mut Runtime,
+    client: &SendRequest<Bytes>,
+    authority: &str,
+    message: &[u8],
+) -> Result<(), String> {
+    let mut client = runtime
+        .block_on(client.clone().ready())
+        .map_err(|err| err.to_string())?;
+    let request = Request::post(&format!("http://{}{}", authority, DELIVER)[..])
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(())
+        .map_err(|err| err.to_string())?;
+    let (response, mut body) = client
+        .send_request(request, false)
+        .map_err(|err| err.to_string())?;
+    body.send_data(Bytes::from(frame(message)), true)
+        .map_err(|err| err.to_string())?;
+    let response = runtime.block_on(response).map_err(|err| err.to_string())?;
+    if response.status() != StatusCode::OK {
+        return Err(format!("endpoint responded {}", response.status()));
+    }
+    let (parts, mut body) = response.into_parts();
+    // the ack carries nothing of interest, only the trailers do
+    let trailers = runtime
+        .block_on(future::poll_fn(move || {
+            while let Some(data) = try_ready!(body.poll()) {
+                let _ = body.release_capacity().release_capacity(data.len());
+            }
+            body.poll_trailers()
+        }))
+        .map_err(|err| err.to_string())?;
+    status(trailers.as_ref().unwrap_or(&parts.headers))
+}
+
+/// the outcome of a call, from its `grpc-status`, which trailers-only
+/// responses, e.g. those of failed calls, send as a header
+fn status(metadata: &HeaderMap) -> Result<(), String> {
+    let value = |name| metadata.get(name).and_then(|value| value.to_str().ok());
+    match value("grpc-status") {
+        Some("0") => Ok(()),
+        Some(status) => Err(format!(
+            "endpoint failed with status {}: {}",
+            status,
+            value("grpc-message").unwrap_or("")
+        )),
+        None => Err("endpoint responded without a grpc-status".to_owned()),
+    }
+}
+
+/// a delivery encoded as an `afterparty.Delivery` protobuf message,
+/// without the credentials it was sent with
+fn encode(delivery: &Delivery) -> Vec<u8> {
+    let mut message = Vec::with_capacity(delivery.unparsed_payload.len() + 512);
+    field(&mut message, 1, delivery.event.as_bytes());
+    field(&mut message, 2, delivery.id.as_bytes());
+    for &(ref name, ref value) in delivery
+        .headers
+        .iter()
+        .filter(|&&(ref name, _)| !owned::credential(name))
+    {
+        let mut entry = Vec::with_capacity(name.len() + value.len() + 4);
+        field(&mut entry, 1, name.as_bytes());
+        field(&mut entry, 2, value.as_bytes());
+        field(&mut message, 3, &entry);
+    }
+    field(&mut message, 4, delivery.unparsed_payload.as_bytes());
+    message
+}
+
+/// appends a length delimited field, the wire type of strings,
+/// bytes and embedded messages
+fn field(message: &mut Vec<u8>, number: u64, value: &[u8]) {
+    varint(message, number << 3 | 2);
+    varint(message, value.len() as u64);
+    message.extend_from_slice(value);
+}
+
+fn varint(message: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        message.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    message.push(value as u8);
+}
+
+/// a message framed as a gRPC request body: an uncompressed
+/// flag followed by the message's big endian length
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(message.len() + 5);
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grpc_encoding() {
+        let headers = vec![
+            ("X-Github-Event".to_owned(), "ping".to_owned()),
+            ("Authorization".to_owned(), "token secret".to_owned()),
+        ];
+        let mut delivery = Delivery::new("id", "ping", "{}", None);
+        delivery.headers = &headers;
+        let mut expected = vec![0x0a, 4];
+        expected.extend_from_slice(b"ping");
+        expected.extend_from_slice(&[0x12, 2]);
+        expected.extend_from_slice(b"id");
+        expected.extend_from_slice(&[0x1a, 22, 0x0a, 14]);
+        expected.extend_from_slice(b"X-Github-Event");
+        expected.extend_from_slice(&[0x12, 4]);
+        expected.extend_from_slice(b"ping");
+        expected.extend_from_slice(&[0x22, 2]);
+        expected.extend_from_slice(b"{}");
+        // the credentials the delivery was sent with aren't encoded
+        let encoded = encode(&delivery);
+        assert_eq!(expected, encoded);
+        assert!(!encoded
+            .windows(b"Authorization".len())
+            .any(|window| window == b"Authorization"));
+
+        let mut long = Vec::new();
+        varint(&mut long, 300);
+        assert_eq!(vec![0xac, 0x02], long);
+        assert_eq!(vec![0, 0, 0, 0, 2, b'{', b'}'], frame(b"{}"));
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        assert!(status(&trailers).is_ok());
+        trailers.insert("grpc-status", "14".parse().unwrap());
+        assert!(status(&trailers).is_err());
+        assert!(status(&HeaderMap::new()).is_err());
+    }
+}
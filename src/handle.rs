@@ -0,0 +1,174 @@
+//! Handles on hubs running in a server
+
+use super::{Delivery, Error, Observer, Shutdown, ShutdownReport, Stats};
+use hyper::server::Listening;
+use stats::Recorder;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counts deliveries as they move through a hub
+#[derive(Default)]
+pub struct Counters {
+    received: AtomicUsize,
+    verified: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Observer for Arc<Counters> {
+    fn on_received(&self, _: &Delivery) {
+        self.received.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_verified(&self, _: &Delivery) {
+        self.verified.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_error(&self, _: &str, _: &str, _: &Error) {
+        self.failed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A handle on a hub listening for deliveries, returned by `Hub::run`.
+///
+/// Dropping the handle blocks until the server stops listening, which
+/// it never does on its own, mirroring hyper's `Listening`
+pub struct HubHandle {
+    listening: Vec<Listening>,
+    shutdown: Shutdown,
+    counters: Arc<Counters>,
+    stats: Arc<Recorder>,
+}
+
+impl HubHandle {
+    pub fn new(
+        listening: Vec<Listening>,
+        shutdown: Shutdown,
+        counters: Arc<Counters>,
+        stats: Arc<Recorder>,
+    ) -> HubHandle {
+        HubHandle {
+            listening: listening,
+            shutdown: shutdown,
+            counters: counters,
+            stats: stats,
+        }
+    }
+
+    /// address the server is bound to, the first of them when it
+    /// listens on several
+    pub fn addr(&self) -> SocketAddr {
+        self.listening[0].socket
+    }
+
+    /// addresses the server is bound to, see `Hub::run_all`
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.listening
+            .iter()
+            .map(|listening| listening.socket)
+            .collect()
+    }
+
+    /// number of deliveries handed to hooks
+    pub fn received(&self) -> usize {
+        self.counters.received.load(Ordering::SeqCst)
+    }
+
+    /// number of deliveries accepted by an authenticating hook
+    pub fn verified(&self) -> usize {
+        self.counters.verified.load(Ordering::SeqCst)
+    }
+
+    /// number of deliveries rejected or which could not be processed
+    pub fn failed(&self) -> usize {
+        self.counters.failed.load(Ordering::SeqCst)
+    }
+
+    /// counts of the deliveries the hub has seen, by event and repository
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    /// number of deliveries currently in progress, including
+    /// those queued behind `Hub::max_in_flight`
+    pub fn in_flight(&self) -> usize {
+        self.shutdown.in_flight()
+    }
+
+    /// blocks for as long as the server is listening
+    pub fn join(self) {}
+
+    /// stops accepting new deliveries and waits up to `deadline` for those
+    /// in progress to complete, see `Shutdown::shutdown`.
+    ///
+    /// hyper can't stop listening, so the server's socket stays bound,
+    /// responding `503 Service Unavailable`, until the process exits
+    pub fn stop(mut self, deadline: Duration) -> ShutdownReport {
+        let report = self.shutdown.shutdown(deadline);
+        self.stats.save();
+        // detaches the listening threads so dropping self doesn't block
+        for listening in &mut self.listening {
+            let _ = listening.close();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::auth::Token;
+    use super::super::server::{XGithubDelivery, XGithubEvent};
+    use super::super::{Hub, TokenAuthHook};
+    use super::*;
+    use hyper::header::{Authorization, Bearer};
+    use hyper::status::StatusCode;
+    use hyper::Client;
+    use serde_json::{self, Value};
+
+    const PUBLIC: &str = include_str!("../data/public.json");
+
+    #[test]
+    fn hub_handle_statistics() {
+        let mut hub = Hub::new();
+        hub.handle("public", TokenAuthHook::bearer("token", |_: &Delivery| {}));
+        hub.admin("/admin/stats", Token::bearer("admin"));
+        let handle = hub.run("127.0.0.1:0").unwrap();
+        let client = Client::new();
+        for token in &["token", "wrong"] {
+            client
+                .post(&format!("http://{}/", handle.addr()))
+                .header(XGithubEvent("public".to_owned()))
+                .header(XGithubDelivery("id".to_owned()))
+                .header(Authorization(Bearer {
+                    token: token.to_string(),
+                }))
+                .body(PUBLIC)
+                .send()
+                .unwrap();
+        }
+        assert_eq!(2, handle.received());
+        assert_eq!(1, handle.verified());
+        assert_eq!(1, handle.failed());
+        assert_eq!(0, handle.in_flight());
+        assert_eq!(2, handle.stats().total().received);
+
+        let stats = format!("http://{}/admin/stats", handle.addr());
+        let unauthorized = client.get(&stats).send().unwrap();
+        assert_eq!(StatusCode::Unauthorized, unauthorized.status);
+        let authorized = client
+            .get(&stats)
+            .header(Authorization(Bearer {
+                token: "admin".to_owned(),
+            }))
+            .send()
+            .unwrap();
+        let stats = serde_json::from_reader::<_, Value>(authorized).unwrap();
+        assert_eq!(
+            Some(1),
+            stats.pointer("/unverified/failed").and_then(Value::as_u64)
+        );
+        let report = handle.stop(Duration::from_secs(1));
+        assert_eq!(0, report.abandoned);
+    }
+}
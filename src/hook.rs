@@ -1,17 +1,27 @@
-use super::Delivery;
-use hex::FromHex;
-use ring::digest;
-use ring::hmac;
+use super::auth::{QueryToken, Signature, Token};
+use super::{AuthError, Delivery};
 
 /// Handles webhook deliveries
 pub trait Hook: Send + Sync {
     /// Implementations are expected to deliveries here
     fn handle(&self, delivery: &Delivery);
+
+    /// describes the filters deliveries pass before this hook handles them
+    fn filters(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// A hook which knows the event it handles, registered with `Hub::register`.
+/// Implemented by hooks generated with the `#[webhook]` attribute
+pub trait Webhook: Hook {
+    /// the event this hook handles
+    fn event(&self) -> &str;
 }
 
 /// A delivery authenticator for hooks
 pub struct AuthenticateHook<H: Hook + 'static> {
-    secret: String,
+    signature: Signature,
     hook: H,
 }
 
@@ -21,34 +31,127 @@ impl<H: Hook + 'static> AuthenticateHook<H> {
         S: Into<String>,
     {
         AuthenticateHook {
-            secret: secret.into(),
+            signature: Signature::new(secret),
             hook: hook,
         }
     }
 
     fn authenticate(&self, payload: &str, signature: &str) -> bool {
-        // https://developer.github.com/webhooks/securing/#validating-payloads-from-github
-        let sans_prefix = signature[5..signature.len()].as_bytes();
-        match Vec::from_hex(sans_prefix) {
-            Ok(sigbytes) => {
-                let sbytes = self.secret.as_bytes();
-                let pbytes = payload.as_bytes();
-                let key = hmac::SigningKey::new(&digest::SHA1, &sbytes);
-                hmac::verify_with_own_key(&key, &pbytes, &sigbytes).is_ok()
-            }
-            Err(_) => false,
-        }
+        self.signature.verify(payload, signature)
     }
 }
 
 impl<H: Hook + 'static> Hook for AuthenticateHook<H> {
     fn handle(&self, delivery: &Delivery) {
         if let Some(sig) = delivery.signature {
-            if self.authenticate(delivery.unparsed_payload, sig) {
+            if self.authenticate(delivery.original_payload(), sig) {
+                delivery.verified();
+                self.hook.handle(delivery)
+            } else {
+                log_delivery!(error, delivery; "failed to authenticate request");
+                delivery.rejected(AuthError::InvalidCredentials);
+            }
+        } else {
+            log_delivery!(error, delivery; "request carried no credentials");
+            delivery.rejected(AuthError::MissingCredentials);
+        }
+    }
+}
+
+/// A delivery authenticator for senders which don't sign payloads
+/// but instead pass a shared credential in the `Authorization` header
+pub struct TokenAuthHook<H: Hook + 'static> {
+    token: Token,
+    hook: H,
+}
+
+impl<H: Hook + 'static> TokenAuthHook<H> {
+    /// authenticates deliveries sent with an `Authorization: Bearer {token}` header
+    pub fn bearer<T>(token: T, hook: H) -> TokenAuthHook<H>
+    where
+        T: Into<String>,
+    {
+        TokenAuthHook {
+            token: Token::bearer(token),
+            hook: hook,
+        }
+    }
+
+    /// authenticates deliveries sent with basic auth credentials
+    pub fn basic<U, P>(username: U, password: P, hook: H) -> TokenAuthHook<H>
+    where
+        U: AsRef<str>,
+        P: AsRef<str>,
+    {
+        TokenAuthHook {
+            token: Token::basic(username, password),
+            hook: hook,
+        }
+    }
+
+    fn authenticate(&self, authorization: &str) -> bool {
+        self.token.verify(authorization)
+    }
+}
+
+impl<H: Hook + 'static> Hook for TokenAuthHook<H> {
+    fn handle(&self, delivery: &Delivery) {
+        if let Some(authorization) = delivery.header("Authorization") {
+            if self.authenticate(authorization) {
+                delivery.verified();
                 self.hook.handle(delivery)
             } else {
-                error!("failed to authenticate request");
+                log_delivery!(error, delivery; "failed to authenticate request");
+                delivery.rejected(AuthError::InvalidCredentials);
             }
+        } else {
+            log_delivery!(error, delivery; "request carried no credentials");
+            delivery.rejected(AuthError::MissingCredentials);
+        }
+    }
+}
+
+/// A delivery authenticator for senders which can only append
+/// a shared token to the webhook url, e.g. `/hooks?token=...`
+///
+/// Tokens in urls tend to end up in access logs and proxy histories
+/// and don't protect the payload from tampering, so prefer
+/// `AuthenticateHook` whenever the sender supports signing payloads
+pub struct QueryTokenHook<H: Hook + 'static> {
+    token: QueryToken,
+    hook: H,
+}
+
+impl<H: Hook + 'static> QueryTokenHook<H> {
+    pub fn new<P, T>(param: P, token: T, hook: H) -> QueryTokenHook<H>
+    where
+        P: Into<String>,
+        T: Into<String>,
+    {
+        let token = QueryToken::new(param, token);
+        warn!(
+            "authenticating deliveries with the '{}' query parameter, which is weaker than signature verification",
+            token.param()
+        );
+        QueryTokenHook {
+            token: token,
+            hook: hook,
+        }
+    }
+
+    fn authenticate(&self, query: &str) -> bool {
+        self.token.verify(query)
+    }
+}
+
+impl<H: Hook + 'static> Hook for QueryTokenHook<H> {
+    fn handle(&self, delivery: &Delivery) {
+        if self.authenticate(delivery.query.unwrap_or("")) {
+            delivery.verified();
+            self.hook.handle(delivery)
+        } else {
+            log_delivery!(error, delivery; "failed to authenticate request");
+            delivery.rejected(AuthError::InvalidCredentials);
         }
     }
 }
@@ -65,25 +168,63 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::super::Delivery;
+    use super::super::{Delivery, Error, Hub, Observer};
     use super::*;
-    use hex::ToHex;
-    use ring::digest;
-    use ring::hmac;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Missing(Arc<AtomicUsize>);
+
+    impl Observer for Missing {
+        fn on_error(&self, _: &str, _: &str, error: &Error) {
+            if let Error::Auth(AuthError::MissingCredentials) = *error {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
 
     #[test]
     fn authenticate_signatures() {
         let authenticated = AuthenticateHook::new("secret", |_: &Delivery| {});
         let payload = r#"{"zen": "Approachable is better than simple."}"#;
-        let secret = "secret";
-        let sbytes = secret.as_bytes();
-        let pbytes = payload.as_bytes();
-        let key = hmac::SigningKey::new(&digest::SHA1, &sbytes);
-        let mut signature = String::new();
-        hmac::sign(&key, &pbytes)
-            .as_ref()
-            .write_hex(&mut signature)
-            .unwrap();
-        assert!(authenticated.authenticate(payload, format!("sha1={}", signature).as_ref()))
+        // HMAC-SHA1 of the payload keyed with "secret", whichever backend verifies it
+        let signature = "4471180c43c722dcd4cd4445dd1ec771bebd9b8e";
+        assert!(authenticated.authenticate(payload, format!("sha1={}", signature).as_ref()));
+        assert!(!authenticated.authenticate(payload, format!("sha2={}", signature).as_ref()));
+        assert!(!authenticated.authenticate(payload, signature));
+    }
+
+    #[test]
+    fn authenticate_tokens() {
+        let bearer = TokenAuthHook::bearer("s3cret", |_: &Delivery| {});
+        assert!(bearer.authenticate("Bearer s3cret"));
+        assert!(bearer.authenticate("bearer s3cret"));
+        assert!(!bearer.authenticate("Bearer s3cre"));
+        assert!(!bearer.authenticate("Basic s3cret"));
+
+        let basic = TokenAuthHook::basic("hubot", "s3cret", |_: &Delivery| {});
+        assert!(basic.authenticate("Basic aHVib3Q6czNjcmV0"));
+        assert!(!basic.authenticate("Basic aHVib3Q6"));
+    }
+
+    #[test]
+    fn authenticate_query_tokens() {
+        let authenticated = QueryTokenHook::new("token", "s3cr+t", |_: &Delivery| {});
+        assert!(authenticated.authenticate("foo=bar&token=s3cr%2Bt"));
+        assert!(!authenticated.authenticate("token=s3cr"));
+        assert!(!authenticated.authenticate("secret=s3cr%2Bt"));
+        assert!(!authenticated.authenticate(""));
+    }
+
+    #[test]
+    fn reject_missing_credentials() {
+        let missing = Arc::new(AtomicUsize::new(0));
+        let mut hub = Hub::new();
+        hub.observe(Missing(missing.clone()));
+        hub.handle("push", AuthenticateHook::new("secret", |_: &Delivery| {}));
+        hub.handle("push", TokenAuthHook::bearer("s3cret", |_: &Delivery| {}));
+        let mut delivery = Delivery::new("id", "push", "{}", None);
+        assert!(hub.deliver(&mut delivery));
+        assert_eq!(2, missing.load(Ordering::SeqCst));
     }
 }
@@ -0,0 +1,270 @@
+//! JSONPath predicates over delivery payloads, e.g. dispatching only when
+//! `$.pull_request.labels[*].name` contains `"deploy"`, without typed structs
+//! for every niche field.
+//!
+//! The supported subset covers `$`, `.key`, `['key']`, `[index]`, the
+//! wildcards `.*` and `[*]`, and recursive descent with `..key`
+
+use super::expr;
+use super::filter::Filter;
+use super::Delivery;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Error returned when compiling an invalid `JsonPath`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidJsonPath(String);
+
+impl fmt::Display for InvalidJsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid json path '{}'", self.0)
+    }
+}
+
+impl Error for InvalidJsonPath {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    Wildcard,
+    Descend(String),
+}
+
+/// A compiled JSONPath selecting values from a payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    steps: Vec<Step>,
+}
+
+impl JsonPath {
+    /// compiles a path, which must start at the root, `$`
+    pub fn parse(path: &str) -> Result<JsonPath, InvalidJsonPath> {
+        let invalid = || InvalidJsonPath(path.to_owned());
+        let mut rest = path.trim();
+        if !rest.starts_with('$') {
+            return Err(invalid());
+        }
+        rest = &rest[1..];
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if rest.starts_with("..") {
+                let (key, tail) = name(&rest[2..]);
+                if key.is_empty() {
+                    return Err(invalid());
+                }
+                steps.push(Step::Descend(key.to_owned()));
+                rest = tail;
+            } else if rest.starts_with(".*") {
+                steps.push(Step::Wildcard);
+                rest = &rest[2..];
+            } else if rest.starts_with('.') {
+                let (key, tail) = name(&rest[1..]);
+                if key.is_empty() {
+                    return Err(invalid());
+                }
+                steps.push(Step::Key(key.to_owned()));
+                rest = tail;
+            } else if rest.starts_with('[') {
+                let end = rest.find(']').ok_or_else(invalid)?;
+                let inner = rest[1..end].trim();
+                let step = if inner == "*" {
+                    Step::Wildcard
+                } else if (inner.starts_with('\'') && inner.ends_with('\''))
+                    || (inner.starts_with('"') && inner.ends_with('"'))
+                {
+                    if inner.len() < 2 {
+                        return Err(invalid());
+                    }
+                    Step::Key(inner[1..inner.len() - 1].to_owned())
+                } else {
+                    Step::Index(inner.parse().map_err(|_| invalid())?)
+                };
+                steps.push(step);
+                rest = &rest[end + 1..];
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(JsonPath { steps: steps })
+    }
+
+    /// returns the values this path selects from `value`
+    pub fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        let mut selected = vec![value];
+        for step in &self.steps {
+            let mut next = Vec::new();
+            for value in selected {
+                match (step, value) {
+                    (&Step::Key(ref key), &Value::Object(ref map)) => next.extend(map.get(key)),
+                    (&Step::Index(index), &Value::Array(ref items)) => {
+                        next.extend(items.get(index))
+                    }
+                    (&Step::Wildcard, &Value::Object(ref map)) => next.extend(map.values()),
+                    (&Step::Wildcard, &Value::Array(ref items)) => next.extend(items.iter()),
+                    (&Step::Descend(ref key), value) => descend(key, value, &mut next),
+                    _ => (),
+                }
+            }
+            selected = next;
+        }
+        selected
+    }
+
+    /// a filter matching deliveries for which this path selects anything
+    pub fn exists(self) -> PathFilter {
+        self.matches(|_| true)
+    }
+
+    /// a filter matching deliveries for which this path selects `value`,
+    /// or selects an array with `value` as one of its elements. strings
+    /// only match when equal, so `deploy` doesn't match `do-not-deploy`
+    pub fn contains<V>(self, value: V) -> PathFilter
+    where
+        V: Into<Value>,
+    {
+        let value = value.into();
+        self.matches(move |selected| match *selected {
+            Value::Array(_) => expr::compare(selected, expr::Cmp::Contains, &value),
+            _ => expr::compare(selected, expr::Cmp::Eq, &value),
+        })
+    }
+
+    /// a filter matching deliveries for which this path selects a value
+    /// `predicate` accepts
+    pub fn matches<P>(self, predicate: P) -> PathFilter
+    where
+        P: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        PathFilter {
+            path: self,
+            predicate: Box::new(predicate),
+        }
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "$")?;
+        for step in &self.steps {
+            match *step {
+                Step::Key(ref key) if key.contains(|c| c == '.' || c == '[') || key == "*" => {
+                    write!(f, "['{}']", key)?
+                }
+                Step::Key(ref key) => write!(f, ".{}", key)?,
+                Step::Index(index) => write!(f, "[{}]", index)?,
+                Step::Wildcard => write!(f, "[*]")?,
+                Step::Descend(ref key) => write!(f, "..{}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for JsonPath {
+    type Err = InvalidJsonPath;
+
+    fn from_str(s: &str) -> Result<JsonPath, InvalidJsonPath> {
+        JsonPath::parse(s)
+    }
+}
+
+/// splits a member name from the front of a path
+fn name(path: &str) -> (&str, &str) {
+    let end = path.find(|c| c == '.' || c == '[').unwrap_or(path.len());
+    (&path[..end], &path[end..])
+}
+
+fn descend<'a>(key: &str, value: &'a Value, found: &mut Vec<&'a Value>) {
+    match *value {
+        Value::Object(ref map) => {
+            found.extend(map.get(key));
+            for child in map.values() {
+                descend(key, child, found)
+            }
+        }
+        Value::Array(ref items) => {
+            for child in items {
+                descend(key, child, found)
+            }
+        }
+        _ => (),
+    }
+}
+
+/// A filter matching deliveries whose payload has a value, selected by
+/// a `JsonPath`, which satisfies a predicate
+pub struct PathFilter {
+    path: JsonPath,
+    predicate: Box<Fn(&Value) -> bool + Send + Sync>,
+}
+
+impl Filter for PathFilter {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        match delivery.json() {
+            Some(payload) => self
+                .path
+                .select(payload)
+                .into_iter()
+                .any(|value| (self.predicate)(value)),
+            None => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("json path {}", self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn json_paths() {
+        let payload = serde_json::from_str::<Value>(
+            r#"{"pull_request": {"labels": [{"name": "bug"}, {"name": "deploy"}]}, "number": 2}"#,
+        )
+        .unwrap();
+        let names = |path: &str| {
+            JsonPath::parse(path)
+                .unwrap()
+                .select(&payload)
+                .into_iter()
+                .filter_map(|value| value.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["bug", "deploy"],
+            names("$.pull_request.labels[*].name")
+        );
+        assert_eq!(vec!["deploy"], names("$['pull_request'].labels[1].name"));
+        assert_eq!(vec!["bug", "deploy"], names("$..name"));
+        assert!(names("$.pull_request.labels[5].name").is_empty());
+        assert!(JsonPath::parse("pull_request").is_err());
+        assert!(JsonPath::parse("$.labels[x]").is_err());
+        assert!(JsonPath::parse("$.").is_err());
+
+        let delivery = Delivery::new("id", "public", include_str!("../data/public.json"), None);
+        let login = JsonPath::parse("$.sender.login").unwrap();
+        assert!(login.clone().contains("Codertocat").matches(&delivery));
+        assert!(!login.contains("hubot").matches(&delivery));
+        assert!(!JsonPath::parse("$.sender.login")
+            .unwrap()
+            .contains("Coder")
+            .matches(&delivery));
+        assert!(JsonPath::parse("$..full_name")
+            .unwrap()
+            .exists()
+            .matches(&delivery));
+
+        let labels = JsonPath::parse("$.pull_request.labels[*].name").unwrap();
+        let unlabelled = r#"{"pull_request": {"labels": [{"name": "do-not-deploy"}]}}"#;
+        let delivery = Delivery::new("id", "pull_request", unlabelled, None);
+        assert!(!labels.clone().contains("deploy").matches(&delivery));
+        assert!(labels.contains("do-not-deploy").matches(&delivery));
+    }
+}
@@ -0,0 +1,142 @@
+//! Filtering issues and pull requests by their labels
+//!
+//! Covers the common "only for `deploy` labeled pull requests" pattern, for
+//! the events of issues and pull requests and of their comments and reviews,
+//! whose payloads carry the issue's or pull request's labels
+
+use super::filter::Filter;
+use super::Delivery;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Any,
+    All,
+    Added,
+}
+
+/// A filter matching deliveries by the labels of their issue or pull request
+#[derive(Debug, Clone)]
+pub struct Labels {
+    labels: Vec<String>,
+    mode: Mode,
+}
+
+impl Labels {
+    /// matches issues and pull requests carrying any of these labels
+    pub fn any<I, L>(labels: I) -> Labels
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<String>,
+    {
+        Labels::with(labels, Mode::Any)
+    }
+
+    /// matches issues and pull requests carrying all of these labels
+    pub fn all<I, L>(labels: I) -> Labels
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<String>,
+    {
+        Labels::with(labels, Mode::All)
+    }
+
+    /// matches deliveries of one of these labels being added,
+    /// those whose action is `labeled`
+    pub fn added<I, L>(labels: I) -> Labels
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<String>,
+    {
+        Labels::with(labels, Mode::Added)
+    }
+
+    fn with<I, L>(labels: I, mode: Mode) -> Labels
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<String>,
+    {
+        Labels {
+            labels: labels.into_iter().map(Into::into).collect(),
+            mode: mode,
+        }
+    }
+
+    fn configured(&self, name: &str) -> bool {
+        self.labels.iter().any(|label| label == name)
+    }
+}
+
+/// the names of the labels of a payload's issue or pull request
+fn carried(payload: &Value) -> Vec<&str> {
+    ["/pull_request/labels", "/issue/labels"]
+        .iter()
+        .filter_map(|pointer| payload.pointer(pointer))
+        .filter_map(Value::as_array)
+        .flat_map(|labels| labels.iter())
+        .filter_map(|label| label.get("name"))
+        .filter_map(Value::as_str)
+        .collect()
+}
+
+impl Filter for Labels {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        let payload = match delivery.json() {
+            Some(payload) => payload,
+            None => return false,
+        };
+        match self.mode {
+            Mode::Any => carried(payload)
+                .into_iter()
+                .any(|name| self.configured(name)),
+            Mode::All => {
+                let carried = carried(payload);
+                self.labels
+                    .iter()
+                    .all(|label| carried.contains(&label.as_str()))
+            }
+            Mode::Added => {
+                payload.get("action").and_then(Value::as_str) == Some("labeled")
+                    && payload
+                        .pointer("/label/name")
+                        .and_then(Value::as_str)
+                        .map(|name| self.configured(name))
+                        .unwrap_or(false)
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mode = match self.mode {
+            Mode::Any => "any of",
+            Mode::All => "all of",
+            Mode::Added => "added",
+        };
+        format!("labels {} {}", mode, self.labels.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_labels() {
+        let labeled = r#"{
+            "action": "labeled",
+            "label": {"name": "deploy"},
+            "pull_request": {"labels": [{"name": "bug"}, {"name": "deploy"}]}
+        }"#;
+        let labeled = Delivery::new("id", "pull_request", labeled, None);
+        let opened = r#"{"action": "opened", "issue": {"labels": [{"name": "bug"}]}}"#;
+        let opened = Delivery::new("id", "issues", opened, None);
+        assert!(Labels::any(vec!["deploy"]).matches(&labeled));
+        assert!(Labels::any(vec!["deploy", "bug"]).matches(&opened));
+        assert!(!Labels::any(vec!["deploy"]).matches(&opened));
+        assert!(Labels::all(vec!["bug", "deploy"]).matches(&labeled));
+        assert!(!Labels::all(vec!["bug", "deploy"]).matches(&opened));
+        assert!(Labels::added(vec!["deploy"]).matches(&labeled));
+        assert!(!Labels::added(vec!["bug"]).matches(&labeled));
+        assert!(!Labels::added(vec!["bug"]).matches(&opened));
+    }
+}
@@ -0,0 +1,111 @@
+//! Serves a hub's hooks from an AWS Lambda function behind
+//! API Gateway or an application load balancer
+
+use super::receipt;
+use super::{Delivery, Hub};
+use lambda_http::{self, Body, Request, Response};
+use lambda_runtime::error::HandlerError;
+use lambda_runtime::Context;
+use serde_json;
+
+/// hands the delivery carried by a lambda request to the hub's hooks,
+/// returning the response to send
+pub fn handle(hub: &Hub, request: &Request) -> Response<Body> {
+    let headers = request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|value| (name.as_str().to_owned(), value.to_owned()))
+        })
+        .collect::<Vec<_>>();
+    let (status, receipt) = match Delivery::parse(&headers, request.body(), None) {
+        Ok(mut delivery) => {
+            delivery.query = request.uri().query();
+            match hub.check(&delivery) {
+                Ok(()) => {
+                    let hooks = hub.interested(delivery.event).map_or(0, Iterator::count);
+                    hub.deliver(&mut delivery);
+                    let duplicate = hub.recent.duplicate(&delivery);
+                    let verified = delivery.is_verified();
+                    (
+                        200,
+                        receipt::accepted(delivery.id, hooks, duplicate, verified),
+                    )
+                }
+                Err(err) => (400, receipt::rejected(delivery.id, err.to_string())),
+            }
+        }
+        Err(err) => {
+            let id = delivery_header(&headers, "X-Github-Delivery");
+            let event = delivery_header(&headers, "X-Github-Event");
+            log_delivery!(error, id, event, None; "failed to parse delivery {:?}: {}", id, err);
+            let receipt = receipt::rejected(id, err.to_string());
+            hub.observers.fail(id, event, &err.into());
+            (400, receipt)
+        }
+    };
+    let body = serde_json::to_string(&receipt).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .expect("failed to build response")
+}
+
+fn delivery_header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+    super::header_value(headers, name).unwrap_or("")
+}
+
+/// runs the hub as this function's handler, polling for requests
+/// until the lambda runtime shuts the function down
+pub fn run(hub: Hub) {
+    lambda_http::start(
+        move |request: Request, _: Context| -> Result<Response<Body>, HandlerError> {
+            Ok(handle(&hub, &request))
+        },
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const PUBLIC: &str = include_str!("../data/public.json");
+
+    #[test]
+    fn lambda_requests() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let mut hub = Hub::new();
+        {
+            let handled = handled.clone();
+            hub.handle("public", move |_: &Delivery| {
+                handled.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let request = lambda_http::http::Request::builder()
+            .header("X-Github-Event", "public")
+            .header("X-Github-Delivery", "id")
+            .body(Body::from(PUBLIC))
+            .unwrap();
+        let response = handle(&hub, &request);
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+        match *response.body() {
+            Body::Text(ref text) => assert_eq!(
+                receipt::accepted("id", 1, false, false),
+                serde_json::from_str(text).unwrap()
+            ),
+            ref other => panic!("unexpected {:?}", other),
+        }
+        let request = lambda_http::http::Request::builder()
+            .body(Body::from(PUBLIC))
+            .unwrap();
+        assert_eq!(400, handle(&hub, &request).status().as_u16());
+    }
+}
@@ -1,76 +1,648 @@
 //! Afterparty is a github webhook handler library for building custom integrations
+//!
+//! The crate's core, delivery parsing and verification, typed events, filters
+//! and the hook registry, has no http dependencies. Optional features add
+//!
+//! * `ring` (default): verifying signatures with ring
+//! * `rustcrypto`: verifying signatures with the pure rust RustCrypto crates
+//!   instead, for targets ring doesn't build on. one of the two is required
+//! * `server` (default): serving hubs over http with hyper
+//! * `tls`: serving hubs over https, with client certificate authentication
+//! * `acme`: obtaining and renewing certificates from Let's Encrypt
+//! * `lambda`: serving hubs from AWS Lambda functions
+//! * `macros`: the `#[webhook]` attribute and `WebhookHandler` derive
+//! * `registration`: registering a hub's webhooks with Github
+//! * `github`: handing hooks clients of Github's api to call back with
+//! * `smee`: subscribing hubs to smee.io channels relaying deliveries
+//! * `tunnel`: serving hubs on development machines through public tunnels
+//! * `grpc`: forwarding deliveries to gRPC services
+//! * `tracing`: logging with structured `tracing` events, which are also
+//!   emitted as `log` records when no subscriber is installed
+//!
+//! Embed the core in other frameworks with `default-features = false` and a
+//! crypto backend, building deliveries with `Delivery::parse` and handing
+//! them to `Hub::deliver`
 
+#[cfg(not(feature = "tracing"))]
 #[macro_use]
 extern crate log;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+#[cfg(feature = "server")]
 #[macro_use]
 extern crate hyper;
+#[cfg(feature = "macros")]
+extern crate afterparty_ng_macros;
+extern crate base64;
+#[cfg(feature = "grpc")]
+extern crate bytes;
 extern crate case;
+extern crate crossbeam_utils;
+#[cfg(feature = "server")]
+extern crate flate2;
+#[cfg(all(feature = "server", unix))]
+extern crate libc;
+#[cfg(feature = "server")]
+extern crate net2;
+#[cfg(feature = "server")]
+extern crate num_cpus;
+#[cfg(feature = "grpc")]
+#[macro_use]
+extern crate futures;
+extern crate glob;
+#[cfg(feature = "grpc")]
+extern crate h2;
 extern crate hex;
+#[cfg(any(feature = "rustcrypto", target_arch = "wasm32"))]
+extern crate hmac;
+#[cfg(feature = "grpc")]
+extern crate http;
+#[cfg(feature = "lambda")]
+extern crate lambda_http;
+#[cfg(feature = "lambda")]
+extern crate lambda_runtime;
+extern crate once_cell;
+#[cfg(feature = "tls")]
+extern crate openssl;
+#[cfg(all(
+    feature = "ring",
+    not(feature = "rustcrypto"),
+    not(target_arch = "wasm32")
+))]
 extern crate ring;
 extern crate serde;
+#[cfg_attr(any(feature = "registration", feature = "github"), macro_use)]
 extern crate serde_json;
+#[cfg(any(feature = "rustcrypto", target_arch = "wasm32"))]
+extern crate sha1;
+#[cfg(any(feature = "rustcrypto", target_arch = "wasm32"))]
+extern crate sha2;
+#[cfg(any(feature = "rustcrypto", target_arch = "wasm32"))]
+extern crate subtle;
+#[cfg(feature = "grpc")]
+extern crate tokio;
+extern crate url;
+extern crate zeroize;
+
+/// logs a message about a delivery, given the delivery or its id, event and
+/// repository. with the `tracing` feature these are recorded as the event's
+/// `delivery`, `event` and `repo` fields
+macro_rules! log_delivery {
+    ($level:ident, $id:expr, $event:expr, $repo:expr; $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        $level!(delivery = %$id, event = %$event, repo = %$repo.unwrap_or(""), $($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        $level!($($arg)+);
+    }};
+    ($level:ident, $delivery:expr; $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        let delivery: &::Delivery = $delivery;
+        log_delivery!($level, delivery.id, delivery.event, delivery.repository(); $($arg)+)
+    }};
+}
 
+#[cfg(feature = "server")]
+mod access;
+#[cfg(feature = "acme")]
+pub mod acme;
+mod actions;
+pub mod auth;
+mod bots;
+mod breaker;
+#[cfg(feature = "server")]
+mod broadcast;
+#[cfg(feature = "github")]
+mod checks;
+mod clock;
+pub mod cloudevents;
+#[doc(hidden)]
+pub mod codegen;
+#[cfg(feature = "github")]
+mod comments;
+#[cfg(feature = "server")]
+pub mod config;
+mod crypto;
+mod debounce;
+#[cfg(feature = "server")]
+mod decode;
+mod error;
 mod events;
+mod export;
+mod expr;
+pub mod extract;
+pub mod filter;
+#[cfg(feature = "server")]
+mod forward;
+#[cfg(feature = "github")]
+pub mod github;
+mod group;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "server")]
+mod handle;
 mod hook;
+mod jsonpath;
+mod labels;
+#[cfg(feature = "lambda")]
+pub mod lambda;
+mod limit;
+#[cfg(any(feature = "github", feature = "registration"))]
+mod link;
+mod net;
+mod observer;
+mod order;
+mod owned;
+mod paths;
+mod people;
+#[cfg(feature = "server")]
+mod pool;
+#[cfg(all(feature = "server", unix))]
+pub mod privileges;
+#[cfg(any(feature = "server", feature = "grpc"))]
+pub mod proxy;
+mod receipt;
+mod redact;
+#[cfg(feature = "registration")]
+pub mod registration;
+mod registry;
+mod schedule;
+mod secret;
+#[cfg(feature = "server")]
+mod selftest;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "server")]
+mod shutdown;
+#[cfg(feature = "smee")]
+pub mod smee;
+mod state;
+mod stats;
+#[cfg(feature = "github")]
+mod statuses;
+mod store;
+#[cfg(all(feature = "server", unix))]
+pub mod systemd;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod transform;
+#[cfg(feature = "tunnel")]
+pub mod tunnel;
+#[cfg(feature = "server")]
+mod websocket;
 
-pub use events::Event;
-pub use hook::{AuthenticateHook, Hook};
-use hyper::server::{Handler, Request, Response};
+#[cfg(feature = "server")]
+pub use access::AccessLog;
+#[cfg(feature = "macros")]
+pub use afterparty_ng_macros::{webhook, WebhookHandler};
+pub use breaker::{BreakerState, CircuitBreaker, Forwarder};
+#[cfg(feature = "server")]
+pub use broadcast::Broadcast;
+#[cfg(feature = "server")]
+use broadcast::Streams;
+#[cfg(feature = "server")]
+pub use config::{ConfigError, HubConfig};
+use crossbeam_utils::thread;
+pub use debounce::Debounce;
+#[cfg(feature = "server")]
+pub use decode::DecodeError;
+pub use error::{AuthError, Error, HookError, ServerError};
+pub use events::{Event, Repo, Repository, User};
+pub use export::Export;
+#[cfg(feature = "server")]
+use export::Exports;
+use extract::IntoHook;
+#[cfg(feature = "server")]
+pub use forward::Forward;
+pub use group::HookGroup;
+#[cfg(feature = "server")]
+pub use handle::HubHandle;
+pub use hook::{AuthenticateHook, Hook, QueryTokenHook, TokenAuthHook, Webhook};
+#[cfg(feature = "server")]
+use limit::Semaphore;
+pub use limit::{ConcurrencyLimitHook, Overflow};
+pub use net::{InvalidIpRange, IpRange, TrustedProxies};
+pub use observer::Observer;
+use observer::Observers;
+use once_cell::sync::OnceCell;
+pub use order::DependencyCycle;
+use order::{Dependencies, Registered};
+pub use owned::{OwnedDelivery, PeerIdentity};
+#[cfg(feature = "server")]
+use pool::BufferPool;
+pub use receipt::Receipt;
+#[cfg(any(feature = "server", feature = "lambda"))]
+use receipt::Recent;
+pub use redact::Redact;
+pub use registry::HookInfo;
+pub use schedule::{Defer, Scheduled, Scheduler};
+#[cfg(feature = "server")]
+pub use selftest::{selftest, SelfTestError};
+use serde_json::Value;
+#[cfg(feature = "server")]
+pub use server::ServerConfig;
+#[cfg(feature = "server")]
+use shutdown::Drain;
+#[cfg(feature = "server")]
+pub use shutdown::{Shutdown, ShutdownReport};
+use state::States;
+pub use stats::{Counts, Stats};
+use std::any::Any;
 use std::collections::HashMap;
-use std::io::Read;
-
-// signature for request
-// see [this document](https://developer.github.com/webhooks/securing/) for more information
-header! {(XHubSignature, "X-Hub-Signature") => [String]}
-
-// name of Github event
-// see [this document](https://developer.github.com/webhooks/#events) for available types
-header! {(XGithubEvent, "X-Github-Event") => [String]}
-
-// unique id for each delivery
-header! {(XGithubDelivery, "X-Github-Delivery") => [String]}
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::slice;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+pub use store::Store;
+pub use transform::Transform;
 
 // A delivery encodes all information about web hook request
 #[derive(Debug)]
 pub struct Delivery<'a> {
     pub id: &'a str,
     pub event: &'a str,
-    pub payload: Event,
     pub unparsed_payload: &'a str,
+    /// the body as it was sent, when it was compressed.
+    /// `unparsed_payload` holds the decompressed body
+    pub encoded_body: Option<&'a [u8]>,
     pub signature: Option<&'a str>,
+    /// identity of the client certificate presented by the sender, if any
+    pub peer: Option<&'a PeerIdentity>,
+    /// all request headers as (name, value) pairs
+    pub headers: &'a [(String, String)],
+    /// the raw query string of the request uri, if any
+    pub query: Option<&'a str>,
+    /// address of the connected peer, which may be a proxy
+    pub remote_addr: Option<SocketAddr>,
+    /// address of the client which sent the delivery, resolved through
+    /// the hub's trusted proxies when configured
+    pub real_ip: Option<IpAddr>,
+    /// wall clock time at which the delivery was received
+    pub received_at: SystemTime,
+    /// the payload as it was received, when a hook handed on
+    /// a transformed or redacted copy of it
+    original: Option<&'a str>,
+    received: Option<Instant>,
+    observers: Option<&'a Observers>,
+    states: Option<&'a States>,
+    payload: OnceCell<Result<Event, PayloadError>>,
+    json: OnceCell<Option<Value>>,
+    verified: AtomicBool,
+}
+
+/// Determines how a hub treats payloads which don't parse as their event
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseMode {
+    /// rejects deliveries, with a `400 Bad Request` when served over http.
+    /// useful for catching schema mismatches in tests and CI
+    Strict,
+    /// hands deliveries to hooks regardless, which may find out why
+    /// parsing failed with `Delivery::payload_error`. the default
+    Lenient,
+}
+
+impl Default for ParseMode {
+    fn default() -> ParseMode {
+        ParseMode::Lenient
+    }
+}
+
+/// Determines how a hub runs the hooks interested in a delivery
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Execution {
+    /// runs hooks one after another, in the order they were
+    /// registered, so they may rely on each other's effects. the default
+    Sequential,
+    /// runs each hook on a thread of its own, completing once all have,
+    /// so slow hooks don't hold up others. hooks declared with
+    /// `Hub::handle_after` still wait for their dependencies, and hooks
+    /// run one after another where threads can't be spawned
+    Parallel,
+}
+
+impl Default for Execution {
+    fn default() -> Execution {
+        Execution::Sequential
+    }
+}
+
+/// Reasons a delivery could not be parsed from a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// a required header was not present
+    MissingHeader(&'static str),
+    /// the body was not valid utf-8
+    InvalidUtf8,
+    /// the signature was missing or did not match the payload
+    InvalidSignature,
+    /// the body could not be parsed as the indicated event. payloads are
+    /// parsed lazily, so deliveries are only rejected for this by hubs
+    /// parsing strictly, see `ParseMode`
+    InvalidPayload(PayloadError),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::MissingHeader(name) => write!(f, "missing {} header", name),
+            ParseError::InvalidUtf8 => write!(f, "payload is not valid utf-8"),
+            ParseError::InvalidSignature => write!(f, "invalid signature"),
+            ParseError::InvalidPayload(ref err) => write!(f, "failed to parse payload: {}", err),
+        }
+    }
+}
+
+impl StdError for ParseError {
+    fn source(&self) -> Option<&(StdError + 'static)> {
+        match *self {
+            ParseError::InvalidPayload(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The reason a payload couldn't be parsed as its event, with the json
+/// error behind it when there is one
+#[derive(Debug, Clone)]
+pub struct PayloadError {
+    message: String,
+    json: Option<Arc<serde_json::Error>>,
+}
+
+impl PayloadError {
+    fn new(message: String, json: Option<serde_json::Error>) -> PayloadError {
+        PayloadError {
+            message: message,
+            json: json.map(Arc::new),
+        }
+    }
+}
+
+impl PartialEq for PayloadError {
+    fn eq(&self, other: &PayloadError) -> bool {
+        self.message == other.message
+    }
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for PayloadError {
+    fn source(&self) -> Option<&(StdError + 'static)> {
+        self.json
+            .as_ref()
+            .map(|err| &**err as &(StdError + 'static))
+    }
 }
 
 impl<'a> Delivery<'a> {
+    /// builds a delivery from the headers and body of a request received
+    /// outside of a hub's server, e.g. by a serverless function handler.
+    ///
+    /// when a `secret` is provided the body's `X-Hub-Signature` is verified.
+    /// the delivery may then be handed to a hub's hooks with `Hub::deliver`
+    pub fn parse(
+        headers: &'a [(String, String)],
+        body: &'a [u8],
+        secret: Option<&str>,
+    ) -> Result<Delivery<'a>, ParseError> {
+        let event = header_value(headers, "X-Github-Event")
+            .ok_or(ParseError::MissingHeader("X-Github-Event"))?;
+        let id = header_value(headers, "X-Github-Delivery")
+            .ok_or(ParseError::MissingHeader("X-Github-Delivery"))?;
+        let signature = header_value(headers, "X-Hub-Signature");
+        let payload = str::from_utf8(body).map_err(|_| ParseError::InvalidUtf8)?;
+        if let Some(secret) = secret {
+            let verified = signature
+                .map(|signature| auth::Signature::new(secret).verify(payload, signature))
+                .unwrap_or(false);
+            if !verified {
+                return Err(ParseError::InvalidSignature);
+            }
+        }
+        let mut delivery = Delivery::new(id, event, payload, signature);
+        delivery.headers = headers;
+        Ok(delivery)
+    }
+
+    /// builds a delivery from its parts. payloads are parsed lazily, when
+    /// first asked for with `payload` or `json`
     pub fn new(
         id: &'a str,
         event: &'a str,
         payload: &'a str,
         signature: Option<&'a str>,
-    ) -> Option<Delivery<'a>> {
+    ) -> Delivery<'a> {
+        Delivery {
+            id: id,
+            event: event,
+            unparsed_payload: payload,
+            encoded_body: None,
+            signature: signature,
+            peer: None,
+            headers: &[],
+            query: None,
+            remote_addr: None,
+            real_ip: None,
+            received_at: clock::now(),
+            original: None,
+            received: clock::instant(),
+            observers: None,
+            states: None,
+            payload: OnceCell::new(),
+            json: OnceCell::new(),
+            verified: AtomicBool::new(false),
+        }
+    }
+
+    /// the payload parsed as a typed event, or None if it isn't one.
+    /// payloads of events without a variant of their own are `Event::Unknown`.
+    /// the payload is parsed on first use and the result reused after
+    pub fn payload(&self) -> Option<&Event> {
+        self.parsed().as_ref().ok()
+    }
+
+    /// the reason the payload couldn't be parsed as a typed event, if it couldn't
+    pub fn payload_error(&self) -> Option<&str> {
+        self.parsed().as_ref().err().map(|err| err.message.as_str())
+    }
+
+    fn parsed(&self) -> &Result<Event, PayloadError> {
+        self.payload.get_or_init(|| self.parse_payload())
+    }
+
+    /// parses the payload as a typed event, without memoizing it
+    fn parse_payload(&self) -> Result<Event, PayloadError> {
         // patching raw payload with camelized name field for enum deserialization
-        let patched = events::patch_payload_json(event, payload);
+        let patched = events::patch_payload_json(self.event, self.unparsed_payload);
         match serde_json::from_str::<Event>(&patched) {
-            Ok(parsed) => Some(Delivery {
-                id: id,
-                event: event,
-                payload: parsed,
-                unparsed_payload: payload,
-                signature: signature,
-            }),
+            Ok(parsed) => Ok(parsed),
+            Err(_) if !events::known(self.event) => match self.json() {
+                Some(payload) => Ok(Event::Unknown {
+                    name: self.event.to_owned(),
+                    payload: payload.clone(),
+                }),
+                None => Err(PayloadError::new("payload is not json".to_owned(), None)),
+            },
             Err(e) => {
-                // println!("{}", e);
-                // println!("failed to parse json {:?}\n{:#?}", e, patched);
-                error!("failed to parse json {:?}\n{:#?}", e, patched);
-                None
+                log_delivery!(error, self; "failed to parse json {:?}\n{:#?}", e, patched);
+                Err(PayloadError::new(e.to_string(), Some(e)))
             }
         }
     }
+
+    /// the full name of the delivery's repository, if it has one
+    fn repository(&self) -> Option<&str> {
+        self.json()
+            .and_then(|payload| payload.get("repository"))
+            .and_then(|repository| repository.get("full_name"))
+            .and_then(Value::as_str)
+    }
+
+    /// the payload as it was received, which signatures are verified against
+    /// and which archives may keep. it differs from `unparsed_payload` when a
+    /// hook, like `Transform` or `Redact`, handed on a copy of the delivery
+    pub fn original_payload(&self) -> &'a str {
+        self.original.unwrap_or(self.unparsed_payload)
+    }
+
+    /// this delivery with another payload, e.g. a redacted copy of its own,
+    /// keeping the original
+    fn with_payload<'b>(&self, payload: &'b str) -> Delivery<'b>
+    where
+        'a: 'b,
+    {
+        Delivery {
+            id: self.id,
+            event: self.event,
+            unparsed_payload: payload,
+            encoded_body: self.encoded_body,
+            signature: self.signature,
+            peer: self.peer,
+            headers: self.headers,
+            query: self.query,
+            remote_addr: self.remote_addr,
+            real_ip: self.real_ip,
+            received_at: self.received_at,
+            original: Some(self.original_payload()),
+            received: self.received,
+            observers: self.observers,
+            states: self.states,
+            payload: OnceCell::new(),
+            json: OnceCell::new(),
+            verified: AtomicBool::new(self.is_verified()),
+        }
+    }
+
+    /// copies the delivery's data into an `OwnedDelivery`, which may be moved
+    /// to another thread, sent over a channel or persisted, and borrowed
+    /// again with `OwnedDelivery::delivery`
+    pub fn into_owned(self) -> OwnedDelivery {
+        OwnedDelivery::from(&self)
+    }
+
+    /// whether the delivery's sender is a bot, e.g. a Github app
+    pub fn from_bot(&self) -> bool {
+        self.json()
+            .and_then(|payload| payload.pointer("/sender/type"))
+            .and_then(Value::as_str)
+            == Some("Bot")
+    }
+
+    /// the payload parsed as untyped json, or None if it isn't json.
+    /// the payload is parsed on first use and the result reused after
+    pub fn json(&self) -> Option<&Value> {
+        self.json
+            .get_or_init(|| serde_json::from_str::<Value>(self.unparsed_payload).ok())
+            .as_ref()
+    }
+
+    /// returns the value of the first request header
+    /// matching `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        header_value(self.headers, name)
+    }
+
+    /// state of type `T` registered with the hub handling this delivery,
+    /// see `Hub::with_state`
+    pub fn state<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.states.and_then(|states| states.get())
+    }
+
+    /// time elapsed since this delivery was received, always zero
+    /// on wasm32 where there is no monotonic clock
+    pub fn elapsed(&self) -> Duration {
+        clock::since(self.received)
+    }
+
+    /// whether an authenticating hook has accepted this delivery
+    pub fn is_verified(&self) -> bool {
+        self.verified.load(Ordering::SeqCst)
+    }
+
+    /// notifies observers that this delivery was authenticated,
+    /// once however many authenticating hooks accept it
+    fn verified(&self) {
+        if self.verified.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(observers) = self.observers {
+            observers.verified(self)
+        }
+    }
+
+    /// notifies observers that this delivery was rejected
+    fn rejected(&self, error: AuthError) {
+        if let Some(observers) = self.observers {
+            observers.fail(self.id, self.event, &Error::Auth(error))
+        }
+    }
 }
 
 /// A hub is a registry of hooks
 #[derive(Default)]
 pub struct Hub {
-    hooks: HashMap<String, Vec<Box<Hook>>>,
+    hooks: HashMap<String, Vec<Registered>>,
+    dependencies: Dependencies,
+    mode: ParseMode,
+    execution: Execution,
+    #[cfg(feature = "server")]
+    in_flight: Option<(Semaphore, Overflow)>,
+    #[cfg(feature = "server")]
+    drain: Arc<Drain>,
+    observers: Observers,
+    states: States,
+    #[cfg(any(feature = "server", feature = "lambda"))]
+    recent: Recent,
+    #[cfg(feature = "server")]
+    proxies: Option<TrustedProxies>,
+    #[cfg(feature = "server")]
+    decompress: Option<usize>,
+    #[cfg(feature = "server")]
+    buffers: BufferPool,
+    #[cfg(feature = "server")]
+    broadcast: Option<(String, Arc<Broadcast>)>,
+    #[cfg(feature = "server")]
+    streams: Streams,
+    #[cfg(feature = "server")]
+    admin: Option<(String, auth::Token)>,
+    #[cfg(feature = "server")]
+    exports: Option<(String, Arc<Exports>)>,
+    #[cfg(feature = "server")]
+    access_log: Option<AccessLog>,
+    #[cfg(feature = "server")]
+    path: Option<String>,
 }
 
 impl Hub {
@@ -81,6 +653,53 @@ impl Hub {
         }
     }
 
+    /// chooses whether deliveries whose payloads don't parse as their
+    /// event are rejected or handed to hooks regardless
+    pub fn parse_mode(&mut self, mode: ParseMode) {
+        self.mode = mode;
+    }
+
+    /// chooses whether hooks interested in the same delivery
+    /// run one after another or at once
+    pub fn execution(&mut self, execution: Execution) {
+        self.execution = execution;
+    }
+
+    /// registers an observer of delivery lifecycle events
+    pub fn observe<O>(&mut self, observer: O)
+    where
+        O: Observer + 'static,
+    {
+        self.observers.push(Box::new(observer))
+    }
+
+    /// shares state of any type with the hub's hooks, which closures take
+    /// as `extract::State<T>` arguments and hooks get with `Delivery::state`.
+    /// registering state of a type again replaces it
+    pub fn with_state<T>(&mut self, state: T)
+    where
+        T: Any + Send + Sync,
+    {
+        self.states.insert(state)
+    }
+
+    /// counts of the deliveries this hub has seen recently, by event and repository
+    pub fn stats(&self) -> Stats {
+        self.observers.stats.snapshot()
+    }
+
+    /// counts deliveries seen over the last `window`, rather than the last day
+    pub fn stats_window(&mut self, window: Duration) {
+        self.observers.stats.window(window)
+    }
+
+    /// keeps the hub's statistics in a json file, continuing from those
+    /// already there so they survive restarts. they're written every few
+    /// seconds as deliveries complete, and when the hub is stopped
+    pub fn persist_stats<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.observers.stats.persist(path.as_ref().to_owned())
+    }
+
     /// adds a new web hook which will only be applied
     /// when a delivery is revcieved with a valid
     /// request signature based on the provided secret
@@ -93,73 +712,238 @@ impl Hub {
     }
 
     /// add a need hook to list of hooks
-    /// interested in a given event
-    pub fn handle<H>(&mut self, event: &str, hook: H)
+    /// interested in a given event. closures taking
+    /// `extract::FromDelivery` arguments may be handed in place of a hook
+    pub fn handle<H, Args>(&mut self, event: &str, hook: H)
     where
-        H: Hook + 'static,
+        H: IntoHook<Args>,
     {
+        self.handle_shared(event, Arc::new(hook.into_hook()))
+    }
+
+    /// adds a hook only interested in some actions of an event, e.g.
+    /// `Actions::allow(vec!["opened", "synchronize"])` of pull requests
+    pub fn handle_actions<H, Args>(&mut self, event: &str, actions: filter::Actions, hook: H)
+    where
+        H: IntoHook<Args>,
+    {
+        self.handle(event, filter::FilterHook::new(actions, hook.into_hook()))
+    }
+
+    /// adds a hook which only runs while `condition` holds, e.g. a closure
+    /// consulting a feature flag or the time of day, or any other filter
+    pub fn handle_when<F, H, Args>(&mut self, event: &str, condition: F, hook: H)
+    where
+        F: filter::Filter + 'static,
+        H: IntoHook<Args>,
+    {
+        self.handle(event, filter::FilterHook::new(condition, hook.into_hook()))
+    }
+
+    /// adds a hook which may also be registered for other events,
+    /// or with other hubs, without being cloned
+    pub fn handle_shared(&mut self, event: &str, hook: Arc<Hook>) {
         self.hooks
             .entry(event.to_owned())
             .or_insert(vec![])
-            .push(Box::new(hook));
+            .push(Registered {
+                hook: hook,
+                name: None,
+            });
+    }
+
+    /// adds a hook interested in events this crate doesn't know, whose
+    /// payloads are `Event::Unknown`. hooks registered for such an event
+    /// by name take precedence
+    pub fn handle_unknown<H, Args>(&mut self, hook: H)
+    where
+        H: IntoHook<Args>,
+    {
+        self.handle(UNKNOWN, hook)
+    }
+
+    /// adds a hook for the event it declares, e.g. one generated
+    /// with the `#[webhook]` attribute
+    pub fn register<W>(&mut self, webhook: W)
+    where
+        W: Webhook + 'static,
+    {
+        let event = webhook.event().to_owned();
+        self.handle(&event, webhook)
+    }
+
+    /// mounts a group of hooks, which applies its own filters and
+    /// authenticators to every delivery before its hooks see them
+    pub fn mount(&mut self, group: HookGroup) {
+        self.handle("*", group)
     }
 
     /// get all interested hooks for a given event
-    pub fn hooks(&self, event: &str) -> Option<Vec<&Box<Hook>>> {
-        let explicit = self.hooks.get(event);
-        let implicit = self.hooks.get("*");
-        let combined = match (explicit, implicit) {
-            (Some(ex), Some(im)) => {
-                Some(ex.iter().chain(im.iter()).into_iter().collect::<Vec<_>>())
-            }
-            (Some(ex), _) => Some(ex.into_iter().collect::<Vec<_>>()),
-            (_, Some(im)) => Some(im.into_iter().collect::<Vec<_>>()),
-            _ => None,
+    pub fn hooks(&self, event: &str) -> Option<Vec<&Arc<Hook>>> {
+        self.interested(event)
+            .map(|hooks| hooks.map(|registered| &registered.hook).collect())
+    }
+
+    /// iterates over the interested hooks for a given event
+    /// without collecting them, as deliveries are dispatched
+    fn interested(&self, event: &str) -> Option<Interested> {
+        let explicit = match self.hooks.get(event) {
+            Some(hooks) => &hooks[..],
+            None if !events::known(event) => self
+                .hooks
+                .get(UNKNOWN)
+                .map(|hooks| &hooks[..])
+                .unwrap_or(&[]),
+            None => &[],
         };
-        combined
-    }
-}
-
-impl Handler for Hub {
-    fn handle(&self, mut req: Request, res: Response) {
-        let headers = req.headers.clone();
-        if let (Some(&XGithubEvent(ref event)), Some(&XGithubDelivery(ref delivery))) = (
-            headers.get::<XGithubEvent>(),
-            headers.get::<XGithubDelivery>(),
-        ) {
-            let signature = headers.get::<XHubSignature>();
-            info!("recv '{}' event with signature '{:?}'", event, signature);
-            if let Some(hooks) = self.hooks(event) {
-                let mut payload = String::new();
-                if let Ok(_) = req.read_to_string(&mut payload) {
-                    match Delivery::new(
-                        delivery,
-                        event,
-                        payload.as_ref(),
-                        signature.map(|s| s.as_ref()),
-                    ) {
-                        Some(delivery) => {
-                            // println!("{:?}", delivery);
-                            for hook in hooks {
-                                hook.handle(&delivery);
-                            }
-                        }
-                        _ => error!(
-                            "failed to parse event {:?} for delivery {:?}",
-                            event, delivery
-                        ),
+        let implicit = self.hooks.get("*").map(|hooks| &hooks[..]).unwrap_or(&[]);
+        if explicit.is_empty() && implicit.is_empty() {
+            None
+        } else {
+            Some(explicit.iter().chain(implicit.iter()))
+        }
+    }
+}
+
+/// the registry key of hooks for events without an `Event` variant
+const UNKNOWN: &str = "?unknown";
+
+/// the hooks interested in a delivery's event
+type Interested<'a> = iter::Chain<slice::Iter<'a, Registered>, slice::Iter<'a, Registered>>;
+
+impl Hub {
+    /// hands a delivery, e.g. one built with `Delivery::parse`, to each hook
+    /// interested in its event, returning false if there were none
+    pub fn deliver<'a>(&'a self, delivery: &mut Delivery<'a>) -> bool {
+        match self.interested(delivery.event) {
+            Some(_) if self.check(delivery).is_err() => false,
+            Some(hooks) => {
+                self.dispatch(delivery, hooks);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// rejects deliveries whose payloads don't parse when parsing strictly,
+    /// notifying observers
+    fn check(&self, delivery: &Delivery) -> Result<(), ParseError> {
+        match (self.mode, delivery.parsed()) {
+            (ParseMode::Strict, &Err(ref err)) => {
+                log_delivery!(warn, delivery; "rejecting delivery {}: {}", delivery.id, err);
+                let error = ParseError::InvalidPayload(err.clone());
+                self.observers
+                    .fail(delivery.id, delivery.event, &error.clone().into());
+                Err(error)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// hands a delivery to each of the given hooks, as the hub's
+    /// execution policy chooses
+    fn dispatch<'a>(&'a self, delivery: &mut Delivery<'a>, hooks: Interested<'a>) {
+        delivery.observers = Some(&self.observers);
+        delivery.states = Some(&self.states);
+        self.observers.received(delivery);
+        let delivery = &*delivery;
+        if self.dependencies.is_empty() {
+            self.run(delivery, hooks);
+        } else {
+            let scheduled = self.dependencies.schedule(hooks.collect());
+            match self.execution {
+                Execution::Sequential => self.run(
+                    delivery,
+                    scheduled.iter().map(|&(_, registered)| registered),
+                ),
+                Execution::Parallel => {
+                    let waves = scheduled.iter().map(|&(wave, _)| wave).max().unwrap_or(0);
+                    for wave in 0..=waves {
+                        let hooks = scheduled
+                            .iter()
+                            .filter(|&&(of, _)| of == wave)
+                            .map(|&(_, registered)| registered);
+                        self.run(delivery, hooks);
                     }
                 }
             }
         }
-        let _ = res.send(b"ok");
-        ()
+        self.observers.completed(delivery);
     }
+
+    /// hands a delivery to hooks which don't depend on each other, one
+    /// after another or each on a thread of its own
+    fn run<'h, I>(&self, delivery: &Delivery, hooks: I)
+    where
+        I: Iterator<Item = &'h Registered>,
+    {
+        match self.execution {
+            Execution::Sequential => {
+                for registered in hooks {
+                    self.invoke(delivery, &registered.hook);
+                }
+            }
+            Execution::Parallel => {
+                let _ = thread::scope(|scope| {
+                    for registered in hooks {
+                        let hook = &registered.hook;
+                        let spawned = scope
+                            .builder()
+                            .name("afterparty-hook".to_owned())
+                            .spawn(move |_| self.invoke(delivery, hook));
+                        if spawned.is_err() {
+                            self.invoke(delivery, hook);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    /// hands a delivery to a hook, reporting a panicking hook to
+    /// observers as failing with a `HookError`
+    fn invoke(&self, delivery: &Delivery, hook: &Arc<Hook>) {
+        self.observers.hook_start(delivery);
+        let started = clock::instant();
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| hook.handle(delivery))) {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| (*message).to_owned())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            log_delivery!(
+                error,
+                delivery;
+                "hook panicked handling delivery {}: {}",
+                delivery.id,
+                message
+            );
+            let error = Error::Hook(HookError::Panicked(message));
+            self.observers.fail(delivery.id, delivery.event, &error);
+        }
+        self.observers.hook_end(delivery, clock::since(started));
+    }
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+        .map(|&(_, ref value)| value.as_str())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Delivery, Hub};
+    use super::{
+        Delivery, Error, Event, Execution, Hook, HookError, Hub, Observer, ParseError, ParseMode,
+        TokenAuthHook,
+    };
+    use std::error::Error as StdError;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    const PUBLIC: &str = include_str!("../data/public.json");
 
     #[test]
     fn hub_hooks() {
@@ -174,4 +958,206 @@ mod tests {
             hub.hooks("push").map(|hooks| hooks.into_iter().count())
         )
     }
+
+    #[test]
+    fn parallel_hooks() {
+        let (sender, receiver) = mpsc::channel();
+        let (receiver, sender) = (Mutex::new(receiver), Mutex::new(sender));
+        let met = Arc::new(AtomicUsize::new(0));
+        let meeting = met.clone();
+        let mut hub = Hub::new();
+        hub.execution(Execution::Parallel);
+        // the first hook only completes in time if the second runs meanwhile
+        hub.handle("push", move |_: &Delivery| {
+            let receiver = receiver.lock().unwrap();
+            if receiver.recv_timeout(Duration::from_secs(5)).is_ok() {
+                meeting.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        hub.handle("push", move |_: &Delivery| {
+            sender.lock().unwrap().send(()).unwrap();
+        });
+        let mut delivery = Delivery::new("id", "push", "{}", None);
+        assert!(hub.deliver(&mut delivery));
+        assert_eq!(1, met.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn conditional_hooks() {
+        let (enabled, handled) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let (flag, counter) = (enabled.clone(), handled.clone());
+        let mut hub = Hub::new();
+        hub.handle_when(
+            "push",
+            move |_: &Delivery| flag.load(Ordering::SeqCst),
+            move |_: &Delivery| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        let delivery = || Delivery::new("id", "push", "{}", None);
+        hub.deliver(&mut delivery());
+        enabled.store(true, Ordering::SeqCst);
+        hub.deliver(&mut delivery());
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shared_hooks() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let hook: Arc<Hook> = Arc::new(move |_: &Delivery| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut hub = Hub::new();
+        hub.handle_shared("public", hook.clone());
+        hub.handle_shared("*", hook.clone());
+        let mut delivery = Delivery::new("id", "public", PUBLIC, None);
+        assert!(hub.deliver(&mut delivery));
+        assert_eq!(2, handled.load(Ordering::SeqCst));
+        assert_eq!(3, Arc::strong_count(&hook));
+    }
+
+    #[test]
+    fn unknown_events() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let mut hub = Hub::new();
+        hub.handle_unknown(move |delivery: &Delivery| match delivery.payload() {
+            Some(&Event::Unknown {
+                ref name,
+                ref payload,
+            }) => {
+                assert_eq!("merge_queue", name);
+                assert!(payload.get("repository").is_some());
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            other => panic!("unexpected {:?}", other),
+        });
+        let mut delivery = Delivery::new("id", "merge_queue", PUBLIC, None);
+        assert!(hub.deliver(&mut delivery));
+        let mut known = Delivery::new("id", "public", PUBLIC, None);
+        assert!(!hub.deliver(&mut known));
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn parse_modes() {
+        let mut hub = Hub::new();
+        let failed = Arc::new(AtomicUsize::new(0));
+        let counter = failed.clone();
+        hub.handle("public", move |delivery: &Delivery| {
+            if delivery.payload_error().is_some() {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let payload = PUBLIC.replace(r#""login": "Codertocat""#, r#""login": 1"#);
+        let mut delivery = Delivery::new("id", "public", &payload, None);
+        assert!(hub.deliver(&mut delivery));
+        assert_eq!(1, failed.load(Ordering::SeqCst));
+        hub.parse_mode(ParseMode::Strict);
+        let mut delivery = Delivery::new("id", "public", &payload, None);
+        assert!(!hub.deliver(&mut delivery));
+        match hub.check(&delivery) {
+            Err(ParseError::InvalidPayload(ref err)) => assert!(StdError::source(err).is_some()),
+            other => panic!("unexpected {:?}", other),
+        }
+        let delivery = Delivery::new("id", "public", PUBLIC, None);
+        assert!(delivery.payload_error().is_none());
+    }
+
+    #[test]
+    fn delivery_headers() {
+        let headers = vec![("Authorization".to_owned(), "Bearer token".to_owned())];
+        let mut delivery = Delivery::new("id", "public", PUBLIC, None);
+        delivery.headers = &headers;
+        assert_eq!(Some("Bearer token"), delivery.header("authorization"));
+        assert_eq!(None, delivery.header("x-missing"));
+    }
+
+    #[test]
+    fn lazy_payloads() {
+        let delivery = Delivery::new("id", "public", PUBLIC, None);
+        assert!(delivery.payload.get().is_none());
+        assert!(delivery.json.get().is_none());
+        let payload = delivery.payload().unwrap() as *const _;
+        assert_eq!(payload, delivery.payload().unwrap() as *const _);
+        assert!(delivery.json().unwrap().get("repository").is_some());
+
+        let raw = Delivery::new("id", "public", "not json", None);
+        assert!(raw.payload().is_none());
+        assert!(raw.json().is_none());
+    }
+
+    #[test]
+    fn parse_deliveries() {
+        let mut headers = vec![
+            ("X-Github-Event".to_owned(), "public".to_owned()),
+            ("X-Github-Delivery".to_owned(), "id".to_owned()),
+        ];
+        let delivery = Delivery::parse(&headers, PUBLIC.as_bytes(), None).unwrap();
+        assert_eq!("id", delivery.id);
+        assert_eq!(
+            Some(ParseError::InvalidSignature),
+            Delivery::parse(&headers, PUBLIC.as_bytes(), Some("secret")).err()
+        );
+        assert_eq!(
+            Some(ParseError::InvalidUtf8),
+            Delivery::parse(&headers, &[0xff], None).err()
+        );
+        headers.pop();
+        assert_eq!(
+            Some(ParseError::MissingHeader("X-Github-Delivery")),
+            Delivery::parse(&headers, PUBLIC.as_bytes(), None).err()
+        );
+    }
+
+    #[derive(Default)]
+    struct Counts {
+        received: AtomicUsize,
+        verified: AtomicUsize,
+        hooks: AtomicUsize,
+        failures: AtomicUsize,
+    }
+
+    impl Observer for Arc<Counts> {
+        fn on_received(&self, _: &Delivery) {
+            self.received.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_verified(&self, _: &Delivery) {
+            self.verified.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_hook_end(&self, _: &Delivery, _: Duration) {
+            self.hooks.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_error(&self, _: &str, _: &str, error: &Error) {
+            if let Error::Hook(HookError::Panicked(ref message)) = *error {
+                assert_eq!("hook failed", message);
+                self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    fn hub_observers() {
+        let counts = Arc::new(Counts::default());
+        let mut hub = Hub::new();
+        hub.observe(counts.clone());
+        hub.handle("public", TokenAuthHook::bearer("token", |_: &Delivery| {}));
+        hub.handle("*", |_: &Delivery| {});
+        hub.handle("*", |_: &Delivery| panic!("hook failed"));
+        let headers = vec![("Authorization".to_owned(), "Bearer token".to_owned())];
+        let mut delivery = Delivery::new("id", "public", PUBLIC, None);
+        delivery.headers = &headers;
+        hub.dispatch(&mut delivery, hub.interested("public").unwrap());
+        assert_eq!(1, counts.received.load(Ordering::SeqCst));
+        assert_eq!(1, counts.verified.load(Ordering::SeqCst));
+        assert_eq!(3, counts.hooks.load(Ordering::SeqCst));
+        assert_eq!(1, counts.failures.load(Ordering::SeqCst));
+    }
 }
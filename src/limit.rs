@@ -0,0 +1,175 @@
+//! Bounds on the number of deliveries processed at once
+
+use super::{Delivery, Hook};
+use std::sync::{Condvar, Mutex};
+
+/// Determines what happens to a delivery arriving while a limit is exhausted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// wait until a delivery in progress completes
+    Queue,
+    /// drop the delivery
+    Shed,
+}
+
+/// A counting semaphore handing out a fixed number of permits
+pub struct Semaphore {
+    permits: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A permit which is returned to its semaphore when dropped
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: permits,
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// blocks until a permit is available
+    pub fn acquire(&self) -> Permit {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit { semaphore: self }
+    }
+
+    /// returns a permit if one is available without waiting
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut available = self.available.lock().unwrap();
+        if *available == 0 {
+            return None;
+        }
+        *available -= 1;
+        Some(Permit { semaphore: self })
+    }
+
+    /// acquires a permit according to the given overflow policy. semaphores
+    /// without permits shed, as queueing would wait forever
+    pub fn admit(&self, overflow: Overflow) -> Option<Permit> {
+        match overflow {
+            Overflow::Queue if self.permits > 0 => Some(self.acquire()),
+            _ => self.try_acquire(),
+        }
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+/// A hook which processes at most `limit` deliveries concurrently
+///
+/// Useful for hooks doing expensive work, like shelling out to `git clone`
+pub struct ConcurrencyLimitHook<H: Hook + 'static> {
+    semaphore: Semaphore,
+    overflow: Overflow,
+    hook: H,
+}
+
+impl<H: Hook + 'static> ConcurrencyLimitHook<H> {
+    /// a `limit` of 0 drops every delivery, whatever the overflow policy
+    pub fn new(limit: usize, overflow: Overflow, hook: H) -> ConcurrencyLimitHook<H> {
+        ConcurrencyLimitHook {
+            semaphore: Semaphore::new(limit),
+            overflow: overflow,
+            hook: hook,
+        }
+    }
+}
+
+impl<H: Hook + 'static> Hook for ConcurrencyLimitHook<H> {
+    fn handle(&self, delivery: &Delivery) {
+        match self.semaphore.admit(self.overflow) {
+            Some(_permit) => self.hook.handle(delivery),
+            None => log_delivery!(
+                warn,
+                delivery;
+                "concurrency limit reached, dropping delivery {}",
+                delivery.id
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn semaphore_permits() {
+        let semaphore = Semaphore::new(2);
+        let first = semaphore.acquire();
+        let _second = semaphore.admit(Overflow::Queue);
+        assert!(semaphore.admit(Overflow::Shed).is_none());
+        drop(first);
+        assert!(semaphore.try_acquire().is_some());
+    }
+
+    #[test]
+    fn concurrency_limits() {
+        let limited = |limit, overflow| {
+            let (handled, gate) = (Arc::new(AtomicUsize::new(0)), Arc::new(Mutex::new(())));
+            let hook = {
+                let (handled, gate) = (handled.clone(), gate.clone());
+                ConcurrencyLimitHook::new(limit, overflow, move |_: &Delivery| {
+                    handled.fetch_add(1, Ordering::SeqCst);
+                    let _ = gate.lock();
+                })
+            };
+            (Arc::new(hook), handled, gate)
+        };
+        let deliver = |hook: &Arc<ConcurrencyLimitHook<_>>| {
+            let hook = hook.clone();
+            thread::spawn(move || {
+                hook.handle(&Delivery::new("id", "ping", "{}", None));
+            })
+        };
+        let entered = |handled: &AtomicUsize, count| {
+            while handled.load(Ordering::SeqCst) < count {
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+
+        let (hook, handled, _) = limited(0, Overflow::Queue);
+        deliver(&hook).join().unwrap();
+        assert_eq!(0, handled.load(Ordering::SeqCst));
+
+        let (hook, handled, gate) = limited(1, Overflow::Shed);
+        let closed = gate.lock().unwrap();
+        let first = deliver(&hook);
+        entered(&handled, 1);
+        deliver(&hook).join().unwrap();
+        drop(closed);
+        first.join().unwrap();
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+
+        let (hook, handled, gate) = limited(1, Overflow::Queue);
+        let closed = gate.lock().unwrap();
+        let first = deliver(&hook);
+        entered(&handled, 1);
+        let queued = deliver(&hook);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+        drop(closed);
+        first.join().unwrap();
+        queued.join().unwrap();
+        assert_eq!(2, handled.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,36 @@
+//! Following the pages of Github's api
+//!
+//! Github pages lists, e.g. of a repository's webhooks or an issue's
+//! comments, and links each page to the next in its `Link` header
+
+/// the url of the `next` page in a `Link` header
+pub fn next_page(link: &str) -> Option<String> {
+    link.split(',')
+        .find(|link| link.contains(r#"rel="next""#))
+        .and_then(|link| link.split(';').next())
+        .map(|url| {
+            url.trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_owned()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_pages() {
+        assert_eq!(
+            Some("https://api.github.com/hooks?cursor=x".to_owned()),
+            next_page(
+                r#"<https://api.github.com/hooks?cursor=x>; rel="next", <https://api.github.com/hooks>; rel="first""#
+            )
+        );
+        assert_eq!(
+            None,
+            next_page(r#"<https://api.github.com/hooks>; rel="first""#)
+        );
+    }
+}
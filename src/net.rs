@@ -0,0 +1,204 @@
+//! Client address resolution for deliveries relayed through proxies
+
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of ip addresses in CIDR notation, e.g. `10.0.0.0/8`.
+/// A bare address is treated as a range of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// Error returned when parsing an invalid `IpRange`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidIpRange(String);
+
+impl fmt::Display for InvalidIpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid ip range '{}'", self.0)
+    }
+}
+
+impl Error for InvalidIpRange {}
+
+impl IpRange {
+    /// returns true if `ip` falls within this range
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(32, self.prefix) as u32;
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(128, self.prefix);
+                as_u128(&net.octets()) & mask == as_u128(&ip.octets()) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask(bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (!0u128 << (bits - prefix)) & (!0u128 >> (128 - bits))
+    }
+}
+
+fn as_u128(octets: &[u8; 16]) -> u128 {
+    octets
+        .iter()
+        .fold(0u128, |acc, &octet| (acc << 8) | u128::from(octet))
+}
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(s: &str) -> Result<IpRange, InvalidIpRange> {
+        let invalid = || InvalidIpRange(s.to_owned());
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = parts
+            .next()
+            .and_then(|addr| addr.parse::<IpAddr>().ok())
+            .ok_or_else(invalid)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(IpRange {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+}
+
+/// Proxies whose forwarding header is trusted to report the
+/// address of the client they are relaying for
+#[derive(Debug, Clone)]
+pub struct TrustedProxies {
+    header: String,
+    proxies: Vec<IpRange>,
+}
+
+impl TrustedProxies {
+    pub fn new<H>(header: H, proxies: Vec<IpRange>) -> TrustedProxies
+    where
+        H: Into<String>,
+    {
+        TrustedProxies {
+            header: header.into(),
+            proxies: proxies,
+        }
+    }
+
+    /// name of the header proxies report client addresses in
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    fn trusted(&self, ip: &IpAddr) -> bool {
+        self.proxies.iter().any(|range| range.contains(ip))
+    }
+
+    /// resolves the address of the client on whose behalf the
+    /// peer connected, given the value of the forwarding header.
+    ///
+    /// the header is only honored when the peer is a trusted proxy. it's
+    /// read right to left, skipping addresses appended by trusted proxies,
+    /// so entries a client prepended itself are never taken at face value
+    pub fn client_ip(&self, peer: IpAddr, forwarded: Option<&str>) -> IpAddr {
+        if !self.trusted(&peer) {
+            return peer;
+        }
+        let mut client = peer;
+        if let Some(forwarded) = forwarded {
+            for ip in forwarded.rsplit(',').map(forwarded_ip) {
+                match ip {
+                    Some(ip) => {
+                        client = ip;
+                        if !self.trusted(&ip) {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+        client
+    }
+}
+
+/// parses an address from an `X-Forwarded-For` style list entry or
+/// an RFC 7239 `Forwarded` element, e.g. `for="[2001:db8::1]:4711"`
+fn forwarded_ip(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    let value = match entry
+        .split(';')
+        .map(|pair| pair.trim())
+        .find(|pair| pair.len() > 4 && pair[..4].eq_ignore_ascii_case("for="))
+    {
+        Some(pair) => &pair[4..],
+        None => entry,
+    };
+    let value = value.trim_matches('"');
+    if value.starts_with('[') {
+        return value[1..]
+            .splitn(2, ']')
+            .next()
+            .and_then(|ip| ip.parse().ok());
+    }
+    value.parse().ok().or_else(|| {
+        // strip a port from ipv4 addresses
+        value.rsplitn(2, ':').nth(1).and_then(|ip| ip.parse().ok())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_ranges() {
+        let range = "10.0.0.0/8".parse::<IpRange>().unwrap();
+        assert!(range.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!range.contains(&"11.0.0.1".parse().unwrap()));
+        let range = "2001:db8::/32".parse::<IpRange>().unwrap();
+        assert!(range.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!range.contains(&"2001:db9::1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<IpRange>()
+            .unwrap()
+            .contains(&"1.2.3.4".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn client_ips() {
+        let proxies = TrustedProxies::new("X-Forwarded-For", vec!["10.0.0.0/8".parse().unwrap()]);
+        let proxy = "10.0.0.1".parse().unwrap();
+        let client = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            client,
+            proxies.client_ip(proxy, Some("198.51.100.1, 203.0.113.7, 10.0.0.2"))
+        );
+        assert_eq!(proxy, proxies.client_ip(proxy, None));
+        assert_eq!(client, proxies.client_ip(client, Some("198.51.100.1")));
+        assert_eq!(
+            client,
+            proxies.client_ip(proxy, Some("for=\"203.0.113.7:4711\";proto=https"))
+        );
+        assert_eq!(
+            "2001:db8::1".parse::<IpAddr>().unwrap(),
+            proxies.client_ip(proxy, Some("for=\"[2001:db8::1]:4711\""))
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Lifecycle callbacks for metrics, tracing and audit integrations
+
+use super::{Delivery, Error};
+use stats::Recorder;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Observes deliveries as they move through a hub
+///
+/// All callbacks default to doing nothing so implementations
+/// only need to provide the ones they are interested in
+pub trait Observer: Send + Sync {
+    /// called once a delivery has been read and parsed, before any hook runs.
+    /// `Delivery::elapsed` reports how long it waited to be processed
+    fn on_received(&self, _delivery: &Delivery) {}
+
+    /// called when an authenticating hook accepts a delivery, once
+    /// however many of its hooks do
+    fn on_verified(&self, _delivery: &Delivery) {}
+
+    /// called before a hook handles a delivery
+    fn on_hook_start(&self, _delivery: &Delivery) {}
+
+    /// called after a hook has handled a delivery with the time the hook took
+    fn on_hook_end(&self, _delivery: &Delivery, _elapsed: Duration) {}
+
+    /// called once every hook has handled a delivery. `Delivery::elapsed`
+    /// reports the total time taken since the delivery was received
+    fn on_completed(&self, _delivery: &Delivery) {}
+
+    /// called when a delivery is rejected or could not be processed,
+    /// with the cause of the failure
+    fn on_error(&self, _id: &str, _event: &str, _error: &Error) {}
+}
+
+/// The observers registered with a hub, along with its statistics
+#[derive(Default)]
+pub struct Observers {
+    observers: Vec<Box<Observer>>,
+    pub stats: Arc<Recorder>,
+}
+
+impl Observers {
+    pub fn push(&mut self, observer: Box<Observer>) {
+        self.observers.push(observer)
+    }
+
+    pub fn received(&self, delivery: &Delivery) {
+        self.stats.received(delivery);
+        for observer in &self.observers {
+            observer.on_received(delivery)
+        }
+    }
+
+    pub fn verified(&self, delivery: &Delivery) {
+        for observer in &self.observers {
+            observer.on_verified(delivery)
+        }
+    }
+
+    pub fn hook_start(&self, delivery: &Delivery) {
+        for observer in &self.observers {
+            observer.on_hook_start(delivery)
+        }
+    }
+
+    pub fn hook_end(&self, delivery: &Delivery, elapsed: Duration) {
+        for observer in &self.observers {
+            observer.on_hook_end(delivery, elapsed)
+        }
+    }
+
+    pub fn completed(&self, delivery: &Delivery) {
+        self.stats.completed(delivery);
+        for observer in &self.observers {
+            observer.on_completed(delivery)
+        }
+    }
+
+    pub fn fail(&self, id: &str, event: &str, error: &Error) {
+        self.stats.failed(id);
+        for observer in &self.observers {
+            observer.on_error(id, event, error)
+        }
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observers({})", self.observers.len())
+    }
+}
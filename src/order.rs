@@ -0,0 +1,196 @@
+//! Orders hooks which declared the hooks they run after
+//!
+//! Hooks registered with `Hub::handle_after` run once the named hooks
+//! interested in the same delivery have, e.g. a notifier after the hook
+//! enriching deliveries it reads. Dependencies are checked for cycles as
+//! they're declared, and hooks which don't depend on each other keep the
+//! order they were registered in
+
+use super::{Hook, Hub};
+use extract::IntoHook;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+/// A hook as registered with a hub
+pub struct Registered {
+    pub hook: Arc<Hook>,
+    pub name: Option<String>,
+}
+
+/// Hooks whose dependencies would make them run after themselves
+#[derive(Debug, Clone, PartialEq)]
+pub struct DependencyCycle(Vec<String>);
+
+impl DependencyCycle {
+    /// the names of the hooks in the cycle, starting and ending with the
+    /// hook whose dependencies were rejected
+    pub fn hooks(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl fmt::Display for DependencyCycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "hooks would run after themselves: {}",
+            self.0.join(" -> ")
+        )
+    }
+}
+
+impl Error for DependencyCycle {}
+
+/// The names of the hooks named hooks run after
+#[derive(Default)]
+pub struct Dependencies(HashMap<String, Vec<String>>);
+
+impl Dependencies {
+    /// declares that `name` runs after the hooks named `after`,
+    /// unless any of them already runs after it
+    pub fn declare(&mut self, name: &str, after: &[&str]) -> Result<(), DependencyCycle> {
+        for dependency in after {
+            if let Some(mut cycle) = self.path(dependency, name) {
+                cycle.insert(0, name.to_owned());
+                return Err(DependencyCycle(cycle));
+            }
+        }
+        let dependencies = self.0.entry(name.to_owned()).or_insert_with(Vec::new);
+        for dependency in after {
+            if !dependencies.iter().any(|known| known == dependency) {
+                dependencies.push((*dependency).to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// the names from `from` to `to` along dependencies, if `from` runs after `to`
+    fn path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        if from == to {
+            return Some(vec![to.to_owned()]);
+        }
+        self.0.get(from)?.iter().find_map(|dependency| {
+            self.path(dependency, to).map(|mut path| {
+                path.insert(0, from.to_owned());
+                path
+            })
+        })
+    }
+
+    /// whether no hook runs after another
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// the names of the hooks `name` runs after
+    pub fn after(&self, name: &str) -> &[String] {
+        self.0.get(name).map(|after| &after[..]).unwrap_or(&[])
+    }
+
+    fn depends(&self, hook: &Registered, on: &Registered) -> bool {
+        match (&hook.name, &on.name) {
+            (&Some(ref name), &Some(ref on)) => self
+                .0
+                .get(name)
+                .map(|dependencies| dependencies.contains(on))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// orders hooks so they run after their dependencies, pairing each with
+    /// its wave: the hooks of a wave only depend on those of earlier ones
+    pub fn schedule<'a>(&self, hooks: Vec<&'a Registered>) -> Vec<(usize, &'a Registered)> {
+        if self.0.is_empty() {
+            return hooks.into_iter().map(|hook| (0, hook)).collect();
+        }
+        let mut pending = hooks;
+        let mut scheduled: Vec<(usize, &Registered)> = Vec::with_capacity(pending.len());
+        while !pending.is_empty() {
+            // cycles are rejected as they're declared, so there's always one
+            let ready = pending
+                .iter()
+                .position(|hook| !pending.iter().any(|other| self.depends(hook, other)))
+                .unwrap_or(0);
+            let hook = pending.remove(ready);
+            let wave = scheduled
+                .iter()
+                .filter(|&&(_, other)| self.depends(hook, other))
+                .map(|&(wave, _)| wave + 1)
+                .max()
+                .unwrap_or(0);
+            scheduled.push((wave, hook));
+        }
+        scheduled
+    }
+}
+
+impl Hub {
+    /// adds a hook which other hooks may run after under `name`
+    pub fn handle_named<H, Args>(&mut self, event: &str, name: &str, hook: H)
+    where
+        H: IntoHook<Args>,
+    {
+        self.hooks
+            .entry(event.to_owned())
+            .or_insert_with(Vec::new)
+            .push(Registered {
+                hook: Arc::new(hook.into_hook()),
+                name: Some(name.to_owned()),
+            });
+    }
+
+    /// adds a hook named `name` which runs after the hooks named `after`
+    /// which are interested in the same deliveries, rejecting it if any
+    /// of those already run after it
+    pub fn handle_after<H, Args>(
+        &mut self,
+        event: &str,
+        name: &str,
+        after: &[&str],
+        hook: H,
+    ) -> Result<(), DependencyCycle>
+    where
+        H: IntoHook<Args>,
+    {
+        self.dependencies.declare(name, after)?;
+        self.handle_named(event, name, hook);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use {Delivery, Execution};
+
+    #[test]
+    fn hooks_run_after_dependencies() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let ran = ran.clone();
+            move |_: &Delivery| ran.lock().unwrap().push(name)
+        };
+        let mut hub = Hub::new();
+        hub.handle_after("push", "notify", &["enrich"], record("notify"))
+            .unwrap();
+        hub.handle("push", record("audit"));
+        hub.handle_named("*", "enrich", record("enrich"));
+        let cycle = hub
+            .handle_after("push", "enrich", &["notify"], record("never"))
+            .unwrap_err();
+        assert_eq!(&["enrich", "notify", "enrich"], cycle.hooks());
+
+        let delivery = || Delivery::new("id", "push", "{}", None);
+        hub.deliver(&mut delivery());
+        assert_eq!(vec!["audit", "enrich", "notify"], *ran.lock().unwrap());
+
+        ran.lock().unwrap().clear();
+        hub.execution(Execution::Parallel);
+        hub.deliver(&mut delivery());
+        assert_eq!(Some(&"notify"), ran.lock().unwrap().last());
+    }
+}
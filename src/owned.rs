@@ -0,0 +1,168 @@
+//! Owned, serializable deliveries
+
+use super::Delivery;
+use serde::{Serialize, Serializer};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, UNIX_EPOCH};
+
+include!(concat!(env!("OUT_DIR"), "/owned.rs"));
+
+/// headers carrying the credentials deliveries were sent with
+const CREDENTIALS: &[&str] = &["Authorization", "Proxy-Authorization", "Cookie"];
+
+impl<'a, 'b> From<&'b Delivery<'a>> for OwnedDelivery {
+    fn from(delivery: &'b Delivery<'a>) -> OwnedDelivery {
+        OwnedDelivery {
+            id: delivery.id.to_owned(),
+            event: delivery.event.to_owned(),
+            unparsed_payload: delivery.unparsed_payload.to_owned(),
+            original: delivery.original.map(|original| original.to_owned()),
+            encoded_body: delivery.encoded_body.map(|body| body.to_vec()),
+            signature: delivery.signature.map(|signature| signature.to_owned()),
+            peer: delivery.peer.cloned(),
+            headers: delivery.headers.to_vec(),
+            query: delivery.query.map(|query| query.to_owned()),
+            remote_addr: delivery.remote_addr,
+            real_ip: delivery.real_ip,
+            received_at: delivery
+                .received_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl OwnedDelivery {
+    /// borrows a delivery which may be handed to a hub with `Hub::deliver`.
+    /// the time it was originally received is kept but `Delivery::elapsed`
+    /// is measured from the time this is called
+    pub fn delivery(&self) -> Delivery {
+        let mut delivery = Delivery::new(
+            &self.id,
+            &self.event,
+            &self.unparsed_payload,
+            self.signature.as_ref().map(|signature| signature.as_str()),
+        );
+        delivery.original = self.original.as_ref().map(|original| original.as_str());
+        delivery.encoded_body = self.encoded_body.as_ref().map(|body| &body[..]);
+        delivery.peer = self.peer.as_ref();
+        delivery.headers = &self.headers;
+        delivery.query = self.query.as_ref().map(|query| query.as_str());
+        delivery.remote_addr = self.remote_addr;
+        delivery.real_ip = self.real_ip;
+        delivery.received_at = UNIX_EPOCH + self.received_at;
+        delivery
+    }
+
+    /// this delivery without the credentials it was sent with: the
+    /// `Authorization`, `Proxy-Authorization` and `Cookie` headers, and the
+    /// query string, which may carry a token. signatures are kept, as they
+    /// only vouch for the payload
+    pub fn without_credentials(mut self) -> OwnedDelivery {
+        self.headers.retain(|&(ref name, _)| !credential(name));
+        self.query = None;
+        self
+    }
+}
+
+/// whether a header carries the credentials a delivery was sent with
+pub fn credential(name: &str) -> bool {
+    CREDENTIALS
+        .iter()
+        .any(|credential| name.eq_ignore_ascii_case(credential))
+}
+
+/// deliveries serialize as their `OwnedDelivery`, which they may be
+/// deserialized as, without the credentials they were sent with so they
+/// aren't written to disk or streamed to others
+impl<'a> Serialize for Delivery<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        OwnedDelivery::from(self)
+            .without_credentials()
+            .serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::auth::{Authenticator, Signature};
+    use super::*;
+    use serde_json;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn owned_deliveries() {
+        let headers = vec![("X-Github-Event".to_owned(), "public".to_owned())];
+        let mut delivery = Delivery::new("id", "public", include_str!("../data/public.json"), None);
+        delivery.headers = &headers;
+        delivery.real_ip = Some("192.30.252.1".parse().unwrap());
+        let json = serde_json::to_string(&delivery).unwrap();
+        let owned = serde_json::from_str::<OwnedDelivery>(&json).unwrap();
+        assert_eq!(OwnedDelivery::from(&delivery), owned);
+
+        let reloaded = owned.delivery();
+        assert_eq!(delivery.id, reloaded.id);
+        assert_eq!(Some("public"), reloaded.header("x-github-event"));
+        assert_eq!(delivery.real_ip, reloaded.real_ip);
+        assert_eq!(
+            delivery.received_at.duration_since(UNIX_EPOCH).unwrap(),
+            reloaded.received_at.duration_since(UNIX_EPOCH).unwrap()
+        );
+        assert!(reloaded.payload().is_some());
+    }
+
+    #[test]
+    fn serialized_credentials() {
+        let headers = vec![
+            ("authorization".to_owned(), "Bearer token".to_owned()),
+            ("X-Github-Event".to_owned(), "public".to_owned()),
+        ];
+        let mut delivery = Delivery::new("id", "public", "{}", None);
+        delivery.headers = &headers;
+        delivery.query = Some("token=s3cret");
+        let json = serde_json::to_string(&delivery).unwrap();
+        assert!(!json.contains("token"));
+        let owned = serde_json::from_str::<OwnedDelivery>(&json).unwrap();
+        assert_eq!(&headers[1..], &owned.headers[..]);
+        assert_eq!(None, owned.query);
+    }
+
+    #[test]
+    fn moved_deliveries() {
+        let (sender, receiver) = mpsc::channel::<OwnedDelivery>();
+        let worker = thread::spawn(move || {
+            let owned = receiver.recv().unwrap();
+            let delivery = owned.delivery();
+            delivery
+                .json()
+                .and_then(|payload| payload.pointer("/repository/full_name"))
+                .and_then(|name| name.as_str().map(str::to_owned))
+        });
+        let payload = include_str!("../data/public.json").to_owned();
+        let delivery = Delivery::new("id", "public", &payload, None);
+        sender.send(delivery.into_owned()).unwrap();
+        drop(payload);
+        assert_eq!(
+            Some("Codertocat/Hello-World".to_owned()),
+            worker.join().unwrap()
+        );
+    }
+
+    #[test]
+    fn transformed_deliveries() {
+        let signature = "sha1=5d61605c3feea9799210ddcb71307d4ba264225f";
+        let delivery = Delivery::new("id", "ping", "{}", Some(signature));
+        let transformed = delivery.with_payload(r#"{"transformed":true}"#);
+        let json = serde_json::to_string(&transformed.into_owned()).unwrap();
+        let owned = serde_json::from_str::<OwnedDelivery>(&json).unwrap();
+        let reloaded = owned.delivery();
+        assert_eq!(r#"{"transformed":true}"#, reloaded.unparsed_payload);
+        // signatures are still verified against the payload as it was received
+        assert_eq!("{}", reloaded.original_payload());
+        assert!(Signature::new("secret").authenticate(&reloaded));
+    }
+}
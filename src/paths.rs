@@ -0,0 +1,150 @@
+//! Filtering pushes by the paths they change
+//!
+//! Lets monorepos hand pushes to the hooks of the components they touch, by
+//! matching the files each of a push's commits added, modified or removed
+//! against globs, e.g. `services/api/**`
+
+use super::filter::Filter;
+use super::Delivery;
+use glob::{MatchOptions, Pattern};
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+
+/// Error returned when compiling an invalid glob
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidGlob(String);
+
+impl fmt::Display for InvalidGlob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid glob '{}'", self.0)
+    }
+}
+
+impl Error for InvalidGlob {}
+
+/// `*` and `?` don't match across directories, `**` does
+const OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A filter matching pushes which changed files matching any of its globs
+#[derive(Debug, Clone)]
+pub struct Paths {
+    globs: Vec<Pattern>,
+    ignored: Vec<Pattern>,
+}
+
+impl Paths {
+    pub fn new<I, G>(globs: I) -> Result<Paths, InvalidGlob>
+    where
+        I: IntoIterator<Item = G>,
+        G: AsRef<str>,
+    {
+        Ok(Paths {
+            globs: compile(globs)?,
+            ignored: Vec::new(),
+        })
+    }
+
+    /// doesn't count changes to files matching these globs,
+    /// e.g. a component's `**/*.md` documentation
+    pub fn ignore<I, G>(mut self, globs: I) -> Result<Paths, InvalidGlob>
+    where
+        I: IntoIterator<Item = G>,
+        G: AsRef<str>,
+    {
+        self.ignored.extend(compile(globs)?);
+        Ok(self)
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        self.globs
+            .iter()
+            .any(|glob| glob.matches_with(path, OPTIONS))
+            && !self
+                .ignored
+                .iter()
+                .any(|glob| glob.matches_with(path, OPTIONS))
+    }
+}
+
+fn compile<I, G>(globs: I) -> Result<Vec<Pattern>, InvalidGlob>
+where
+    I: IntoIterator<Item = G>,
+    G: AsRef<str>,
+{
+    globs
+        .into_iter()
+        .map(|glob| Pattern::new(glob.as_ref()).map_err(|_| InvalidGlob(glob.as_ref().to_owned())))
+        .collect()
+}
+
+impl Filter for Paths {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        let commits = match delivery
+            .json()
+            .and_then(|payload| payload.get("commits"))
+            .and_then(Value::as_array)
+        {
+            Some(commits) => commits,
+            None => return false,
+        };
+        commits
+            .iter()
+            .flat_map(|commit| {
+                ["added", "modified", "removed"]
+                    .iter()
+                    .filter_map(move |change| commit.get(change))
+            })
+            .filter_map(Value::as_array)
+            .flat_map(|paths| paths.iter())
+            .filter_map(Value::as_str)
+            .any(|path| self.matches_path(path))
+    }
+
+    fn describe(&self) -> String {
+        let globs = |patterns: &[Pattern]| {
+            patterns
+                .iter()
+                .map(Pattern::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if self.ignored.is_empty() {
+            format!("paths {}", globs(&self.globs))
+        } else {
+            format!(
+                "paths {} except {}",
+                globs(&self.globs),
+                globs(&self.ignored)
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_paths() {
+        let push = r#"{"commits": [
+            {"added": ["services/web/index.html"], "modified": [], "removed": []},
+            {"added": [], "modified": ["services/api/src/main.rs"], "removed": ["README.md"]}
+        ]}"#;
+        let push = Delivery::new("id", "push", push, None);
+        assert!(Paths::new(vec!["services/api/**"]).unwrap().matches(&push));
+        assert!(Paths::new(vec!["*.md"]).unwrap().matches(&push));
+        assert!(!Paths::new(vec!["services/*.rs"]).unwrap().matches(&push));
+        assert!(!Paths::new(vec!["services/db/**"]).unwrap().matches(&push));
+        let web = Paths::new(vec!["services/web/**"]).unwrap();
+        assert!(!web.ignore(vec!["**/*.html"]).unwrap().matches(&push));
+        assert!(!Paths::new(vec!["**"])
+            .unwrap()
+            .matches(&Delivery::new("id", "ping", "{}", None)));
+        assert!(Paths::new(vec!["services/[api"]).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+//! Filtering deliveries by the people behind them
+//!
+//! Matches the sender of a delivery, or the authors or committers of a
+//! push's commits, against allow and deny lists, e.g. to ignore pushes of a
+//! release bot or restrict automation to trusted users. Commits name whoever
+//! their author says they are, so only the logins Github resolved them to
+//! are allowed, while names and emails may be denied. With the
+//! `registration` feature people may also be required to be members of an
+//! organization, which is looked up with Github's api
+
+#[cfg(feature = "registration")]
+pub use self::membership::Membership;
+use super::filter::Filter;
+use super::Delivery;
+use serde_json::Value;
+#[cfg(feature = "registration")]
+use std::collections::HashMap;
+#[cfg(feature = "registration")]
+use std::time::{Duration, Instant};
+
+/// the most memberships remembered at once
+#[cfg(feature = "registration")]
+const MAX_KNOWN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Who {
+    Sender,
+    Authors,
+    Committers,
+}
+
+/// A filter matching deliveries whose people are all allowed
+pub struct People {
+    who: Who,
+    allowed: Vec<String>,
+    denied: Vec<String>,
+    #[cfg(feature = "registration")]
+    members: Option<Membership>,
+}
+
+/// a person, by their login and, for commit authors
+/// and committers, their email and name
+struct Person<'a> {
+    login: Option<&'a str>,
+    email: Option<&'a str>,
+    name: Option<&'a str>,
+}
+
+impl<'a> Person<'a> {
+    fn from(value: &'a Value, login: &str) -> Person<'a> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        Person {
+            login: field(login),
+            email: field("email"),
+            name: field("name"),
+        }
+    }
+
+    fn is(&self, identity: &str) -> bool {
+        [self.login, self.email, self.name]
+            .iter()
+            .any(|field| *field == Some(identity))
+    }
+
+    /// whether this is the person with a login, as Github resolved it
+    fn has_login(&self, login: &str) -> bool {
+        self.login == Some(login)
+    }
+}
+
+impl People {
+    /// matches deliveries by their `sender`
+    pub fn senders() -> People {
+        People::of(Who::Sender)
+    }
+
+    /// matches pushes by the authors of their commits
+    pub fn authors() -> People {
+        People::of(Who::Authors)
+    }
+
+    /// matches pushes by the committers of their commits
+    pub fn committers() -> People {
+        People::of(Who::Committers)
+    }
+
+    fn of(who: Who) -> People {
+        People {
+            who: who,
+            allowed: Vec::new(),
+            denied: Vec::new(),
+            #[cfg(feature = "registration")]
+            members: None,
+        }
+    }
+
+    /// only matches people with one of these logins. commit authors and
+    /// committers without a Github account, who have no login, aren't allowed
+    pub fn allow<I, L>(mut self, people: I) -> People
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<String>,
+    {
+        self.allowed.extend(people.into_iter().map(Into::into));
+        self
+    }
+
+    /// doesn't match people with one of these logins, emails or names
+    pub fn deny<I, L>(mut self, people: I) -> People
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<String>,
+    {
+        self.denied.extend(people.into_iter().map(Into::into));
+        self
+    }
+
+    /// only matches members of an organization, see `Membership`.
+    /// allowed people needn't be members
+    #[cfg(feature = "registration")]
+    pub fn members(mut self, membership: Membership) -> People {
+        self.members = Some(membership);
+        self
+    }
+
+    fn people<'a>(&self, payload: &'a Value) -> Vec<Person<'a>> {
+        let role = match self.who {
+            Who::Sender => {
+                return payload
+                    .get("sender")
+                    .map(|sender| vec![Person::from(sender, "login")])
+                    .unwrap_or_default()
+            }
+            Who::Authors => "author",
+            Who::Committers => "committer",
+        };
+        payload
+            .get("commits")
+            .and_then(Value::as_array)
+            .map(|commits| {
+                commits
+                    .iter()
+                    .filter_map(|commit| commit.get(role))
+                    .map(|person| Person::from(person, "username"))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn admits(&self, person: &Person) -> bool {
+        if self.denied.iter().any(|denied| person.is(denied)) {
+            return false;
+        }
+        if self.allowed.iter().any(|allowed| person.has_login(allowed)) {
+            return true;
+        }
+        #[cfg(feature = "registration")]
+        {
+            if let Some(ref members) = self.members {
+                return person
+                    .login
+                    .map(|login| members.member(login))
+                    .unwrap_or(false);
+            }
+        }
+        self.allowed.is_empty()
+    }
+}
+
+impl Filter for People {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        let people = match delivery.json() {
+            Some(payload) => self.people(payload),
+            None => return false,
+        };
+        !people.is_empty() && people.iter().all(|person| self.admits(person))
+    }
+
+    fn describe(&self) -> String {
+        let mut description = match self.who {
+            Who::Sender => "senders",
+            Who::Authors => "authors",
+            Who::Committers => "committers",
+        }
+        .to_owned();
+        if !self.allowed.is_empty() {
+            description = format!("{} {}", description, self.allowed.join(", "));
+        }
+        #[cfg(feature = "registration")]
+        {
+            if let Some(ref members) = self.members {
+                description = format!("{} in {}", description, members.org());
+            }
+        }
+        if !self.denied.is_empty() {
+            description = format!("{} except {}", description, self.denied.join(", "));
+        }
+        description
+    }
+}
+
+/// Memberships looked up recently, forgotten after `ttl` so people removed
+/// from an organization stop passing, and capped at `MAX_KNOWN` people
+#[cfg(feature = "registration")]
+struct Known {
+    ttl: Duration,
+    members: HashMap<String, (bool, Instant)>,
+}
+
+#[cfg(feature = "registration")]
+impl Known {
+    fn new(ttl: Duration) -> Known {
+        Known {
+            ttl: ttl,
+            members: HashMap::new(),
+        }
+    }
+
+    /// whether `login` was a member, if looked up within `ttl` of `now`
+    fn get(&self, login: &str, now: Instant) -> Option<bool> {
+        self.members
+            .get(login)
+            .filter(|&&(_, at)| now.duration_since(at) < self.ttl)
+            .map(|&(member, _)| member)
+    }
+
+    /// remembers `login`'s membership, forgetting expired lookups and then
+    /// the oldest once `MAX_KNOWN` people are remembered
+    fn insert(&mut self, login: &str, member: bool, now: Instant) {
+        if self.members.len() >= MAX_KNOWN && !self.members.contains_key(login) {
+            let ttl = self.ttl;
+            self.members
+                .retain(|_, &mut (_, at)| now.duration_since(at) < ttl);
+            if self.members.len() >= MAX_KNOWN {
+                let oldest = self
+                    .members
+                    .iter()
+                    .min_by_key(|&(_, &(_, at))| at)
+                    .map(|(login, _)| login.clone());
+                if let Some(oldest) = oldest {
+                    self.members.remove(&oldest);
+                }
+            }
+        }
+        self.members.insert(login.to_owned(), (member, now));
+    }
+}
+
+#[cfg(feature = "registration")]
+mod membership {
+    use super::super::tls::TlsClient;
+    use super::Known;
+    use hyper;
+    use hyper::header::{Authorization, UserAgent};
+    use hyper::net::HttpsConnector;
+    use hyper::status::StatusCode;
+    use hyper::Client;
+    use secret::Secret;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    const API: &str = "https://api.github.com";
+    /// how long looked up memberships are remembered by default
+    const TTL: Duration = Duration::from_secs(10 * 60);
+    const USER_AGENT: &str = concat!("afterparty/", env!("CARGO_PKG_VERSION"));
+
+    /// Looks up whether people are members of an organization with
+    /// Github's api, remembering the answers for ten minutes
+    pub struct Membership {
+        org: String,
+        token: Secret,
+        api: String,
+        client: Client,
+        known: Mutex<Known>,
+    }
+
+    impl Membership {
+        /// looks up members of `org` with a `token` which may read its members
+        pub fn new<O, T>(org: O, token: T) -> hyper::Result<Membership>
+        where
+            O: Into<String>,
+            T: Into<String>,
+        {
+            let tls = TlsClient::new().map_err(|err| hyper::Error::Ssl(Box::new(err)))?;
+            Ok(Membership {
+                org: org.into(),
+                token: Secret::new(token),
+                api: API.to_owned(),
+                client: Client::with_connector(HttpsConnector::new(tls)),
+                known: Mutex::new(Known::new(TTL)),
+            })
+        }
+
+        /// uses another api, e.g. a Github Enterprise server's
+        /// `https://github.example.com/api/v3`
+        pub fn api<A: Into<String>>(mut self, api: A) -> Membership {
+            self.api = api.into().trim_end_matches('/').to_owned();
+            self
+        }
+
+        /// remembers looked up memberships for `ttl` instead of ten minutes
+        pub fn ttl(mut self, ttl: Duration) -> Membership {
+            self.known = Mutex::new(Known::new(ttl));
+            self
+        }
+
+        /// the organization whose members are looked up
+        pub fn org(&self) -> &str {
+            &self.org
+        }
+
+        /// whether `login` is a member. failed lookups count as not,
+        /// and are tried again next time
+        pub fn member(&self, login: &str) -> bool {
+            if let Some(member) = self.known.lock().unwrap().get(login, Instant::now()) {
+                return member;
+            }
+            let url = format!("{}/orgs/{}/members/{}", self.api, self.org, login);
+            let response = self
+                .client
+                .get(&url)
+                .header(Authorization(format!("token {}", self.token.as_str())))
+                .header(UserAgent(USER_AGENT.to_owned()))
+                .send();
+            let member = match response.map(|response| response.status) {
+                Ok(StatusCode::NoContent) => true,
+                Ok(StatusCode::NotFound) | Ok(StatusCode::Found) => false,
+                Ok(status) => {
+                    warn!(
+                        "failed to look up {} membership of {}: {}",
+                        self.org, login, status
+                    );
+                    return false;
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to look up {} membership of {}: {}",
+                        self.org, login, err
+                    );
+                    return false;
+                }
+            };
+            self.known
+                .lock()
+                .unwrap()
+                .insert(login, member, Instant::now());
+            member
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_people() {
+        let push = r#"{
+            "sender": {"login": "octocat"},
+            "commits": [
+                {"author": {"name": "Mona", "email": "mona@example.com", "username": "mona"},
+                 "committer": {"name": "GitHub", "email": "noreply@github.com"}},
+                {"author": {"name": "release-bot", "email": "bot@example.com"},
+                 "committer": {"name": "GitHub", "email": "noreply@github.com"}}
+            ]
+        }"#;
+        let push = Delivery::new("id", "push", push, None);
+        assert!(People::senders().allow(vec!["octocat"]).matches(&push));
+        assert!(!People::senders().deny(vec!["octocat"]).matches(&push));
+        assert!(People::senders().deny(vec!["release-bot"]).matches(&push));
+        assert!(!People::authors().deny(vec!["release-bot"]).matches(&push));
+        assert!(!People::authors().allow(vec!["mona"]).matches(&push));
+        assert!(!People::authors()
+            .allow(vec!["mona", "bot@example.com", "release-bot"])
+            .matches(&push));
+        assert!(!People::authors()
+            .deny(vec!["bot@example.com"])
+            .matches(&push));
+        assert!(!People::committers()
+            .allow(vec!["noreply@github.com", "GitHub"])
+            .matches(&push));
+        let mona = r#"{"commits": [{"author": {"name": "Mona", "username": "mona"}}]}"#;
+        let mona = Delivery::new("id", "push", mona, None);
+        assert!(People::authors().allow(vec!["mona"]).matches(&mona));
+        assert!(!People::authors().allow(vec!["Mona"]).matches(&mona));
+        let ping = Delivery::new("id", "ping", "{}", None);
+        assert!(!People::authors().matches(&ping));
+    }
+
+    #[cfg(feature = "registration")]
+    #[test]
+    fn known_members() {
+        let (now, ttl) = (Instant::now(), Duration::from_secs(60));
+        let mut known = Known::new(ttl);
+        known.insert("mona", true, now);
+        assert_eq!(Some(true), known.get("mona", now));
+        assert_eq!(None, known.get("mona", now + ttl));
+        assert_eq!(None, known.get("octocat", now));
+        for n in 0..MAX_KNOWN + 10 {
+            known.insert(&n.to_string(), false, now + Duration::from_millis(n as u64));
+        }
+        let later = now + Duration::from_secs(2);
+        assert_eq!(MAX_KNOWN, known.members.len());
+        assert_eq!(None, known.get("mona", later));
+        assert_eq!(None, known.get("9", later));
+        assert_eq!(Some(false), known.get("10", later));
+    }
+}
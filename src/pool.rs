@@ -0,0 +1,108 @@
+//! Reusable request body buffers
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// number of idle buffers kept by default
+const BUFFERS: usize = 32;
+
+/// largest buffer kept by default. larger buffers, grown by
+/// unusually large deliveries, are freed rather than reused
+const MAX_CAPACITY: usize = 1024 * 1024;
+
+/// A pool of buffers which request bodies are read into, saving an
+/// allocation, and the reallocations of growing it, per delivery
+pub struct BufferPool {
+    idle: Mutex<Vec<Vec<u8>>>,
+    buffers: usize,
+    max_capacity: usize,
+}
+
+/// A buffer which is returned to its pool, emptied, when dropped
+pub struct Buffer<'a> {
+    pool: &'a BufferPool,
+    buffer: Vec<u8>,
+}
+
+impl BufferPool {
+    /// keeps up to `buffers` idle buffers of up to `max_capacity` bytes
+    pub fn new(buffers: usize, max_capacity: usize) -> BufferPool {
+        BufferPool {
+            idle: Mutex::new(Vec::with_capacity(buffers)),
+            buffers: buffers,
+            max_capacity: max_capacity,
+        }
+    }
+
+    /// takes an idle buffer, or allocates a new one if there are none
+    pub fn take(&self) -> Buffer {
+        let buffer = self.idle.lock().unwrap().pop().unwrap_or_default();
+        Buffer {
+            pool: self,
+            buffer: buffer,
+        }
+    }
+
+    fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > self.max_capacity {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.buffers {
+            buffer.clear();
+            idle.push(buffer)
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new(BUFFERS, MAX_CAPACITY)
+    }
+}
+
+impl<'a> Deref for Buffer<'a> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl<'a> DerefMut for Buffer<'a> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl<'a> Drop for Buffer<'a> {
+    fn drop(&mut self) {
+        let buffer = ::std::mem::replace(&mut self.buffer, Vec::new());
+        self.pool.give(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_pools() {
+        let pool = BufferPool::new(1, 64);
+        let reused = {
+            let mut buffer = pool.take();
+            buffer.extend_from_slice(b"payload");
+            buffer.as_ptr()
+        };
+        {
+            let buffer = pool.take();
+            assert!(buffer.is_empty());
+            assert_eq!(reused, buffer.as_ptr());
+            // the pool only keeps one idle buffer
+            let _other = pool.take();
+        }
+        assert_eq!(1, pool.idle.lock().unwrap().len());
+        pool.take().reserve(128);
+        assert_eq!(0, pool.idle.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,177 @@
+//! Dropping root privileges once a hub's sockets are bound
+//!
+//! Binding ports below 1024, e.g. 443, takes root, but processing deliveries
+//! needn't. `ServerConfig::run_as` names the user, and `run_as_group` the
+//! group, a hub switches to after it starts listening and before it handles
+//! any delivery, taking on the user's supplementary groups.
+//!
+//! Files the hub reads or writes later, e.g. certificates `Tls::sni` reloads
+//! or those `Acme` renews, may only be accessible to root. The hub fails to
+//! start unless the user may access those named with `ServerConfig::reads`
+//! and `ServerConfig::writes`, rather than failing once they change
+
+use libc::{self, c_char, c_int, gid_t, uid_t};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+/// the size of buffers lookups start with, doubled while they're too small
+const BUFFER_SIZE: usize = 1024;
+
+/// switches the process to `user` and `group`, the group defaulting to the
+/// user's primary group. fails when privileges could be regained afterwards
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    let user = match user {
+        Some(user) => Some((user, lookup_user(user)?)),
+        None => None,
+    };
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, (_, gid))| gid),
+    };
+    if let Some(gid) = gid {
+        let groups = match user {
+            Some((name, _)) => unsafe { libc::initgroups(c_string(name)?.as_ptr(), gid as _) },
+            None => unsafe { libc::setgroups(1, &gid) },
+        };
+        check(groups)?;
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some((_, (uid, _))) = user {
+        check(unsafe { libc::setuid(uid) })?;
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "root privileges could be regained",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// fails unless the process may read `path`, or write it when `write`
+/// is set. paths to write which don't exist yet need a writable parent
+pub fn accessible(path: &Path, write: bool) -> io::Result<()> {
+    let checked = match path.parent() {
+        Some(parent) if write && !path.exists() => parent,
+        _ => path,
+    };
+    let mode = match (write, checked.is_dir()) {
+        (false, false) => libc::R_OK,
+        (false, true) => libc::R_OK | libc::X_OK,
+        (true, false) => libc::W_OK,
+        (true, true) => libc::W_OK | libc::X_OK,
+    };
+    let name = CString::new(checked.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if unsafe { libc::access(name.as_ptr(), mode) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(
+            err.kind(),
+            format!(
+                "{} isn't {}: {}",
+                checked.display(),
+                if write { "writable" } else { "readable" },
+                err
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// the uid and primary gid of the user called `name`
+fn lookup_user(name: &str) -> io::Result<(uid_t, gid_t)> {
+    let name = c_string(name)?;
+    let mut buffer = vec![0 as c_char; BUFFER_SIZE];
+    loop {
+        let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+        let mut found = ptr::null_mut();
+        let looked_up = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        };
+        match looked_up {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if found.is_null() => return Err(not_found("user", &name)),
+            0 => return Ok((passwd.pw_uid, passwd.pw_gid)),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// the gid of the group called `name`
+fn lookup_group(name: &str) -> io::Result<gid_t> {
+    let name = c_string(name)?;
+    let mut buffer = vec![0 as c_char; BUFFER_SIZE];
+    loop {
+        let mut group = unsafe { mem::zeroed::<libc::group>() };
+        let mut found = ptr::null_mut();
+        let looked_up = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut group,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        };
+        match looked_up {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if found.is_null() => return Err(not_found("group", &name)),
+            0 => return Ok(group.gr_gid),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+fn c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+fn not_found(kind: &str, name: &CString) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no {} called '{}'", kind, name.to_string_lossy()),
+    )
+}
+
+fn check(result: c_int) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privilege_lookups() {
+        assert_eq!(0, lookup_user("root").unwrap().0);
+        assert_eq!(
+            0,
+            lookup_group("root")
+                .or_else(|_| lookup_group("wheel"))
+                .unwrap()
+        );
+        let err = lookup_user("no-such-afterparty-user").unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert!(drop_privileges(None, None).is_ok());
+        assert!(accessible(Path::new("/"), false).is_ok());
+        let missing = Path::new("/no-such-afterparty-dir/file");
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            accessible(missing, false).unwrap_err().kind()
+        );
+    }
+}
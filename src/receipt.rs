@@ -0,0 +1,127 @@
+//! Telling senders what became of their deliveries
+//!
+//! Hubs respond to deliveries with a `Receipt`, which Github shows in the
+//! delivery log of the webhook's settings page, and which probes like
+//! `selftest` check. Duplicates are still handed to hooks, as redeliveries
+//! from the settings page reuse the GUID they were first delivered with;
+//! `auth::ReplayGuard` rejects them instead. Only the GUIDs of verified
+//! deliveries are remembered, so a forged delivery can't have the genuine
+//! one with its GUID answered as a duplicate
+
+#[cfg(any(feature = "server", feature = "lambda"))]
+use super::Delivery;
+#[cfg(any(feature = "server", feature = "lambda"))]
+use std::collections::{HashSet, VecDeque};
+#[cfg(any(feature = "server", feature = "lambda"))]
+use std::sync::Mutex;
+
+include!(concat!(env!("OUT_DIR"), "/receipt.rs"));
+
+/// number of GUIDs remembered to recognize duplicates by
+#[cfg(any(feature = "server", feature = "lambda"))]
+const REMEMBERED: usize = 1024;
+
+/// the receipt of a delivery handed to `hooks` registered hooks,
+/// which `verified` it or not
+#[cfg(any(feature = "server", feature = "lambda"))]
+pub fn accepted(delivery: &str, hooks: usize, duplicate: bool, verified: bool) -> Receipt {
+    Receipt {
+        delivery: delivery.to_owned(),
+        status: if duplicate { "duplicate" } else { "accepted" }.to_owned(),
+        hooks: hooks,
+        verified: verified,
+        reason: None,
+    }
+}
+
+/// the receipt of a delivery rejected for `reason`
+#[cfg(any(feature = "server", feature = "lambda"))]
+pub fn rejected<R: Into<String>>(delivery: &str, reason: R) -> Receipt {
+    Receipt {
+        delivery: delivery.to_owned(),
+        status: "rejected".to_owned(),
+        hooks: 0,
+        verified: false,
+        reason: Some(reason.into()),
+    }
+}
+
+/// The GUIDs of the deliveries a hub received most recently
+#[cfg(any(feature = "server", feature = "lambda"))]
+#[derive(Default)]
+pub struct Recent(Mutex<Remembered>);
+
+#[cfg(any(feature = "server", feature = "lambda"))]
+#[derive(Default)]
+struct Remembered {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+#[cfg(any(feature = "server", feature = "lambda"))]
+impl Recent {
+    /// remembers a delivery's GUID, returning true if it already was
+    pub fn seen(&self, delivery: &str) -> bool {
+        let mut remembered = self.0.lock().unwrap();
+        if remembered.ids.contains(delivery) {
+            return true;
+        }
+        if remembered.order.len() == REMEMBERED {
+            if let Some(oldest) = remembered.order.pop_front() {
+                remembered.ids.remove(&oldest);
+            }
+        }
+        remembered.ids.insert(delivery.to_owned());
+        remembered.order.push_back(delivery.to_owned());
+        false
+    }
+
+    /// whether a delivery's GUID was remembered, without remembering it
+    pub fn contains(&self, delivery: &str) -> bool {
+        self.0.lock().unwrap().ids.contains(delivery)
+    }
+
+    /// whether a delivery handed to hooks is a duplicate, remembering
+    /// its GUID only if one of them verified it
+    pub fn duplicate(&self, delivery: &Delivery) -> bool {
+        if delivery.is_verified() {
+            self.seen(delivery.id)
+        } else {
+            self.contains(delivery.id)
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "server", feature = "lambda")))]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn delivery_receipts() {
+        let recent = Recent::default();
+        assert!(!recent.seen("first"));
+        assert!(recent.seen("first"));
+        for id in 0..REMEMBERED {
+            recent.seen(&id.to_string());
+        }
+        assert!(!recent.seen("first"));
+
+        let delivery = Delivery::new("second", "ping", "{}", None);
+        assert!(!recent.duplicate(&delivery));
+        assert!(!recent.contains("second"));
+        delivery.verified();
+        assert!(!recent.duplicate(&delivery));
+        assert!(recent.duplicate(&delivery));
+
+        let json = serde_json::to_string(&accepted("first", 2, true, true)).unwrap();
+        assert_eq!(
+            r#"{"delivery":"first","status":"duplicate","hooks":2,"verified":true,"reason":null}"#,
+            json
+        );
+        assert_eq!(
+            Some("too large".to_owned()),
+            rejected("second", "too large").reason
+        );
+    }
+}
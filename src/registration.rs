@@ -0,0 +1,519 @@
+//! Registering a hub's webhooks with Github
+//!
+//! A `Registration` creates, or updates, the webhook of each of its
+//! repositories and organizations so that Github delivers the events the hub
+//! has hooks for to the hub's url, signed with its secret
+//!
+//! ```no_run
+//! # use afterparty_ng::Hub;
+//! # use afterparty_ng::registration::Registration;
+//! # let hub = Hub::new();
+//! Registration::new("token", "https://hooks.example.com/")
+//!     .secret("secret")
+//!     .repo("octocat/hello-world")
+//!     .org("octo-org")
+//!     .apply(&hub)
+//!     .unwrap();
+//! ```
+//!
+//! After an outage `catch_up` hands a hub the deliveries Github couldn't
+//! deliver meanwhile, fetched from the webhooks' recent deliveries
+
+use super::link;
+use super::tls::TlsClient;
+use super::{clock, crypto, Delivery, Hub, UNKNOWN};
+use hex;
+use hyper;
+use hyper::header::{Authorization, ContentType, Headers, UserAgent};
+use hyper::method::Method;
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use hyper::Client;
+use secret::Secret;
+use serde_json::{self, Value};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::str;
+use std::time::SystemTime;
+use zeroize::{Zeroize, Zeroizing};
+
+const API: &str = "https://api.github.com";
+const USER_AGENT: &str = concat!("afterparty/", env!("CARGO_PKG_VERSION"));
+
+/// Reasons a webhook couldn't be registered
+#[derive(Debug)]
+pub enum RegistrationError {
+    /// the request to Github failed
+    Http(hyper::Error),
+    /// Github responded with an error status and message
+    Status(StatusCode, String),
+    /// Github's response wasn't json
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegistrationError::Http(ref err) => write!(f, "request failed: {}", err),
+            RegistrationError::Status(status, ref message) => {
+                write!(f, "github responded {}: {}", status, message)
+            }
+            RegistrationError::Json(ref err) => write!(f, "invalid response: {}", err),
+        }
+    }
+}
+
+impl Error for RegistrationError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            RegistrationError::Http(ref err) => Some(err),
+            RegistrationError::Status(..) => None,
+            RegistrationError::Json(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<hyper::Error> for RegistrationError {
+    fn from(err: hyper::Error) -> RegistrationError {
+        RegistrationError::Http(err)
+    }
+}
+
+impl From<serde_json::Error> for RegistrationError {
+    fn from(err: serde_json::Error) -> RegistrationError {
+        RegistrationError::Json(err)
+    }
+}
+
+/// owners of webhooks
+enum Target {
+    Repo(String),
+    Org(String),
+}
+
+impl Target {
+    /// the url listing and creating the target's webhooks
+    fn hooks(&self, api: &str) -> String {
+        match *self {
+            Target::Repo(ref repo) => format!("{}/repos/{}/hooks", api, repo),
+            Target::Org(ref org) => format!("{}/orgs/{}/hooks", api, org),
+        }
+    }
+}
+
+/// The webhook configuration of a hub
+pub struct Registration {
+    /// the `Authorization` header's value, including the token
+    authorization: Secret,
+    url: String,
+    secret: Option<Secret>,
+    api: String,
+    targets: Vec<Target>,
+}
+
+impl Registration {
+    /// registers webhooks delivering to `url`, authenticating with Github
+    /// using a personal access or app installation `token`
+    pub fn new<T, U>(token: T, url: U) -> Registration
+    where
+        T: Into<String>,
+        U: Into<String>,
+    {
+        // scrubs the token as given once it's in the header
+        let token: Zeroizing<String> = Zeroizing::new(token.into());
+        Registration {
+            authorization: Secret::new(format!("token {}", token.as_str())),
+            url: url.into(),
+            secret: None,
+            api: API.to_owned(),
+            targets: Vec::new(),
+        }
+    }
+
+    /// signs deliveries with a secret, which the hub's hooks
+    /// should be registered with `handle_authenticated`
+    pub fn secret<S: Into<String>>(mut self, secret: S) -> Registration {
+        self.secret = Some(Secret::new(secret));
+        self
+    }
+
+    /// uses another api, e.g. a Github Enterprise server's
+    /// `https://github.example.com/api/v3`
+    pub fn api<A: Into<String>>(mut self, api: A) -> Registration {
+        self.api = api.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// registers a webhook for a repository, named `owner/name`
+    pub fn repo<R: Into<String>>(mut self, repo: R) -> Registration {
+        self.targets.push(Target::Repo(repo.into()));
+        self
+    }
+
+    /// registers a webhook for all of an organization's repositories
+    pub fn org<O: Into<String>>(mut self, org: O) -> Registration {
+        self.targets.push(Target::Org(org.into()));
+        self
+    }
+
+    /// creates each target's webhook, or updates the one already delivering
+    /// to this url, to deliver the events `hub` has hooks for
+    pub fn apply(&self, hub: &Hub) -> Result<(), RegistrationError> {
+        let client = client()?;
+        let mut webhook = self.webhook(&events(hub));
+        let registered = self
+            .targets
+            .iter()
+            .map(|target| self.register(&client, target, &webhook))
+            .collect();
+        if let Some(&mut Value::String(ref mut secret)) = webhook.pointer_mut("/config/secret") {
+            secret.zeroize();
+        }
+        registered
+    }
+
+    /// hands `hub` the deliveries of the targets' webhooks which Github
+    /// failed to deliver since `since`, oldest first, returning how many.
+    /// Github's api doesn't keep payloads byte for byte, so they're
+    /// signed anew with the registration's secret
+    pub fn catch_up(&self, hub: &Hub, since: SystemTime) -> Result<usize, RegistrationError> {
+        let client = client()?;
+        let mut caught_up = 0;
+        for target in &self.targets {
+            let hooks = target.hooks(&self.api);
+            let existing = self.list(&client, &hooks)?;
+            let deliveries = match self.existing(&existing) {
+                Some(id) => format!("{}/{}/deliveries", hooks, id),
+                None => continue,
+            };
+            let mut recent = Vec::new();
+            let mut page = Some(format!("{}?per_page=100", deliveries));
+            while let Some(url) = page {
+                let (listed, next) = self.exchange(&client, Method::Get, &url, None)?;
+                let listed = listed.as_array().cloned().unwrap_or_default();
+                // attempts whose time is missing or malformed don't end the listing
+                let earlier = listed.iter().any(|delivery| {
+                    delivered(delivery)
+                        .map(|delivered| delivered < since)
+                        .unwrap_or(false)
+                });
+                recent.extend(listed);
+                page = if earlier { None } else { next };
+            }
+            for id in missed(&recent, since) {
+                let url = format!("{}/{}", deliveries, id);
+                let attempt = self.request(&client, Method::Get, &url, None)?;
+                let (headers, payload) = match self.replay(&attempt) {
+                    Some(replayed) => replayed,
+                    None => continue,
+                };
+                match Delivery::parse(&headers, payload.as_bytes(), None) {
+                    Ok(mut delivery) => {
+                        info!("catching up on delivery {}", delivery.id);
+                        hub.deliver(&mut delivery);
+                        caught_up += 1;
+                    }
+                    Err(err) => warn!("failed to catch up on delivery {}: {}", id, err),
+                }
+            }
+        }
+        Ok(caught_up)
+    }
+
+    /// the headers and payload of a delivery attempt fetched from Github,
+    /// signed with the registration's secret
+    fn replay(&self, attempt: &Value) -> Option<(Vec<(String, String)>, String)> {
+        let request = attempt.get("request")?;
+        let payload = serde_json::to_string(request.get("payload")?).ok()?;
+        let mut headers = request
+            .get("headers")?
+            .as_object()?
+            .iter()
+            .filter(|&(name, _)| !name.to_lowercase().starts_with("x-hub-signature"))
+            .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_owned())))
+            .collect::<Vec<_>>();
+        if let Some(ref secret) = self.secret {
+            let signature = crypto::sign_hmac_sha1(secret.as_bytes(), payload.as_bytes());
+            headers.push((
+                "X-Hub-Signature".to_owned(),
+                format!("sha1={}", hex::encode(signature)),
+            ));
+        }
+        Some((headers, payload))
+    }
+
+    fn register(
+        &self,
+        client: &Client,
+        target: &Target,
+        webhook: &Value,
+    ) -> Result<(), RegistrationError> {
+        let hooks = target.hooks(&self.api);
+        let existing = self.list(client, &hooks)?;
+        match self.existing(&existing) {
+            Some(id) => {
+                info!("updating webhook {} at {}", id, hooks);
+                let hook = format!("{}/{}", hooks, id);
+                self.request(client, Method::Patch, &hook, Some(webhook))?;
+            }
+            None => {
+                info!("creating webhook at {}", hooks);
+                self.request(client, Method::Post, &hooks, Some(webhook))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the webhook to create or update
+    fn webhook(&self, events: &[String]) -> Value {
+        let mut config = json!({
+            "url": self.url,
+            "content_type": "json",
+        });
+        if let (Some(config), Some(secret)) = (config.as_object_mut(), self.secret.as_ref()) {
+            config.insert(
+                "secret".to_owned(),
+                Value::String(secret.as_str().to_owned()),
+            );
+        }
+        json!({
+            "name": "web",
+            "active": true,
+            "events": events,
+            "config": config,
+        })
+    }
+
+    /// the id of the webhook already delivering to this url, if any
+    fn existing(&self, hooks: &Value) -> Option<u64> {
+        hooks
+            .as_array()
+            .and_then(|hooks| {
+                hooks.iter().find(|hook| {
+                    hook.pointer("/config/url").and_then(Value::as_str) == Some(&self.url)
+                })
+            })
+            .and_then(|hook| hook.get("id"))
+            .and_then(Value::as_u64)
+    }
+
+    /// every page of the list at `url`, e.g. of a target's webhooks
+    fn list(&self, client: &Client, url: &str) -> Result<Value, RegistrationError> {
+        let mut listed = Vec::new();
+        let mut page = Some(format!("{}?per_page=100", url));
+        while let Some(url) = page {
+            let (response, next) = self.exchange(client, Method::Get, &url, None)?;
+            listed.extend(response.as_array().cloned().unwrap_or_default());
+            page = next;
+        }
+        Ok(Value::Array(listed))
+    }
+
+    fn request(
+        &self,
+        client: &Client,
+        method: Method,
+        url: &str,
+        body: Option<&Value>,
+    ) -> Result<Value, RegistrationError> {
+        self.exchange(client, method, url, body)
+            .map(|(response, _)| response)
+    }
+
+    /// requests `url`, returning the response and the
+    /// url of the next page of it, if there is one
+    fn exchange(
+        &self,
+        client: &Client,
+        method: Method,
+        url: &str,
+        body: Option<&Value>,
+    ) -> Result<(Value, Option<String>), RegistrationError> {
+        // bodies of webhooks include their secret
+        let body = body
+            .map(serde_json::to_string)
+            .transpose()?
+            .map(Zeroizing::new);
+        let mut headers = Headers::new();
+        headers.set(Authorization(self.authorization.as_str().to_owned()));
+        headers.set(UserAgent(USER_AGENT.to_owned()));
+        headers.set_raw("Accept", vec![b"application/vnd.github.v3+json".to_vec()]);
+        let mut request = client.request(method, url);
+        if let Some(ref body) = body {
+            headers.set(ContentType::json());
+            request = request.body(body.as_str());
+        }
+        let mut response = request.headers(headers).send()?;
+        let mut text = String::new();
+        response
+            .read_to_string(&mut text)
+            .map_err(hyper::Error::Io)?;
+        if !response.status.is_success() {
+            return Err(RegistrationError::Status(response.status, text));
+        }
+        let next = response
+            .headers
+            .get_raw("Link")
+            .and_then(|values| values.first())
+            .and_then(|value| str::from_utf8(value).ok())
+            .and_then(link::next_page);
+        Ok((serde_json::from_str(&text)?, next))
+    }
+}
+
+fn client() -> Result<Client, RegistrationError> {
+    let tls = TlsClient::new().map_err(|err| hyper::Error::Ssl(Box::new(err)))?;
+    Ok(Client::with_connector(HttpsConnector::new(tls)))
+}
+
+/// when a listed delivery attempt was made
+fn delivered(delivery: &Value) -> Option<SystemTime> {
+    delivery
+        .get("delivered_at")
+        .and_then(Value::as_str)
+        .and_then(clock::parse_rfc3339)
+}
+
+/// the ids of the latest attempts of deliveries none of whose attempts since
+/// `since` succeeded, oldest first. attempts are listed latest first
+fn missed(attempts: &[Value], since: SystemTime) -> Vec<u64> {
+    let recent = attempts
+        .iter()
+        .filter(|attempt| delivered(attempt).map(|at| at >= since).unwrap_or(false))
+        .collect::<Vec<_>>();
+    let succeeded = recent
+        .iter()
+        .filter(|attempt| {
+            attempt
+                .get("status_code")
+                .and_then(Value::as_u64)
+                .map(|status| status >= 200 && status < 300)
+                .unwrap_or(false)
+        })
+        .filter_map(|attempt| attempt.get("guid").and_then(Value::as_str))
+        .collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    let mut missed = recent
+        .iter()
+        .filter_map(|attempt| {
+            let guid = attempt.get("guid").and_then(Value::as_str)?;
+            if succeeded.contains(guid) || !seen.insert(guid) {
+                return None;
+            }
+            attempt.get("id").and_then(Value::as_u64)
+        })
+        .collect::<Vec<_>>();
+    missed.reverse();
+    missed
+}
+
+/// the events a hub has hooks for, or every event
+/// if any of its hooks handles more than one
+fn events(hub: &Hub) -> Vec<String> {
+    let mut events = hub.hooks.keys().cloned().collect::<Vec<_>>();
+    if events.iter().any(|event| event == "*" || event == UNKNOWN) {
+        return vec!["*".to_owned()];
+    }
+    events.sort();
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::net::HttpListener;
+    use hyper::server::{Request, Response, Server};
+    use std::net::TcpListener;
+    use Delivery;
+
+    #[test]
+    fn registration_webhooks() {
+        let mut hub = Hub::new();
+        hub.handle("push", |_: &Delivery| {});
+        hub.handle("issues", |_: &Delivery| {});
+        assert_eq!(vec!["issues", "push"], events(&hub));
+        let registration = Registration::new("token", "https://hooks.example.com/")
+            .secret("secret")
+            .api("https://github.example.com/api/v3/")
+            .repo("octocat/hello-world");
+        assert_eq!(
+            "https://github.example.com/api/v3/repos/octocat/hello-world/hooks",
+            registration.targets[0].hooks(&registration.api)
+        );
+        let webhook = registration.webhook(&events(&hub));
+        assert_eq!(
+            Some("secret"),
+            webhook.pointer("/config/secret").and_then(Value::as_str)
+        );
+        assert_eq!(
+            Some("push"),
+            webhook.pointer("/events/1").and_then(Value::as_str)
+        );
+
+        let hooks = json!([
+            { "id": 1, "config": { "url": "https://elsewhere.example.com/" } },
+            { "id": 2, "config": { "url": "https://hooks.example.com/" } },
+        ]);
+        assert_eq!(Some(2), registration.existing(&hooks));
+        assert_eq!(None, registration.existing(&json!([])));
+
+        hub.handle("*", |_: &Delivery| {});
+        assert_eq!(vec!["*"], events(&hub));
+    }
+
+    #[test]
+    fn registration_pages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap());
+        let next = format!("<{}/repos/o/r/hooks?page=2>; rel=\"next\"", api);
+        let mut listening = Server::new(HttpListener::from(listener))
+            .handle(move |req: Request, mut res: Response| {
+                let body = match req.uri.to_string().as_str() {
+                    "/repos/o/r/hooks?per_page=100" => {
+                        res.headers_mut()
+                            .set_raw("Link", vec![next.clone().into_bytes()]);
+                        r#"[{ "id": 1, "config": { "url": "https://elsewhere.example.com/" } }]"#
+                    }
+                    _ => r#"[{ "id": 2, "config": { "url": "https://hooks.example.com/" } }]"#,
+                };
+                res.send(body.as_bytes()).unwrap();
+            })
+            .unwrap();
+        let registration = Registration::new("token", "https://hooks.example.com/").api(api);
+        let url = Target::Repo("o/r".to_owned()).hooks(&registration.api);
+        let hooks = registration.list(&client().unwrap(), &url).unwrap();
+        assert_eq!(Some(2), hooks.as_array().map(Vec::len));
+        assert_eq!(Some(2), registration.existing(&hooks));
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn registration_catch_up() {
+        let attempts = json!([
+            { "id": 5, "guid": "c", "delivered_at": "2019-06-03T01:00:00Z", "status_code": 502 },
+            { "id": 4, "guid": "b", "delivered_at": "2019-06-03T00:59:00Z", "status_code": 200 },
+            { "id": 3, "guid": "a", "delivered_at": "2019-06-03T00:58:00Z", "status_code": 502 },
+            { "id": 2, "guid": "b", "delivered_at": "2019-06-03T00:57:00Z", "status_code": 502 },
+            { "id": 1, "guid": "z", "delivered_at": "2019-06-02T00:00:00Z", "status_code": 502 },
+        ]);
+        let since = clock::parse_rfc3339("2019-06-03T02:30:00+02:00").unwrap();
+        assert_eq!(None, clock::parse_rfc3339("2019é06-03T02:30:00Z"));
+        assert_eq!(None, clock::parse_rfc3339("2019-06-03T02:30:00+0é00"));
+        assert_eq!(vec![3, 5], missed(attempts.as_array().unwrap(), since));
+
+        let attempt = json!({
+            "request": {
+                "headers": { "X-GitHub-Event": "ping", "X-GitHub-Delivery": "a", "X-Hub-Signature": "sha1=stale" },
+                "payload": { "zen": "Keep it logically awesome." },
+            }
+        });
+        let registration =
+            Registration::new("token", "https://hooks.example.com/").secret("secret");
+        let (headers, payload) = registration.replay(&attempt).unwrap();
+        let delivery = Delivery::parse(&headers, payload.as_bytes(), Some("secret")).unwrap();
+        assert_eq!("a", delivery.id);
+        assert_eq!("ping", delivery.event);
+    }
+}
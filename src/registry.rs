@@ -0,0 +1,127 @@
+//! Lists the hooks registered with a hub
+//!
+//! `Hub::registry` describes each hook by the event it's registered for,
+//! its name, the hooks it runs after and the filters guarding it, e.g. for
+//! admin tooling to show or startup logs to tell what a hub will do with
+//! the deliveries it receives
+
+use super::Hub;
+use std::fmt;
+
+/// A description of a hook registered with a hub
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookInfo<'a> {
+    event: &'a str,
+    name: Option<&'a str>,
+    after: &'a [String],
+    filters: Vec<String>,
+}
+
+impl<'a> HookInfo<'a> {
+    /// the event, or `*`, the hook is registered for
+    pub fn event(&self) -> &'a str {
+        self.event
+    }
+
+    /// the name the hook is registered under, if any
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    /// the names of the hooks this hook runs after
+    pub fn after(&self) -> &'a [String] {
+        self.after
+    }
+
+    /// describes the filters deliveries pass before the hook handles them,
+    /// outermost first
+    pub fn filters(&self) -> &[String] {
+        &self.filters
+    }
+}
+
+impl<'a> fmt::Display for HookInfo<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.event)?;
+        if let Some(name) = self.name {
+            write!(f, " {}", name)?;
+        }
+        if !self.after.is_empty() {
+            write!(f, " after {}", self.after.join(", "))?;
+        }
+        if !self.filters.is_empty() {
+            write!(f, " when {}", self.filters.join(" and "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Hub {
+    /// describes the registered hooks, by event and then in the order
+    /// they were registered
+    pub fn registry(&self) -> Vec<HookInfo> {
+        let mut events = self.hooks.keys().collect::<Vec<_>>();
+        events.sort();
+        events
+            .into_iter()
+            .flat_map(|event| {
+                self.hooks[event].iter().map(move |registered| {
+                    let name = registered.name.as_ref().map(String::as_str);
+                    HookInfo {
+                        event: event,
+                        name: name,
+                        after: name
+                            .map(|name| self.dependencies.after(name))
+                            .unwrap_or(&[]),
+                        filters: registered.hook.filters(),
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filter::{Actions, Expr, IgnoreBots};
+    use {Delivery, HookGroup};
+
+    #[test]
+    fn hub_registry() {
+        let mut hub = Hub::new();
+        hub.handle_named("push", "audit", |_: &Delivery| {});
+        hub.handle_after("push", "notify", &["audit"], |_: &Delivery| {})
+            .unwrap();
+        hub.handle_actions(
+            "pull_request",
+            Actions::allow(vec!["opened"]).except(vec!["closed"]),
+            |_: &Delivery| {},
+        );
+        hub.handle_when(
+            "issues",
+            Expr::parse("payload.action == \"labeled\"").unwrap(),
+            |_: &Delivery| {},
+        );
+        hub.mount(
+            HookGroup::new()
+                .filter(IgnoreBots::new())
+                .filter(|_: &Delivery| true),
+        );
+
+        let registry = hub.registry();
+        let listed = registry.iter().map(HookInfo::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "* when no bots and condition",
+                "issues when expr payload.action == \"labeled\"",
+                "pull_request when actions opened except closed",
+                "push audit",
+                "push notify after audit",
+            ],
+            listed
+        );
+        assert_eq!(Some("notify"), registry[4].name());
+        assert_eq!(&["audit".to_owned()], registry[4].after());
+    }
+}
@@ -0,0 +1,302 @@
+//! Handing deliveries to hooks again later
+//!
+//! A `Scheduler` holds on to deliveries until they're due and then hands
+//! them to its hook, e.g. to check on a deployment again in five minutes or
+//! to process a release once its embargo lifts. Scheduled deliveries are
+//! kept in a json file, so those due while a process wasn't running are
+//! handed on once it's started again. They're kept without the
+//! credentials they were sent with, in a file only their owner may read,
+//! and are handed on with the states of the hub they were scheduled from
+
+use super::extract::IntoHook;
+use super::{clock, store, Delivery, Hook, OwnedDelivery};
+use serde_json;
+use state::States;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+include!(concat!(env!("OUT_DIR"), "/schedule.rs"));
+
+/// longest the scheduler waits before looking at the clock again,
+/// so a clock set forward doesn't hold back deliveries for long
+const POLL: Duration = Duration::from_secs(1);
+
+/// Hands deliveries to a hook once they're due
+///
+/// Schedulers are cheap to clone, all clones sharing the deliveries
+/// scheduled, so hooks may hold on to one to schedule deliveries with
+#[derive(Clone)]
+pub struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    hook: Box<Hook>,
+    path: PathBuf,
+    scheduled: Mutex<Vec<Scheduled>>,
+    /// the states of the hub deliveries were last scheduled from
+    states: Mutex<Option<States>>,
+    changed: Condvar,
+}
+
+impl Scheduler {
+    /// hands `hook` the deliveries scheduled with this scheduler, keeping
+    /// them in the file at `path`, after any already scheduled there
+    pub fn open<P, H, Args>(path: P, hook: H) -> io::Result<Scheduler>
+    where
+        P: AsRef<Path>,
+        H: IntoHook<Args>,
+    {
+        let path = path.as_ref().to_owned();
+        let scheduled = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let shared = Arc::new(Shared {
+            hook: Box::new(hook.into_hook()),
+            path: path,
+            scheduled: Mutex::new(scheduled),
+            states: Mutex::new(None),
+            changed: Condvar::new(),
+        });
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || hand_on(weak));
+        Ok(Scheduler { shared: shared })
+    }
+
+    /// schedules a delivery to be handed to the hook at `due`
+    pub fn schedule(&self, delivery: &Delivery, due: SystemTime) -> io::Result<()> {
+        let scheduled = Scheduled {
+            due: due.duration_since(UNIX_EPOCH).unwrap_or_default(),
+            delivery: OwnedDelivery::from(delivery).without_credentials(),
+        };
+        if let Some(states) = delivery.states {
+            *self.shared.states.lock().unwrap() = Some(states.clone());
+        }
+        let mut pending = self.shared.scheduled.lock().unwrap();
+        let position = pending
+            .iter()
+            .position(|other| other.due > scheduled.due)
+            .unwrap_or_else(|| pending.len());
+        pending.insert(position, scheduled);
+        if let Err(err) = save(&self.shared.path, &pending) {
+            pending.remove(position);
+            return Err(err);
+        }
+        self.shared.changed.notify_one();
+        Ok(())
+    }
+
+    /// schedules a delivery to be handed to the hook once `delay` elapsed
+    pub fn schedule_in(&self, delivery: &Delivery, delay: Duration) -> io::Result<()> {
+        self.schedule(delivery, clock::now() + delay)
+    }
+
+    /// the deliveries scheduled and not yet handed on, soonest first
+    pub fn scheduled(&self) -> Vec<Scheduled> {
+        self.shared.scheduled.lock().unwrap().clone()
+    }
+
+    /// a hook scheduling the deliveries it handles for the time `when`
+    /// tells, and handing those it tells no time, or a past one, to this
+    /// scheduler's hook at once
+    pub fn defer<F>(&self, when: F) -> Defer<F>
+    where
+        F: Fn(&Delivery) -> Option<SystemTime> + Send + Sync,
+    {
+        Defer {
+            scheduler: self.clone(),
+            when: when,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.shared.path
+    }
+}
+
+impl Shared {
+    /// hands on the deliveries which are due, or waits for the next one.
+    /// deliveries are only unscheduled once handed on, so those which were
+    /// being handled when the process stopped are handed on again
+    fn hand_on_due(&self) {
+        let now = clock::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let pending = self.scheduled.lock().unwrap();
+        let due = pending
+            .iter()
+            .take_while(|scheduled| scheduled.due <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            let wait = pending
+                .first()
+                .map(|next| next.due - now)
+                .map(|wait| wait.min(POLL))
+                .unwrap_or(POLL);
+            let _ = self.changed.wait_timeout(pending, wait);
+            return;
+        }
+        drop(pending);
+        let states = self.states.lock().unwrap().clone();
+        for scheduled in &due {
+            let mut delivery = scheduled.delivery.delivery();
+            delivery.states = states.as_ref();
+            let handled = panic::catch_unwind(AssertUnwindSafe(|| self.hook.handle(&delivery)));
+            if handled.is_err() {
+                log_delivery!(
+                    error,
+                    &delivery;
+                    "hook panicked handling scheduled delivery {}",
+                    delivery.id
+                );
+            }
+        }
+        let mut pending = self.scheduled.lock().unwrap();
+        for scheduled in &due {
+            if let Some(position) = pending.iter().position(|other| other == scheduled) {
+                pending.remove(position);
+            }
+        }
+        if let Err(err) = save(&self.path, &pending) {
+            warn!(
+                "failed to persist scheduled deliveries to {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// hands on due deliveries until the scheduler is dropped
+fn hand_on(shared: Weak<Shared>) {
+    while let Some(shared) = shared.upgrade() {
+        shared.hand_on_due();
+    }
+}
+
+/// writes scheduled deliveries to a file as json, replacing
+/// it only once they have been written in full
+fn save(path: &Path, scheduled: &[Scheduled]) -> io::Result<()> {
+    let json = serde_json::to_vec(&scheduled)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    let partial = path.with_extension("partial");
+    store::private()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial)?
+        .write_all(&json)?;
+    fs::rename(&partial, path)
+}
+
+/// A hook scheduling the deliveries it handles, made with `Scheduler::defer`
+pub struct Defer<F> {
+    scheduler: Scheduler,
+    when: F,
+}
+
+impl<F> Hook for Defer<F>
+where
+    F: Fn(&Delivery) -> Option<SystemTime> + Send + Sync,
+{
+    fn handle(&self, delivery: &Delivery) {
+        match (self.when)(delivery) {
+            Some(due) if due > clock::now() => {
+                if let Err(err) = self.scheduler.schedule(delivery, due) {
+                    log_delivery!(
+                        error,
+                        delivery;
+                        "failed to schedule delivery {}: {}",
+                        delivery.id,
+                        err
+                    );
+                }
+            }
+            _ => self.scheduler.shared.hook.handle(delivery),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::sync::mpsc;
+
+    #[test]
+    fn scheduled_deliveries() {
+        let path = env::temp_dir().join(format!("afterparty-schedule-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let scheduler = Scheduler::open(&path, move |delivery: &Delivery| {
+            // scheduled deliveries are handed on with the hub's states
+            let environment = delivery.state::<&str>().map(|environment| *environment);
+            sender
+                .lock()
+                .unwrap()
+                .send((delivery.id.to_owned(), environment))
+                .unwrap();
+        })
+        .unwrap();
+        let headers = vec![("Authorization".to_owned(), "token secret".to_owned())];
+        let mut states = States::default();
+        states.insert("production");
+        let delivery = |id| {
+            let mut delivery = Delivery::new(id, "deployment", "{}", None);
+            delivery.headers = &headers;
+            delivery.states = Some(&states);
+            delivery
+        };
+        scheduler
+            .schedule_in(&delivery("later"), Duration::from_secs(3600))
+            .unwrap();
+        scheduler
+            .schedule_in(&delivery("soon"), Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(
+            Ok(("soon".to_owned(), Some("production"))),
+            receiver.recv_timeout(Duration::from_secs(5))
+        );
+        // deliveries are unscheduled once their hook returned
+        for _ in 0..500 {
+            if scheduler.scheduled().len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let embargo = scheduler.defer(|delivery: &Delivery| match delivery.id {
+            "embargoed" => Some(clock::now() + Duration::from_secs(7200)),
+            _ => None,
+        });
+        embargo.handle(&delivery("embargoed"));
+        embargo.handle(&delivery("public"));
+        assert_eq!(
+            Ok(("public".to_owned(), Some("production"))),
+            receiver.try_recv()
+        );
+        drop((scheduler, embargo));
+
+        let reopened = Scheduler::open(&path, |_: &Delivery| {}).unwrap();
+        let scheduled = reopened.scheduled();
+        let ids = scheduled
+            .iter()
+            .map(|scheduled| scheduled.delivery.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["later", "embargoed"], ids);
+        // credentials aren't kept with the deliveries scheduled
+        assert!(scheduled
+            .iter()
+            .all(|scheduled| scheduled.delivery.headers.is_empty()));
+        fs::remove_file(&path).unwrap();
+    }
+}
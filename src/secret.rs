@@ -0,0 +1,59 @@
+//! Secrets which are scrubbed from memory once they're dropped
+
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+/// A webhook secret or token, overwritten with zeros when it's dropped so
+/// it doesn't linger in freed memory. Debug formatting doesn't reveal it
+#[derive(Clone)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    /// takes ownership of a secret, without copying it when it's a `String`
+    pub fn new<S: Into<String>>(secret: S) -> Secret {
+        Secret(Zeroizing::new(secret.into()))
+    }
+
+    #[cfg(any(feature = "server", feature = "grpc"))]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// wipes the secret in place, as dropping it does
+impl Zeroize for Secret {
+    fn zeroize(&mut self) {
+        self.0.zeroize()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    #[test]
+    fn secret_scrubbed() {
+        let mut secret = Secret::new(String::from("s3cr3t"));
+        assert_eq!(b"s3cr3t", secret.as_bytes());
+        assert_eq!("Secret(..)", format!("{:?}", secret));
+        assert!(!format!("{:?}", secret).contains("s3cr3t"));
+
+        let (bytes, len) = (secret.as_bytes().as_ptr(), secret.as_bytes().len());
+        secret.zeroize();
+        assert!(secret.as_bytes().is_empty());
+        // the buffer it was kept in, still allocated, is overwritten in place
+        let wiped = unsafe { slice::from_raw_parts(bytes, len) };
+        assert_eq!(&[0; 6], wiped);
+    }
+}
@@ -0,0 +1,140 @@
+//! Smoke testing deployed hubs
+
+use super::crypto;
+use super::receipt::Receipt;
+use super::server::{XGithubDelivery, XGithubEvent, XHubSignature};
+#[cfg(feature = "tls")]
+use super::tls::TlsClient;
+use hex;
+use hyper;
+use hyper::header::ContentType;
+#[cfg(feature = "tls")]
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use hyper::Client;
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PING: &str = include_str!("../data/ping.json");
+
+/// Reasons a self test failed
+#[derive(Debug)]
+pub enum SelfTestError {
+    /// the ping couldn't be sent
+    Http(hyper::Error),
+    /// the hub, or a proxy in front of it, responded with an error status
+    Status(StatusCode),
+    /// something other than a hub responded, with this body
+    Response(String),
+    /// the hub accepted the ping but none of its hooks verified it,
+    /// e.g. as it was signed with another secret than theirs
+    Unverified,
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SelfTestError::Http(ref err) => write!(f, "failed to send ping: {}", err),
+            SelfTestError::Status(status) => write!(f, "ping was answered with {}", status),
+            SelfTestError::Response(ref body) => write!(f, "unexpected response {:?}", body),
+            SelfTestError::Unverified => write!(f, "the hub didn't verify the ping"),
+        }
+    }
+}
+
+impl Error for SelfTestError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            SelfTestError::Http(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<hyper::Error> for SelfTestError {
+    fn from(err: hyper::Error) -> SelfTestError {
+        SelfTestError::Http(err)
+    }
+}
+
+/// sends a synthetic `ping` delivery, signed with `secret` as Github would,
+/// to the hub served at `url` and checks the hub's receipt of it. with the
+/// `tls` feature `url` may be `https`.
+///
+/// this exercises the route from the internet to the hub, e.g. reverse
+/// proxies rewriting paths or dropping headers, and fails when none of
+/// the hub's `ping` hooks verified the ping, e.g. as the secret differs
+pub fn selftest(url: &str, secret: &str) -> Result<(), SelfTestError> {
+    let signature = crypto::sign_hmac_sha1(secret.as_bytes(), PING.as_bytes());
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or_default();
+    let delivery = format!("selftest-{}", id);
+    let mut response = client()?
+        .post(url)
+        .header(XGithubEvent("ping".to_owned()))
+        .header(XGithubDelivery(delivery.clone()))
+        .header(XHubSignature(format!("sha1={}", hex::encode(signature))))
+        .header(ContentType::json())
+        .body(PING)
+        .send()?;
+    if !response.status.is_success() {
+        return Err(SelfTestError::Status(response.status));
+    }
+    let mut body = String::new();
+    response
+        .read_to_string(&mut body)
+        .map_err(hyper::Error::Io)?;
+    match serde_json::from_str::<Receipt>(&body) {
+        Ok(ref receipt) if receipt.delivery == delivery && receipt.status != "rejected" => {
+            if receipt.verified {
+                Ok(())
+            } else {
+                Err(SelfTestError::Unverified)
+            }
+        }
+        _ => Err(SelfTestError::Response(body)),
+    }
+}
+
+#[cfg(feature = "tls")]
+fn client() -> Result<Client, SelfTestError> {
+    let tls = TlsClient::new().map_err(|err| hyper::Error::Ssl(Box::new(err)))?;
+    Ok(Client::with_connector(HttpsConnector::new(tls)))
+}
+
+#[cfg(not(feature = "tls"))]
+fn client() -> Result<Client, SelfTestError> {
+    Ok(Client::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use {Delivery, Hub};
+
+    #[test]
+    fn selftest_pings() {
+        let mut hub = Hub::new();
+        hub.handle_authenticated("ping", "secret", |_: &Delivery| {});
+        let handle = hub.run("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", handle.addr());
+        selftest(&url, "secret").unwrap();
+        match selftest(&url, "wrong") {
+            Err(SelfTestError::Unverified) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(2, handle.received());
+        assert_eq!(1, handle.verified());
+        match selftest("http://127.0.0.1:1/", "secret") {
+            Err(SelfTestError::Http(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        handle.stop(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,894 @@
+//! Serves hubs over http with hyper
+
+use super::auth::Token;
+use super::{
+    header_value, Delivery, Hub, HubHandle, IpRange, Overflow, PeerIdentity, Shutdown,
+    TrustedProxies,
+};
+use access::Access;
+use config::HubConfig;
+use decode::{self, DecodeError};
+use error::ServerError;
+use handle::Counters;
+use hyper;
+use hyper::header::Allow;
+use hyper::method::Method;
+use hyper::net::{HttpListener, NetworkListener};
+use hyper::server::{Handler, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use limit::Semaphore;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+use net2::TcpBuilder;
+use num_cpus;
+use pool::BufferPool;
+#[cfg(unix)]
+use privileges;
+use receipt::{self, Receipt};
+use serde_json;
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(unix)]
+use systemd;
+#[cfg(feature = "tls")]
+use tls;
+
+// signature for request
+// see [this document](https://developer.github.com/webhooks/securing/) for more information
+header! {(XHubSignature, "X-Hub-Signature") => [String]}
+
+// name of Github event
+// see [this document](https://developer.github.com/webhooks/#events) for available types
+header! {(XGithubEvent, "X-Github-Event") => [String]}
+
+// unique id for each delivery
+header! {(XGithubDelivery, "X-Github-Delivery") => [String]}
+
+/// the length of the queue of connections waiting to be accepted, that of
+/// `TcpListener::bind`
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Connection settings for servers a hub is mounted in.
+///
+/// hyper speaks HTTP/1.1 only, so proxies multiplexing deliveries over
+/// HTTP/2 should be configured to downgrade their upstream connections
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    keep_alive: Option<Option<Duration>>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    threads: Option<usize>,
+    reuse_port: bool,
+    user: Option<String>,
+    group: Option<String>,
+    accessed: Vec<(PathBuf, bool)>,
+}
+
+impl ServerConfig {
+    pub fn new() -> ServerConfig {
+        ServerConfig::default()
+    }
+
+    /// how long idle keep-alive connections are held open, `None`
+    /// disabling keep-alive. hyper defaults to 5 seconds
+    pub fn keep_alive(mut self, timeout: Option<Duration>) -> ServerConfig {
+        self.keep_alive = Some(timeout);
+        self
+    }
+
+    /// how long to wait on reads of request headers and bodies
+    pub fn read_timeout(mut self, timeout: Duration) -> ServerConfig {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// how long to wait on writes of responses
+    pub fn write_timeout(mut self, timeout: Duration) -> ServerConfig {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// number of threads handling connections, which bounds the number of
+    /// connections served at once. hyper defaults to 1.25 per cpu
+    pub fn threads(mut self, threads: usize) -> ServerConfig {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// binds addresses with `SO_REUSEPORT`, so several processes may serve
+    /// the same port, e.g. an old and a new release during a deploy, or one
+    /// per core, with the kernel spreading connections between them
+    #[cfg(unix)]
+    pub fn reuse_port(mut self, reuse: bool) -> ServerConfig {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// switches to `user` once the hub is listening, before any delivery
+    /// is handled, so a hub started as root to bind e.g. port 443 doesn't
+    /// process deliveries as root. see the `privileges` module
+    #[cfg(unix)]
+    pub fn run_as<U: Into<String>>(mut self, user: U) -> ServerConfig {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// switches to `group` rather than the primary group of the user
+    /// given to `run_as`
+    #[cfg(unix)]
+    pub fn run_as_group<G: Into<String>>(mut self, group: G) -> ServerConfig {
+        self.group = Some(group.into());
+        self
+    }
+
+    /// fails to start the hub unless the user given to `run_as` may read
+    /// `path`, e.g. a certificate `Tls::sni` reloads once it's renewed,
+    /// which would otherwise only fail once privileges were dropped
+    #[cfg(unix)]
+    pub fn reads<P: Into<PathBuf>>(mut self, path: P) -> ServerConfig {
+        self.accessed.push((path.into(), false));
+        self
+    }
+
+    /// like `reads`, for files and directories the hub writes, e.g.
+    /// the directory `Acme` keeps the certificates it renews in
+    #[cfg(unix)]
+    pub fn writes<P: Into<PathBuf>>(mut self, path: P) -> ServerConfig {
+        self.accessed.push((path.into(), true));
+        self
+    }
+
+    /// applies these settings to a server, e.g. one created with
+    /// `Server::https`, returning the number of threads to handle it with
+    pub fn configure<L>(&self, server: &mut Server<L>) -> Option<usize>
+    where
+        L: NetworkListener,
+    {
+        if let Some(keep_alive) = self.keep_alive {
+            server.keep_alive(keep_alive);
+        }
+        if self.read_timeout.is_some() {
+            server.set_read_timeout(self.read_timeout);
+        }
+        if self.write_timeout.is_some() {
+            server.set_write_timeout(self.write_timeout);
+        }
+        self.threads
+    }
+}
+
+impl Hub {
+    /// bounds the number of deliveries processed at once across all hooks.
+    /// deliveries arriving while the limit is exhausted either wait
+    /// or are rejected with a `503 Service Unavailable`
+    pub fn max_in_flight(&mut self, limit: usize, overflow: Overflow) {
+        self.in_flight = Some((Semaphore::new(limit), overflow));
+    }
+
+    /// returns a handle which may be used to gracefully shut this hub
+    /// down after it has been moved into a server
+    pub fn shutdown_handle(&self) -> Shutdown {
+        Shutdown::new(self.drain.clone())
+    }
+
+    /// decompresses gzip and deflate encoded request bodies. bodies of or
+    /// decompressing to more than `limit` bytes are rejected with a
+    /// `413 Payload Too Large`, protecting against compression bombs, and
+    /// aren't read past the limit
+    pub fn decompress(&mut self, limit: usize) {
+        self.decompress = Some(limit);
+    }
+
+    /// keeps up to `buffers` request body buffers of up to `max_size` bytes
+    /// for reuse by later deliveries. hubs keep 32 buffers of up to 1MiB
+    /// by default, and `buffer_pool(0, 0)` disables reuse
+    pub fn buffer_pool(&mut self, buffers: usize, max_size: usize) {
+        self.buffers = BufferPool::new(buffers, max_size);
+    }
+
+    /// trusts the given forwarding header, e.g. `X-Forwarded-For`, to report
+    /// the real client ip for deliveries relayed by the given proxies
+    pub fn trust_proxies<S>(&mut self, header: S, proxies: Vec<IpRange>)
+    where
+        S: Into<String>,
+    {
+        self.proxies = Some(TrustedProxies::new(header, proxies));
+    }
+
+    /// only accepts deliveries posted to `path`, e.g. `/github`, responding
+    /// to requests for other paths with a `404 Not Found`. hubs accept
+    /// deliveries posted to any path by default
+    pub fn delivery_path(&mut self, path: &str) {
+        self.path = Some(path.to_owned());
+    }
+
+    /// serves the hub's statistics as json in response to `GET` requests
+    /// for `path`, e.g. `/admin/stats`, authorized with `token`
+    pub fn admin(&mut self, path: &str, token: Token) {
+        self.admin = Some((path.to_owned(), token));
+    }
+
+    /// serves deliveries on the given address over plain http,
+    /// returning a handle which reports live statistics and
+    /// may be used to stop the hub
+    pub fn run<A>(self, addr: A) -> hyper::Result<HubHandle>
+    where
+        A: ToSocketAddrs,
+    {
+        self.run_with(addr, ServerConfig::default())
+    }
+
+    /// like `run`, with the given connection settings
+    pub fn run_with<A>(self, addr: A, config: ServerConfig) -> hyper::Result<HubHandle>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener = bind(addr, config.reuse_port)?;
+        self.run_listener(listener, config)
+    }
+
+    /// like `run_with`, serving on each of the given addresses, e.g. both
+    /// `0.0.0.0:4567` and `[::]:4567`, or several ports. each address is
+    /// served by threads of its own
+    pub fn run_all<A>(self, addrs: &[A], config: ServerConfig) -> hyper::Result<HubHandle>
+    where
+        A: ToSocketAddrs,
+    {
+        let listeners = addrs
+            .iter()
+            .map(|addr| bind(addr, config.reuse_port))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.run_listeners(listeners, config)
+    }
+
+    /// like `run_with`, serving on a socket which is already listening,
+    /// e.g. one inherited from a parent process
+    pub fn run_listener(
+        self,
+        listener: TcpListener,
+        config: ServerConfig,
+    ) -> hyper::Result<HubHandle> {
+        self.run_listeners(vec![listener], config)
+    }
+
+    /// like `run_listener`, serving on each of the given sockets
+    pub fn run_listeners(
+        self,
+        listeners: Vec<TcpListener>,
+        config: ServerConfig,
+    ) -> hyper::Result<HubHandle> {
+        let servers = listeners
+            .into_iter()
+            .map(|listener| Server::new(HttpListener::from(listener)))
+            .collect();
+        self.serve(servers, config)
+    }
+
+    /// like `run_with`, serving on the socket systemd passed the process
+    /// when activating it. taking it unsets environment variables, so run
+    /// the hub before starting any threads, see the `systemd` module
+    #[cfg(unix)]
+    pub fn run_activated(self, config: ServerConfig) -> hyper::Result<HubHandle> {
+        self.run_listener(systemd::listener()?, config)
+    }
+
+    /// applies `config`, see `HubConfig::apply`, and serves the hub on its
+    /// address, over https when it names a certificate and key
+    pub fn run_config(mut self, config: &HubConfig) -> hyper::Result<HubHandle> {
+        config.apply(&mut self);
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = config.tls() {
+                let tls = tls.map_err(|err| hyper::Error::Ssl(Box::new(err)))?;
+                let server = Server::https(config.addr(), tls)?;
+                return self.serve(vec![server], config.server_config());
+            }
+        }
+        self.run_with(config.addr(), config.server_config())
+    }
+
+    fn serve<L>(mut self, servers: Vec<Server<L>>, config: ServerConfig) -> hyper::Result<HubHandle>
+    where
+        L: NetworkListener + Send + 'static,
+    {
+        if servers.is_empty() {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to listen on");
+            return Err(err.into());
+        }
+        #[cfg(unix)]
+        {
+            let (user, group) = (config.user.as_ref(), config.group.as_ref());
+            if user.is_some() || group.is_some() {
+                privileges::drop_privileges(user.map(String::as_str), group.map(String::as_str))?;
+                for &(ref path, write) in &config.accessed {
+                    privileges::accessible(path, write)?;
+                }
+                info!("hub running as user {:?} and group {:?}", user, group);
+            }
+        }
+        // streams each hold on to a thread, so one is always left for deliveries
+        let threads = config.threads.unwrap_or_else(default_threads);
+        self.streams.limit(threads.saturating_sub(1));
+        let counters = Arc::new(Counters::default());
+        self.observe(counters.clone());
+        let shutdown = self.shutdown_handle();
+        let stats = self.observers.stats.clone();
+        for hook in self.registry() {
+            info!("hub handling {}", hook);
+        }
+        let hub = Arc::new(self);
+        let mut listening = Vec::with_capacity(servers.len());
+        for mut server in servers {
+            let listener = match config.configure(&mut server) {
+                Some(threads) => server.handle_threads(Shared(hub.clone()), threads)?,
+                None => server.handle(Shared(hub.clone()))?,
+            };
+            info!("hub listening on {}", listener.socket);
+            listening.push(listener);
+        }
+        Ok(HubHandle::new(listening, shutdown, counters, stats))
+    }
+
+    /// reads a delivery from a request and hands it to interested hooks,
+    /// returning the status and receipt to respond with
+    fn receive(&self, req: &mut Request) -> (StatusCode, Receipt) {
+        let (received_at, received) = (SystemTime::now(), Some(Instant::now()));
+        let headers = req.headers.clone();
+        if let (Some(&XGithubEvent(ref event)), Some(&XGithubDelivery(ref delivery))) = (
+            headers.get::<XGithubEvent>(),
+            headers.get::<XGithubDelivery>(),
+        ) {
+            let signature = headers.get::<XHubSignature>();
+            let peer = peer_identity(req);
+            let header_pairs = headers
+                .iter()
+                .map(|header| (header.name().to_owned(), header.value_string()))
+                .collect::<Vec<_>>();
+            let query = match req.uri {
+                RequestUri::AbsolutePath(ref path) => {
+                    path.splitn(2, '?').nth(1).map(|q| q.to_owned())
+                }
+                _ => None,
+            };
+            let real_ip = match self.proxies {
+                Some(ref proxies) => proxies.client_ip(
+                    req.remote_addr.ip(),
+                    header_value(&header_pairs, proxies.header()),
+                ),
+                None => req.remote_addr.ip(),
+            };
+            log_delivery!(
+                info,
+                delivery,
+                event,
+                None;
+                "recv '{}' event with signature '{:?}'",
+                event,
+                signature
+            );
+            if let Some(hooks) = self.interested(event) {
+                let _in_flight = match self.drain.enter() {
+                    Some(in_flight) => in_flight,
+                    None => {
+                        log_delivery!(
+                            warn,
+                            delivery,
+                            event,
+                            None;
+                            "shutting down, rejecting delivery {}",
+                            delivery
+                        );
+                        self.observers
+                            .fail(delivery, event, &ServerError::ShuttingDown.into());
+                        let receipt = receipt::rejected(delivery, "shutting down");
+                        return (StatusCode::ServiceUnavailable, receipt);
+                    }
+                };
+                let _permit = match self.in_flight {
+                    Some((ref semaphore, overflow)) => match semaphore.admit(overflow) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            log_delivery!(
+                                warn,
+                                delivery,
+                                event,
+                                None;
+                                "in-flight limit reached, rejecting delivery {}",
+                                delivery
+                            );
+                            self.observers
+                                .fail(delivery, event, &ServerError::Overloaded.into());
+                            let receipt = receipt::rejected(delivery, "in-flight limit reached");
+                            return (StatusCode::ServiceUnavailable, receipt);
+                        }
+                    },
+                    None => None,
+                };
+                let mut raw = self.buffers.take();
+                // a byte past the limit is read so oversized bodies are rejected
+                let limit = self.decompress.map(|limit| limit as u64 + 1);
+                if let Err(err) = req
+                    .by_ref()
+                    .take(limit.unwrap_or(u64::max_value()))
+                    .read_to_end(&mut raw)
+                {
+                    log_delivery!(
+                        warn,
+                        delivery,
+                        event,
+                        None;
+                        "failed to read delivery {}: {}",
+                        delivery,
+                        err
+                    );
+                    let receipt = receipt::rejected(delivery, format!("failed to read: {}", err));
+                    self.observers
+                        .fail(delivery, event, &ServerError::Io(err).into());
+                    return (StatusCode::BadRequest, receipt);
+                }
+                let body = match self.decompress {
+                    Some(limit) => {
+                        match decode::decode(
+                            header_value(&header_pairs, "Content-Encoding"),
+                            &raw[..],
+                            limit,
+                        ) {
+                            Ok(body) => body,
+                            Err(err) => {
+                                log_delivery!(
+                                    warn,
+                                    delivery,
+                                    event,
+                                    None;
+                                    "failed to decode delivery {}: {}",
+                                    delivery,
+                                    err
+                                );
+                                let status = match err {
+                                    DecodeError::Unsupported(_) => StatusCode::UnsupportedMediaType,
+                                    DecodeError::TooLarge => StatusCode::PayloadTooLarge,
+                                    DecodeError::Invalid => StatusCode::BadRequest,
+                                };
+                                let receipt = receipt::rejected(delivery, err.to_string());
+                                self.observers.fail(delivery, event, &err.into());
+                                return (status, receipt);
+                            }
+                        }
+                    }
+                    None => Cow::Borrowed(&raw[..]),
+                };
+                match Delivery::parse(&header_pairs, &body, None) {
+                    Ok(mut delivery) => {
+                        if let Cow::Owned(_) = body {
+                            delivery.encoded_body = Some(&raw[..]);
+                        }
+                        delivery.peer = peer.as_ref();
+                        delivery.query = query.as_ref().map(|q| q.as_str());
+                        delivery.remote_addr = Some(req.remote_addr);
+                        delivery.real_ip = Some(real_ip);
+                        delivery.received_at = received_at;
+                        delivery.received = received;
+                        // println!("{:?}", delivery);
+                        if let Err(err) = self.check(&delivery) {
+                            let receipt = receipt::rejected(delivery.id, err.to_string());
+                            return (StatusCode::BadRequest, receipt);
+                        }
+                        let handed = hooks.clone().count();
+                        self.dispatch(&mut delivery, hooks);
+                        let duplicate = self.recent.duplicate(&delivery);
+                        return (
+                            StatusCode::Ok,
+                            receipt::accepted(
+                                delivery.id,
+                                handed,
+                                duplicate,
+                                delivery.is_verified(),
+                            ),
+                        );
+                    }
+                    Err(err) => {
+                        log_delivery!(
+                            error,
+                            delivery,
+                            event,
+                            None;
+                            "failed to parse event {:?} for delivery {:?}: {}",
+                            event, delivery, err
+                        );
+                        let receipt = receipt::rejected(delivery, err.to_string());
+                        self.observers.fail(delivery, event, &err.into());
+                        return (StatusCode::BadRequest, receipt);
+                    }
+                }
+            }
+            return (
+                StatusCode::Ok,
+                receipt::accepted(delivery, 0, self.recent.contains(delivery), false),
+            );
+        }
+        let receipt = receipt::rejected("", "missing X-Github-Event or X-Github-Delivery header");
+        (StatusCode::BadRequest, receipt)
+    }
+}
+
+/// A hub shared by the servers of each address it listens on
+struct Shared(Arc<Hub>);
+
+impl Handler for Shared {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a>) {
+        Handler::handle(&*self.0, req, res)
+    }
+}
+
+impl Handler for Hub {
+    fn handle(&self, req: Request, res: Response) {
+        match self.access_log {
+            Some(format) => {
+                let access = Access::new(&req);
+                let status = self.route(req, res);
+                info!(target: "afterparty::access", "{}", access.line(format, status));
+            }
+            None => {
+                self.route(req, res);
+            }
+        }
+    }
+}
+
+impl Hub {
+    /// responds to a request, returning the status responded with
+    fn route(&self, mut req: Request, mut res: Response) -> StatusCode {
+        let path = match (&req.method, &req.uri) {
+            (&Method::Get, &RequestUri::AbsolutePath(ref uri)) => uri.splitn(2, '?').next(),
+            _ => None,
+        };
+        if let Some((ref admin, ref token)) = self.admin {
+            if path == Some(admin.as_str()) {
+                return self.respond_stats(&req, res, token);
+            }
+        }
+        if let Some((ref exports_path, ref exports)) = self.exports {
+            if path == Some(exports_path.as_str()) {
+                return exports.respond(req, res, &self.drain, &self.streams);
+            }
+        }
+        if let Some((ref broadcast_path, ref broadcast)) = self.broadcast {
+            if path == Some(broadcast_path.as_str()) {
+                return broadcast.respond(req, res, &self.drain, &self.streams);
+            }
+        }
+        if let Err((status, reason)) = self.guard(&req) {
+            debug!("rejecting request: {}", reason);
+            *res.status_mut() = status;
+            if status == StatusCode::MethodNotAllowed {
+                res.headers_mut().set(Allow(vec![Method::Post]));
+            }
+            let _ = res.send(reason.as_bytes());
+            return status;
+        }
+        let (status, receipt) = self.receive(&mut req);
+        *res.status_mut() = status;
+        res.headers_mut()
+            .set_raw("Content-Type", vec![b"application/json".to_vec()]);
+        let _ = res.send(&serde_json::to_vec(&receipt).unwrap_or_default());
+        status
+    }
+
+    /// rejects requests which can't be deliveries, with
+    /// the status and reason to respond with
+    fn guard(&self, req: &Request) -> Result<(), (StatusCode, &'static str)> {
+        if let Some(ref path) = self.path {
+            let requested = match req.uri {
+                RequestUri::AbsolutePath(ref uri) => uri.splitn(2, '?').next(),
+                _ => None,
+            };
+            if requested != Some(path.as_str()) {
+                return Err((StatusCode::NotFound, "not found"));
+            }
+        }
+        if req.method != Method::Post {
+            return Err((StatusCode::MethodNotAllowed, "deliveries must be posted"));
+        }
+        if req.headers.get::<XGithubEvent>().is_none() {
+            return Err((StatusCode::BadRequest, "missing X-Github-Event header"));
+        }
+        if req.headers.get::<XGithubDelivery>().is_none() {
+            return Err((StatusCode::BadRequest, "missing X-Github-Delivery header"));
+        }
+        Ok(())
+    }
+
+    /// responds with the hub's statistics, when authorized
+    fn respond_stats(&self, req: &Request, mut res: Response, token: &Token) -> StatusCode {
+        let authorized = req
+            .headers
+            .get_raw("Authorization")
+            .and_then(|values| values.first())
+            .and_then(|value| str::from_utf8(value).ok())
+            .map(|authorization| token.verify(authorization))
+            .unwrap_or(false);
+        if !authorized {
+            *res.status_mut() = StatusCode::Unauthorized;
+            let _ = res.send(b"unauthorized");
+            return StatusCode::Unauthorized;
+        }
+        match serde_json::to_vec(&self.stats()) {
+            Ok(json) => {
+                res.headers_mut()
+                    .set_raw("Content-Type", vec![b"application/json".to_vec()]);
+                let _ = res.send(&json);
+                StatusCode::Ok
+            }
+            Err(err) => {
+                warn!("failed to serialize statistics: {}", err);
+                *res.status_mut() = StatusCode::InternalServerError;
+                let _ = res.send(b"failed");
+                StatusCode::InternalServerError
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn peer_identity(req: &Request) -> Option<PeerIdentity> {
+    req.ssl::<tls::TlsStream>()
+        .and_then(|stream| stream.peer().cloned())
+}
+
+#[cfg(not(feature = "tls"))]
+fn peer_identity(_: &Request) -> Option<PeerIdentity> {
+    None
+}
+
+/// the number of threads hyper handles connections with by default
+pub fn default_threads() -> usize {
+    num_cpus::get() * 5 / 4
+}
+
+/// binds the first of the addresses `addr` resolves to which can be bound,
+/// like `TcpListener::bind`
+fn bind<A: ToSocketAddrs>(addr: A, reuse_port: bool) -> io::Result<TcpListener> {
+    let mut failed = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_addr(&addr, reuse_port) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => failed = Some(err),
+        }
+    }
+    Err(failed.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to listen on")
+    }))
+}
+
+/// binds ipv6 addresses for ipv6 only, so `[::]` may be bound alongside
+/// `0.0.0.0` on the same port
+fn bind_addr(addr: &SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    #[cfg(unix)]
+    {
+        builder.reuse_address(true)?;
+        if reuse_port {
+            builder.reuse_port(true)?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    builder.bind(addr)?.listen(LISTEN_BACKLOG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use hex;
+    use hyper::header::{ContentEncoding, Encoding};
+    use std::io::Write;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn server_config() {
+        let config = ServerConfig::new()
+            .keep_alive(None)
+            .read_timeout(Duration::from_secs(10))
+            .threads(4);
+        let mut server = Server::http("127.0.0.1:0").unwrap();
+        assert_eq!(Some(4), config.configure(&mut server));
+        assert_eq!(None, ServerConfig::new().configure(&mut server));
+        let handle = Hub::new().run_with("127.0.0.1:0", config).unwrap();
+        handle.stop(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn request_guards() {
+        let mut hub = Hub::new();
+        hub.delivery_path("/hooks");
+        hub.handle_authenticated("ping", "secret", |_: &Delivery| {});
+        let handle = hub.run("127.0.0.1:0").unwrap();
+        let url = |path| format!("http://{}{}", handle.addr(), path);
+        let client = hyper::Client::new();
+
+        let response = client.get(&url("/hooks")).send().unwrap();
+        assert_eq!(StatusCode::MethodNotAllowed, response.status);
+        assert_eq!(
+            Some(&Allow(vec![Method::Post])),
+            response.headers.get::<Allow>()
+        );
+        let response = client.post(&url("/other")).body("{}").send().unwrap();
+        assert_eq!(StatusCode::NotFound, response.status);
+        let mut response = client
+            .post(&url("/hooks?source=test"))
+            .header(XGithubDelivery("id".to_owned()))
+            .body("{}")
+            .send()
+            .unwrap();
+        assert_eq!(StatusCode::BadRequest, response.status);
+        let mut reason = String::new();
+        response.read_to_string(&mut reason).unwrap();
+        assert_eq!("missing X-Github-Event header", reason);
+        let signed = format!(
+            "sha1={}",
+            hex::encode(crypto::sign_hmac_sha1(b"secret", b"{}"))
+        );
+        let deliver = |signature: &str| {
+            let response = client
+                .post(&url("/hooks"))
+                .header(XGithubEvent("ping".to_owned()))
+                .header(XGithubDelivery("id".to_owned()))
+                .header(XHubSignature(signature.to_owned()))
+                .body("{}")
+                .send()
+                .unwrap();
+            assert_eq!(StatusCode::Ok, response.status);
+            serde_json::from_reader::<_, Receipt>(response).unwrap()
+        };
+        // forged deliveries don't have the genuine one answered as a duplicate
+        assert_eq!(receipt::accepted("id", 1, false, false), deliver("sha1=00"));
+        assert_eq!(receipt::accepted("id", 1, false, true), deliver(&signed));
+        assert_eq!("duplicate", deliver(&signed).status);
+        let response = client
+            .post(&url("/hooks"))
+            .header(XGithubEvent("ping".to_owned()))
+            .header(XGithubDelivery("invalid".to_owned()))
+            .body(&b"\xff"[..])
+            .send()
+            .unwrap();
+        assert_eq!(StatusCode::BadRequest, response.status);
+        handle.stop(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn in_flight_limits() {
+        let limited = |overflow| {
+            let gate = Arc::new(Mutex::new(()));
+            let mut hub = Hub::new();
+            hub.max_in_flight(1, overflow);
+            let held = gate.clone();
+            Hub::handle(&mut hub, "ping", move |_: &Delivery| {
+                let _ = held.lock();
+            });
+            let config = ServerConfig::new().threads(4);
+            (hub.run_with("127.0.0.1:0", config).unwrap(), gate)
+        };
+        let deliver = |addr: SocketAddr| {
+            thread::spawn(move || {
+                hyper::Client::new()
+                    .post(&format!("http://{}/", addr))
+                    .header(XGithubEvent("ping".to_owned()))
+                    .header(XGithubDelivery("id".to_owned()))
+                    .body("{}")
+                    .send()
+                    .unwrap()
+                    .status
+            })
+        };
+        let entered = |handle: &HubHandle, count| {
+            while handle.in_flight() < count {
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+
+        let (handle, gate) = limited(Overflow::Shed);
+        let closed = gate.lock().unwrap();
+        let first = deliver(handle.addr());
+        entered(&handle, 1);
+        assert_eq!(
+            StatusCode::ServiceUnavailable,
+            deliver(handle.addr()).join().unwrap()
+        );
+        drop(closed);
+        assert_eq!(StatusCode::Ok, first.join().unwrap());
+        handle.stop(Duration::from_secs(1));
+
+        let (handle, gate) = limited(Overflow::Queue);
+        let closed = gate.lock().unwrap();
+        let first = deliver(handle.addr());
+        entered(&handle, 1);
+        let queued = deliver(handle.addr());
+        entered(&handle, 2);
+        drop(closed);
+        assert_eq!(StatusCode::Ok, first.join().unwrap());
+        assert_eq!(StatusCode::Ok, queued.join().unwrap());
+        handle.stop(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn decompressed_bodies() {
+        let decoded = Arc::new(Mutex::new(String::new()));
+        let mut hub = Hub::new();
+        hub.decompress(64);
+        let seen = decoded.clone();
+        Hub::handle(&mut hub, "ping", move |delivery: &Delivery| {
+            *seen.lock().unwrap() = delivery.unparsed_payload.to_owned();
+        });
+        let handle = hub.run("127.0.0.1:0").unwrap();
+        let deliver = |encoding: &str, body: &[u8]| {
+            hyper::Client::new()
+                .post(&format!("http://{}/", handle.addr()))
+                .header(XGithubEvent("ping".to_owned()))
+                .header(XGithubDelivery("id".to_owned()))
+                .header(ContentEncoding(vec![Encoding::EncodingExt(
+                    encoding.to_owned(),
+                )]))
+                .body(body)
+                .send()
+                .unwrap()
+                .status
+        };
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"zen":"hi"}"#).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        assert_eq!(StatusCode::Ok, deliver("gzip", &gzipped));
+        assert_eq!(r#"{"zen":"hi"}"#, *decoded.lock().unwrap());
+        assert_eq!(
+            StatusCode::PayloadTooLarge,
+            deliver("identity", &[b' '; 1024][..])
+        );
+        assert_eq!(StatusCode::UnsupportedMediaType, deliver("br", b"{}"));
+        assert_eq!(StatusCode::BadRequest, deliver("gzip", b"{}"));
+        handle.stop(Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn multiple_listeners() {
+        let shared = bind("127.0.0.1:0", true).unwrap();
+        let addr = shared.local_addr().unwrap();
+        assert!(bind(addr, false).is_err());
+        drop(shared);
+        let config = ServerConfig::new().reuse_port(true);
+        let mut hub = Hub::new();
+        Hub::handle(&mut hub, "ping", |_: &Delivery| {});
+        let handle = hub.run_all(&[addr, addr], config).unwrap();
+        let addrs = handle.addrs();
+        assert_eq!(vec![addr, addr], addrs);
+        let client = hyper::Client::new();
+        for (id, addr) in addrs.iter().enumerate() {
+            let response = client
+                .post(&format!("http://{}/", addr))
+                .header(XGithubEvent("ping".to_owned()))
+                .header(XGithubDelivery(id.to_string()))
+                .body("{}")
+                .send()
+                .unwrap();
+            assert_eq!(StatusCode::Ok, response.status);
+        }
+        assert_eq!(2, handle.received());
+        handle.stop(Duration::from_secs(1));
+    }
+}
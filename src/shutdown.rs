@@ -0,0 +1,178 @@
+//! Drain-aware shutdown of a hub
+//!
+//! hyper servers can't be stopped once they start listening, so a hub
+//! instead stops accepting deliveries, responding to new ones with a
+//! `503 Service Unavailable`, and waits for those in progress to complete.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Outcome of shutting down a hub
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShutdownReport {
+    /// deliveries in progress at shutdown which completed before the deadline
+    pub drained: usize,
+    /// deliveries still in progress when the deadline passed
+    pub abandoned: usize,
+}
+
+struct State {
+    accepting: bool,
+    in_flight: usize,
+}
+
+/// Tracks deliveries in progress on behalf of a hub
+pub struct Drain {
+    state: Mutex<State>,
+    idle: Condvar,
+}
+
+/// Marks a delivery as in progress until dropped
+pub struct InFlight<'a> {
+    drain: &'a Drain,
+}
+
+impl Default for Drain {
+    fn default() -> Drain {
+        Drain {
+            state: Mutex::new(State {
+                accepting: true,
+                in_flight: 0,
+            }),
+            idle: Condvar::new(),
+        }
+    }
+}
+
+impl Drain {
+    /// registers a new delivery, unless the hub is shutting down
+    pub fn enter(&self) -> Option<InFlight> {
+        let mut state = self.state.lock().unwrap();
+        if !state.accepting {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(InFlight { drain: self })
+    }
+
+    /// whether the hub is still accepting deliveries
+    pub fn accepting(&self) -> bool {
+        self.state.lock().unwrap().accepting
+    }
+
+    /// number of deliveries currently in progress
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    fn shutdown(&self, deadline: Duration) -> ShutdownReport {
+        let until = Instant::now() + deadline;
+        let mut state = self.state.lock().unwrap();
+        state.accepting = false;
+        let pending = state.in_flight;
+        while state.in_flight > 0 {
+            let now = Instant::now();
+            if now >= until {
+                break;
+            }
+            state = self.idle.wait_timeout(state, until - now).unwrap().0;
+        }
+        ShutdownReport {
+            drained: pending - state.in_flight,
+            abandoned: state.in_flight,
+        }
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        let mut state = self.drain.state.lock().unwrap();
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            self.drain.idle.notify_all();
+        }
+    }
+}
+
+/// A handle for shutting down a hub after it has been mounted in a server
+#[derive(Clone)]
+pub struct Shutdown {
+    drain: Arc<Drain>,
+}
+
+impl Shutdown {
+    pub fn new(drain: Arc<Drain>) -> Shutdown {
+        Shutdown { drain: drain }
+    }
+
+    /// number of deliveries currently in progress
+    pub fn in_flight(&self) -> usize {
+        self.drain.in_flight()
+    }
+
+    /// whether the hub has been shut down, e.g. for work
+    /// outside of deliveries to stop along with it
+    pub fn stopped(&self) -> bool {
+        !self.drain.accepting()
+    }
+
+    /// stops accepting new deliveries and waits up to `deadline`
+    /// for those in progress to complete.
+    ///
+    /// hyper keeps listening after this returns, so callers typically
+    /// exit the process once they've logged the report
+    pub fn shutdown(&self, deadline: Duration) -> ShutdownReport {
+        let report = self.drain.shutdown(deadline);
+        info!(
+            "shutdown drained {} deliveries, abandoned {}",
+            report.drained, report.abandoned
+        );
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn shutdown_drains_deliveries() {
+        let drain = Arc::new(Drain::default());
+        let shutdown = Shutdown::new(drain.clone());
+        let worker = {
+            let drain = drain.clone();
+            thread::spawn(move || {
+                let _in_flight = drain.enter().unwrap();
+                thread::sleep(Duration::from_millis(20));
+            })
+        };
+        while drain.in_flight() == 0 {
+            thread::yield_now();
+        }
+        let report = shutdown.shutdown(Duration::from_secs(5));
+        worker.join().unwrap();
+        assert_eq!(
+            ShutdownReport {
+                drained: 1,
+                abandoned: 0,
+            },
+            report
+        );
+        assert!(drain.enter().is_none());
+    }
+
+    #[test]
+    fn shutdown_abandons_after_deadline() {
+        let drain = Arc::new(Drain::default());
+        let _in_flight = drain.enter().unwrap();
+        let report = Shutdown::new(drain.clone()).shutdown(Duration::from_millis(10));
+        assert_eq!(
+            ShutdownReport {
+                drained: 0,
+                abandoned: 1,
+            },
+            report
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Receives deliveries relayed through a [smee.io](https://smee.io) channel
+//!
+//! Instead of listening for deliveries, a hub may subscribe to a channel
+//! which Github delivers to, receiving each delivery as a server sent event.
+//! Hubs behind firewalls, or on development machines, then receive
+//! deliveries without being reachable themselves
+
+use super::tls::TlsClient;
+use super::{Delivery, Hub};
+use hyper;
+use hyper::header::Headers;
+use hyper::net::HttpsConnector;
+use hyper::Client;
+use serde_json::{self, Value};
+use std::io::{self, BufRead, BufReader};
+use std::thread;
+use std::time::Duration;
+
+/// subscribes the hub to a channel, e.g. `https://smee.io/abc123`, handing
+/// the deliveries relayed through it to its hooks. reconnects when the
+/// channel's connection drops, so this only returns if tls isn't available
+pub fn run(hub: Hub, channel: &str) -> hyper::Result<()> {
+    let tls = TlsClient::new().map_err(|err| hyper::Error::Ssl(Box::new(err)))?;
+    let client = Client::with_connector(HttpsConnector::new(tls));
+    loop {
+        if let Err(err) = subscribe(&hub, &client, channel) {
+            warn!("smee channel {} failed: {}", channel, err);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// hands the deliveries relayed through a channel to the hub's
+/// hooks until the channel's connection closes
+fn subscribe(hub: &Hub, client: &Client, channel: &str) -> hyper::Result<()> {
+    let mut headers = Headers::new();
+    headers.set_raw("Accept", vec![b"text/event-stream".to_vec()]);
+    let response = client.get(channel).headers(headers).send()?;
+    if !response.status.is_success() {
+        return Err(hyper::Error::Status);
+    }
+    info!("subscribed to smee channel {}", channel);
+    for event in Events::new(BufReader::new(response)) {
+        let (name, data) = event?;
+        if name == "message" {
+            deliver(hub, &data);
+        }
+    }
+    Ok(())
+}
+
+/// hands a delivery relayed through a channel, the json data of one of the
+/// channel's events, to the hub's hooks. returns false if it wasn't a
+/// delivery or no hooks were interested in it
+pub fn deliver(hub: &Hub, data: &str) -> bool {
+    let (headers, body) = match parts(data) {
+        Some(parts) => parts,
+        None => {
+            debug!("ignoring smee event {}", data);
+            return false;
+        }
+    };
+    match Delivery::parse(&headers, body.as_bytes(), None) {
+        Ok(mut delivery) => hub.deliver(&mut delivery),
+        Err(err) => {
+            let id = super::header_value(&headers, "X-Github-Delivery").unwrap_or("");
+            let event = super::header_value(&headers, "X-Github-Event").unwrap_or("");
+            log_delivery!(error, id, event, None; "failed to parse delivery {:?}: {}", id, err);
+            hub.observers.fail(id, event, &err.into());
+            false
+        }
+    }
+}
+
+/// the headers and body of a relayed delivery. channels relay
+/// headers as string fields alongside the json body
+fn parts(data: &str) -> Option<(Vec<(String, String)>, String)> {
+    let relayed = serde_json::from_str::<Value>(data).ok()?;
+    let fields = relayed.as_object()?;
+    // the body's fields are relayed in their original order, so that
+    // it reserializes as Github sent it and its signature still verifies
+    let body = serde_json::to_string(fields.get("body")?).ok()?;
+    let headers = fields
+        .iter()
+        .filter_map(|(name, value)| value.as_str().map(|value| (name.clone(), value.to_owned())))
+        .collect();
+    Some((headers, body))
+}
+
+/// Server sent events read from a stream, as their names and data
+struct Events<R> {
+    lines: io::Lines<R>,
+}
+
+impl<R: BufRead> Events<R> {
+    fn new(reader: R) -> Events<R> {
+        Events {
+            lines: reader.lines(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Events<R> {
+    type Item = io::Result<(String, String)>;
+
+    fn next(&mut self) -> Option<io::Result<(String, String)>> {
+        let mut name = None;
+        let mut data: Option<String> = None;
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err)),
+            };
+            if line.is_empty() {
+                match data {
+                    Some(data) => {
+                        let name = name.unwrap_or_else(|| "message".to_owned());
+                        return Some(Ok((name, data)));
+                    }
+                    None => {
+                        name = None;
+                        continue;
+                    }
+                }
+            }
+            let (field, value) = match line.find(':') {
+                // comments, e.g. keep alives
+                Some(0) => continue,
+                Some(colon) => {
+                    let value = &line[colon + 1..];
+                    (&line[..colon], value.trim_start_matches(' '))
+                }
+                None => (&line[..], ""),
+            };
+            match field {
+                "event" => name = Some(value.to_owned()),
+                "data" => match data {
+                    Some(ref mut data) => {
+                        data.push('\n');
+                        data.push_str(value)
+                    }
+                    None => data = Some(value.to_owned()),
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto;
+    use hex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn smee_events() {
+        let stream = ": keep alive\n\nevent: ready\ndata: {}\n\ndata: {\"a\":\ndata: 1}\n\n";
+        let events = Events::new(stream.as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                ("ready".to_owned(), "{}".to_owned()),
+                ("message".to_owned(), "{\"a\":\n1}".to_owned()),
+            ],
+            events
+        );
+
+        let body = serde_json::to_string(
+            &serde_json::from_str::<Value>(include_str!("../data/public.json")).unwrap(),
+        )
+        .unwrap();
+        let signature = hex::encode(crypto::sign_hmac_sha1(b"secret", body.as_bytes()));
+        let data = format!(
+            r#"{{"x-github-event":"public","x-github-delivery":"id","x-hub-signature":"sha1={}","body":{},"timestamp":1}}"#,
+            signature, body
+        );
+        let verified = Arc::new(AtomicUsize::new(0));
+        let counter = verified.clone();
+        let mut hub = Hub::new();
+        hub.handle_authenticated("public", "secret", move |_: &Delivery| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(deliver(&hub, &data));
+        assert_eq!(1, verified.load(Ordering::SeqCst));
+        assert!(!deliver(&hub, "{}"));
+    }
+}
@@ -0,0 +1,21 @@
+//! State shared by a hub's hooks
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// the states registered with a hub, by type
+#[derive(Debug, Clone, Default)]
+pub struct States(HashMap<TypeId, Arc<Any + Send + Sync>>);
+
+impl States {
+    pub fn insert<T: Any + Send + Sync>(&mut self, state: T) {
+        self.0.insert(TypeId::of::<T>(), Arc::new(state));
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.0
+            .get(&TypeId::of::<T>())
+            .and_then(|state| state.clone().downcast().ok())
+    }
+}
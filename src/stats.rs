@@ -0,0 +1,386 @@
+//! Counts of recent deliveries by event, repository and outcome
+//!
+//! Hubs count the deliveries they saw over a rolling window, the last day
+//! unless `Hub::stats_window` says otherwise, which `Hub::stats` reports,
+//! so operators can tell which repositories generate load and which fail.
+//! Only verified deliveries are counted by event and repository, since
+//! anyone can name any event and repository in a delivery. The rest are
+//! counted together as `unverified`, as are failures which happen before a
+//! delivery could be read, e.g. hitting the in-flight limit. Past
+//! `MAX_KEYS` events or repositories, the rest are counted under `(other)`
+
+use super::{clock, Delivery};
+use serde_json;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+include!(concat!(env!("OUT_DIR"), "/stats.rs"));
+
+/// how often persisted statistics are written as deliveries complete
+const PERSIST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// how long deliveries are counted for by default
+const DEFAULT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// how many buckets a window's counts expire in
+const BUCKETS: u64 = 24;
+
+/// how many events, and how many repositories, are counted by name
+const MAX_KEYS: usize = 1024;
+
+/// what events and repositories past `MAX_KEYS` are counted as
+const OTHER: &str = "(other)";
+
+/// how many deliveries being dispatched are tracked for failures
+const MAX_DISPATCHING: usize = 4096;
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.received += other.received;
+        self.verified += other.verified;
+        self.failed += other.failed;
+    }
+}
+
+impl Stats {
+    /// the counts of all deliveries together, verified or not
+    pub fn total(&self) -> Counts {
+        let mut total = self.unverified.clone();
+        for counts in self.events.values() {
+            total.add(counts)
+        }
+        total
+    }
+
+    /// reads statistics written with `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Stats> {
+        serde_json::from_reader(File::open(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+    }
+
+    /// writes these statistics to a file as json, replacing
+    /// it only once they have been written in full
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        let partial = path.with_extension("partial");
+        fs::write(&partial, json)?;
+        fs::rename(&partial, path)
+    }
+
+    fn count<F>(&mut self, event: &str, repository: Option<&str>, outcome: F)
+    where
+        F: Fn(&mut Counts),
+    {
+        outcome(counts(&mut self.events, event));
+        if let Some(repository) = repository {
+            outcome(counts(&mut self.repositories, repository));
+        }
+    }
+
+    fn merge(&mut self, other: &Stats) {
+        for (event, other) in &other.events {
+            counts(&mut self.events, event).add(other)
+        }
+        for (repository, other) in &other.repositories {
+            counts(&mut self.repositories, repository).add(other)
+        }
+        self.unverified.add(&other.unverified);
+    }
+}
+
+/// the counts for `key`, or for `OTHER` once `MAX_KEYS` other keys are counted
+fn counts<'a>(keyed: &'a mut BTreeMap<String, Counts>, key: &str) -> &'a mut Counts {
+    let key = if keyed.len() >= MAX_KEYS && !keyed.contains_key(key) {
+        OTHER
+    } else {
+        key
+    };
+    keyed.entry(key.to_owned()).or_insert_with(Counts::default)
+}
+
+/// time elapsed since the unix epoch
+fn now() -> Duration {
+    clock::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// statistics counted over a rolling window, in buckets which expire
+/// as a whole once they fall out of it. statistics restored from a file
+/// expire together, with the oldest delivery they counted
+struct Window {
+    length: Duration,
+    buckets: VecDeque<Stats>,
+}
+
+impl Default for Window {
+    fn default() -> Window {
+        Window {
+            length: DEFAULT_WINDOW,
+            buckets: VecDeque::new(),
+        }
+    }
+}
+
+impl Window {
+    /// how many seconds each bucket counts deliveries for
+    fn bucket(&self) -> u64 {
+        cmp::max(self.length.as_secs() / BUCKETS, 1)
+    }
+
+    fn expire(&mut self, now: Duration) {
+        let span = Duration::from_secs(self.bucket()) + self.length;
+        while self
+            .buckets
+            .front()
+            .map_or(false, |stats| stats.since + span <= now)
+        {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// the bucket deliveries are counted in at `now`
+    fn current(&mut self, now: Duration) -> &mut Stats {
+        self.expire(now);
+        let start = Duration::from_secs(now.as_secs() - now.as_secs() % self.bucket());
+        if self
+            .buckets
+            .back()
+            .map_or(true, |stats| stats.since < start)
+        {
+            self.buckets.push_back(Stats {
+                since: start,
+                ..Stats::default()
+            });
+        }
+        self.buckets.back_mut().unwrap()
+    }
+
+    fn snapshot(&mut self, now: Duration) -> Stats {
+        self.expire(now);
+        let mut snapshot = Stats {
+            since: self.buckets.front().map_or(now, |stats| stats.since),
+            ..Stats::default()
+        };
+        for stats in &self.buckets {
+            snapshot.merge(stats)
+        }
+        snapshot
+    }
+}
+
+/// where statistics are persisted, and when they last were
+struct Persisted {
+    path: PathBuf,
+    saved: Option<Instant>,
+}
+
+/// Records the statistics of a hub as its observers are notified
+#[derive(Default)]
+pub struct Recorder {
+    window: Mutex<Window>,
+    /// deliveries being dispatched, and whether they failed
+    dispatching: Mutex<HashMap<String, bool>>,
+    persisted: Mutex<Option<Persisted>>,
+}
+
+impl Recorder {
+    pub fn snapshot(&self) -> Stats {
+        self.window.lock().unwrap().snapshot(now())
+    }
+
+    /// counts deliveries over the last `length` from now on
+    pub fn window(&self, length: Duration) {
+        self.window.lock().unwrap().length = length
+    }
+
+    /// continues counting from the statistics persisted at `path`, if
+    /// any, and persists them there from now on
+    pub fn persist(&self, path: PathBuf) -> io::Result<()> {
+        match Stats::load(&path) {
+            Ok(stats) => {
+                let mut window = self.window.lock().unwrap();
+                window.buckets.clear();
+                window.buckets.push_back(stats);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        *self.persisted.lock().unwrap() = Some(Persisted {
+            path: path,
+            saved: clock::instant(),
+        });
+        Ok(())
+    }
+
+    /// writes the statistics to where they're persisted, if anywhere
+    pub fn save(&self) {
+        if let Some(ref mut persisted) = *self.persisted.lock().unwrap() {
+            if let Err(err) = self.snapshot().save(&persisted.path) {
+                warn!(
+                    "failed to persist statistics to {}: {}",
+                    persisted.path.display(),
+                    err
+                );
+            }
+            persisted.saved = clock::instant();
+        }
+    }
+
+    /// tracks a delivery until it completes, when it's counted
+    /// as whether or not it was verified by then
+    pub fn received(&self, delivery: &Delivery) {
+        let mut dispatching = self.dispatching.lock().unwrap();
+        if dispatching.len() < MAX_DISPATCHING {
+            dispatching.insert(delivery.id.to_owned(), false);
+        }
+    }
+
+    /// counts a failure, once per delivery
+    pub fn failed(&self, id: &str) {
+        match self.dispatching.lock().unwrap().get_mut(id) {
+            Some(failed) => *failed = true,
+            None => {
+                let mut window = self.window.lock().unwrap();
+                window.current(now()).unverified.failed += 1
+            }
+        }
+    }
+
+    pub fn completed(&self, delivery: &Delivery) {
+        let failed = self
+            .dispatching
+            .lock()
+            .unwrap()
+            .remove(delivery.id)
+            .unwrap_or(false);
+        {
+            let mut window = self.window.lock().unwrap();
+            let stats = window.current(now());
+            if delivery.is_verified() {
+                stats.count(delivery.event, delivery.repository(), |counts| {
+                    counts.received += 1;
+                    counts.verified += 1;
+                    if failed {
+                        counts.failed += 1
+                    }
+                });
+            } else {
+                stats.unverified.received += 1;
+                if failed {
+                    stats.unverified.failed += 1
+                }
+            }
+        }
+        let due = match *self.persisted.lock().unwrap() {
+            Some(ref persisted) => {
+                persisted.saved.is_some() && clock::since(persisted.saved) >= PERSIST_INTERVAL
+            }
+            None => false,
+        };
+        if due {
+            self.save()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Hub, TokenAuthHook};
+    use super::*;
+    use std::env;
+    use std::process;
+
+    const PUBLIC: &str = include_str!("../data/public.json");
+
+    #[test]
+    fn stats_counts() {
+        let mut hub = Hub::new();
+        hub.handle("public", TokenAuthHook::bearer("token", |_: &Delivery| {}));
+        for &(id, token) in &[("verified", "token"), ("rejected", "wrong")] {
+            let headers = vec![("Authorization".to_owned(), format!("Bearer {}", token))];
+            let mut delivery = Delivery::new(id, "public", PUBLIC, None);
+            delivery.headers = &headers;
+            hub.deliver(&mut delivery);
+        }
+        hub.deliver(&mut Delivery::new("pushed", "push", "{}", None));
+
+        let stats = hub.stats();
+        let counts = Counts {
+            received: 1,
+            verified: 1,
+            failed: 0,
+        };
+        assert_eq!(Some(&counts), stats.events.get("public"));
+        assert_eq!(
+            Some(&counts),
+            stats.repositories.get("Codertocat/Hello-World")
+        );
+        assert_eq!(None, stats.events.get("push"));
+        let unverified = Counts {
+            received: 1,
+            verified: 0,
+            failed: 1,
+        };
+        assert_eq!(unverified, stats.unverified);
+        let total = Counts {
+            received: 2,
+            verified: 1,
+            failed: 1,
+        };
+        assert_eq!(total, stats.total());
+
+        let path = env::temp_dir().join(format!("afterparty-stats-{}.json", process::id()));
+        stats.save(&path).unwrap();
+        let mut restarted = Hub::new();
+        restarted.persist_stats(&path).unwrap();
+        assert_eq!(stats, restarted.stats());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stats_window() {
+        let mut window = Window {
+            length: Duration::from_secs(60),
+            buckets: VecDeque::new(),
+        };
+        let start = Duration::from_secs(1_000_000);
+        window
+            .current(start)
+            .count("public", None, |counts| counts.received += 1);
+        window
+            .current(start + Duration::from_secs(30))
+            .unverified
+            .received += 1;
+        let stats = window.snapshot(start + Duration::from_secs(59));
+        assert_eq!(2, stats.total().received);
+        assert_eq!(start, stats.since);
+
+        let stats = window.snapshot(start + Duration::from_secs(90));
+        assert_eq!(None, stats.events.get("public"));
+        assert_eq!(1, stats.total().received);
+        assert_eq!(start + Duration::from_secs(30), stats.since);
+        assert_eq!(
+            0,
+            window
+                .snapshot(start + Duration::from_secs(200))
+                .total()
+                .received
+        );
+
+        let mut stats = Stats::default();
+        for n in 0..MAX_KEYS + 10 {
+            stats.count(&format!("event-{}", n), None, |counts| counts.received += 1);
+        }
+        assert_eq!(MAX_KEYS + 1, stats.events.len());
+        assert_eq!(
+            Some(10),
+            stats.events.get(OTHER).map(|counts| counts.received)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Reporting the outcome of work on a commit as a commit status
+//!
+//! A `CommitStatus` marks the head commit of a push, pull request or merge
+//! queue group pending as soon as it's received, runs a closure on a thread
+//! of its own and then marks the commit with the closure's outcome, the
+//! classic status check
+
+use super::extract::FromDelivery;
+use super::github::Client;
+use super::{checks, Delivery, Hook, OwnedDelivery};
+use serde_json::Value;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+/// Github cuts descriptions of statuses short after this many characters
+const DESCRIPTION_LIMIT: usize = 140;
+
+/// A hook running a check on the head commit of pushes and pull requests,
+/// reporting it as a commit status. the closure succeeds with `Ok` and fails
+/// with `Err`, describing its outcome, and is handed a client of the hub's
+/// `GitHub`
+pub struct CommitStatus<F> {
+    context: String,
+    target_url: Option<String>,
+    check: Arc<F>,
+}
+
+impl<F> CommitStatus<F>
+where
+    F: Fn(&Delivery, &Client) -> Result<String, String> + Send + Sync + 'static,
+{
+    /// runs `check`, reporting it as the status of `context`, e.g. `ci/tests`
+    pub fn new<C: Into<String>>(context: C, check: F) -> CommitStatus<F> {
+        CommitStatus {
+            context: context.into(),
+            target_url: None,
+            check: Arc::new(check),
+        }
+    }
+
+    /// links statuses to a page with details, e.g. of the build
+    pub fn target_url<U: Into<String>>(mut self, url: U) -> CommitStatus<F> {
+        self.target_url = Some(url.into());
+        self
+    }
+
+    /// the status of the commit, in one of Github's states
+    fn status(&self, state: &str, description: &str) -> Value {
+        json!({
+            "state": state,
+            "context": self.context,
+            "description": description.chars().take(DESCRIPTION_LIMIT).collect::<String>(),
+            "target_url": self.target_url,
+        })
+    }
+}
+
+impl<F> Hook for CommitStatus<F>
+where
+    F: Fn(&Delivery, &Client) -> Result<String, String> + Send + Sync + 'static,
+{
+    fn handle(&self, delivery: &Delivery) {
+        let sha = match head_sha(delivery) {
+            Some(sha) => sha,
+            None => return,
+        };
+        let client = match Client::from_delivery(delivery) {
+            Some(client) => client,
+            None => return,
+        };
+        let statuses = match client.repo_path(&format!("statuses/{}", sha)) {
+            Some(statuses) => statuses,
+            None => return,
+        };
+        if let Err(err) = client.post(&statuses, &self.status("pending", "in progress")) {
+            log_delivery!(error, delivery; "failed to set status {}: {}", self.context, err);
+            return;
+        }
+        let status = CommitStatus {
+            context: self.context.clone(),
+            target_url: self.target_url.clone(),
+            check: self.check.clone(),
+        };
+        let owned = OwnedDelivery::from(delivery);
+        thread::spawn(move || {
+            let delivery = owned.delivery();
+            let concluded =
+                panic::catch_unwind(AssertUnwindSafe(|| (status.check)(&delivery, &client)));
+            let reported = match concluded {
+                Ok(Ok(description)) => status.status("success", &description),
+                Ok(Err(description)) => status.status("failure", &description),
+                Err(_) => status.status("error", "the check panicked"),
+            };
+            if let Err(err) = client.post(&statuses, &reported) {
+                log_delivery!(error, &delivery; "failed to set status {}: {}", status.context, err);
+            }
+        });
+    }
+}
+
+/// the commit a delivery asks to be checked, that of pushes creating or
+/// updating a ref, of pull requests opened or updated and of merge queue
+/// groups requesting checks
+fn head_sha(delivery: &Delivery) -> Option<String> {
+    if delivery.event != "pull_request" {
+        return checks::head_sha(delivery)
+            .filter(|_| delivery.event == "push" || delivery.event == "merge_group");
+    }
+    let payload = delivery.json()?;
+    match payload.get("action").and_then(Value::as_str) {
+        Some("opened") | Some("synchronize") | Some("reopened") => payload
+            .pointer("/pull_request/head/sha")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_statuses() {
+        let delivery = |event, payload| Delivery::new("id", event, payload, None);
+        assert_eq!(
+            Some("6dcb09b5".to_owned()),
+            head_sha(&delivery(
+                "pull_request",
+                r#"{"action":"synchronize","pull_request":{"head":{"sha":"6dcb09b5"}}}"#
+            ))
+        );
+        assert_eq!(
+            None,
+            head_sha(&delivery(
+                "pull_request",
+                r#"{"action":"closed","pull_request":{"head":{"sha":"6dcb09b5"}}}"#
+            ))
+        );
+
+        let status = CommitStatus::new("ci/tests", |_: &Delivery, _: &Client| Ok(String::new()))
+            .target_url("https://ci.example.com/builds/1");
+        let reported = status.status("failure", &"x".repeat(200));
+        assert_eq!(
+            Some("failure"),
+            reported.get("state").and_then(Value::as_str)
+        );
+        assert_eq!(
+            Some("ci/tests"),
+            reported.get("context").and_then(Value::as_str)
+        );
+        assert_eq!(
+            Some(140),
+            reported
+                .get("description")
+                .and_then(Value::as_str)
+                .map(str::len)
+        );
+    }
+}
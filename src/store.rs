@@ -0,0 +1,436 @@
+//! Keeping deliveries in a file
+//!
+//! A `Store` appends each delivery it handles to a file as a line of json,
+//! the serialized `OwnedDelivery`, so the file is itself newline delimited
+//! json which analytics tools can read, and may be exported from with
+//! `Store::export`
+//!
+//! Stores may prune deliveries older than their retention, and the oldest
+//! deliveries once the file outgrows its maximum size. Stores encrypting
+//! deliveries write each as a json string of the base64 encoded, sealed
+//! delivery instead, as payloads can tell a lot about private repositories
+
+use super::{clock, Delivery, Export, Hook, OwnedDelivery};
+use serde_json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "server")]
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+#[cfg(feature = "server")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+/// number of deliveries buffered for a follower which
+/// isn't keeping up before further ones are dropped
+#[cfg(feature = "server")]
+const BACKLOG: usize = 64;
+
+/// how often stores with a retention prune expired deliveries
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A hook appending deliveries to a file
+pub struct Store {
+    path: PathBuf,
+    file: Mutex<File>,
+    retention: Option<Duration>,
+    max_size: Option<u64>,
+    key: Option<Zeroizing<Vec<u8>>>,
+    pruned: Mutex<SystemTime>,
+    #[cfg(feature = "server")]
+    followers: Mutex<Vec<SyncSender<Arc<String>>>>,
+}
+
+impl Store {
+    /// stores deliveries in the file at `path`,
+    /// after those already stored there
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Store> {
+        let file = private().create(true).append(true).open(path.as_ref())?;
+        Ok(Store {
+            path: path.as_ref().to_owned(),
+            file: Mutex::new(file),
+            retention: None,
+            max_size: None,
+            key: None,
+            pruned: Mutex::new(UNIX_EPOCH),
+            #[cfg(feature = "server")]
+            followers: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// prunes deliveries received longer than `retention` ago
+    pub fn retain(mut self, retention: Duration) -> Store {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// prunes the oldest deliveries once the file grows past `bytes`
+    pub fn max_size(mut self, bytes: u64) -> Store {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// encrypts deliveries stored from now on with AES-256-GCM under `key`,
+    /// which has to be 32 bytes. deliveries stored in plain text before
+    /// remain readable. sealing requires ring, so this fails with the
+    /// `rustcrypto` backend and on wasm32
+    pub fn encrypt(mut self, key: &[u8]) -> io::Result<Store> {
+        if !sealing::SUPPORTED {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "encrypting stores requires the ring backend",
+            ));
+        }
+        if key.len() != 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "store encryption keys are 32 bytes",
+            ));
+        }
+        self.key = Some(Zeroizing::new(key.to_vec()));
+        Ok(self)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// removes expired deliveries and, past the maximum size, the oldest
+    /// ones, returning how many were removed. lines which can't be read as
+    /// deliveries only count towards the size
+    pub fn prune(&self) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        let now = clock::now();
+        *self.pruned.lock().unwrap() = now;
+        let received = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut kept = Vec::new();
+        let mut pruned = 0;
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line = line?;
+            let expired = match (self.retention, self.delivery(&line)) {
+                (Some(retention), Some(owned)) => owned.received_at + retention < received,
+                _ => false,
+            };
+            if expired {
+                pruned += 1;
+            } else {
+                kept.push(line);
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            let mut size = kept.iter().map(|line| line.len() as u64 + 1).sum::<u64>();
+            let oldest = kept
+                .iter()
+                .take_while(|line| {
+                    let over = size > max_size;
+                    size -= line.len() as u64 + 1;
+                    over
+                })
+                .count();
+            kept.drain(..oldest);
+            pruned += oldest;
+        }
+        if pruned > 0 {
+            let partial = self.path.with_extension("partial");
+            let mut lines = kept.join("\n");
+            if !lines.is_empty() {
+                lines.push('\n');
+            }
+            private()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&partial)?
+                .write_all(lines.as_bytes())?;
+            fs::rename(&partial, &self.path)?;
+            *file = OpenOptions::new().append(true).open(&self.path)?;
+        }
+        Ok(pruned)
+    }
+
+    /// writes the stored deliveries `export` matches to `out`, as newline
+    /// delimited json, returning how many there were. lines which
+    /// aren't deliveries, e.g. one cut short by a crash, are skipped
+    pub fn export<W: Write>(&self, export: &Export, out: &mut W) -> io::Result<usize> {
+        let mut exported = 0;
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line = match self.plain(line?) {
+                Some(line) => line,
+                None => continue,
+            };
+            if matches(export, &line) {
+                out.write_all(line.as_bytes())?;
+                out.write_all(b"\n")?;
+                exported += 1;
+            }
+        }
+        Ok(exported)
+    }
+
+    /// the lines of deliveries stored from now on
+    #[cfg(feature = "server")]
+    pub fn follow(&self) -> Receiver<Arc<String>> {
+        let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+        self.followers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// the stored delivery of a line
+    fn delivery(&self, line: &str) -> Option<OwnedDelivery> {
+        self.plain(line.to_owned())
+            .and_then(|line| serde_json::from_str(&line).ok())
+    }
+
+    /// the line a delivery is stored as, sealed when the store encrypts
+    fn stored(&self, line: &str) -> Option<String> {
+        match self.key {
+            Some(ref key) => sealing::seal(key, line),
+            None => Some(line.to_owned()),
+        }
+    }
+
+    /// the json of a stored line, opening it when it's sealed
+    fn plain(&self, line: String) -> Option<String> {
+        if !line.starts_with('"') {
+            return Some(line);
+        }
+        sealing::open(self.key.as_ref()?, &line)
+    }
+
+    /// whether a store of limited retention or size is due to be pruned
+    fn due(&self, file: &File) -> bool {
+        let oversized = match (self.max_size, file.metadata()) {
+            (Some(max_size), Ok(metadata)) => metadata.len() > max_size,
+            _ => false,
+        };
+        let expiring = self.retention.is_some()
+            && clock::now()
+                .duration_since(*self.pruned.lock().unwrap())
+                .map(|since| since >= PRUNE_INTERVAL)
+                .unwrap_or(false);
+        oversized || expiring
+    }
+}
+
+impl Hook for Store {
+    fn handle(&self, delivery: &Delivery) {
+        let line = match serde_json::to_string(delivery) {
+            Ok(line) => line,
+            Err(err) => {
+                log_delivery!(
+                    error,
+                    delivery;
+                    "failed to serialize delivery {}: {}",
+                    delivery.id,
+                    err
+                );
+                return;
+            }
+        };
+        let sealed = match self.stored(&line) {
+            Some(sealed) => sealed,
+            None => {
+                log_delivery!(error, delivery; "failed to encrypt delivery {}", delivery.id);
+                return;
+            }
+        };
+        let (stored, due) = {
+            let mut file = self.file.lock().unwrap();
+            let stored = file
+                .write_all(format!("{}\n", sealed).as_bytes())
+                .and_then(|_| file.flush());
+            (stored, self.due(&file))
+        };
+        if due {
+            if let Err(err) = self.prune() {
+                log_delivery!(error, delivery; "failed to prune {}: {}", self.path.display(), err);
+            }
+        }
+        if let Err(err) = stored {
+            log_delivery!(
+                error,
+                delivery;
+                "failed to store delivery {} in {}: {}",
+                delivery.id,
+                self.path.display(),
+                err
+            );
+        }
+        #[cfg(feature = "server")]
+        {
+            let line = Arc::new(line);
+            self.followers.lock().unwrap().retain(|follower| {
+                match follower.try_send(line.clone()) {
+                    Err(TrySendError::Disconnected(_)) => false,
+                    _ => true,
+                }
+            });
+        }
+    }
+}
+
+#[cfg(all(
+    feature = "ring",
+    not(feature = "rustcrypto"),
+    not(target_arch = "wasm32")
+))]
+mod sealing {
+    use super::super::crypto;
+    use base64;
+    use serde_json;
+
+    pub const SUPPORTED: bool = true;
+
+    /// seals a line as a json string of its base64 encoded ciphertext
+    pub fn seal(key: &[u8], line: &str) -> Option<String> {
+        crypto::seal(key, line.as_bytes())
+            .and_then(|sealed| serde_json::to_string(&base64::encode(&sealed)).ok())
+    }
+
+    /// opens a line `seal` sealed
+    pub fn open(key: &[u8], line: &str) -> Option<String> {
+        let sealed = serde_json::from_str::<String>(line).ok()?;
+        let sealed = base64::decode(&sealed).ok()?;
+        crypto::open(key, &sealed).and_then(|plain| String::from_utf8(plain).ok())
+    }
+}
+
+/// without ring there's nothing to seal with, and stores can't be encrypted
+#[cfg(not(all(
+    feature = "ring",
+    not(feature = "rustcrypto"),
+    not(target_arch = "wasm32")
+)))]
+mod sealing {
+    pub const SUPPORTED: bool = false;
+
+    pub fn seal(_: &[u8], _: &str) -> Option<String> {
+        None
+    }
+
+    pub fn open(_: &[u8], _: &str) -> Option<String> {
+        None
+    }
+}
+
+/// options opening files deliveries are kept in, which are created
+/// only readable by their owner as payloads may be private
+pub fn private() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+/// whether a stored line is a delivery `export` matches
+pub fn matches(export: &Export, line: &str) -> bool {
+    serde_json::from_str::<OwnedDelivery>(line)
+        .map(|owned| export.matches(&owned.delivery()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn store_deliveries() {
+        let path = env::temp_dir().join(format!("afterparty-store-{}.ndjson", process::id()));
+        let _ = fs::remove_file(&path);
+        let store = Store::open(&path).unwrap();
+        let public = include_str!("../data/public.json");
+        store.handle(&Delivery::new("public", "public", public, None));
+        store.handle(&Delivery::new("ping", "ping", "{}", None));
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(b"{\"id\": cut short"))
+            .unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(2, store.export(&Export::new(), &mut out).unwrap());
+        let mut out = Vec::new();
+        let export = Export::new().repo("Codertocat/Hello-World");
+        assert_eq!(1, store.export(&export, &mut out).unwrap());
+        let exported = serde_json::from_slice::<OwnedDelivery>(&out[..out.len() - 1]).unwrap();
+        assert_eq!("public", exported.id);
+        assert_eq!(public, exported.unparsed_payload);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prune_deliveries() {
+        let path = env::temp_dir().join(format!("afterparty-prune-{}.ndjson", process::id()));
+        let _ = fs::remove_file(&path);
+        let store = Store::open(&path)
+            .unwrap()
+            .retain(Duration::from_secs(3600));
+        let mut expired = Delivery::new("expired", "ping", "{}", None);
+        expired.received_at = UNIX_EPOCH + Duration::from_secs(1);
+        store.handle(&expired);
+        store.handle(&Delivery::new("fresh", "ping", "{}", None));
+        let mut out = Vec::new();
+        assert_eq!(1, store.export(&Export::new(), &mut out).unwrap());
+
+        let size = fs::metadata(&path).unwrap().len();
+        let store = Store::open(&path).unwrap().max_size(size + 10);
+        store.handle(&Delivery::new("latest", "ping", "{}", None));
+        let mut out = Vec::new();
+        assert_eq!(1, store.export(&Export::new(), &mut out).unwrap());
+        let exported = serde_json::from_slice::<OwnedDelivery>(&out[..out.len() - 1]).unwrap();
+        assert_eq!("latest", exported.id);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(all(
+        feature = "ring",
+        not(feature = "rustcrypto"),
+        not(target_arch = "wasm32")
+    ))]
+    #[test]
+    fn encrypted_deliveries() {
+        let path = env::temp_dir().join(format!("afterparty-sealed-{}.ndjson", process::id()));
+        let _ = fs::remove_file(&path);
+        let store = Store::open(&path).unwrap().encrypt(&[7; 32]).unwrap();
+        let public = include_str!("../data/public.json");
+        store.handle(&Delivery::new("public", "public", public, None));
+        assert!(!fs::read_to_string(&path).unwrap().contains("Codertocat"));
+
+        let mut out = Vec::new();
+        let export = Export::new().repo("Codertocat/Hello-World");
+        assert_eq!(1, store.export(&export, &mut out).unwrap());
+        let exported = serde_json::from_slice::<OwnedDelivery>(&out[..out.len() - 1]).unwrap();
+        assert_eq!(public, exported.unparsed_payload);
+
+        let mistaken = Store::open(&path).unwrap().encrypt(&[8; 32]).unwrap();
+        assert_eq!(0, mistaken.export(&export, &mut Vec::new()).unwrap());
+        assert!(Store::open(&path).unwrap().encrypt(&[7; 16]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(not(all(
+        feature = "ring",
+        not(feature = "rustcrypto"),
+        not(target_arch = "wasm32")
+    )))]
+    #[test]
+    fn unsupported_encryption() {
+        let path = env::temp_dir().join(format!("afterparty-unsealed-{}.ndjson", process::id()));
+        assert!(Store::open(&path).unwrap().encrypt(&[7; 32]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+//! Socket activation by systemd
+//!
+//! systemd may bind a hub's listening socket itself, e.g. to a privileged
+//! port without running the hub as root, and pass it to the hub's process
+//! when it starts, keeping it open and connections queued across restarts.
+//! `listeners` takes the sockets passed, like `sd_listen_fds`, and
+//! `Hub::run_activated` serves on the first of them.
+//!
+//! Taking the sockets unsets the environment variables passing them, which
+//! races with other threads reading the environment, so either must happen
+//! before the process starts any threads, e.g. first thing in `main`, with
+//! the sockets taken handed to `Hub::run_listeners` later
+//!
+//! ```ini
+//! # hooks.socket
+//! [Socket]
+//! ListenStream=443
+//!
+//! # hooks.service
+//! [Service]
+//! ExecStart=/usr/local/bin/hooks
+//! ```
+
+use libc;
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process;
+
+/// the first file descriptor systemd passes
+const LISTEN_FDS_START: RawFd = 3;
+
+/// the most sockets taken, far more than units pass
+const MAX_LISTEN_FDS: usize = 1024;
+
+/// takes the listening sockets systemd passed this process, if it passed any.
+/// the variables passing them are unset, and the sockets closed on exec, so
+/// child processes don't take them too, and later calls return none. call it
+/// before starting any threads, see the module's documentation
+pub fn listeners() -> Vec<TcpListener> {
+    let passed = passed(
+        env::var("LISTEN_PID").ok().as_ref().map(String::as_str),
+        env::var("LISTEN_FDS").ok().as_ref().map(String::as_str),
+        process::id(),
+    );
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    (0..passed)
+        .map(|fd| LISTEN_FDS_START + fd as RawFd)
+        .filter(|&fd| match close_on_exec(fd) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("ignoring socket {} passed by systemd: {}", fd, err);
+                false
+            }
+        })
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+/// the first socket systemd passed, failing when it passed none. like
+/// `listeners`, call it before starting any threads
+pub fn listener() -> io::Result<TcpListener> {
+    listeners()
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no sockets were passed by systemd"))
+}
+
+/// the number of sockets passed to the process `pid`
+fn passed(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    match (
+        listen_pid.and_then(|listen| listen.parse::<u32>().ok()),
+        listen_fds,
+    ) {
+        (Some(listen), Some(fds)) if listen == pid => match fds.parse() {
+            Ok(fds) if fds <= MAX_LISTEN_FDS => fds,
+            Ok(fds) => {
+                warn!("ignoring {} sockets passed by systemd, too many", fds);
+                0
+            }
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// keeps child processes from inheriting a file descriptor,
+/// failing when it isn't open
+fn close_on_exec(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::ServerConfig;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+    use Hub;
+
+    #[test]
+    fn systemd_sockets() {
+        assert_eq!(2, passed(Some("42"), Some("2"), 42));
+        assert_eq!(0, passed(Some("41"), Some("2"), 42));
+        assert_eq!(0, passed(None, Some("2"), 42));
+        assert_eq!(0, passed(Some("42"), Some("two"), 42));
+        assert_eq!(0, passed(Some("42"), Some("2147483647"), 42));
+        assert!(close_on_exec(-1).is_err());
+        assert!(listeners().is_empty());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        close_on_exec(listener.as_raw_fd()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = Hub::new()
+            .run_listener(listener, ServerConfig::new())
+            .unwrap();
+        assert_eq!(addr, handle.addr());
+        handle.stop(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,478 @@
+//! TLS termination for hub listeners, optionally requiring client certificates
+//!
+//! A `Tls` value implements hyper's `SslServer` so it may be handed to
+//! `hyper::Server::https`. When configured with a client CA, connections
+//! without a certificate signed by that CA are rejected during the handshake
+//! and the verified peer's identity is made available as `Delivery::peer`.
+//!
+//! `Tls::sni` serves several hostnames from one listener, choosing each
+//! connection's certificate by the hostname its client asked for and
+//! reloading certificates whose files changed, which it checks once a second.
+//!
+//! A `TlsClient` implements hyper's `SslClient` for making https requests,
+//! verifying servers against the system's trusted certificates.
+
+use super::PeerIdentity;
+use hex;
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslClient, SslServer};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::ssl::{
+    HandshakeError, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslConnector, SslContext,
+    SslFiletype, SslMethod, SslStream, SslVerifyMode,
+};
+use openssl::x509::{X509NameRef, X509Ref};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// how often the files of certificates served by hostname are checked for
+/// changes, rather than on every handshake
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server side TLS configuration
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: Arc<SslAcceptor>,
+}
+
+impl Tls {
+    /// creates a new TLS configuration from PEM encoded
+    /// certificate chain and private key files
+    pub fn new<C, K>(cert_chain: C, key: K) -> Result<Tls, ErrorStack>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        Ok(Tls {
+            acceptor: Arc::new(acceptor(cert_chain.as_ref(), key.as_ref())?.build()),
+        })
+    }
+
+    /// creates a new TLS configuration which requires clients
+    /// to present a certificate signed by one of the PEM encoded
+    /// certificate authorities in `client_ca`
+    pub fn mutual<C, K, A>(cert_chain: C, key: K, client_ca: A) -> Result<Tls, ErrorStack>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+        A: AsRef<Path>,
+    {
+        let mut builder = acceptor(cert_chain.as_ref(), key.as_ref())?;
+        builder.set_ca_file(client_ca)?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        Ok(Tls {
+            acceptor: Arc::new(builder.build()),
+        })
+    }
+
+    /// creates a new TLS configuration serving each connection the
+    /// certificate of the hostname its client asked for. hubs dropping
+    /// privileges should name the certificates' files with `ServerConfig::reads`
+    pub fn sni(certificates: Certificates) -> Result<Tls, ErrorStack> {
+        let mut builder = acceptor(&certificates.default.cert, &certificates.default.key)?;
+        builder.set_servername_callback(move |ssl, _| {
+            let host = ssl.servername(NameType::HOST_NAME).map(str::to_lowercase);
+            let context = certificates.select(host.as_ref().map(|host| &host[..]));
+            ssl.set_ssl_context(&context)
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+        Ok(Tls {
+            acceptor: Arc::new(builder.build()),
+        })
+    }
+}
+
+/// The certificates of the hostnames a listener serves, handed to `Tls::sni`
+pub struct Certificates {
+    default: Certificate,
+    hosts: HashMap<String, Certificate>,
+}
+
+impl Certificates {
+    /// served to clients which don't name a host, or name an unknown one
+    pub fn new<C, K>(cert_chain: C, key: K) -> Result<Certificates, ErrorStack>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        Ok(Certificates {
+            default: Certificate::load(cert_chain.as_ref(), key.as_ref())?,
+            hosts: HashMap::new(),
+        })
+    }
+
+    /// serves a certificate to clients asking for `host`, e.g.
+    /// `hooks.example.com`, or any subdomain of a `*.example.com` wildcard
+    pub fn host<H, C, K>(
+        mut self,
+        host: H,
+        cert_chain: C,
+        key: K,
+    ) -> Result<Certificates, ErrorStack>
+    where
+        H: AsRef<str>,
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let certificate = Certificate::load(cert_chain.as_ref(), key.as_ref())?;
+        self.hosts.insert(host.as_ref().to_lowercase(), certificate);
+        Ok(self)
+    }
+
+    fn select(&self, host: Option<&str>) -> SslContext {
+        host.and_then(|host| {
+            self.hosts.get(host).or_else(|| {
+                let parent = host.splitn(2, '.').nth(1)?;
+                self.hosts.get(&format!("*.{}", parent))
+            })
+        })
+        .unwrap_or(&self.default)
+        .context()
+    }
+}
+
+/// A certificate chain and key, reloaded when either file changes
+struct Certificate {
+    cert: PathBuf,
+    key: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+/// a certificate's context, when its files were modified
+/// as it was loaded, and when they were last checked
+struct Loaded {
+    modified: Option<SystemTime>,
+    checked: Instant,
+    context: SslContext,
+}
+
+impl Certificate {
+    fn load(cert: &Path, key: &Path) -> Result<Certificate, ErrorStack> {
+        let modified = modified(cert, key);
+        let context = acceptor(cert, key)?.build().into_context();
+        Ok(Certificate {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            loaded: Mutex::new(Loaded {
+                modified: modified,
+                checked: Instant::now(),
+                context: context,
+            }),
+        })
+    }
+
+    /// the certificate's context, reloading it when its files changed
+    /// since it was loaded, checking at most every `RELOAD_INTERVAL`. a
+    /// certificate which fails to reload, e.g. while it's only partially
+    /// written, is served until it's fixed
+    fn context(&self) -> SslContext {
+        let mut loaded = self
+            .loaded
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if loaded.checked.elapsed() < RELOAD_INTERVAL {
+            return loaded.context.clone();
+        }
+        loaded.checked = Instant::now();
+        let modified = modified(&self.cert, &self.key);
+        if modified.is_some() && modified != loaded.modified {
+            match acceptor(&self.cert, &self.key) {
+                Ok(builder) => {
+                    info!("reloaded certificate {}", self.cert.display());
+                    loaded.modified = modified;
+                    loaded.context = builder.build().into_context();
+                }
+                Err(err) => warn!(
+                    "failed to reload certificate {}: {}",
+                    self.cert.display(),
+                    err
+                ),
+            }
+        }
+        loaded.context.clone()
+    }
+}
+
+/// when the later of a certificate's files was last modified
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    modified(cert).max(modified(key))
+}
+
+fn acceptor(cert_chain: &Path, key: &Path) -> Result<SslAcceptorBuilder, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(cert_chain)?;
+    builder.check_private_key()?;
+    Ok(builder)
+}
+
+impl SslServer for Tls {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        match self.acceptor.accept(stream) {
+            Ok(stream) => {
+                let peer = stream.ssl().peer_certificate().map(|cert| identity(&cert));
+                Ok(TlsStream {
+                    stream: Arc::new(Mutex::new(stream)),
+                    peer: peer.map(Arc::new),
+                })
+            }
+            Err(HandshakeError::SetupFailure(e)) => Err(hyper::Error::Ssl(Box::new(e))),
+            Err(HandshakeError::Failure(s)) | Err(HandshakeError::WouldBlock(s)) => {
+                error!("tls handshake failed {}", s.error());
+                Err(hyper::Error::Ssl(Box::new(s.into_error())))
+            }
+        }
+    }
+}
+
+/// Client side TLS configuration
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: Arc<SslConnector>,
+}
+
+impl TlsClient {
+    /// creates a new TLS configuration verifying servers
+    /// against the system's trusted certificate authorities
+    pub fn new() -> Result<TlsClient, ErrorStack> {
+        let builder = SslConnector::builder(SslMethod::tls())?;
+        Ok(TlsClient {
+            connector: Arc::new(builder.build()),
+        })
+    }
+}
+
+impl SslClient for TlsClient {
+    type Stream = TlsStream;
+
+    fn wrap_client(&self, stream: HttpStream, host: &str) -> hyper::Result<TlsStream> {
+        match self.connector.connect(host, stream) {
+            Ok(stream) => Ok(TlsStream {
+                stream: Arc::new(Mutex::new(stream)),
+                peer: None,
+            }),
+            Err(HandshakeError::SetupFailure(e)) => Err(hyper::Error::Ssl(Box::new(e))),
+            Err(HandshakeError::Failure(s)) | Err(HandshakeError::WouldBlock(s)) => {
+                error!("tls handshake with {} failed {}", host, s.error());
+                Err(hyper::Error::Ssl(Box::new(s.into_error())))
+            }
+        }
+    }
+}
+
+/// A TLS protected connection
+#[derive(Clone)]
+pub struct TlsStream {
+    stream: Arc<Mutex<SslStream<HttpStream>>>,
+    peer: Option<Arc<PeerIdentity>>,
+}
+
+impl TlsStream {
+    /// returns the identity of the client's verified certificate, if any
+    pub fn peer(&self) -> Option<&PeerIdentity> {
+        self.peer.as_ref().map(|peer| &**peer)
+    }
+
+    fn lock(&self) -> MutexGuard<SslStream<HttpStream>> {
+        self.stream
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock().get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.lock().get_mut().close(how)
+    }
+}
+
+fn common_name(name: &X509NameRef) -> Option<String> {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|cn| cn.to_string())
+}
+
+fn identity(cert: &X509Ref) -> PeerIdentity {
+    PeerIdentity {
+        common_name: common_name(cert.subject_name()),
+        dns_names: cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.dnsname().map(|dns| dns.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        fingerprint: cert
+            .digest(MessageDigest::sha256())
+            .map(|digest| hex::encode(&*digest))
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::env;
+    use std::net::{TcpListener, TcpStream};
+    use std::process;
+    use std::thread;
+
+    fn self_signed(common_name: &str) -> (PKey<Private>, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("hooks.internal")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, builder.build())
+    }
+
+    #[test]
+    fn peer_identity_from_certificate() {
+        let (_, cert) = self_signed("ci.internal");
+        let peer = identity(&cert);
+        assert_eq!(Some("ci.internal".to_owned()), peer.common_name);
+        assert_eq!(vec!["hooks.internal".to_owned()], peer.dns_names);
+        assert_eq!(64, peer.fingerprint.len());
+    }
+
+    /// writes a certificate and key to files named after `name`
+    fn write(name: &str, common_name: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir();
+        let (key, cert) = self_signed(common_name);
+        let files = (
+            dir.join(format!("afterparty-{}-{}.crt", name, process::id())),
+            dir.join(format!("afterparty-{}-{}.key", name, process::id())),
+        );
+        fs::write(&files.0, cert.to_pem().unwrap()).unwrap();
+        fs::write(&files.1, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        files
+    }
+
+    /// the common name of the certificate served to a client asking for `host`
+    fn served(tls: &Tls, host: &str) -> Option<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tls.clone();
+        let accepted = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = server.wrap_server(HttpStream(stream));
+        });
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = connector
+            .build()
+            .connect(host, TcpStream::connect(addr).unwrap())
+            .unwrap();
+        let name = stream
+            .ssl()
+            .peer_certificate()
+            .and_then(|cert| common_name(cert.subject_name()));
+        drop(stream);
+        accepted.join().unwrap();
+        name
+    }
+
+    #[test]
+    fn certificates_by_sni() {
+        let default = write("sni-default", "default");
+        let a = write("sni-a", "hooks.a.example");
+        let wildcard = write("sni-b", "*.b.example");
+        let certificates = Certificates::new(&default.0, &default.1)
+            .unwrap()
+            .host("hooks.a.example", &a.0, &a.1)
+            .unwrap()
+            .host("*.b.example", &wildcard.0, &wildcard.1)
+            .unwrap();
+        let tls = Tls::sni(certificates).unwrap();
+        assert_eq!(
+            Some("hooks.a.example"),
+            served(&tls, "HOOKS.A.example").as_ref().map(|cn| &cn[..])
+        );
+        assert_eq!(
+            Some("*.b.example"),
+            served(&tls, "hooks.b.example").as_ref().map(|cn| &cn[..])
+        );
+        assert_eq!(
+            Some("default"),
+            served(&tls, "hooks.c.example").as_ref().map(|cn| &cn[..])
+        );
+
+        // renewed certificates are noticed by their files' modification
+        // time, so they're rewritten once it may tell them apart, as its
+        // resolution may be as coarse as a couple of seconds
+        thread::sleep(Duration::from_secs(2));
+        write("sni-a", "renewed.a.example");
+        thread::sleep(RELOAD_INTERVAL);
+        assert_eq!(
+            Some("renewed.a.example"),
+            served(&tls, "hooks.a.example").as_ref().map(|cn| &cn[..])
+        );
+
+        for files in &[default, a, wildcard] {
+            let _ = fs::remove_file(&files.0);
+            let _ = fs::remove_file(&files.1);
+        }
+    }
+}
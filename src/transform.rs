@@ -0,0 +1,90 @@
+//! Transforming payloads before hooks see them
+//!
+//! A `Transform` hook hands its inner hook a copy of each delivery with the
+//! payload its function produced, e.g. enriched with computed fields or
+//! normalized across providers. The payload as it was received stays
+//! available, as `Delivery::original_payload`, for verifying signatures and
+//! archiving deliveries
+
+use super::{Delivery, Hook};
+use serde_json::{self, Value};
+
+/// A hook handing its inner hook deliveries with transformed payloads
+pub struct Transform<F, H: Hook + 'static> {
+    transform: F,
+    hook: H,
+}
+
+impl<F, H> Transform<F, H>
+where
+    F: Fn(&Delivery) -> Option<Value> + Send + Sync,
+    H: Hook + 'static,
+{
+    /// hands `hook` deliveries with the payload `transform` returns
+    /// for them, or the delivery itself when it returns None
+    pub fn new(transform: F, hook: H) -> Transform<F, H> {
+        Transform {
+            transform: transform,
+            hook: hook,
+        }
+    }
+}
+
+impl<F, H> Hook for Transform<F, H>
+where
+    F: Fn(&Delivery) -> Option<Value> + Send + Sync,
+    H: Hook + 'static,
+{
+    fn handle(&self, delivery: &Delivery) {
+        let transformed = match (self.transform)(delivery) {
+            Some(transformed) => transformed,
+            None => return self.hook.handle(delivery),
+        };
+        match serde_json::to_string(&transformed) {
+            Ok(payload) => self.hook.handle(&delivery.with_payload(&payload)),
+            Err(err) => log_delivery!(
+                error,
+                delivery;
+                "failed to transform delivery {}: {}",
+                delivery.id,
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use AuthenticateHook;
+
+    #[test]
+    fn transform_payloads() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let enrich = |delivery: &Delivery| {
+            let mut payload = delivery.json()?.clone();
+            let count = payload.get("commits")?.as_array()?.len();
+            payload
+                .as_object_mut()?
+                .insert("commit_count".to_owned(), Value::from(count as u64));
+            Some(payload)
+        };
+        // signatures are verified against the original payload
+        let authenticated = AuthenticateHook::new("secret", move |delivery: &Delivery| {
+            let count = delivery
+                .json()
+                .and_then(|payload| payload.get("commit_count"));
+            assert_eq!(Some(&Value::from(2u64)), count);
+            assert_eq!(r#"{"commits":[{},{}]}"#, delivery.original_payload());
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let hook = Transform::new(enrich, authenticated);
+        let signature = "sha1=d86a99e0e5df48382980cf965cd365e39ff9cc53";
+        let push = Delivery::new("id", "push", r#"{"commits":[{},{}]}"#, Some(signature));
+        hook.handle(&push);
+        assert_eq!(1, handled.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,205 @@
+//! Public tunnels to hubs running on development machines
+//!
+//! `Hub::run_tunneled` serves a hub locally and opens a `Tunnel` to it,
+//! returning the public url to register as the webhook url on Github, so real
+//! deliveries reach a laptop without deploying anything
+//!
+//! ```no_run
+//! # use afterparty_ng::{Delivery, Hub};
+//! # use afterparty_ng::tunnel::LocalTunnel;
+//! let mut hub = Hub::new();
+//! hub.handle("push", |delivery: &Delivery| {});
+//! let (handle, url) = hub.run_tunneled("127.0.0.1:0", LocalTunnel::new()).unwrap();
+//! println!("register {} as the webhook url on Github", url);
+//! handle.join();
+//! ```
+
+use super::tls::TlsClient;
+use super::{Hub, HubHandle, Shutdown};
+use hyper;
+use hyper::net::HttpsConnector;
+use hyper::Client;
+use serde_json::{self, Value};
+use std::io::{self, Read};
+use std::net::{self, SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+const LOCALTUNNEL: &str = "https://localtunnel.me";
+
+/// A public endpoint forwarding http requests to a local address
+pub trait Tunnel {
+    /// opens the tunnel to `local`, returning its public url. implementations
+    /// keep forwarding requests after returning, e.g. from their own threads,
+    /// until `shutdown` reports the hub stopped
+    fn open(self, local: SocketAddr, shutdown: Shutdown) -> io::Result<String>;
+}
+
+/// A tunnel through a [localtunnel](https://github.com/localtunnel/server)
+/// server, by default the public `localtunnel.me`
+pub struct LocalTunnel {
+    host: String,
+    subdomain: Option<String>,
+}
+
+impl LocalTunnel {
+    /// tunnels through `localtunnel.me`
+    pub fn new() -> LocalTunnel {
+        LocalTunnel {
+            host: LOCALTUNNEL.to_owned(),
+            subdomain: None,
+        }
+    }
+
+    /// uses a self hosted localtunnel server
+    pub fn host<H: Into<String>>(mut self, host: H) -> LocalTunnel {
+        self.host = host.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// requests a stable subdomain, so the webhook url registered
+    /// on Github survives restarts, if it isn't taken
+    pub fn subdomain<S: Into<String>>(mut self, subdomain: S) -> LocalTunnel {
+        self.subdomain = Some(subdomain.into());
+        self
+    }
+}
+
+impl Default for LocalTunnel {
+    fn default() -> LocalTunnel {
+        LocalTunnel::new()
+    }
+}
+
+impl Tunnel for LocalTunnel {
+    fn open(self, local: SocketAddr, shutdown: Shutdown) -> io::Result<String> {
+        let endpoint = match self.subdomain {
+            Some(ref subdomain) => format!("{}/{}", self.host, subdomain),
+            None => format!("{}/?new", self.host),
+        };
+        let tunnel = request(&endpoint).map_err(|err| invalid(err.to_string()))?;
+        let url = tunnel.get("url").and_then(Value::as_str);
+        let port = tunnel.get("port").and_then(Value::as_u64);
+        let (url, port) = match (url, port) {
+            (Some(url), Some(port)) => (url.to_owned(), port as u16),
+            _ => return Err(invalid(format!("unexpected tunnel {}", tunnel))),
+        };
+        let connections = tunnel
+            .get("max_conn_count")
+            .and_then(Value::as_u64)
+            .unwrap_or(1);
+        let host = Url::parse(&self.host)
+            .ok()
+            .and_then(|host| host.host_str().map(|host| host.to_owned()))
+            .ok_or_else(|| invalid(format!("invalid host {}", self.host)))?;
+        let remote = (&host[..], port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| invalid(format!("failed to resolve {}", host)))?;
+        for _ in 0..connections {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || forward(remote, local, &shutdown));
+        }
+        Ok(url)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+/// requests a tunnel from a localtunnel server
+fn request(endpoint: &str) -> hyper::Result<Value> {
+    let tls = TlsClient::new().map_err(|err| hyper::Error::Ssl(Box::new(err)))?;
+    let client = Client::with_connector(HttpsConnector::new(tls));
+    let mut body = String::new();
+    client.get(endpoint).send()?.read_to_string(&mut body)?;
+    serde_json::from_str(&body).map_err(|err| hyper::Error::Io(invalid(err.to_string())))
+}
+
+/// keeps a connection from the tunnel server to a local address open,
+/// reconnecting as either end closes it until the hub stops
+fn forward(remote: SocketAddr, local: SocketAddr, shutdown: &Shutdown) {
+    while !shutdown.stopped() {
+        if let Err(err) = proxy(remote, local) {
+            warn!("tunnel connection to {} failed: {}", remote, err);
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// copies bytes between a connection from the tunnel
+/// server and one to the local address until either closes
+fn proxy(remote: SocketAddr, local: SocketAddr) -> io::Result<()> {
+    let remote = TcpStream::connect(remote)?;
+    let local = TcpStream::connect(local)?;
+    let (mut remote_reader, mut local_writer) = (remote.try_clone()?, local.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let copied = io::copy(&mut remote_reader, &mut local_writer);
+        let _ = local_writer.shutdown(net::Shutdown::Write);
+        copied
+    });
+    let (mut local_reader, mut remote_writer) = (local, remote);
+    let copied = io::copy(&mut local_reader, &mut remote_writer);
+    let _ = remote_writer.shutdown(net::Shutdown::Both);
+    let _ = upstream.join();
+    copied.map(|_| ())
+}
+
+impl Hub {
+    /// like `run`, additionally opening a tunnel to the hub. returns the
+    /// hub's handle along with the tunnel's public url, to register as the
+    /// webhook url on Github
+    pub fn run_tunneled<A, T>(self, addr: A, tunnel: T) -> hyper::Result<(HubHandle, String)>
+    where
+        A: ToSocketAddrs,
+        T: Tunnel,
+    {
+        let shutdown = self.shutdown_handle();
+        let handle = self.run(addr)?;
+        let url = tunnel.open(handle.addr(), shutdown)?;
+        info!("tunnel to {} opened at {}", handle.addr(), url);
+        Ok((handle, url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use Delivery;
+
+    #[test]
+    fn tunnel_proxies() {
+        let mut hub = Hub::new();
+        hub.handle("ping", |_: &Delivery| {});
+        let shutdown = hub.shutdown_handle();
+        let handle = hub.run("127.0.0.1:0").unwrap();
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (remote, local) = (server.local_addr().unwrap(), handle.addr());
+        let proxied = thread::spawn(move || proxy(remote, local));
+        let (mut tunneled, _) = server.accept().unwrap();
+        let payload = include_str!("../data/ping.json");
+        write!(
+            tunneled,
+            "POST / HTTP/1.1\r\nHost: tunnel\r\nX-Github-Event: ping\r\n\
+             X-Github-Delivery: id\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            payload.len(),
+            payload
+        )
+        .unwrap();
+        let mut response = String::new();
+        tunneled.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        proxied.join().unwrap().unwrap();
+        assert_eq!(1, handle.received());
+
+        // the tunnel server is gone, so forwarding retries until the hub stops
+        drop(server);
+        let forwarding = thread::spawn(move || forward(remote, local, &shutdown));
+        handle.stop(Duration::from_secs(1));
+        forwarding.join().unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! A minimal WebSocket server, streaming broadcast deliveries to clients
+//!
+//! Clients are only ever sent messages. Messages they send, other than
+//! closing the connection, are ignored
+
+use base64;
+use broadcast::Message;
+use crypto;
+use hyper::header::Headers;
+use hyper::server::Response;
+use hyper::status::StatusCode;
+use shutdown::Drain;
+use std::io;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// appended to clients' keys to accept their handshakes, see RFC 6455
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// how often idle connections are pinged, keeping proxies from
+/// timing them out and noticing clients which disconnected
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+
+/// the key of a request to upgrade to a WebSocket, if it is one
+pub fn key(headers: &Headers) -> Option<String> {
+    let upgrade = headers
+        .get_raw("Upgrade")
+        .and_then(|values| values.first())
+        .map(|value| value.eq_ignore_ascii_case(b"websocket"))
+        .unwrap_or(false);
+    if !upgrade {
+        return None;
+    }
+    headers
+        .get_raw("Sec-WebSocket-Key")
+        .and_then(|values| values.first())
+        .and_then(|value| String::from_utf8(value.clone()).ok())
+}
+
+/// the `Sec-WebSocket-Accept` value accepting a client's key
+fn accept(key: &str) -> String {
+    base64::encode(&crypto::sha1(format!("{}{}", key.trim(), GUID).as_bytes()))
+}
+
+/// an unfragmented, unmasked frame, as servers send them
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// completes a client's handshake and sends it messages of the given
+/// events, or all of them, as json text messages until it disconnects
+/// or the hub shuts down
+pub fn stream(
+    mut res: Response,
+    key: &str,
+    messages: &Receiver<Arc<Message>>,
+    events: &[String],
+    drain: &Drain,
+) -> io::Result<()> {
+    *res.status_mut() = StatusCode::SwitchingProtocols;
+    res.headers_mut()
+        .set_raw("Upgrade", vec![b"websocket".to_vec()]);
+    res.headers_mut()
+        .set_raw("Connection", vec![b"Upgrade".to_vec()]);
+    res.headers_mut()
+        .set_raw("Sec-WebSocket-Accept", vec![accept(key).into_bytes()]);
+    // the handshake has no body, so write frames to the connection itself
+    let (_, body, _, _) = res.start()?.deconstruct();
+    let out = body.into_inner();
+    out.flush()?;
+    while drain.accepting() {
+        match messages.recv_timeout(KEEP_ALIVE) {
+            Ok(message) => {
+                if events.is_empty() || events.contains(&message.event) {
+                    out.write_all(&frame(TEXT, message.data.as_bytes()))?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => out.write_all(&frame(PING, b""))?,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        out.flush()?;
+    }
+    out.write_all(&frame(CLOSE, b""))?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::server::{XGithubDelivery, XGithubEvent, XHubSignature};
+    use super::*;
+    use auth::Token;
+    use hex;
+    use hyper::Client;
+    use serde_json::{self, Value};
+    use server::ServerConfig;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use {Delivery, Hub};
+
+    #[test]
+    fn websocket_broadcasts() {
+        // the example handshake of RFC 6455
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+        assert_eq!(vec![0x81, 2, b'h', b'i'], frame(TEXT, b"hi"));
+        assert_eq!(&[0x81, 126, 1, 0], &frame(TEXT, &[0; 256])[..4]);
+
+        let mut hub = Hub::new();
+        hub.handle_authenticated("*", "secret", |_: &Delivery| {});
+        hub.broadcast("/events", Token::bearer("admin"));
+        let config = ServerConfig::new().threads(4);
+        let handle = hub.run_with("127.0.0.1:0", config).unwrap();
+        let mut client = TcpStream::connect(handle.addr()).unwrap();
+        write!(
+            client,
+            "GET /events?event=public HTTP/1.1\r\nHost: hub\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nAuthorization: Bearer admin\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+        let mut client = BufReader::new(client);
+        let mut handshake = Vec::new();
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            handshake.push(line.trim_end().to_owned());
+        }
+        assert_eq!("HTTP/1.1 101 Switching Protocols", handshake[0]);
+        assert!(handshake
+            .iter()
+            .any(|line| line == "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let payload = include_str!("../data/public.json");
+        let signature = crypto::sign_hmac_sha1(b"secret", payload.as_bytes());
+        for event in &["push", "public"] {
+            Client::new()
+                .post(&format!("http://{}/", handle.addr()))
+                .header(XGithubEvent(event.to_string()))
+                .header(XGithubDelivery(format!("{}-id", event)))
+                .header(XHubSignature(format!("sha1={}", hex::encode(&signature))))
+                .body(payload)
+                .send()
+                .unwrap();
+        }
+        let mut head = [0; 4];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!([0x81, 126], [head[0], head[1]]);
+        let mut message = vec![0; u16::from_be_bytes([head[2], head[3]]) as usize];
+        client.read_exact(&mut message).unwrap();
+        let message = serde_json::from_slice::<Value>(&message).unwrap();
+        assert_eq!(Some("public-id"), message.get("id").and_then(Value::as_str));
+        handle.stop(Duration::from_secs(1));
+    }
+}